@@ -1,15 +1,49 @@
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
-use tracing::info;
+use chrono::DateTime;
+use tracing::{error, info, warn};
 
+use crate::cardinality::AtomDistribution;
+use crate::database::{dataset_version_count, get_pool, imported_versions};
+use crate::dataset_lock::DatasetLock;
 use crate::errors::{Error, ParseError};
+use crate::readers::mappings::FieldMappings;
 use crate::readers::meta::Meta;
-use crate::{loggers, upsert_meta, ProgressStream};
+use crate::utils::compare_versions;
+use crate::{loggers, upsert_meta, FrameProgress, ProgressStream};
 
 
+/// The totals of a single file imported from an archive
 #[derive(Debug)]
+struct FileSummary {
+    path: String,
+    import_type: ImportType,
+    expected: Option<u64>,
+    frames: u64,
+    operations: u64,
+    inserted: u64,
+    atoms: AtomDistribution,
+}
+
+
+/// A data file in an archive and where its content starts
+#[derive(Debug)]
+struct Member {
+    path: String,
+    import_type: ImportType,
+    position: u64,
+    size: u64,
+}
+
+
+/// The kind of records in an archive member.
+///
+/// The variants are in the order the types are imported from a seekable archive, which
+/// puts the taxa before the acts and collections that refer to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ImportType {
     Unknown,
     Taxa,
@@ -19,25 +53,69 @@ pub enum ImportType {
     Collections,
     Accessions,
     Sequences,
+    Names,
 }
 
+/// The type of an archive member from its file name.
+///
+/// Members can be nested in directories and their names are matched case insensitively. A
+/// type can also be split across several files by suffixing the name with a part, like
+/// `collections-part1.csv.br`, since the types themselves only use underscores. Each part is
+/// imported on its own like any other file, so it gets its own dataset version.
 impl From<String> for ImportType {
     fn from(value: String) -> Self {
         use ImportType::*;
 
-        match value.as_str() {
-            "taxa.csv.br" => Taxa,
-            "publications.csv.br" => Publications,
-            "taxonomic_acts.csv.br" => TaxonomicActs,
-            "nomenclatural_acts.csv.br" => NomenclaturalActs,
-            "collections.csv.br" => Collections,
-            "accessions.csv.br" => Accessions,
-            "sequences.csv.br" => Sequences,
+        let name = member_name(&value);
+        let Some(stem) = name.strip_suffix(".csv.br")
+        else {
+            return Unknown;
+        };
+        let stem = stem.split_once('-').map(|(stem, _part)| stem).unwrap_or(stem);
+
+        match stem {
+            "taxa" => Taxa,
+            "publications" => Publications,
+            "taxonomic_acts" => TaxonomicActs,
+            "nomenclatural_acts" => NomenclaturalActs,
+            "collections" => Collections,
+            "accessions" => Accessions,
+            "sequences" => Sequences,
+            "names" => Names,
             _ => Unknown,
         }
     }
 }
 
+impl ImportType {
+    /// Returns true if files of the type can be imported. Accessions and sequences are
+    /// recognised but can't be imported from an archive yet
+    pub fn is_supported(&self) -> bool {
+        !matches!(self, ImportType::Unknown | ImportType::Accessions | ImportType::Sequences)
+    }
+
+    /// The file name of the type when it isn't split into parts
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ImportType::Unknown => "",
+            ImportType::Taxa => "taxa.csv.br",
+            ImportType::Publications => "publications.csv.br",
+            ImportType::TaxonomicActs => "taxonomic_acts.csv.br",
+            ImportType::NomenclaturalActs => "nomenclatural_acts.csv.br",
+            ImportType::Collections => "collections.csv.br",
+            ImportType::Accessions => "accessions.csv.br",
+            ImportType::Sequences => "sequences.csv.br",
+            ImportType::Names => "names.csv.br",
+        }
+    }
+}
+
+
+/// The lowercase file name of an archive member without the directories it is nested in
+fn member_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or(path).to_lowercase()
+}
+
 
 pub struct Archive {
     path: PathBuf,
@@ -49,54 +127,404 @@ impl Archive {
     }
 
     pub fn meta(&self) -> Result<Meta, Error> {
+        parse_meta(&self.read_to_string("meta.toml")?)
+    }
+
+    /// Get the field mappings referenced by the dataset meta along with the dataset
+    /// defaults, or no mappings if the dataset doesn't have any overrides
+    pub fn mappings(&self, meta: &Meta) -> Result<FieldMappings, Error> {
+        let mappings = match &meta.dataset.mappings {
+            Some(filename) => Some(self.read_to_string(filename)?),
+            None => None,
+        };
+        parse_mappings(mappings.as_deref(), meta)
+    }
+
+    /// Read a file in the archive into a string
+    fn read_to_string(&self, filename: &str) -> Result<String, Error> {
         let file = File::open(&self.path)?;
         let mut archive = tar::Archive::new(file);
-        let meta_filename = String::from("meta.toml");
 
         for entry in archive.entries_with_seek()? {
             let mut file = entry?;
             let path = file.header().path()?.to_str().unwrap_or_default().to_string();
 
-            if path == meta_filename {
+            if member_name(&path) == member_name(filename) {
                 let mut s = String::new();
                 file.read_to_string(&mut s)?;
-                let meta = toml::from_str(&s).map_err(|err| Error::Parsing(ParseError::Toml(err)))?;
-                return Ok(meta);
+                return Ok(s);
             }
         }
 
-        Err(Error::Parsing(ParseError::FileNotFound(meta_filename)))
+        Err(Error::Parsing(ParseError::FileNotFound(filename.to_string())))
     }
 
-    pub fn import(&self) -> Result<(), Error> {
-        let meta = self.meta()?;
-        info!(name = meta.dataset.short_name, version = meta.dataset.version, "Upserting dataset");
-        upsert_meta(meta.clone())?;
+    /// The amount of files in the archive that will be imported as operation logs
+    pub fn importable_files(&self) -> Result<usize, Error> {
+        Ok(self.members()?.len())
+    }
 
+    /// The data files of the archive in the order they are imported.
+    ///
+    /// Files are grouped by their type, and the parts of a type split across several files
+    /// are sorted by their path in natural order so that `part10` comes after `part9`. The
+    /// order doesn't depend on the order the files were packed in. Files of a type that
+    /// can't be imported are left out with a warning so that they are known before any
+    /// file is imported.
+    fn members(&self) -> Result<Vec<Member>, Error> {
         let file = File::open(&self.path)?;
         let mut archive = tar::Archive::new(file);
+        let mut members = Vec::new();
 
         for entry in archive.entries_with_seek()? {
             let entry = entry?;
             let path = entry.header().path()?.to_str().unwrap_or_default().to_string();
-            let size = entry.header().size()?;
+
             let import_type = ImportType::from(path.clone());
+            match import_type {
+                ImportType::Unknown => {}
+                _ if !import_type.is_supported() => {
+                    warn!(path, ?import_type, "Archive import not supported for the type, skipping")
+                }
+                _ => members.push(Member {
+                    path,
+                    import_type,
+                    position: entry.raw_file_position(),
+                    size: entry.header().size()?,
+                }),
+            }
+        }
+
+        members.sort_by(|a, b| {
+            a.import_type
+                .cmp(&b.import_type)
+                .then_with(|| compare_versions(&a.path.to_lowercase(), &b.path.to_lowercase()))
+        });
+        Ok(members)
+    }
 
-            info!(path, size, ?import_type);
-            let stream = ProgressStream::new(entry, size as usize);
+    /// Returns true if every file in the archive has already been imported for its dataset version.
+    ///
+    /// An import creates a dataset version for each file once it starts importing it, so
+    /// an archive that failed part way through will be imported again.
+    pub fn is_imported(&self, meta: &Meta) -> Result<bool, Error> {
+        let total = self.importable_files()?;
+        let imported = dataset_version_count(&meta.dataset.id, &meta.dataset.version)?;
+        Ok(total > 0 && imported as usize >= total)
+    }
 
-            match import_type {
-                ImportType::Unknown => info!("Unknown type, skipping"),
-                ImportType::Taxa => loggers::taxa::import(stream, &meta.dataset)?,
-                ImportType::Publications => loggers::publications::import_archive(stream, &meta.dataset)?,
-                ImportType::TaxonomicActs => loggers::taxonomic_acts::import(stream, &meta.dataset)?,
-                ImportType::NomenclaturalActs => loggers::nomenclatural_acts::import_archive(stream, &meta.dataset)?,
-                ImportType::Collections => loggers::collections::import_archive(stream, &meta.dataset)?,
-                ImportType::Accessions => todo!(),
-                ImportType::Sequences => todo!(),
+    pub fn import(&self) -> Result<(), Error> {
+        let meta = self.meta()?;
+        let _lock = prepare_import(&meta)?;
+        let mappings = self.mappings(&meta)?;
+
+        let mut summaries = Vec::new();
+        for member in self.members()? {
+            let mut file = File::open(&self.path)?;
+            file.seek(SeekFrom::Start(member.position))?;
+
+            let content = file.take(member.size);
+            if let Some(summary) = import_entry(content, member.size, member.path, &meta, &mappings)? {
+                summaries.push(summary);
             }
         }
 
+        audit(&meta, &summaries);
         Ok(())
     }
 }
+
+
+/// Import an archive as it is streamed in, like from stdin.
+///
+/// A stream can't seek back to read the meta before the data files, so the archive has to
+/// be packed with `meta.toml` first, followed by the mappings file it refers to if it has
+/// one. Every entry is read once in the order it comes in and decompressed as it is
+/// imported, so the archive is never written to disk. A data file that comes before the
+/// meta or the mappings fails the import, as it can't be imported without them. Unlike a
+/// seekable archive the data files are imported in the order they were packed in.
+pub fn import_stream<R: Read>(reader: R) -> Result<(), Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut meta: Option<Meta> = None;
+    let mut mappings: Option<FieldMappings> = None;
+    let mut _lock: Option<DatasetLock> = None;
+    let mut summaries = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.header().path()?.to_str().unwrap_or_default().to_string();
+
+        if member_name(&path) == "meta.toml" {
+            let mut s = String::new();
+            entry.read_to_string(&mut s)?;
+
+            let parsed = parse_meta(&s)?;
+            _lock = Some(prepare_import(&parsed)?);
+            if parsed.dataset.mappings.is_none() {
+                mappings = Some(parse_mappings(None, &parsed)?);
+            }
+            meta = Some(parsed);
+            continue;
+        }
+
+        if let Some(meta) = &meta {
+            if meta.dataset.mappings.as_deref().map(member_name) == Some(member_name(&path)) {
+                let mut s = String::new();
+                entry.read_to_string(&mut s)?;
+                mappings = Some(parse_mappings(Some(&s), meta)?);
+                continue;
+            }
+        }
+
+        let size = entry.header().size()?;
+        let summary = match (&meta, &mappings) {
+            (Some(meta), Some(mappings)) => import_entry(entry, size, path, meta, mappings)?,
+            _ if !ImportType::from(path.clone()).is_supported() => None,
+            _ => return Err(Error::Parsing(ParseError::StreamOrder(path))),
+        };
+        summaries.extend(summary);
+    }
+
+    match meta {
+        Some(meta) => audit(&meta, &summaries),
+        None => return Err(Error::Parsing(ParseError::FileNotFound("meta.toml".to_string()))),
+    }
+    Ok(())
+}
+
+
+fn parse_meta(s: &str) -> Result<Meta, Error> {
+    let meta = toml::from_str(s).map_err(|err| Error::Parsing(ParseError::Toml(err)))?;
+    Ok(meta)
+}
+
+/// Parse the mappings file, if the dataset has one, and add the dataset defaults to it
+fn parse_mappings(s: Option<&str>, meta: &Meta) -> Result<FieldMappings, Error> {
+    let mappings: FieldMappings = match s {
+        Some(s) => toml::from_str(s).map_err(|err| Error::Parsing(ParseError::Toml(err)))?,
+        None => FieldMappings::default(),
+    };
+
+    Ok(mappings
+        .with_defaults(meta.dataset.defaults.clone())
+        .with_unknowns(meta.dataset.unknowns.clone()))
+}
+
+
+/// Lock the dataset of the archive and upsert its meta once its dependencies are checked.
+/// The lock is held until the returned guard is dropped.
+fn prepare_import(meta: &Meta) -> Result<DatasetLock, Error> {
+    let lock = DatasetLock::acquire(&get_pool()?, &meta.dataset.id)?;
+    check_dependencies(meta)?;
+
+    info!(name = meta.dataset.short_name, version = meta.dataset.version, "Upserting dataset");
+    upsert_meta(meta.clone())?;
+    Ok(lock)
+}
+
+
+/// Import a single file of an archive, returning its totals if it is a file that gets imported
+fn import_entry<R: Read>(
+    content: R,
+    size: u64,
+    path: String,
+    meta: &Meta,
+    mappings: &FieldMappings,
+) -> Result<Option<FileSummary>, Error> {
+    let import_type = ImportType::from(path.clone());
+
+    info!(path, size, ?import_type);
+    let stream = ProgressStream::new(content, size as usize);
+    let bars = stream.bars();
+
+    match import_type {
+        ImportType::Unknown => {
+            info!("Unknown type, skipping");
+            return Ok(None);
+        }
+        ImportType::Taxa => loggers::taxa::import(stream, &meta.dataset, mappings)?,
+        ImportType::Publications => loggers::publications::import_archive(stream, &meta.dataset, mappings)?,
+        ImportType::TaxonomicActs => loggers::taxonomic_acts::import(stream, &meta.dataset, mappings)?,
+        ImportType::NomenclaturalActs => loggers::nomenclatural_acts::import_archive(stream, &meta.dataset, mappings)?,
+        ImportType::Collections => loggers::collections::import_archive(stream, &meta.dataset, mappings)?,
+        ImportType::Accessions | ImportType::Sequences => {
+            warn!("Archive import not supported for the type, skipping");
+            return Ok(None);
+        }
+        ImportType::Names => loggers::names::import_archive(stream, mappings)?,
+    }
+
+    Ok(Some(FileSummary {
+        expected: meta.expected_rows(&member_name(&path)),
+        path,
+        import_type,
+        frames: bars.frames.position(),
+        operations: bars.operations.position(),
+        inserted: bars.inserted.position(),
+        atoms: bars.atoms.distribution(),
+    }))
+}
+
+
+/// Check that the datasets the archive depends on have been imported.
+///
+/// A dependency is met when any imported version of the dataset is at least the minimum
+/// version, compared in natural order. Optional dependencies that aren't met are only
+/// reported so that the archive can still be imported ahead of its dependency.
+fn check_dependencies(meta: &Meta) -> Result<(), Error> {
+    for dependency in &meta.dependencies {
+        let versions = imported_versions(&dependency.dataset_id)?;
+        let latest = versions.iter().max_by(|a, b| compare_versions(a, b));
+
+        let met = match (&dependency.min_version, latest) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(min_version), Some(latest)) => compare_versions(latest, min_version).is_ge(),
+        };
+
+        let required = match &dependency.min_version {
+            Some(min_version) => format!("{} version {min_version} or later", dependency.dataset_id),
+            None => dependency.dataset_id.clone(),
+        };
+
+        match met {
+            true => info!(dependency = dependency.dataset_id, latest, "Dataset dependency met"),
+            false if dependency.optional => warn!(dependency = required, latest, "Optional dataset dependency not met"),
+            false => return Err(Error::MissingDependency(meta.dataset.id.clone(), required)),
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Compare the rows imported from each file with the counts declared by the provider.
+///
+/// A file with fewer rows than declared is most likely a truncated upload, but either way
+/// the operation logs will be missing or have extra data so every discrepancy is flagged.
+/// A type split into parts can declare the count of each part or the total of the type
+/// under its unsplit name. Files that are declared but weren't in the archive are flagged as well.
+fn audit(meta: &Meta, summaries: &[FileSummary]) {
+    let mut discrepancies = 0;
+    let mut parts: BTreeMap<ImportType, (u64, u64, u64, bool)> = BTreeMap::new();
+
+    for summary in summaries {
+        let FileSummary {
+            path,
+            import_type,
+            expected,
+            frames,
+            operations,
+            inserted,
+            atoms,
+        } = summary;
+
+        match expected {
+            Some(expected) if expected != frames => {
+                warn!(path, expected, rows = frames, operations, inserted, "Row count does not match meta.toml");
+                discrepancies += 1;
+            }
+            _ => info!(
+                path,
+                ?expected,
+                rows = frames,
+                operations,
+                inserted,
+                atoms_median = atoms.median,
+                atoms_p95 = atoms.p95,
+                atoms_max = atoms.max,
+                "Imported file"
+            ),
+        }
+
+        let totals = parts.entry(*import_type).or_default();
+        totals.0 += frames;
+        totals.1 += operations;
+        totals.2 += inserted;
+        totals.3 |= member_name(path) != import_type.file_name();
+    }
+
+    // the unsplit name was already audited above unless the type came in parts
+    for (import_type, (frames, operations, inserted, split)) in parts {
+        let filename = import_type.file_name();
+        match meta.expected_rows(filename) {
+            Some(expected) if split && expected != frames => {
+                warn!(filename, expected, rows = frames, operations, inserted, "Row count does not match meta.toml");
+                discrepancies += 1;
+            }
+            _ => {}
+        }
+    }
+
+    for filename in meta.dataset.expected_rows.keys() {
+        let filename = filename.to_lowercase();
+        let imported = summaries.iter().any(|summary| {
+            let name = member_name(&summary.path);
+            let logical = summary.import_type.file_name();
+            [name.as_str(), logical].iter().any(|name| *name == filename || name.trim_end_matches(".br") == filename)
+        });
+        if !imported {
+            warn!(filename, "File declared in meta.toml was not imported");
+            discrepancies += 1;
+        }
+    }
+
+    match discrepancies {
+        0 => info!(files = summaries.len(), "Import audit passed"),
+        total => warn!(files = summaries.len(), discrepancies = total, "Import audit found discrepancies"),
+    }
+}
+
+
+/// Import every archive in a directory in the order they were published.
+///
+/// Operation logs use the dataset version to resolve conflicts so archives have to be
+/// imported in the order they were published rather than the order they arrived in.
+/// Archives that have already been fully imported are skipped, and because the order
+/// matters the import stops at the first archive that fails.
+pub fn import_all(dir: &Path) -> Result<(), Error> {
+    let mut archives = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let archive = Archive::new(path.clone());
+        match archive.meta() {
+            Ok(meta) => {
+                // compare the instants rather than the strings since the offsets can differ between archives
+                let published_at = DateTime::parse_from_rfc3339(&meta.dataset.published_at.to_string())
+                    .map_err(ParseError::from)?
+                    .to_utc();
+                archives.push((published_at, meta, archive));
+            }
+            Err(err) => warn!(?path, ?err, "Not a dataset archive, skipping"),
+        }
+    }
+
+    archives.sort_by_key(|(published_at, _, _)| *published_at);
+    info!(total = archives.len(), "Found dataset archives");
+
+    for (_, meta, archive) in archives {
+        let name = &meta.dataset.short_name;
+        let version = &meta.dataset.version;
+        let path = &archive.path;
+
+        if archive.is_imported(&meta)? {
+            info!(name, version, ?path, status = "skipped", "Archive already imported");
+            continue;
+        }
+
+        match archive.import() {
+            Ok(()) => info!(name, version, ?path, status = "imported", "Archive imported"),
+            Err(err) => {
+                error!(name, version, ?path, status = "failed", ?err, "Archive import failed");
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
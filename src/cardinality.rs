@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use diesel::*;
+use tracing::{info, warn};
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+/// The amount of previous imports into a logger that its norm is taken from
+const HISTORY: i64 = 20;
+
+/// How many times the usual 95th percentile an import can reach before it is flagged
+const TOLERANCE: f64 = 2.0;
+
+
+// cardinalities aren't part of arga_core as they are only used by the oplogger to spot
+// mappings that explode. the logger is the log table that the operations were imported into
+diesel::table! {
+    atom_cardinality (logger, recorded_at) {
+        logger -> Varchar,
+        recorded_at -> Timestamptz,
+        frames -> Int8,
+        operations -> Int8,
+        median -> Int4,
+        p95 -> Int4,
+        max -> Int4,
+    }
+}
+
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = atom_cardinality)]
+struct CardinalityRow {
+    logger: String,
+    recorded_at: DateTime<Utc>,
+    frames: i64,
+    operations: i64,
+    median: i32,
+    p95: i32,
+    max: i32,
+}
+
+
+/// A summary of the amount of atoms in each frame of an import
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AtomDistribution {
+    pub frames: u64,
+    pub operations: u64,
+    pub median: usize,
+    pub p95: usize,
+    pub max: usize,
+}
+
+
+/// The amount of atoms each frame of an import decomposed into.
+///
+/// A row usually becomes a handful of atoms, one for each column with a value. A mapping that
+/// goes wrong, like one that turns every value of a record into its own set of atoms, can
+/// multiply that many times over and bloat the logs without failing the import. The counts are
+/// shared by every clone so the parser can record them while the summary is read elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct AtomCardinality {
+    counts: Arc<Mutex<BTreeMap<usize, u64>>>,
+}
+
+impl AtomCardinality {
+    /// Count the atoms of each frame in a chunk
+    pub fn record(&self, atoms_per_frame: &[usize]) {
+        let mut counts = self.counts.lock().expect("Atom cardinality lock poisoned");
+        for atoms in atoms_per_frame {
+            *counts.entry(*atoms).or_default() += 1;
+        }
+    }
+
+    pub fn distribution(&self) -> AtomDistribution {
+        let counts = self.counts.lock().expect("Atom cardinality lock poisoned");
+        let frames: u64 = counts.values().sum();
+        let operations: u64 = counts.iter().map(|(atoms, total)| *atoms as u64 * total).sum();
+
+        let percentile = |fraction: f64| {
+            let rank = (frames as f64 * fraction).ceil() as u64;
+            let mut seen = 0;
+            for (atoms, total) in counts.iter() {
+                seen += total;
+                if seen >= rank {
+                    return *atoms;
+                }
+            }
+            0
+        };
+
+        AtomDistribution {
+            frames,
+            operations,
+            median: percentile(0.5),
+            p95: percentile(0.95),
+            max: counts.keys().last().copied().unwrap_or_default(),
+        }
+    }
+
+    /// Compare the distribution with the previous imports into the logger and store it with them.
+    ///
+    /// The norm is the median 95th percentile of the last imports into the same logs. An import
+    /// well above it is warned about rather than rejected since a dataset can legitimately carry
+    /// more columns than the others. Imports where every row was skipped aren't stored so they
+    /// don't drag the norm down.
+    pub fn check(&self, pool: &PgPool, logger: &str) -> Result<AtomDistribution, Error> {
+        use atom_cardinality::dsl;
+
+        let distribution = self.distribution();
+        let AtomDistribution {
+            frames,
+            operations,
+            median,
+            p95,
+            max,
+        } = distribution;
+
+        info!(logger, frames, operations, median, p95, max, "Atoms per frame");
+        if frames == 0 {
+            return Ok(distribution);
+        }
+
+        let mut conn = pool.get()?;
+        create_atom_cardinality_table(&mut conn)?;
+
+        let mut history = dsl::atom_cardinality
+            .filter(dsl::logger.eq(logger))
+            .order(dsl::recorded_at.desc())
+            .limit(HISTORY)
+            .select(dsl::p95)
+            .load::<i32>(&mut conn)?;
+        history.sort();
+
+        if let Some(norm) = history.get(history.len() / 2).copied() {
+            if p95 as f64 > norm as f64 * TOLERANCE {
+                warn!(
+                    logger,
+                    p95,
+                    norm,
+                    imports = history.len(),
+                    "Atoms per frame are well above the norm for the logger"
+                );
+            }
+        }
+
+        diesel::insert_into(dsl::atom_cardinality)
+            .values(CardinalityRow {
+                logger: logger.to_string(),
+                recorded_at: Utc::now(),
+                frames: frames as i64,
+                operations: operations as i64,
+                median: median as i32,
+                p95: p95 as i32,
+                max: max as i32,
+            })
+            .execute(&mut conn)?;
+
+        Ok(distribution)
+    }
+}
+
+
+fn create_atom_cardinality_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS atom_cardinality (
+            logger varchar NOT NULL,
+            recorded_at timestamptz NOT NULL,
+            frames bigint NOT NULL,
+            operations bigint NOT NULL,
+            median integer NOT NULL,
+            p95 integer NOT NULL,
+            max integer NOT NULL,
+            PRIMARY KEY (logger, recorded_at)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,1053 @@
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use arga_core::models::DatasetVersion;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, ValueEnum};
+use tracing::{error, warn};
+
+use crate::database::{create_dataset_version, create_file_dataset_version, get_pool, name_lookup, AsOf};
+use crate::dataset_lock::DatasetLock;
+use crate::errors::{Error, ErrorSummary, ParseError};
+use crate::graph::GraphFormat;
+use crate::journal::Journal;
+use crate::links::LinkStage;
+use crate::loggers::datasets::Datasets;
+use crate::loggers::names::Names;
+use crate::loggers::sources::Sources;
+use crate::loggers::*;
+use crate::maintenance::LegacyTable;
+use crate::output::{Compression, ExportFormat, PrintFormat};
+use crate::precedence::AtomPrecedence;
+use crate::readers::analyze::analyze_file;
+use crate::readers::describe::{self, SchemaFormat};
+use crate::readers::institutions::InstitutionRegistry;
+use crate::readers::mappings::FieldMappings;
+use crate::readers::sensitivity::SensitivityList;
+use crate::readers::xlsx::SheetOptions;
+use crate::readers::{abcd, plazi};
+use crate::updates::UpdateStage;
+use crate::utils::ProgressMode;
+use crate::{
+    archive,
+    concept_links,
+    determinism,
+    entity_views,
+    graph,
+    links,
+    maintenance,
+    output,
+    precedence,
+    push,
+    relink,
+    schema_check,
+    updates,
+    utils,
+    vocabulary,
+    warnings,
+};
+
+/// The ARGA operation logger
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Skip comparing the arga_core schema with the live database before running the command
+    #[arg(long, global = true)]
+    skip_schema_check: bool,
+
+    /// Don't draw progress bars, logging plain text progress lines instead
+    #[arg(long, global = true)]
+    no_progress: bool,
+
+    /// Don't report any progress
+    #[arg(long, global = true)]
+    quiet: bool,
+
+    /// The separators used to split list columns like collected_by into individual values
+    #[arg(long, global = true, default_values = [";", "|"])]
+    list_separator: Vec<String>,
+
+    /// Print a JSON summary of the errors to stderr when the command finishes
+    #[arg(long, global = true)]
+    json_errors: bool,
+
+    /// Also write JSON logs to this file, rotating it daily. The date is appended to the file name
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Write every warning logged by the command to this CSV, along with the fields logged with it
+    #[arg(long, global = true)]
+    warnings_csv: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Progress bars are only drawn when stderr is a terminal, otherwise they fill
+    /// logs with control characters when running under nohup or in CI
+    fn progress_mode(&self) -> ProgressMode {
+        if self.quiet {
+            ProgressMode::Hidden
+        }
+        else if self.no_progress || !std::io::stderr().is_terminal() {
+            ProgressMode::Plain
+        }
+        else {
+            ProgressMode::Bars
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum Commands {
+    /// Process and import an ARGA dataset archive as operation logs
+    Import {
+        /// The archive to import, a directory of archives when using --all, or - to read the archive from stdin
+        path: PathBuf,
+        /// Import every archive in the directory in the order they were published
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Process and import a csv as operation logs
+    #[command(subcommand)]
+    ImportFile(ImportCommand),
+
+    /// Reduce operation logs and output as an ARGA CSV
+    #[command(subcommand)]
+    Reduce(ReduceCommand),
+
+    /// Update the database with the latest reduced data
+    Update {
+        /// Copy the updated tables into the ARGA web database at this url once the update finishes
+        #[arg(long, global = true)]
+        push: Option<String>,
+        /// Replace the stored TOML file of the datasets to trust for specific atoms, overriding the latest change
+        #[arg(long, global = true)]
+        trust_rules: Option<PathBuf>,
+        /// Record the rank and nomenclatural code of each name that its taxa agree on once the taxa are updated
+        #[arg(long, global = true)]
+        classify_names: bool,
+        /// Replace the stored CSV list of sensitive taxa whose coordinates are generalized in every output
+        #[arg(long, global = true)]
+        sensitive_taxa: Option<PathBuf>,
+        #[command(subcommand)]
+        table: UpdateCommand,
+    },
+
+    /// Link records with the latest reduced data
+    #[command(subcommand)]
+    Link(LinkCommand),
+
+    /// Rebuild the name links of a re-imported taxonomy dataset in bulk
+    Relink {
+        /// The global id of the taxonomy dataset that was re-imported
+        #[arg(long)]
+        scope: String,
+    },
+
+    /// Specific commands for the plazi treatment bank dataset
+    #[command(subcommand)]
+    Plazi(PlaziCommand),
+
+    /// Infer nomenclatural acts from the authorship of the reduced taxa and import them as operation logs
+    InferActs(InferActsArgs),
+
+    /// Run a reduction twice and report any entities that reduce to a different output
+    VerifyDeterminism {
+        /// The logs to reduce
+        #[arg(long)]
+        table: ReduceTable,
+        /// The amount of threads to use for the second reduction
+        #[arg(long)]
+        parallelism: Option<usize>,
+    },
+
+    /// Create or refresh the entity views used to page through the log tables during an update
+    RefreshEntityViews,
+
+    /// Reduce and print matching entities straight from the logs without updating anything
+    #[command(subcommand)]
+    Query(QueryCommand),
+
+    /// Tidy up the provenance tables
+    #[command(subcommand)]
+    Maintenance(MaintenanceCommand),
+
+    /// Compare the reduced taxa with an external backbone CSV and output the differences as a CSV
+    CompareBackbone {
+        /// The backbone CSV with canonical name, authorship, rank and status columns
+        path: PathBuf,
+        /// The dataset ids to prefer when merging the reduced taxa, from highest to lowest precedence
+        #[arg(long, value_delimiter = ',')]
+        precedence: Vec<String>,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+
+    /// Export records in formats meant for other tools
+    #[command(subcommand)]
+    Export(ExportCommand),
+
+    /// Describe the formats that the importer accepts
+    #[command(subcommand)]
+    Describe(DescribeCommand),
+
+    /// Count the distinct values of an atom across a log table and output them as a CSV
+    AnalyzeVocabulary {
+        /// The log table to aggregate. eg (specimen_logs, sequence_logs)
+        #[arg(long)]
+        table: String,
+        /// The name of the atom to count values of. eg (Preparation, Sex)
+        #[arg(long)]
+        atom: String,
+    },
+}
+
+impl Commands {
+    /// The dataset that the command imports into or changes, if it only affects one dataset.
+    ///
+    /// Archive imports read the dataset from the archive meta and lock it themselves.
+    fn dataset_scope(&self) -> Option<&str> {
+        match self {
+            Commands::ImportFile(cmd) => match cmd {
+                _ if cmd.analyzes() => None,
+                ImportCommand::TaxonomicActs(args)
+                | ImportCommand::NomenclaturalActs(args)
+                | ImportCommand::Collections(args)
+                | ImportCommand::Sequences(args) => Some(&args.import.dataset_id),
+                ImportCommand::Taxa(args) | ImportCommand::Abcd(args) => Some(&args.dataset_id),
+                ImportCommand::Curation(args) => Some(&args.dataset_id),
+                ImportCommand::Sources { .. } | ImportCommand::Datasets { .. } | ImportCommand::Names { .. } => None,
+            },
+            Commands::Plazi(PlaziCommand::Import(args)) => Some(&args.dataset_id),
+            Commands::InferActs(args) => Some(&args.dataset_id),
+            Commands::Relink { scope } => Some(scope),
+            _ => None,
+        }
+    }
+
+    /// Whether the command connects to the database at all.
+    ///
+    /// Describing the import schemas and analysing a file only read the file, so they can be
+    /// run without a database and aren't journaled.
+    fn uses_database(&self) -> bool {
+        match self {
+            Commands::Describe(_) => false,
+            Commands::ImportFile(cmd) => !cmd.analyzes(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum QueryCommand {
+    /// Reduce the taxa matching an entity id or a name
+    Taxa {
+        /// The entity id of the taxon in the taxa logs
+        #[arg(long, required_unless_present = "name")]
+        entity: Option<String>,
+        /// The scientific or canonical name of the taxon
+        #[arg(long)]
+        name: Option<String>,
+        /// How to print the reduced taxa
+        #[arg(long, value_enum, default_value_t = PrintFormat::Table)]
+        format: PrintFormat,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum ExportCommand {
+    /// Output the derivation chain of an organism or specimen as a graph of nodes and edges
+    Graph {
+        /// The organism id or specimen entity id to start the chain from
+        #[arg(long)]
+        entity: String,
+        /// How to write the graph
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum DescribeCommand {
+    /// Print the columns of an import CSV with their types, optionality and accepted values
+    Schema {
+        #[arg(value_enum)]
+        table: SchemaTable,
+        /// How to write the columns
+        #[arg(long, value_enum, default_value_t = SchemaFormat::Markdown)]
+        format: SchemaFormat,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum MaintenanceCommand {
+    /// Remove dataset versions that no operation log refers to
+    DatasetVersions {
+        /// List the dataset versions that would be removed without removing them
+        #[arg(long)]
+        dry_run: bool,
+        /// Also remove datasets that are left without any versions
+        #[arg(long)]
+        datasets: bool,
+    },
+    /// Remove operations that set a field to an empty value
+    CleanLogs {
+        /// Count the operations that would be removed without removing them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Merge names that only differ in case or whitespace and repoint their references
+    MergeNames {
+        /// List the names that would be merged without merging them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Merge publications with the same DOI, or the same title and year, and repoint their references
+    MergePublications {
+        /// List the publications that would be merged without merging them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Recompute the entity ids of reduced rows inserted before the entity model
+    BackfillEntityIds {
+        #[arg(value_enum)]
+        table: LegacyTable,
+        /// Count the rows that would be backfilled without updating them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReduceTable {
+    Taxa,
+    TaxonomicActs,
+    NomenclaturalActs,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SchemaTable {
+    Taxa,
+    TaxonomicActs,
+    NomenclaturalActs,
+    Collections,
+    Sequences,
+    Sources,
+    Datasets,
+    Names,
+}
+
+#[derive(Args)]
+pub struct DefaultImportArgs {
+    /// The global identifier describing the dataset
+    dataset_id: String,
+    /// The version of this dataset. eg (v4, 20240102, abf839sfa0939faz204).
+    /// Use `auto` to derive it from the file content
+    version: String,
+    /// The timestamp of when this dataset version was created. in yyyy-mm-dd hh:mm:ss format
+    created_at: String,
+    /// The path to the file or directory to import as operation logs
+    path: PathBuf,
+
+    /// Append every operation in the file as-is without merging it with the existing logs. For forensics only
+    #[arg(long, requires = "confirm_no_merge")]
+    no_merge: bool,
+    /// Confirm a --no-merge import by repeating the dataset id
+    #[arg(long)]
+    confirm_no_merge: Option<String>,
+}
+
+impl DefaultImportArgs {
+    /// Create the dataset version for the import and record the hash of the imported content.
+    ///
+    /// A --no-merge import has to be confirmed with the dataset id it appends to, and marks the
+    /// version it creates so that the raw operations can be found and removed later.
+    fn dataset_version(&self) -> Result<DatasetVersion, Error> {
+        if self.no_merge {
+            if self.confirm_no_merge.as_ref() != Some(&self.dataset_id) {
+                return Err(Error::UnconfirmedNoMerge(self.dataset_id.clone()));
+            }
+            warn!(dataset_id = self.dataset_id, "Appending every operation without merging with the existing logs");
+            set_no_merge();
+        }
+
+        create_file_dataset_version(&self.dataset_id, &self.version, &self.created_at, &self.path)
+    }
+}
+
+/// The arguments of the imports that read a single CSV or spreadsheet file row by row
+#[derive(Args)]
+pub struct FileImportArgs {
+    #[command(flatten)]
+    import: DefaultImportArgs,
+
+    #[command(flatten)]
+    sheet: SheetArgs,
+
+    /// Sample the file and report values that can't be parsed instead of importing it
+    #[arg(long)]
+    analyze: bool,
+
+    /// Only import rows with a timestamp in this column that isn't older than the last import of the dataset
+    #[arg(long)]
+    since_file: Option<String>,
+
+    /// A TOML file of the values that mean a column was recorded but is unknown, keyed by column name
+    #[arg(long)]
+    unknowns: Option<PathBuf>,
+}
+
+impl FileImportArgs {
+    /// The field mappings of the file, which only has the unknown sentinels when they are given
+    fn mappings(&self) -> Result<FieldMappings, Error> {
+        let unknowns = match &self.unknowns {
+            Some(path) => {
+                let s = std::fs::read_to_string(path)?;
+                toml::from_str(&s).map_err(|err| Error::Parsing(ParseError::Toml(err)))?
+            }
+            None => HashMap::new(),
+        };
+        Ok(FieldMappings::default().with_unknowns(unknowns))
+    }
+}
+
+#[derive(Args)]
+pub struct CurationArgs {
+    /// The global identifier of the curation dataset the edits are attributed to
+    dataset_id: String,
+    /// The version of the curation. Use `auto` to derive it from the file content
+    version: String,
+    /// The timestamp of when the curation was made. in yyyy-mm-dd hh:mm:ss format
+    created_at: String,
+    /// The path to the edited CSV from `reduce taxa`
+    path: PathBuf,
+    /// The point the CSV was reduced at, which the edits are compared with. Either a timestamp or a dataset version id
+    #[arg(long)]
+    base: AsOf,
+}
+
+#[derive(Args)]
+pub struct SheetArgs {
+    /// The name of the sheet to import from a spreadsheet. Defaults to the first sheet
+    #[arg(long)]
+    sheet: Option<String>,
+    /// The row number of the column headers in a spreadsheet. Rows above it are ignored
+    #[arg(long, default_value_t = 1)]
+    header_row: usize,
+}
+
+impl SheetArgs {
+    fn options(&self) -> SheetOptions {
+        SheetOptions {
+            sheet: self.sheet.clone(),
+            header_row: self.header_row.saturating_sub(1),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct InferActsArgs {
+    /// The global identifier of the dataset used to attribute inferred acts
+    dataset_id: String,
+    /// The version of the inference. eg (v1, 20240102)
+    version: String,
+    /// The timestamp of when the inference was made. in yyyy-mm-dd hh:mm:ss format
+    created_at: String,
+}
+
+#[derive(clap::Subcommand)]
+pub enum ImportCommand {
+    /// Import taxa from a CSV dataset
+    Taxa(DefaultImportArgs),
+
+    /// Import taxonomic acts from a CSV or spreadsheet dataset
+    TaxonomicActs(FileImportArgs),
+
+    /// Import nomenclatural acts from a CSV or spreadsheet dataset
+    NomenclaturalActs(FileImportArgs),
+
+    /// Import collections from a CSV or spreadsheet dataset
+    Collections(FileImportArgs),
+
+    /// Import collections from an ABCD XML document or a directory of them
+    Abcd(DefaultImportArgs),
+
+    /// Import sequences from a CSV or spreadsheet dataset
+    Sequences(FileImportArgs),
+
+    /// Import curated edits of a reduced taxa CSV as operations of a curation dataset
+    Curation(CurationArgs),
+
+    /// Import sources from a CSV dataset
+    Sources { path: PathBuf },
+
+    /// Import datasets from a CSV dataset
+    Datasets {
+        path: PathBuf,
+        /// Check that every DOI resolves at doi.org. This makes a request for each dataset with a DOI
+        #[arg(long)]
+        check_doi: bool,
+    },
+
+    /// Import names from a names CSV, parsing the authorship out of the scientific name if needed
+    Names { path: PathBuf },
+}
+
+impl ImportCommand {
+    /// Whether the command only analyzes the file instead of importing it
+    fn analyzes(&self) -> bool {
+        match self {
+            ImportCommand::TaxonomicActs(args)
+            | ImportCommand::NomenclaturalActs(args)
+            | ImportCommand::Collections(args)
+            | ImportCommand::Sequences(args) => args.analyze,
+            _ => false,
+        }
+    }
+
+    /// Sample the file and report the values the import can't parse
+    fn analyze(&self) -> Result<(), Error> {
+        match self {
+            ImportCommand::TaxonomicActs(args) => {
+                analyze_file::<taxonomic_acts::Record>(&args.import.path, args.mappings()?)
+            }
+            ImportCommand::NomenclaturalActs(args) => {
+                analyze_file::<nomenclatural_acts::Record>(&args.import.path, args.mappings()?)
+            }
+            ImportCommand::Collections(args) => {
+                analyze_file::<collections::Record>(&args.import.path, args.mappings()?)
+            }
+            ImportCommand::Sequences(args) => analyze_file::<sequences::Record>(&args.import.path, args.mappings()?),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum ReduceCommand {
+    /// Reduce taxa logs into a CSV
+    Taxa {
+        #[command(flatten)]
+        args: ReduceArgs,
+        /// Merge the taxa from every dataset into a single row per name
+        #[arg(long)]
+        consensus: bool,
+        /// The dataset ids to prefer when merging, from highest to lowest precedence
+        #[arg(long, value_delimiter = ',', requires = "consensus")]
+        precedence: Vec<String>,
+    },
+    /// Reduce taxonomic act logs into a CSV
+    TaxonomicActs(ReduceArgs),
+    /// Reduce nomenclatural act logs into a CSV
+    NomenclaturalActs(ReduceArgs),
+    /// Reduce publication logs into a CSV
+    Publications(ReduceArgs),
+    /// Reduce specimen logs into a CSV
+    Specimens(ReduceArgs),
+    /// Reduce sequence logs into a CSV of their foreign references and whether they resolve
+    SequenceLinks(ReduceArgs),
+    /// Export the source registry with its licenses, rights and content types
+    Sources {
+        /// How to write the exported sources
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        #[command(flatten)]
+        args: ReduceArgs,
+    },
+    /// Export the datasets with their source, rights, content types and attribution details
+    Datasets {
+        /// How to write the exported datasets
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        #[command(flatten)]
+        args: ReduceArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct ReduceArgs {
+    /// Only reduce operations imported at or before this point. Either a timestamp or a dataset version id
+    #[arg(long)]
+    as_of: Option<AsOf>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+pub struct OutputArgs {
+    /// Compress the output as it is written
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+}
+
+impl ReduceArgs {
+    fn cutoff(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        self.as_of.as_ref().map(|as_of| as_of.cutoff()).transpose()
+    }
+}
+
+#[derive(Args)]
+pub struct UpdateArgs {
+    /// Trace the reduction of a single entity from its operations to the row that would be upserted, without updating
+    #[arg(long)]
+    explain_entity: Option<String>,
+    /// Write every entity instead of skipping the ones that haven't changed since the last update
+    #[arg(long)]
+    full: bool,
+}
+
+#[derive(clap::Subcommand)]
+pub enum UpdateCommand {
+    /// Update the taxa with the reduced logs
+    Taxa(UpdateArgs),
+    /// Update taxonomic acts with the reduced logs
+    TaxonomicActs(UpdateArgs),
+    /// Update nomenclatural acts with the reduced logs
+    NomenclaturalActs,
+    /// Update publications with the reduced logs
+    Publications,
+    /// Update collections with the reduced logs
+    Collections {
+        /// A CSV registry of institution codes to normalize against. eg. GRSciColl codes
+        #[arg(long)]
+        institutions: Option<PathBuf>,
+    },
+    /// Update all tables in dependency order, running independent tables concurrently
+    All {
+        /// The maximum amount of tables to update at the same time
+        #[arg(long, default_value_t = 2)]
+        parallelism: usize,
+    },
+}
+
+impl UpdateCommand {
+    /// The update stages that wrote to the database. Explaining an entity doesn't write anything
+    fn stages(&self) -> Vec<UpdateStage> {
+        match self {
+            UpdateCommand::Taxa(args) if args.explain_entity.is_none() => vec![UpdateStage::Taxa],
+            UpdateCommand::TaxonomicActs(args) if args.explain_entity.is_none() => vec![UpdateStage::TaxonomicActs],
+            UpdateCommand::Taxa(_) | UpdateCommand::TaxonomicActs(_) => vec![],
+            UpdateCommand::NomenclaturalActs => vec![UpdateStage::NomenclaturalActs],
+            UpdateCommand::Publications => vec![UpdateStage::Publications],
+            UpdateCommand::Collections { .. } => vec![UpdateStage::Collections],
+            UpdateCommand::All { .. } => UpdateStage::all(),
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum LinkCommand {
+    /// Link the name variants to the names of their correct spelling
+    Names,
+    /// Link the taxa with the reduced logs
+    Taxa,
+    /// Link the taxa of different datasets that describe the same concept
+    Concepts {
+        /// A CSV of curated links between the scientific names of two datasets to store before matching by name
+        #[arg(long)]
+        mapping: Option<PathBuf>,
+    },
+    /// Run all links in dependency order
+    All {
+        /// Only run these links
+        #[arg(long, value_enum, value_delimiter = ',')]
+        only: Vec<LinkStage>,
+        /// Don't run these links
+        #[arg(long, value_enum, value_delimiter = ',')]
+        skip: Vec<LinkStage>,
+    },
+}
+
+
+#[derive(clap::Subcommand)]
+pub enum PlaziCommand {
+    /// Transform and import plazi treatment bank xml files from a directory, zip, or tar archive
+    Import(DefaultImportArgs),
+}
+
+
+/// Log to the console and optionally to a rotating JSON file for machine readable run logs
+fn init_tracing(log_file: Option<&PathBuf>) {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    let file_layer = log_file.map(|path| {
+        let directory = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let prefix = path.file_name().unwrap_or(path.as_os_str());
+        let appender = tracing_appender::rolling::daily(directory, prefix);
+
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_ansi(false)
+            .with_writer(appender)
+    });
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(file_layer)
+        .with(warnings::WarningsLayer)
+        .with(LevelFilter::INFO)
+        .init();
+}
+
+
+/// Run the command and exit with a code for the category of error that occurred.
+///
+/// The exit codes are listed in `ErrorCategory`. A command that skipped records because of
+/// errors but otherwise finished exits with the partial success code.
+pub fn main() -> ExitCode {
+    dotenvy::dotenv().ok();
+
+    let cli = Cli::parse();
+    init_tracing(cli.log_file.as_ref());
+    utils::set_progress_mode(cli.progress_mode());
+    utils::set_list_separators(cli.list_separator.clone());
+    if cli.warnings_csv.is_some() {
+        warnings::keep_all();
+    }
+
+    let result = run_with_journal(&cli);
+    if let Err(err) = &result {
+        error!(?err, "{err}");
+    }
+
+    if !cli.quiet {
+        warnings::print_summary();
+    }
+    if let Some(path) = &cli.warnings_csv {
+        if let Err(err) = warnings::write_csv(path) {
+            error!(?err, "Failed to write the warnings CSV");
+        }
+    }
+
+    let summary = ErrorSummary::new(&result);
+    if cli.json_errors {
+        match serde_json::to_string(&summary) {
+            Ok(json) => eprintln!("{json}"),
+            Err(err) => error!(?err, "Failed to serialize the error summary"),
+        }
+    }
+
+    ExitCode::from(summary.exit_code)
+}
+
+
+fn run_with_journal(cli: &Cli) -> Result<(), Error> {
+    if !cli.command.uses_database() {
+        return run(cli);
+    }
+
+    if !cli.skip_schema_check {
+        schema_check::check_schema(&get_pool()?)?;
+    }
+
+    let args: Vec<String> = std::env::args().collect();
+    let journal = Journal::start(get_pool()?, &args)?;
+
+    let result = run(cli);
+
+    // failing to journal the outcome shouldn't hide the outcome itself
+    if let Err(err) = journal.finish(&result) {
+        error!(?err, "Failed to record the end of the command in the journal");
+    }
+    result
+}
+
+
+fn run(cli: &Cli) -> Result<(), Error> {
+    // held until the command finishes so that two processes can't change the same dataset at once
+    let _lock = match cli.command.dataset_scope() {
+        Some(dataset_id) => Some(DatasetLock::acquire(&get_pool()?, dataset_id)?),
+        None => None,
+    };
+
+    match &cli.command {
+        Commands::Import { path, all } => match all {
+            true => archive::import_all(path)?,
+            false if path == Path::new("-") => archive::import_stream(std::io::stdin().lock())?,
+            false => archive::Archive::new(path.clone()).import()?,
+        },
+        Commands::ImportFile(cmd) if cmd.analyzes() => cmd.analyze()?,
+        Commands::ImportFile(cmd) => match cmd {
+            ImportCommand::Taxa(args) => {
+                let dataset_version = args.dataset_version()?;
+                // let taxa = Taxa {
+                //     path: args.path.clone(),
+                //     dataset_version_id: dataset_version.id,
+                // };
+                // taxa.import()?
+            }
+
+            ImportCommand::TaxonomicActs(args) => {
+                let dataset_version = args.import.dataset_version()?;
+                let taxa = TaxonomicActs {
+                    path: args.import.path.clone(),
+                    dataset_version_id: dataset_version.id,
+                    sheet: args.sheet.options(),
+                    since: args.since_file.clone(),
+                    mappings: args.mappings()?,
+                };
+                taxa.import()?
+            }
+
+            ImportCommand::NomenclaturalActs(args) => {
+                let dataset_version = args.import.dataset_version()?;
+                let acts = NomenclaturalActs {
+                    path: args.import.path.clone(),
+                    dataset_version_id: dataset_version.id,
+                    sheet: args.sheet.options(),
+                    since: args.since_file.clone(),
+                    mappings: args.mappings()?,
+                };
+                acts.import()?
+            }
+
+            ImportCommand::Collections(args) => {
+                let dataset_version = args.import.dataset_version()?;
+                let collections = Collections {
+                    path: args.import.path.clone(),
+                    dataset_version_id: dataset_version.id,
+                    sheet: args.sheet.options(),
+                    since: args.since_file.clone(),
+                    mappings: args.mappings()?,
+                };
+                collections.import()?
+            }
+
+            ImportCommand::Abcd(args) => {
+                let dataset_version = args.dataset_version()?;
+                abcd::import_all(args.path.clone(), dataset_version.id)?;
+            }
+
+            ImportCommand::Sequences(args) => {
+                let dataset_version = args.import.dataset_version()?;
+                let sequences = Sequences {
+                    path: args.import.path.clone(),
+                    dataset_version_id: dataset_version.id,
+                    sheet: args.sheet.options(),
+                    since: args.since_file.clone(),
+                    mappings: args.mappings()?,
+                };
+                sequences.import()?
+            }
+
+            ImportCommand::Curation(args) => {
+                let base = args.base.cutoff()?;
+                let dataset_version =
+                    create_file_dataset_version(&args.dataset_id, &args.version, &args.created_at, &args.path)?;
+                taxa::curate(get_pool()?, &args.path, base, dataset_version.id)?;
+            }
+
+            ImportCommand::Sources { path } => {
+                let sources = Sources { path: path.clone() };
+                sources.import()?
+            }
+
+            ImportCommand::Datasets { path, check_doi } => {
+                let datasets = Datasets {
+                    path: path.clone(),
+                    check_doi: *check_doi,
+                };
+                datasets.import()?
+            }
+
+            ImportCommand::Names { path } => {
+                let names = Names { path: path.clone() };
+                names.import()?
+            }
+        },
+        Commands::Reduce(cmd) => {
+            precedence::set_precedence(AtomPrecedence::load(&get_pool()?)?);
+
+            match cmd {
+                ReduceCommand::Taxa {
+                    args,
+                    consensus,
+                    precedence,
+                } => {
+                    let mut records = taxa::reduce(get_pool()?, args.cutoff()?)?;
+                    if *consensus {
+                        records = taxa::consensus(records, precedence);
+                    }
+
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::TaxonomicActs(args) => {
+                    let records = TaxonomicActs::reduce(args.cutoff()?)?;
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::NomenclaturalActs(args) => {
+                    let records = NomenclaturalActs::reduce(args.cutoff()?)?;
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::Publications(args) => {
+                    let records = publications::reduce(get_pool()?, args.cutoff()?)?;
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::Specimens(args) => {
+                    let records = collections::reduce(get_pool()?, args.cutoff()?)?;
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::SequenceLinks(args) => {
+                    let records = sequences::links(get_pool()?, args.cutoff()?)?;
+                    output::write_records(records, args.output.compress)?;
+                }
+                ReduceCommand::Sources { format, args } => {
+                    let records = sources::reduce(get_pool()?, args.cutoff()?)?;
+                    output::export_records(records, *format, args.output.compress)?;
+                }
+                ReduceCommand::Datasets { format, args } => {
+                    let records = datasets::reduce(get_pool()?, args.cutoff()?)?;
+                    output::export_records(records, *format, args.output.compress)?;
+                }
+            }
+        }
+
+        Commands::Update {
+            push: web_url,
+            trust_rules,
+            classify_names,
+            sensitive_taxa,
+            table,
+        } => {
+            if let Some(path) = trust_rules {
+                precedence::store_rules(&get_pool()?, path)?;
+            }
+            precedence::set_precedence(AtomPrecedence::load(&get_pool()?)?);
+
+            if let Some(path) = sensitive_taxa {
+                let mut pool = get_pool()?;
+                let names = name_lookup(&mut pool)?;
+                SensitivityList::from_path(path, &names)?.store(&pool)?;
+            }
+
+            match table {
+                UpdateCommand::Taxa(args) => match &args.explain_entity {
+                    Some(entity_id) => taxa::explain(get_pool()?, entity_id)?,
+                    None => taxa::update(get_pool()?, args.full)?,
+                },
+                UpdateCommand::TaxonomicActs(args) => match &args.explain_entity {
+                    Some(entity_id) => taxonomic_acts::explain(get_pool()?, entity_id)?,
+                    None => taxonomic_acts::update(get_pool()?, args.full)?,
+                },
+                UpdateCommand::NomenclaturalActs => NomenclaturalActs::update(get_pool()?)?,
+                UpdateCommand::Publications => publications::update(get_pool()?)?,
+                UpdateCommand::Collections { institutions } => {
+                    let registry = match institutions {
+                        Some(path) => Some(InstitutionRegistry::from_path(path)?),
+                        None => None,
+                    };
+                    collections::update(get_pool()?, registry)?
+                }
+                UpdateCommand::All { parallelism } => updates::update_all(get_pool()?, *parallelism)?,
+            }
+
+            if *classify_names && table.stages().contains(&UpdateStage::Taxa) {
+                names::classify(&get_pool()?)?;
+            }
+
+            if let Some(url) = web_url {
+                push::push(&get_pool()?, url, &table.stages())?;
+            }
+        }
+
+        Commands::Link(cmd) => match cmd {
+            LinkCommand::Names => names::link_variants(&get_pool()?)?,
+            LinkCommand::Taxa => taxa::link()?,
+            LinkCommand::Concepts { mapping } => concept_links::link(&get_pool()?, mapping.as_deref())?,
+            LinkCommand::All { only, skip } => links::link_all(get_pool()?, only, skip)?,
+        },
+        Commands::Relink { scope } => relink::relink(&get_pool()?, scope)?,
+
+        Commands::Plazi(cmd) => match cmd {
+            PlaziCommand::Import(args) => {
+                let dataset_version = args.dataset_version()?;
+                plazi::document::import_all(args.path.clone(), dataset_version.id)?;
+            }
+        },
+
+        Commands::InferActs(args) => {
+            let dataset_version = create_dataset_version(&args.dataset_id, &args.version, &args.created_at)?;
+            nomenclatural_acts::infer_from_taxa(get_pool()?, dataset_version.id)?;
+        }
+
+        Commands::VerifyDeterminism { table, parallelism } => match table {
+            ReduceTable::Taxa => {
+                let pool = get_pool()?;
+                determinism::verify(|| taxa::reduce(pool.clone(), None), *parallelism)?
+            }
+            ReduceTable::TaxonomicActs => determinism::verify(|| TaxonomicActs::reduce(None), *parallelism)?,
+            ReduceTable::NomenclaturalActs => determinism::verify(|| NomenclaturalActs::reduce(None), *parallelism)?,
+        },
+        Commands::RefreshEntityViews => entity_views::refresh_all(&get_pool()?)?,
+        Commands::CompareBackbone {
+            path,
+            precedence,
+            output: output_args,
+        } => {
+            let taxa = taxa::reduce(get_pool()?, None)?;
+            let differences = taxa::compare_backbone(taxa, precedence, path)?;
+            output::write_records(differences, output_args.compress)?;
+        }
+        Commands::Export(cmd) => match cmd {
+            ExportCommand::Graph { entity, format } => {
+                let graph = graph::derivation_graph(&get_pool()?, entity)?;
+                graph.write(*format, std::io::stdout())?;
+            }
+        },
+        Commands::Describe(cmd) => match cmd {
+            DescribeCommand::Schema { table, format } => {
+                let columns = match table {
+                    SchemaTable::Taxa => taxa::describe(),
+                    SchemaTable::TaxonomicActs => TaxonomicActs::describe(),
+                    SchemaTable::NomenclaturalActs => NomenclaturalActs::describe(),
+                    SchemaTable::Collections => Collections::describe(),
+                    SchemaTable::Sequences => Sequences::describe(),
+                    SchemaTable::Sources => Sources::describe(),
+                    SchemaTable::Datasets => Datasets::describe(),
+                    SchemaTable::Names => Names::describe(),
+                };
+                describe::write_schema(&columns, *format, std::io::stdout())?;
+            }
+        },
+        Commands::AnalyzeVocabulary { table, atom } => {
+            let terms = vocabulary::analyze(&get_pool()?, table, atom)?;
+
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for term in terms {
+                writer.serialize(term)?;
+            }
+            writer.flush()?;
+        }
+        Commands::Query(cmd) => match cmd {
+            QueryCommand::Taxa { entity, name, format } => {
+                let records = taxa::query(get_pool()?, entity.as_deref(), name.as_deref())?;
+                output::print_records(&records, *format)?
+            }
+        },
+        Commands::Maintenance(cmd) => match cmd {
+            MaintenanceCommand::DatasetVersions { dry_run, datasets } => {
+                maintenance::collect_dataset_versions(&get_pool()?, *dry_run, *datasets)?
+            }
+            MaintenanceCommand::CleanLogs { dry_run } => maintenance::clean_logs(&get_pool()?, *dry_run)?,
+            MaintenanceCommand::MergeNames { dry_run } => maintenance::merge_names(&get_pool()?, *dry_run)?,
+            MaintenanceCommand::MergePublications { dry_run } => {
+                maintenance::merge_publications(&get_pool()?, *dry_run)?
+            }
+            MaintenanceCommand::BackfillEntityIds { table, dry_run } => {
+                maintenance::backfill_entity_ids(&get_pool()?, *table, *dry_run)?
+            }
+        },
+    }
+
+    Ok(())
+}
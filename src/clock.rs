@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use arga_core::crdt::DataFrameOperation;
+use bigdecimal::BigDecimal;
+use diesel::sql_types::Numeric;
+use diesel::*;
+use tracing::info;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+// the clock only coordinates oplogger processes so it isn't part of arga_core
+diesel::table! {
+    operation_clock (id) {
+        id -> Int4,
+        high_water_mark -> Numeric,
+        updated_at -> Timestamptz,
+    }
+}
+
+
+#[derive(QueryableByName)]
+struct Reserved {
+    #[diesel(sql_type = Numeric)]
+    high_water_mark: BigDecimal,
+}
+
+
+/// A database backed allocator that keeps operation ids monotonic across processes.
+///
+/// Operation ids come from the `Version` logical clock of the process doing the import, so
+/// two imports running at the same time on different machines can interleave their ids, and
+/// a machine with a slow clock can create operations that sort before ones already imported.
+/// Both break the last-write-wins comparisons in `distinct_changes`.
+///
+/// Before a chunk of operations is sent to the database the clock reserves a range of ids
+/// above both the `operation_clock` high water mark and the ids the process generated, and
+/// restamps the chunk into that range in the order the ids were generated. The reservation is
+/// a single atomic update of the mark so imports only wait on each other for that statement,
+/// and every reserved range is newer than the ones reserved before it no matter which machine
+/// or clock it came from.
+pub struct OperationClock {
+    pool: PgPool,
+    /// The new ids of the operations restamped in the previous chunk
+    previous: HashMap<BigDecimal, BigDecimal>,
+}
+
+impl OperationClock {
+    pub fn new(pool: &PgPool) -> Result<OperationClock, Error> {
+        let mut conn = pool.get()?;
+        create_table(&mut conn)?;
+
+        Ok(OperationClock {
+            pool: pool.clone(),
+            previous: HashMap::new(),
+        })
+    }
+
+    /// Reserve a range of ids for the operations and restamp them with it.
+    ///
+    /// The parent ids of a frame point at the operations of the frame before it, which can
+    /// be in the previous chunk, so they are remapped with the ids of both chunks.
+    pub fn stamp<A>(&mut self, operations: &mut [DataFrameOperation<A>]) -> Result<(), Error> {
+        let Some(floor) = operations.iter().map(|op| &op.operation_id).max().cloned()
+        else {
+            return Ok(());
+        };
+
+        let high_water_mark = self.reserve(floor, operations.len())?;
+        let first = high_water_mark - BigDecimal::from(operations.len() as u64) + BigDecimal::from(1);
+
+        let stamped = restamp(operations, first, &self.previous);
+        self.previous = stamped;
+        Ok(())
+    }
+
+    /// Move the high water mark past the floor and the amount of ids, returning the new mark
+    fn reserve(&self, floor: BigDecimal, amount: usize) -> Result<BigDecimal, Error> {
+        let mut conn = self.pool.get()?;
+
+        let reserved = sql_query(
+            "INSERT INTO operation_clock (id, high_water_mark, updated_at) VALUES (1, $1 + $2, now())
+             ON CONFLICT (id) DO UPDATE
+             SET high_water_mark = GREATEST(operation_clock.high_water_mark, $1) + $2, updated_at = now()
+             RETURNING high_water_mark",
+        )
+        .bind::<Numeric, _>(floor)
+        .bind::<Numeric, _>(BigDecimal::from(amount as u64))
+        .get_result::<Reserved>(&mut conn)?;
+
+        info!(high_water_mark = %reserved.high_water_mark, amount, "Operation ids reserved");
+        Ok(reserved.high_water_mark)
+    }
+}
+
+
+/// Give the operations consecutive ids from `first` in the order of their current ids.
+///
+/// Returns the new id of every operation keyed by its old id. Parent ids are remapped with
+/// the ids of this chunk and then the `previous` chunk, and left alone when neither has them.
+fn restamp<A>(
+    operations: &mut [DataFrameOperation<A>],
+    first: BigDecimal,
+    previous: &HashMap<BigDecimal, BigDecimal>,
+) -> HashMap<BigDecimal, BigDecimal> {
+    let mut order: Vec<usize> = (0..operations.len()).collect();
+    order.sort_by(|a, b| operations[*a].operation_id.cmp(&operations[*b].operation_id));
+
+    let mut stamped = HashMap::with_capacity(operations.len());
+    let mut next = first;
+    for idx in order {
+        stamped.insert(operations[idx].operation_id.clone(), next.clone());
+        operations[idx].operation_id = next.clone();
+        next += BigDecimal::from(1);
+    }
+
+    for op in operations.iter_mut() {
+        if let Some(parent_id) = stamped.get(&op.parent_id).or_else(|| previous.get(&op.parent_id)) {
+            op.parent_id = parent_id.clone();
+        }
+    }
+
+    stamped
+}
+
+
+fn create_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS operation_clock (
+            id integer PRIMARY KEY,
+            high_water_mark numeric NOT NULL,
+            updated_at timestamptz NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arga_core::crdt::{DataFrame, Version};
+    use arga_core::models::TaxonAtom;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn operations(atoms: usize) -> Vec<DataFrameOperation<TaxonAtom>> {
+        let mut frame = DataFrame::create("entity".to_string(), Uuid::new_v4(), Version::new());
+        for idx in 0..atoms {
+            frame.push(TaxonAtom::TaxonId(idx.to_string()));
+        }
+        frame.collect()
+    }
+
+    #[test]
+    fn restamp_keeps_the_order_of_the_generated_ids() {
+        let mut ops = operations(3);
+        ops.reverse();
+        let original: Vec<BigDecimal> = ops.iter().map(|op| op.operation_id.clone()).collect();
+
+        restamp(&mut ops, BigDecimal::from(100), &HashMap::new());
+
+        // the largest generated id gets the largest new id
+        let ids: Vec<BigDecimal> = ops.iter().map(|op| op.operation_id.clone()).collect();
+        assert_eq!(ids, vec![BigDecimal::from(102), BigDecimal::from(101), BigDecimal::from(100)]);
+        assert!(original[0] > original[2]);
+    }
+
+    #[test]
+    fn restamp_remaps_parents_from_the_previous_chunk() {
+        let mut first = operations(2);
+        let stamped = restamp(&mut first, BigDecimal::from(10), &HashMap::new());
+
+        let mut second = operations(1);
+        let parent = stamped.keys().next().unwrap().clone();
+        second[0].parent_id = parent.clone();
+        restamp(&mut second, BigDecimal::from(12), &stamped);
+
+        assert_eq!(second[0].operation_id, BigDecimal::from(12));
+        assert_eq!(second[0].parent_id, stamped[&parent]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use arga_core::schema;
+use diesel::*;
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::{dataset_lookup, PgPool};
+use crate::errors::Error;
+
+
+// the concept links are derived from the taxa tables of different datasets so they only exist
+// in the oplogger database until arga_core has a table for them
+diesel::table! {
+    taxon_concept_links (taxon_id, linked_taxon_id) {
+        taxon_id -> Uuid,
+        linked_taxon_id -> Uuid,
+        method -> Text,
+    }
+}
+
+
+/// A link made by a curator in a mapping CSV
+const MAPPING: &str = "mapping";
+
+/// A link made between taxa with the same name, authorship and nomenclatural code
+const NAME_AUTHORSHIP: &str = "name_authorship";
+
+
+/// A pair of taxa in different datasets that are the same concept
+#[derive(Debug, Deserialize)]
+struct MappingRecord {
+    dataset_id: String,
+    scientific_name: String,
+    linked_dataset_id: String,
+    linked_scientific_name: String,
+}
+
+
+/// Link the taxa of different taxonomic systems that describe the same concept.
+///
+/// The links in the mapping CSV are stored first and replace any heuristic link between the
+/// same taxa. Every other taxon is linked to the taxa of other datasets with the same canonical
+/// name, authorship, and nomenclatural code, as long as the name and authorship only occur once
+/// in each dataset. Taxa without an authorship are never linked by name since homonyms can't be
+/// told apart. Links are stored in both directions so that the other systems of a taxon can be
+/// found from either side.
+///
+/// The name links are rebuilt on every run so that they follow changes to the taxa, while the
+/// mapped links are kept until their taxa are removed.
+pub fn link(pool: &PgPool, mapping: Option<&Path>) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    create_concept_links_table(&mut conn)?;
+
+    if let Some(path) = mapping {
+        import_mapping(pool, path)?;
+    }
+
+    conn.transaction::<_, Error, _>(|conn| {
+        let removed = diesel::delete(taxon_concept_links::table)
+            .filter(taxon_concept_links::method.eq(NAME_AUTHORSHIP))
+            .execute(conn)?;
+
+        let linked = sql_query(format!(
+            "WITH keyed AS (
+                SELECT id, dataset_id, nomenclatural_code,
+                       lower(canonical_name) AS name,
+                       lower(regexp_replace(btrim(authorship), '\\s+', ' ', 'g')) AS authorship
+                FROM taxa
+                WHERE authorship IS NOT NULL AND btrim(authorship) <> ''
+             ),
+             counted AS (
+                SELECT *, count(*) OVER (PARTITION BY dataset_id, name, authorship, nomenclatural_code) AS total
+                FROM keyed
+             ),
+             unique_keys AS (
+                SELECT * FROM counted WHERE total = 1
+             )
+             INSERT INTO taxon_concept_links (taxon_id, linked_taxon_id, method)
+             SELECT a.id, b.id, '{NAME_AUTHORSHIP}'
+             FROM unique_keys a
+             JOIN unique_keys b ON a.name = b.name
+                AND a.authorship = b.authorship
+                AND a.nomenclatural_code = b.nomenclatural_code
+                AND a.dataset_id <> b.dataset_id
+             ON CONFLICT (taxon_id, linked_taxon_id) DO NOTHING"
+        ))
+        .execute(conn)?;
+
+        info!(removed, linked, "Linked taxon concepts by name and authorship");
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+
+/// Store the links of a mapping CSV with `dataset_id`, `scientific_name`, `linked_dataset_id`,
+/// and `linked_scientific_name` columns. Rows naming a dataset or taxon that doesn't exist are
+/// skipped with a warning
+fn import_mapping(pool: &PgPool, path: &Path) -> Result<(), Error> {
+    use diesel::upsert::excluded;
+    use taxon_concept_links::dsl::*;
+
+    let mut reader = csv::Reader::from_path(path)?;
+    let records = reader.deserialize::<MappingRecord>().collect::<Result<Vec<_>, _>>()?;
+
+    let datasets = dataset_lookup(&mut pool.clone())?;
+    let mut conn = pool.get()?;
+
+    // only the taxa named in the mapping are loaded rather than every taxon
+    let mut names: Vec<&String> = records
+        .iter()
+        .flat_map(|record| [&record.scientific_name, &record.linked_scientific_name])
+        .collect();
+    names.sort();
+    names.dedup();
+
+    let mut found: HashMap<(Uuid, String), Uuid> = HashMap::new();
+    for chunk in names.chunks(10_000) {
+        use schema::taxa;
+
+        let rows = taxa::table
+            .filter(taxa::scientific_name.eq_any(chunk.to_vec()))
+            .select((taxa::dataset_id, taxa::scientific_name, taxa::id))
+            .load::<(Uuid, String, Uuid)>(&mut conn)?;
+        found.extend(rows.into_iter().map(|(dataset, name, uuid)| ((dataset, name), uuid)));
+    }
+
+    let find = |dataset: &str, name: &str| {
+        let dataset_uuid = datasets.get(dataset)?;
+        found.get(&(*dataset_uuid, name.to_string())).copied()
+    };
+
+    let mut links = Vec::new();
+    for record in &records {
+        let from = find(&record.dataset_id, &record.scientific_name);
+        let to = find(&record.linked_dataset_id, &record.linked_scientific_name);
+
+        match (from, to) {
+            (Some(from), Some(to)) => {
+                links.push((taxon_id.eq(from), linked_taxon_id.eq(to), method.eq(MAPPING)));
+                links.push((taxon_id.eq(to), linked_taxon_id.eq(from), method.eq(MAPPING)));
+            }
+            _ => warn!(
+                dataset_id = %record.dataset_id,
+                scientific_name = %record.scientific_name,
+                linked_dataset_id = %record.linked_dataset_id,
+                linked_scientific_name = %record.linked_scientific_name,
+                "Mapped taxon not found"
+            ),
+        }
+    }
+
+    for chunk in links.chunks(10_000) {
+        diesel::insert_into(taxon_concept_links)
+            .values(chunk)
+            .on_conflict((taxon_id, linked_taxon_id))
+            .do_update()
+            .set(method.eq(excluded(method)))
+            .execute(&mut conn)?;
+    }
+
+    info!(total = records.len(), linked = links.len() / 2, "Taxon concept mapping imported");
+    Ok(())
+}
+
+
+pub fn create_concept_links_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS taxon_concept_links (
+            taxon_id uuid NOT NULL REFERENCES taxa (id) ON DELETE CASCADE,
+            linked_taxon_id uuid NOT NULL REFERENCES taxa (id) ON DELETE CASCADE,
+            method text NOT NULL,
+            PRIMARY KEY (taxon_id, linked_taxon_id)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 use std::time::Duration;
 
 use arga_core::models::DatasetVersion;
@@ -9,8 +11,9 @@ use diesel::*;
 use tracing::info;
 use uuid::Uuid;
 
-use crate::errors::Error;
-use crate::utils::new_spinner;
+use crate::errors::{Error, ParseError};
+use crate::loggers::{is_no_merge, NO_MERGE_VERSION_SUFFIX};
+use crate::utils::{content_hash, new_spinner, parse_date_time};
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 
@@ -22,6 +25,23 @@ pub type StringMap = HashMap<String, Uuid>;
 /// for scoping such as all strings from a specific dataset
 pub type UuidStringMap = HashMap<(Uuid, String), Uuid>;
 
+/// The version that is replaced by the content hash of the imported file
+pub const AUTO_VERSION: &str = "auto";
+
+/// The dataset versions created by this process. Other processes can import at the same
+/// time so the versions can't be found by when they were imported
+static CREATED_VERSIONS: Mutex<Vec<Uuid>> = Mutex::new(Vec::new());
+
+
+// the content hash of the file a dataset version was imported from. arga_core doesn't have a
+// column for it so it is kept alongside to check re-imports of the same version against
+diesel::table! {
+    dataset_version_hashes (dataset_version_id) {
+        dataset_version_id -> Uuid,
+        content_hash -> Varchar,
+    }
+}
+
 /// A refreshable materialized view
 pub enum MaterializedView {
     TaxaDag,
@@ -84,9 +104,174 @@ pub fn create_dataset_version(dataset_id: &str, version: &str, created_at: &str)
         .returning(DatasetVersion::as_select())
         .get_result(&mut conn)?;
 
+    CREATED_VERSIONS
+        .lock()
+        .expect("Created versions lock poisoned")
+        .push(dataset_version.id);
+
     Ok(dataset_version)
 }
 
+/// The ids of every dataset version created by this process so far
+pub fn created_dataset_versions() -> Vec<Uuid> {
+    CREATED_VERSIONS.lock().expect("Created versions lock poisoned").clone()
+}
+
+/// Create a dataset version for an imported file, deriving the version from its content on request.
+///
+/// Operators supply the version by hand which makes it easy to typo, so a version of `auto`
+/// is replaced with the content hash instead, giving the same content the same version. The
+/// hash is stored with the dataset version so the content can be checked on a later import.
+/// Hashing reads the whole file so it is only done for `auto`, any other version is created
+/// as is.
+pub fn create_file_dataset_version(
+    dataset_id: &str,
+    version: &str,
+    created_at: &str,
+    path: &Path,
+) -> Result<DatasetVersion, Error> {
+    if version != AUTO_VERSION {
+        let version = match is_no_merge() {
+            true => format!("{version}{NO_MERGE_VERSION_SUFFIX}"),
+            false => version.to_string(),
+        };
+        return create_dataset_version(dataset_id, &version, created_at);
+    }
+
+    let spinner = new_spinner("Hashing file content");
+    let hash = content_hash(path)?;
+    spinner.finish();
+
+    let version = match is_no_merge() {
+        true => format!("{hash}{NO_MERGE_VERSION_SUFFIX}"),
+        false => hash.clone(),
+    };
+
+    let pool = get_pool()?;
+    let mut conn = pool.get()?;
+    create_dataset_version_hashes_table(&mut conn)?;
+
+    let dataset_version = create_dataset_version(dataset_id, &version, created_at)?;
+
+    diesel::insert_into(dataset_version_hashes::table)
+        .values((
+            dataset_version_hashes::dataset_version_id.eq(dataset_version.id),
+            dataset_version_hashes::content_hash.eq(&hash),
+        ))
+        .execute(&mut conn)?;
+
+    Ok(dataset_version)
+}
+
+fn create_dataset_version_hashes_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS dataset_version_hashes (
+            dataset_version_id uuid PRIMARY KEY REFERENCES dataset_versions ON DELETE CASCADE,
+            content_hash varchar NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+/// The amount of dataset versions created for a specific version of a dataset.
+///
+/// Every file imported from an archive creates its own dataset version so this
+/// is also the amount of files that have been imported for the dataset version.
+pub fn dataset_version_count(dataset_id: &str, dataset_version: &str) -> Result<i64, Error> {
+    use schema::{dataset_versions, datasets};
+
+    let pool = get_pool()?;
+    let mut conn = pool.get()?;
+
+    let dataset_ids = datasets::table
+        .filter(datasets::global_id.eq(dataset_id))
+        .select(datasets::id);
+
+    let total = dataset_versions::table
+        .filter(dataset_versions::dataset_id.eq_any(dataset_ids))
+        .filter(dataset_versions::version.eq(dataset_version))
+        .count()
+        .get_result::<i64>(&mut conn)?;
+
+    Ok(total)
+}
+
+/// The dataset versions imported with `--no-merge`.
+///
+/// Their operations are appended to the logs as-is and have newer operation ids than anything
+/// imported before them, so they would win the last write wins merge. Every query that reads
+/// the logs to reduce them or to merge an import into them leaves these versions out.
+pub fn no_merge_versions(conn: &mut PgConnection) -> Result<Vec<Uuid>, Error> {
+    use schema::dataset_versions::dsl::*;
+
+    let ids = dataset_versions
+        .filter(version.like(format!("%{NO_MERGE_VERSION_SUFFIX}")))
+        .select(id)
+        .load::<Uuid>(conn)?;
+
+    Ok(ids)
+}
+
+/// The versions of a dataset that have been imported
+pub fn imported_versions(dataset_id: &str) -> Result<Vec<String>, Error> {
+    use schema::{dataset_versions, datasets};
+
+    let pool = get_pool()?;
+    let mut conn = pool.get()?;
+
+    let versions = dataset_versions::table
+        .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+        .filter(datasets::global_id.eq(dataset_id))
+        .select(dataset_versions::version)
+        .distinct()
+        .load::<String>(&mut conn)?;
+
+    Ok(versions)
+}
+
+/// A point in time to reduce the operation logs at.
+///
+/// Operations become visible when the dataset version they belong to is imported, so
+/// reducing as of a dataset version includes every operation imported up to and including
+/// that version and nothing imported after it.
+#[derive(Debug, Clone)]
+pub enum AsOf {
+    Timestamp(DateTime<Utc>),
+    DatasetVersion(Uuid),
+}
+
+impl std::str::FromStr for AsOf {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match Uuid::parse_str(value) {
+            Ok(uuid) => Ok(AsOf::DatasetVersion(uuid)),
+            Err(_) => Ok(AsOf::Timestamp(parse_date_time(value)?)),
+        }
+    }
+}
+
+impl AsOf {
+    /// The latest import time of the operations visible at this point
+    pub fn cutoff(&self) -> Result<DateTime<Utc>, Error> {
+        use schema::dataset_versions::dsl::*;
+
+        match self {
+            AsOf::Timestamp(timestamp) => Ok(*timestamp),
+            AsOf::DatasetVersion(uuid) => {
+                let pool = get_pool()?;
+                let mut conn = pool.get()?;
+                let cutoff = dataset_versions
+                    .filter(id.eq(uuid))
+                    .select(imported_at)
+                    .get_result::<DateTime<Utc>>(&mut conn)?;
+                Ok(cutoff)
+            }
+        }
+    }
+}
+
 /// Refreshes a materialized view.
 /// This can be a costly operation depending on the view being refreshed.
 /// Because we cant use bound parameters on this query we instead use an enum to
@@ -133,6 +318,39 @@ pub fn dataset_lookup(pool: &mut PgPool) -> Result<StringMap, Error> {
     Ok(map)
 }
 
+/// The global id and uuid of the dataset a reduced record belongs to.
+///
+/// Reduced outputs carry both so that they can be joined downstream on either the global id
+/// used by the archives or the uuid used by the tables. The dataset named by the DatasetId atom
+/// is used when it is in the lookup, the same as the updates do, otherwise it is the dataset
+/// that logged the operations of the entity, given as its global id and uuid.
+pub fn reduced_dataset(dataset_id: &str, logged_by: (&str, Uuid), datasets: &StringMap) -> (String, Uuid) {
+    match datasets.get(dataset_id) {
+        Some(uuid) => (dataset_id.to_string(), *uuid),
+        None => (logged_by.0.to_string(), logged_by.1),
+    }
+}
+
+/// The global id and uuid of the dataset each dataset version belongs to.
+///
+/// Used to find the dataset of a reduced record whose log operations don't name one.
+pub fn dataset_version_lookup(pool: &mut PgPool) -> Result<HashMap<Uuid, (String, Uuid)>, Error> {
+    use schema::{dataset_versions, datasets};
+    info!("Creating dataset version map");
+
+    let mut conn = pool.get()?;
+
+    let map = dataset_versions::table
+        .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+        .select((dataset_versions::id, (datasets::global_id, datasets::id)))
+        .load::<(Uuid, (String, Uuid))>(&mut conn)?
+        .into_iter()
+        .collect::<HashMap<Uuid, (String, Uuid)>>();
+
+    info!(total = map.len(), "Creating dataset version map finished");
+    Ok(map)
+}
+
 pub fn taxon_lookup(pool: &mut PgPool, datasets: &Vec<Uuid>) -> Result<UuidStringMap, Error> {
     use schema::taxa::dsl::*;
     info!(?datasets, "Creating taxa map");
@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::sql_types::{BigInt, Bool, Nullable, Text, Timestamptz};
+use diesel::*;
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+#[derive(QueryableByName)]
+struct Locked {
+    #[diesel(sql_type = Bool)]
+    locked: bool,
+}
+
+#[derive(QueryableByName)]
+struct LockHolder {
+    #[diesel(sql_type = BigInt)]
+    pid: i64,
+    #[diesel(sql_type = Nullable<Text>)]
+    application_name: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    client_addr: Option<String>,
+    #[diesel(sql_type = Nullable<Timestamptz>)]
+    backend_start: Option<DateTime<Utc>>,
+}
+
+impl std::fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.application_name {
+            Some(name) if !name.is_empty() => write!(f, "{name}")?,
+            _ => write!(f, "backend pid {}", self.pid)?,
+        }
+        if let Some(addr) = &self.client_addr {
+            write!(f, " from {addr}")?;
+        }
+        if let Some(started) = &self.backend_start {
+            write!(f, " since {started}")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// A postgres advisory lock on a single dataset.
+///
+/// The `OperationClock` keeps the operation ids of concurrent imports apart but doesn't stop
+/// two imports of the same dataset from creating their dataset versions and frames against
+/// different baselines. The dataset lock is held for the whole command and fails straight away when another process already holds it, naming
+/// that process so the operator knows who to wait for. The lock is released when dropped.
+pub struct DatasetLock {
+    conn: PooledConnection<ConnectionManager<PgConnection>>,
+    dataset_id: String,
+    key: i64,
+}
+
+impl DatasetLock {
+    pub fn acquire(pool: &PgPool, dataset_id: &str) -> Result<DatasetLock, Error> {
+        let mut conn = pool.get()?;
+        let key = lock_key(dataset_id);
+
+        // name the session so that a process waiting on the lock can report who holds it
+        sql_query("SELECT set_config('application_name', $1, false)")
+            .bind::<Text, _>(format!("oplogger pid {}", std::process::id()))
+            .execute(&mut conn)?;
+
+        let acquired = sql_query("SELECT pg_try_advisory_lock($1) AS locked")
+            .bind::<BigInt, _>(key)
+            .get_result::<Locked>(&mut conn)?;
+
+        if !acquired.locked {
+            let holder = lock_holder(&mut conn, key)?;
+            let holder = holder.map(|holder| holder.to_string()).unwrap_or_else(|| "another process".to_string());
+            return Err(Error::DatasetLocked(dataset_id.to_string(), holder));
+        }
+
+        info!(dataset_id, "Dataset lock acquired");
+        Ok(DatasetLock {
+            conn,
+            dataset_id: dataset_id.to_string(),
+            key,
+        })
+    }
+}
+
+impl Drop for DatasetLock {
+    fn drop(&mut self) {
+        let unlocked = sql_query("SELECT pg_advisory_unlock($1)")
+            .bind::<BigInt, _>(self.key)
+            .execute(&mut self.conn);
+
+        if let Err(err) = unlocked {
+            warn!(?err, dataset_id = self.dataset_id, "Failed to release the dataset lock");
+        }
+    }
+}
+
+
+/// The advisory lock key of a dataset.
+///
+/// Keys share a single namespace with every other advisory lock in the database, so the
+/// dataset id is hashed with a prefix to keep it clear of locks taken by other applications.
+fn lock_key(dataset_id: &str) -> i64 {
+    xxh3_64(format!("oplogger dataset {dataset_id}").as_bytes()) as i64
+}
+
+
+/// Find the session holding the advisory lock.
+///
+/// pg_locks splits a bigint advisory key into the high and low 32 bits of the classid and
+/// objid columns. The lock may be released between the failed attempt and this query, in
+/// which case there is no holder to report.
+fn lock_holder(conn: &mut PgConnection, key: i64) -> Result<Option<LockHolder>, Error> {
+    let holder = sql_query(
+        "SELECT activity.pid::bigint AS pid, activity.application_name, activity.client_addr::text AS client_addr, activity.backend_start
+         FROM pg_locks locks
+         JOIN pg_stat_activity activity ON activity.pid = locks.pid
+         WHERE locks.locktype = 'advisory'
+         AND locks.granted
+         AND locks.objsubid = 1
+         AND locks.classid::bigint = $1
+         AND locks.objid::bigint = $2
+         LIMIT 1",
+    )
+    .bind::<BigInt, _>((key as u64 >> 32) as i64)
+    .bind::<BigInt, _>((key as u64 & 0xffff_ffff) as i64)
+    .get_result::<LockHolder>(conn)
+    .optional()?;
+
+    Ok(holder)
+}
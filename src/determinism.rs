@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::errors::{Error, ReduceError};
+
+
+/// A reduced record that can be compared with the same entity from another reduction
+pub trait EntityRecord: Serialize {
+    fn entity_id(&self) -> &str;
+}
+
+
+/// Serialized rows of a reduction keyed by entity id.
+///
+/// An entity can produce more than one row, so the rows are sorted to make the
+/// comparison independent of the order that the reducer emitted them in.
+type Snapshot = BTreeMap<String, Vec<String>>;
+
+fn snapshot<R: EntityRecord>(records: Vec<R>) -> Result<Snapshot, Error> {
+    let mut snapshot = Snapshot::new();
+
+    for record in records {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        writer.serialize(&record)?;
+        writer.flush()?;
+
+        let row = String::from_utf8_lossy(writer.get_ref()).to_string();
+        snapshot.entry(record.entity_id().to_string()).or_default().push(row);
+    }
+
+    for rows in snapshot.values_mut() {
+        rows.sort();
+    }
+
+    Ok(snapshot)
+}
+
+
+/// Run a reduction twice and compare the outputs.
+///
+/// The second reduction is run in a rayon thread pool limited to `parallelism` threads when
+/// specified, which helps surface issues that only appear when the chunks are processed in
+/// a different order. Every entity that is missing from one of the reductions or that has a
+/// different output is reported and will fail the check.
+pub fn verify<R, F>(reduce: F, parallelism: Option<usize>) -> Result<(), Error>
+where
+    R: EntityRecord + Send,
+    F: Fn() -> Result<Vec<R>, Error> + Sync,
+{
+    info!("Running first reduction");
+    let first = snapshot(reduce()?)?;
+
+    info!(?parallelism, "Running second reduction");
+    let second = match parallelism {
+        None => reduce()?,
+        Some(threads) => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .expect("Failed to build the thread pool");
+            pool.install(&reduce)?
+        }
+    };
+    let second = snapshot(second)?;
+
+    let entities: BTreeSet<&String> = first.keys().chain(second.keys()).collect();
+    let mut divergent = 0;
+
+    for entity_id in entities {
+        match (first.get(entity_id), second.get(entity_id)) {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) => {
+                warn!(entity_id, first = ?a, second = ?b, "Entity reduced to different outputs");
+                divergent += 1;
+            }
+            (Some(_), None) => {
+                warn!(entity_id, "Entity missing from the second reduction");
+                divergent += 1;
+            }
+            (None, Some(_)) => {
+                warn!(entity_id, "Entity missing from the first reduction");
+                divergent += 1;
+            }
+            (None, None) => {}
+        }
+    }
+
+    info!(total = first.len(), divergent, "Finished verifying determinism");
+
+    match divergent {
+        0 => Ok(()),
+        total => Err(ReduceError::Nondeterministic(total).into()),
+    }
+}
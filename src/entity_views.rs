@@ -0,0 +1,93 @@
+use diesel::*;
+use tracing::info;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+// The entity views aren't part of arga_core as they are only used by the oplogger to
+// page through the log tables. They are materialized views with a single entity_id column
+diesel::table! {
+    taxa_entities (entity_id) {
+        entity_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    taxonomic_act_entities (entity_id) {
+        entity_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    specimen_entities (entity_id) {
+        entity_id -> Varchar,
+    }
+}
+
+
+/// The distinct entities in a log table.
+///
+/// Grouping the log tables by entity id for every page of a reduction gets slower the
+/// further into the table we get, so instead each paged log has a materialized view of
+/// its distinct entity ids with a unique index. The views have to be refreshed after an
+/// import for the new entities to be reduced, which the update stages do before paging.
+#[derive(Debug, Clone, Copy)]
+pub enum EntityView {
+    Taxa,
+    TaxonomicActs,
+    Specimens,
+}
+
+impl EntityView {
+    pub fn all() -> [EntityView; 3] {
+        [EntityView::Taxa, EntityView::TaxonomicActs, EntityView::Specimens]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntityView::Taxa => "taxa_entities",
+            EntityView::TaxonomicActs => "taxonomic_act_entities",
+            EntityView::Specimens => "specimen_entities",
+        }
+    }
+
+    pub fn log_table(&self) -> &'static str {
+        match self {
+            EntityView::Taxa => "taxa_logs",
+            EntityView::TaxonomicActs => "taxonomic_act_logs",
+            EntityView::Specimens => "specimen_logs",
+        }
+    }
+
+    /// Create the view if it doesn't exist yet and refresh it with the current entities
+    pub fn refresh(&self, pool: &PgPool) -> Result<(), Error> {
+        let mut conn = pool.get()?;
+        let name = self.name();
+        let log_table = self.log_table();
+
+        info!(name, log_table, "Refreshing entity view");
+
+        sql_query(format!(
+            "CREATE MATERIALIZED VIEW IF NOT EXISTS {name} AS SELECT DISTINCT entity_id FROM {log_table} WITH NO DATA"
+        ))
+        .execute(&mut conn)?;
+
+        // the unique index is what makes paging fast, but it also allows the view to be
+        // refreshed concurrently if we ever need to refresh while an update is running
+        sql_query(format!("CREATE UNIQUE INDEX IF NOT EXISTS {name}_entity_id ON {name} (entity_id)"))
+            .execute(&mut conn)?;
+
+        sql_query(format!("REFRESH MATERIALIZED VIEW {name}")).execute(&mut conn)?;
+        Ok(())
+    }
+}
+
+
+/// Create and refresh every entity view
+pub fn refresh_all(pool: &PgPool) -> Result<(), Error> {
+    for view in EntityView::all() {
+        view.refresh(pool)?;
+    }
+    Ok(())
+}
@@ -6,6 +6,9 @@ pub enum Error {
     #[error("an error occurred getting a database connection")]
     Pool(#[from] diesel::r2d2::PoolError),
 
+    #[error("an error occurred connecting to the database")]
+    Connection(#[from] diesel::ConnectionError),
+
     #[error("an error occurred parsing the file")]
     Csv(#[from] csv::Error),
 
@@ -18,6 +21,12 @@ pub enum Error {
     #[error(transparent)]
     XmlParser(#[from] quick_xml::Error),
 
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("an error occurred reading the spreadsheet")]
+    Spreadsheet(#[from] calamine::Error),
+
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
 
@@ -29,6 +38,30 @@ pub enum Error {
 
     #[error(transparent)]
     Reduce(#[from] ReduceError),
+
+    #[error("the database schema does not match arga_core: {0}")]
+    SchemaDrift(String),
+
+    #[error("dataset {0} is already locked by {1}")]
+    DatasetLocked(String, String),
+
+    #[error("dataset {0} depends on {1} which hasn't been imported")]
+    MissingDependency(String, String),
+
+    #[error("the identifier service failed: {0}")]
+    Minting(String),
+
+    #[error("the database rejected operation {1} of entity {0}: {2}")]
+    RejectedOperation(String, String, diesel::result::Error),
+
+    #[error("no update is registered with the name {0}")]
+    UnknownUpdate(String),
+
+    #[error("the stages {0} depend on stages that are never run or on each other")]
+    UnresolvedStages(String),
+
+    #[error("--no-merge appends every operation to dataset {0} as-is. Pass --confirm-no-merge {0} to go ahead")]
+    UnconfirmedNoMerge(String),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -42,6 +75,12 @@ pub enum ParseError {
     #[error("invalid archive: could not find {0}")]
     FileNotFound(String),
 
+    #[error("invalid archive stream: {0} comes before meta.toml or the mappings it refers to")]
+    StreamOrder(String),
+
+    #[error("the file has no {0} column")]
+    MissingColumn(String),
+
     #[error(transparent)]
     Toml(#[from] toml::de::Error),
 
@@ -68,10 +107,138 @@ pub enum LookupError {
 
     #[error("cannot find name in database: {0}")]
     Name(String),
+
+    #[error("the DOI does not resolve: {0}")]
+    Doi(String),
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ReduceError {
     #[error("The entity is incomplete and missing an required atom: entity_id: {0}, atom: {1}")]
     MissingAtom(String, String),
+
+    #[error("The reduction is not deterministic. {0} entities diverged between runs")]
+    Nondeterministic(usize),
+
+    #[error("The {0} record does not match its output schema. Unexpected or missing column: {1}")]
+    SchemaMismatch(String, String),
+
+    #[error("The taxonomy has {0} cycles in its parent links")]
+    TaxonomyCycles(usize),
+
+    #[error("The parent links of a cycle in the taxonomy were skipped: {0}")]
+    CyclicParents(String),
+}
+
+
+/// The broad kind of failure, used to pick an exit code that orchestrators can branch on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The command or its environment is misconfigured. eg. a missing file or schema drift
+    Config,
+    /// The data being imported or reduced is invalid
+    Parse,
+    /// The database or identifier service couldn't be reached or rejected a request
+    Database,
+    /// A record refers to something that couldn't be found in the database
+    Lookup,
+    /// The command finished but some records were skipped because of errors
+    PartialSuccess,
+}
+
+impl ErrorCategory {
+    /// The process exit code of the category.
+    ///
+    /// Codes start at 3 since clap already exits with 2 for usage errors and 1 is left for
+    /// failures outside of a command, like a panic handler or the shell.
+    ///
+    /// | Code | Category        |
+    /// | ---- | --------------- |
+    /// | 3    | config          |
+    /// | 4    | parse           |
+    /// | 5    | database        |
+    /// | 6    | lookup          |
+    /// | 7    | partial success |
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorCategory::Config => 3,
+            ErrorCategory::Parse => 4,
+            ErrorCategory::Database => 5,
+            ErrorCategory::Lookup => 6,
+            ErrorCategory::PartialSuccess => 7,
+        }
+    }
+}
+
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Database(_) | Error::Pool(_) | Error::Connection(_) | Error::Minting(_) => ErrorCategory::Database,
+            Error::Io(_)
+            | Error::SchemaDrift(_)
+            | Error::DatasetLocked(_, _)
+            | Error::MissingDependency(_, _)
+            | Error::UnknownUpdate(_)
+            | Error::UnresolvedStages(_)
+            | Error::UnconfirmedNoMerge(_) => ErrorCategory::Config,
+            Error::Lookup(_) => ErrorCategory::Lookup,
+            Error::Reduce(ReduceError::SchemaMismatch(_, _)) => ErrorCategory::Config,
+            Error::Reduce(_) => ErrorCategory::Parse,
+            Error::Parsing(ParseError::FileNotFound(_)) => ErrorCategory::Config,
+            Error::Csv(_)
+            | Error::Parsing(_)
+            | Error::XmlParser(_)
+            | Error::Zip(_)
+            | Error::Spreadsheet(_)
+            | Error::ParseIntError(_)
+            | Error::NomenclaturalActType(_)
+            | Error::RejectedOperation(_, _, _) => ErrorCategory::Parse,
+        }
+    }
+}
+
+
+/// The errors of records that were skipped so that the rest of a command could finish
+static SKIPPED: std::sync::Mutex<std::collections::BTreeMap<ErrorCategory, usize>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// Log the error of a record that is being skipped and count it towards the error summary
+pub fn skip_record(err: &Error) {
+    tracing::error!(?err);
+    let mut skipped = SKIPPED.lock().expect("skipped record counts poisoned");
+    *skipped.entry(err.category()).or_default() += 1;
+}
+
+
+/// A summary of the errors encountered while running a command.
+///
+/// A command that fails reports the category of the error that stopped it, while one that
+/// finishes after skipping records reports a partial success. The counts of skipped records
+/// are included either way so that the cause of a partial success can be found.
+#[derive(Debug, serde::Serialize)]
+pub struct ErrorSummary {
+    pub category: Option<ErrorCategory>,
+    pub exit_code: u8,
+    pub error: Option<String>,
+    pub skipped: std::collections::BTreeMap<ErrorCategory, usize>,
+}
+
+impl ErrorSummary {
+    pub fn new(result: &Result<(), Error>) -> ErrorSummary {
+        let skipped = SKIPPED.lock().expect("skipped record counts poisoned").clone();
+
+        let category = match result {
+            Err(err) => Some(err.category()),
+            Ok(()) if !skipped.is_empty() => Some(ErrorCategory::PartialSuccess),
+            Ok(()) => None,
+        };
+
+        ErrorSummary {
+            exit_code: category.map(|category| category.exit_code()).unwrap_or(0),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            category,
+            skipped,
+        }
+    }
 }
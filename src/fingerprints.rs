@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use diesel::*;
+use serde::Serialize;
+use tracing::info;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+// fingerprints aren't part of arga_core as they are only used by the oplogger to skip
+// unchanged entities during an update. the table is the reduced table they were written to
+diesel::table! {
+    entity_fingerprints (table_name, entity_id) {
+        table_name -> Varchar,
+        entity_id -> Varchar,
+        fingerprint -> Int8,
+    }
+}
+
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = entity_fingerprints)]
+struct EntityFingerprint {
+    table_name: String,
+    entity_id: String,
+    fingerprint: i64,
+}
+
+
+/// Hash the winning atoms of a reduced entity.
+///
+/// The atoms are hashed by their serialized value in sorted order so the fingerprint
+/// doesn't depend on the order the LWW map stores them in.
+pub fn fingerprint<'a, A, I>(atoms: I) -> Result<i64, Error>
+where
+    A: Serialize + 'a,
+    I: IntoIterator<Item = &'a A>,
+{
+    let mut values = Vec::new();
+    for atom in atoms {
+        values.push(serde_json::to_string(atom).map_err(std::io::Error::other)?);
+    }
+    values.sort();
+
+    let mut hasher = Xxh3::new();
+    for value in values {
+        hasher.update(value.as_bytes());
+        hasher.update(&[0]);
+    }
+
+    // postgres has no unsigned integers so the bits are stored as a bigint
+    Ok(hasher.digest() as i64)
+}
+
+
+/// The fingerprints of the entities last written to a reduced table.
+///
+/// An update compares the fingerprint of every reduced entity with the stored one and
+/// skips upserting entities that haven't changed. This avoids rewriting every row on a
+/// nightly run, which postgres would otherwise turn into a new row version and WAL entry
+/// even when the values are the same. New fingerprints are only stored once the caller
+/// has written the records so a failed update won't skip them on the next run.
+///
+/// Only the atoms are hashed, so a change to a lookup like the dataset or taxon ids won't
+/// be picked up. Use a full update to rewrite every entity in that case.
+pub struct Fingerprints {
+    pool: PgPool,
+    table: &'static str,
+    pending: Vec<EntityFingerprint>,
+    pub written: usize,
+    pub skipped: usize,
+}
+
+impl Fingerprints {
+    pub fn new(pool: PgPool, table: &'static str) -> Result<Fingerprints, Error> {
+        let mut conn = pool.get()?;
+        create_fingerprints_table(&mut conn)?;
+
+        Ok(Fingerprints {
+            pool,
+            table,
+            pending: Vec::new(),
+            written: 0,
+            skipped: 0,
+        })
+    }
+
+    /// Load the stored fingerprints of the entities
+    pub fn load(&self, entity_ids: &[&String]) -> Result<HashMap<String, i64>, Error> {
+        use entity_fingerprints::dsl;
+        let mut conn = self.pool.get()?;
+
+        let stored = dsl::entity_fingerprints
+            .select((dsl::entity_id, dsl::fingerprint))
+            .filter(dsl::table_name.eq(self.table))
+            .filter(dsl::entity_id.eq_any(entity_ids))
+            .load::<(String, i64)>(&mut conn)?;
+
+        Ok(stored.into_iter().collect())
+    }
+
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Queue the fingerprint of an entity that will be written
+    pub fn push(&mut self, entity_id: String, fingerprint: i64) {
+        self.pending.push(EntityFingerprint {
+            table_name: self.table.to_string(),
+            entity_id,
+            fingerprint,
+        });
+    }
+
+    /// Store the queued fingerprints. Call this after the records they belong to are written
+    pub fn commit(&mut self) -> Result<(), Error> {
+        use diesel::upsert::excluded;
+        use entity_fingerprints::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        for chunk in self.pending.chunks(1000) {
+            diesel::insert_into(entity_fingerprints)
+                .values(chunk)
+                .on_conflict((table_name, entity_id))
+                .do_update()
+                .set(fingerprint.eq(excluded(fingerprint)))
+                .execute(&mut conn)?;
+        }
+
+        self.written += self.pending.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn finish(&self) {
+        info!(table = self.table, written = self.written, skipped = self.skipped, "Unchanged entities skipped");
+    }
+}
+
+
+/// Remove the stored fingerprints of a table so that the next update writes every entity
+pub fn clear(pool: &PgPool, table: &str) -> Result<(), Error> {
+    use entity_fingerprints::dsl::*;
+
+    let mut conn = pool.get()?;
+    create_fingerprints_table(&mut conn)?;
+
+    let removed = diesel::delete(entity_fingerprints.filter(table_name.eq(table))).execute(&mut conn)?;
+    info!(table, removed, "Cleared entity fingerprints");
+    Ok(())
+}
+
+
+fn create_fingerprints_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS entity_fingerprints (
+            table_name varchar NOT NULL,
+            entity_id varchar NOT NULL,
+            fingerprint bigint NOT NULL,
+            PRIMARY KEY (table_name, entity_id)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use arga_core::schema::dataset_versions;
+use csv::StringRecord;
+use diesel::*;
+use tracing::info;
+use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+// digests aren't part of arga_core as they are only used by the oplogger to skip unchanged
+// frames during an import. the record type is the `IntoFrame::RECORD_TYPE` of the CSV record
+diesel::table! {
+    frame_digests (record_type, dataset_id, entity_id) {
+        record_type -> Varchar,
+        dataset_id -> Uuid,
+        entity_id -> Varchar,
+        digest -> Int8,
+    }
+}
+
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = frame_digests)]
+struct FrameDigest {
+    record_type: String,
+    dataset_id: Uuid,
+    entity_id: String,
+    digest: i64,
+}
+
+
+/// Hash a CSV row with the headers it is deserialized with.
+///
+/// The row is hashed after the field mappings are applied so that changing a mapping
+/// changes the digest of every row it applies to.
+pub fn row_digest(headers: &StringRecord, row: &StringRecord) -> i64 {
+    let mut hasher = Xxh3::new();
+    for (header, field) in headers.iter().zip(row.iter()) {
+        hasher.update(header.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(field.as_bytes());
+        hasher.update(&[0]);
+    }
+
+    // postgres has no unsigned integers so the bits are stored as a bigint
+    hasher.digest() as i64
+}
+
+
+/// The digests of the rows last imported from a dataset.
+///
+/// Providers often deliver the same file again under a new version with most of the rows
+/// unchanged. An unchanged row produces the same frame, and comparing it with the existing
+/// operations only to find nothing changed is most of the work of such an import. Rows
+/// whose digest matches the one stored for the entity in the same dataset are skipped
+/// before they are framed, so they never reach the database comparison at all.
+///
+/// New digests are only stored once every chunk has been imported so a failed import won't
+/// skip the rows it didn't get to on the next run, and never for an entity that had an operation
+/// rejected by the database so that its rows are imported again once they are fixed. A skipped
+/// row doesn't reassert its values, so a change another dataset made to the entity since stays
+/// until the row itself changes. Re-importing an older version of the file is never skipped as
+/// its rows have different digests to the latest ones.
+#[derive(Clone)]
+pub struct FrameDigests {
+    pool: PgPool,
+    record_type: &'static str,
+    dataset_id: Uuid,
+    state: Arc<Mutex<DigestState>>,
+}
+
+#[derive(Default)]
+struct DigestState {
+    stored: HashMap<String, i64>,
+    pending: HashMap<String, i64>,
+    skipped: usize,
+}
+
+impl FrameDigests {
+    /// Load every digest stored for the records of the dataset the version belongs to
+    pub fn load(pool: PgPool, record_type: &'static str, dataset_version_id: &Uuid) -> Result<FrameDigests, Error> {
+        use frame_digests::dsl;
+
+        let mut conn = pool.get()?;
+        create_frame_digests_table(&mut conn)?;
+
+        let dataset_id = dataset_versions::table
+            .filter(dataset_versions::id.eq(dataset_version_id))
+            .select(dataset_versions::dataset_id)
+            .get_result::<Uuid>(&mut conn)?;
+
+        let stored = dsl::frame_digests
+            .select((dsl::entity_id, dsl::digest))
+            .filter(dsl::record_type.eq(record_type))
+            .filter(dsl::dataset_id.eq(dataset_id))
+            .load::<(String, i64)>(&mut conn)?;
+
+        let state = DigestState {
+            stored: stored.into_iter().collect(),
+            ..Default::default()
+        };
+
+        Ok(FrameDigests {
+            pool,
+            record_type,
+            dataset_id,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Returns true if the entity was last imported from a row with the same digest.
+    /// Otherwise the digest is queued to be stored once the import finishes.
+    ///
+    /// Once a row of an entity has changed every later row of it in the same import is
+    /// imported as well, otherwise an earlier row could win over a later one that was skipped.
+    pub fn unchanged(&self, entity_id: &str, digest: i64) -> bool {
+        let mut state = self.state.lock().expect("Frame digests lock poisoned");
+
+        if !state.pending.contains_key(entity_id) && state.stored.get(entity_id) == Some(&digest) {
+            state.skipped += 1;
+            return true;
+        }
+
+        state.pending.insert(entity_id.to_string(), digest);
+        false
+    }
+
+    /// Store the queued digests of the entities that weren't rejected. Call this after every frame
+    /// of the import has been upserted
+    pub fn commit(&self, rejected: &HashSet<String>) -> Result<(), Error> {
+        use diesel::upsert::excluded;
+        use frame_digests::dsl::*;
+
+        let mut state = self.state.lock().expect("Frame digests lock poisoned");
+        let mut conn = self.pool.get()?;
+
+        let pending: Vec<FrameDigest> = state
+            .pending
+            .drain()
+            .filter(|(entity, _)| !rejected.contains(entity))
+            .map(|(entity, value)| FrameDigest {
+                record_type: self.record_type.to_string(),
+                dataset_id: self.dataset_id,
+                entity_id: entity,
+                digest: value,
+            })
+            .collect();
+
+        for chunk in pending.chunks(1000) {
+            diesel::insert_into(frame_digests)
+                .values(chunk)
+                .on_conflict((record_type, dataset_id, entity_id))
+                .do_update()
+                .set(digest.eq(excluded(digest)))
+                .execute(&mut conn)?;
+        }
+
+        info!(
+            written = pending.len(),
+            skipped = state.skipped,
+            rejected = rejected.len(),
+            "Unchanged frames skipped"
+        );
+        Ok(())
+    }
+}
+
+
+fn create_frame_digests_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS frame_digests (
+            record_type varchar NOT NULL,
+            dataset_id uuid NOT NULL REFERENCES datasets ON DELETE CASCADE,
+            entity_id varchar NOT NULL,
+            digest bigint NOT NULL,
+            PRIMARY KEY (record_type, dataset_id, entity_id)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
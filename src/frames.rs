@@ -5,6 +5,18 @@ use crate::errors::Error;
 
 pub trait IntoFrame {
     type Atom;
+
+    /// The name that the digests of the records are stored under. It is persisted so it must
+    /// stay the same when the record type is renamed or moved
+    const RECORD_TYPE: &'static str;
+
+    /// The columns that a provider can record as unknown rather than leaving them out.
+    /// See `Recorded` for how they are logged
+    const UNKNOWN_FIELDS: &'static [&'static str] = &[];
+
+    /// Mark one of the `UNKNOWN_FIELDS` as recorded but unknown
+    fn set_unknown(&mut self, _field: &str) {}
+
     fn into_frame(self, frame: DataFrame<Self::Atom>) -> DataFrame<Self::Atom>;
     fn entity_hashable(&self) -> &[u8];
 }
@@ -87,13 +99,24 @@ impl<A: Default> Frames<A> {
     }
 
     pub fn operations<Op>(self) -> Result<Vec<Op>, Error>
+    where
+        Op: From<DataFrameOperation<A>>,
+    {
+        Ok(self.operations_per_frame()?.0)
+    }
+
+    /// The operations of every frame along with the amount of operations each frame decomposed into
+    pub fn operations_per_frame<Op>(self) -> Result<(Vec<Op>, Vec<usize>), Error>
     where
         Op: From<DataFrameOperation<A>>,
     {
         let mut ops: Vec<Op> = Vec::new();
+        let mut counts = Vec::with_capacity(self.0.len());
         for frame in self.0 {
-            ops.extend(frame?.collect());
+            let frame_ops: Vec<Op> = frame?.collect();
+            counts.push(frame_ops.len());
+            ops.extend(frame_ops);
         }
-        Ok(ops)
+        Ok((ops, counts))
     }
 }
@@ -0,0 +1,267 @@
+use crate::errors::ParseError;
+
+
+/// The geodetic datums used by Australian collections.
+///
+/// Older herbarium and museum records were georeferenced against the Australian Geodetic
+/// Datums which are offset from WGS84 by up to 200 metres. GDA94 and GDA2020 are within
+/// two metres of WGS84 which is below the precision of almost all specimen coordinates,
+/// so they are treated as equivalent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeodeticDatum {
+    Agd66,
+    Agd84,
+    Gda94,
+    Gda2020,
+    Wgs84,
+}
+
+impl std::str::FromStr for GeodeticDatum {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        use GeodeticDatum::*;
+
+        let normalized: String = value.chars().filter(|c| c.is_alphanumeric() || *c == ':').collect();
+
+        match normalized.to_lowercase().as_str() {
+            "agd66" | "australiangeodeticdatum1966" | "epsg:4202" => Ok(Agd66),
+            "agd84" | "australiangeodeticdatum1984" | "epsg:4203" => Ok(Agd84),
+            "gda94" | "geocentricdatumofaustralia1994" | "epsg:4283" => Ok(Gda94),
+            "gda2020" | "geocentricdatumofaustralia2020" | "epsg:7844" => Ok(Gda2020),
+            "wgs84" | "worldgeodeticsystem1984" | "epsg:4326" => Ok(Wgs84),
+            _ => Err(ParseError::InvalidValue(value.to_string())),
+        }
+    }
+}
+
+
+impl GeodeticDatum {
+    /// The common abbreviation of the datum
+    pub fn name(&self) -> &'static str {
+        match self {
+            GeodeticDatum::Agd66 => "AGD66",
+            GeodeticDatum::Agd84 => "AGD84",
+            GeodeticDatum::Gda94 => "GDA94",
+            GeodeticDatum::Gda2020 => "GDA2020",
+            GeodeticDatum::Wgs84 => "WGS84",
+        }
+    }
+}
+
+
+/// An ellipsoid defined by its semi-major axis and inverse flattening
+struct Ellipsoid {
+    a: f64,
+    inverse_flattening: f64,
+}
+
+impl Ellipsoid {
+    fn eccentricity_squared(&self) -> f64 {
+        let f = 1.0 / self.inverse_flattening;
+        f * (2.0 - f)
+    }
+}
+
+/// Australian National Spheroid, used by AGD66 and AGD84
+const ANS: Ellipsoid = Ellipsoid {
+    a: 6_378_160.0,
+    inverse_flattening: 298.25,
+};
+
+/// GRS80, used by GDA94 and effectively the same as the WGS84 ellipsoid
+const GRS80: Ellipsoid = Ellipsoid {
+    a: 6_378_137.0,
+    inverse_flattening: 298.257_222_101,
+};
+
+
+/// A seven parameter Helmert transformation using the coordinate frame rotation convention.
+///
+/// Translations are in metres, rotations in arc seconds, and the scale in parts per million.
+struct Helmert {
+    tx: f64,
+    ty: f64,
+    tz: f64,
+    rx: f64,
+    ry: f64,
+    rz: f64,
+    scale: f64,
+}
+
+impl Helmert {
+    fn transform(&self, (x, y, z): (f64, f64, f64)) -> (f64, f64, f64) {
+        let arc_second = std::f64::consts::PI / (180.0 * 3600.0);
+        let (rx, ry, rz) = (self.rx * arc_second, self.ry * arc_second, self.rz * arc_second);
+        let s = 1.0 + self.scale * 1e-6;
+
+        (
+            self.tx + s * (x + rz * y - ry * z),
+            self.ty + s * (-rz * x + y + rx * z),
+            self.tz + s * (ry * x - rx * y + z),
+        )
+    }
+}
+
+/// AGD66 to GDA94 national transformation parameters from the GDA technical manual
+const AGD66_TO_GDA94: Helmert = Helmert {
+    tx: -117.808,
+    ty: -51.536,
+    tz: 137.784,
+    rx: -0.303,
+    ry: -0.446,
+    rz: -0.234,
+    scale: -0.290,
+};
+
+/// AGD84 to GDA94 transformation parameters from the GDA technical manual
+const AGD84_TO_GDA94: Helmert = Helmert {
+    tx: -117.763,
+    ty: -51.510,
+    tz: 139.061,
+    rx: -0.292,
+    ry: -0.443,
+    rz: -0.277,
+    scale: -0.191,
+};
+
+
+fn to_cartesian(latitude: f64, longitude: f64, ellipsoid: &Ellipsoid) -> (f64, f64, f64) {
+    let (lat, lon) = (latitude.to_radians(), longitude.to_radians());
+    let e2 = ellipsoid.eccentricity_squared();
+    let n = ellipsoid.a / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+
+    (n * lat.cos() * lon.cos(), n * lat.cos() * lon.sin(), n * (1.0 - e2) * lat.sin())
+}
+
+fn to_geodetic((x, y, z): (f64, f64, f64), ellipsoid: &Ellipsoid) -> (f64, f64) {
+    let e2 = ellipsoid.eccentricity_squared();
+    let p = (x * x + y * y).sqrt();
+    let longitude = y.atan2(x);
+
+    // iterate the latitude until it converges, which only takes a few rounds at the
+    // earth's surface since the height is so small compared to the radius
+    let mut latitude = z.atan2(p * (1.0 - e2));
+    for _ in 0..10 {
+        let n = ellipsoid.a / (1.0 - e2 * latitude.sin().powi(2)).sqrt();
+        let height = p / latitude.cos() - n;
+        latitude = z.atan2(p * (1.0 - e2 * n / (n + height)));
+    }
+
+    (latitude.to_degrees(), longitude.to_degrees())
+}
+
+
+/// Convert a latitude and longitude in the specified datum to WGS84.
+///
+/// The Australian Geodetic Datums are converted with the national seven parameter
+/// transformations which are accurate to a few metres. The grid based transformations
+/// are more accurate but the difference is well below the precision of specimen records.
+pub fn to_wgs84(latitude: f64, longitude: f64, datum: GeodeticDatum) -> (f64, f64) {
+    let transformation = match datum {
+        GeodeticDatum::Agd66 => AGD66_TO_GDA94,
+        GeodeticDatum::Agd84 => AGD84_TO_GDA94,
+        GeodeticDatum::Gda94 | GeodeticDatum::Gda2020 | GeodeticDatum::Wgs84 => return (latitude, longitude),
+    };
+
+    let cartesian = to_cartesian(latitude, longitude, &ANS);
+    to_geodetic(transformation.transform(cartesian), &GRS80)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WGS84: Ellipsoid = Ellipsoid {
+        a: 6378137.0,
+        inverse_flattening: 298.257223563,
+    };
+
+    /// Ground distance north and east of the first point in metres, close enough for small offsets
+    fn offset((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> (f64, f64) {
+        let metres_per_degree = 6371000.0 * std::f64::consts::PI / 180.0;
+        let north = (lat2 - lat1) * metres_per_degree;
+        let east = (lon2 - lon1) * metres_per_degree * lat1.to_radians().cos();
+        (north, east)
+    }
+
+    fn assert_close(actual: (f64, f64, f64), expected: (f64, f64, f64), tolerance: f64) {
+        assert!((actual.0 - expected.0).abs() < tolerance, "{actual:?} != {expected:?}");
+        assert!((actual.1 - expected.1).abs() < tolerance, "{actual:?} != {expected:?}");
+        assert!((actual.2 - expected.2).abs() < tolerance, "{actual:?} != {expected:?}");
+    }
+
+    #[test]
+    fn helmert_matches_the_epsg_coordinate_frame_example() {
+        // IOGP guidance note 7-2, EPSG method 1032, WGS 72 to WGS 84
+        let transformation = Helmert {
+            tx: 0.0,
+            ty: 0.0,
+            tz: 4.5,
+            rx: 0.0,
+            ry: 0.0,
+            rz: -0.554,
+            scale: 0.219,
+        };
+
+        let transformed = transformation.transform((3657660.66, 255768.55, 5201382.11));
+        assert_close(transformed, (3657660.78, 255778.43, 5201387.75), 0.01);
+    }
+
+    #[test]
+    fn cartesian_matches_the_epsg_geocentric_example() {
+        // IOGP guidance note 7-2, EPSG method 9602, at an ellipsoidal height of 73 metres
+        let latitude = 53.0 + 48.0 / 60.0 + 33.82 / 3600.0;
+        let longitude = 2.0 + 7.0 / 60.0 + 46.38 / 3600.0;
+        let (x, y, z) = to_cartesian(latitude, longitude, &WGS84);
+
+        // to_cartesian is on the ellipsoid surface so raise the point along its normal
+        let (lat, lon) = (latitude.to_radians(), longitude.to_radians());
+        let height = 73.0;
+        let raised = (x + height * lat.cos() * lon.cos(), y + height * lat.cos() * lon.sin(), z + height * lat.sin());
+
+        assert_close(raised, (3771793.968, 140253.342, 5124304.349), 0.001);
+    }
+
+    #[test]
+    fn geodetic_round_trips_through_cartesian() {
+        for ellipsoid in [&ANS, &GRS80] {
+            for (latitude, longitude) in [(-10.5, 142.2), (-27.47, 153.03), (-37.81, 144.96), (-43.5, 147.3)] {
+                let (lat, lon) = to_geodetic(to_cartesian(latitude, longitude, ellipsoid), ellipsoid);
+                assert!((lat - latitude).abs() < 1e-9, "{lat} != {latitude}");
+                assert!((lon - longitude).abs() < 1e-9, "{lon} != {longitude}");
+            }
+        }
+    }
+
+    #[test]
+    fn australian_geodetic_datums_shift_north_east() {
+        // AGD coordinates sit roughly 200 metres south west of the same point in GDA94
+        for (datum, point) in [
+            (GeodeticDatum::Agd66, (-37.5, 145.2)),
+            (GeodeticDatum::Agd84, (-31.9, 115.9)),
+        ] {
+            let (north, east) = offset(point, to_wgs84(point.0, point.1, datum));
+            let distance = (north * north + east * east).sqrt();
+
+            assert!(north > 0.0 && east > 0.0, "{datum:?} shifted {north} north and {east} east");
+            assert!((150.0..250.0).contains(&distance), "{datum:?} shifted {distance} metres");
+        }
+    }
+
+    #[test]
+    fn geocentric_datums_are_unchanged() {
+        for datum in [GeodeticDatum::Gda94, GeodeticDatum::Gda2020, GeodeticDatum::Wgs84] {
+            assert_eq!(to_wgs84(-35.3, 149.1, datum), (-35.3, 149.1));
+        }
+    }
+
+    #[test]
+    fn parses_names_and_epsg_codes() {
+        assert_eq!("AGD66".parse::<GeodeticDatum>().unwrap(), GeodeticDatum::Agd66);
+        assert_eq!("EPSG:4203".parse::<GeodeticDatum>().unwrap(), GeodeticDatum::Agd84);
+        assert_eq!("Geocentric Datum of Australia 1994".parse::<GeodeticDatum>().unwrap(), GeodeticDatum::Gda94);
+        assert!("NAD27".parse::<GeodeticDatum>().is_err());
+    }
+}
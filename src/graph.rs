@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+
+use arga_core::crdt::lww::Map;
+use arga_core::models::{self, SequenceAtom, SequenceOperation};
+use arga_core::schema;
+use clap::ValueEnum;
+use diesel::dsl::sql;
+use diesel::sql_types::{Bool, Text};
+use diesel::*;
+use serde::Serialize;
+use tracing::info;
+
+use crate::collections::generalize_specimen;
+use crate::database::{no_merge_versions, PgPool};
+use crate::errors::{Error, ParseError};
+use crate::operations::group_operations;
+use crate::readers::sensitivity::SensitivityList;
+
+
+/// How to write a derivation graph
+#[derive(Clone, Copy, ValueEnum)]
+pub enum GraphFormat {
+    /// A graphviz digraph with the attributes of each node in its label
+    Dot,
+    /// A pretty printed JSON object of nodes and edges
+    Json,
+}
+
+
+#[derive(Debug, Serialize)]
+pub struct Node {
+    pub id: String,
+    pub kind: &'static str,
+    pub attributes: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub relation: &'static str,
+}
+
+/// The records derived from an organism or specimen and how they relate
+#[derive(Debug, Default, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+    #[serde(skip)]
+    node_ids: HashSet<String>,
+}
+
+impl Graph {
+    /// Add the node unless one with the same id is already in the graph
+    fn add_node(&mut self, node: Node) {
+        if self.node_ids.insert(node.id.clone()) {
+            self.nodes.push(node);
+        }
+    }
+
+    fn add_edge(&mut self, from: &str, to: &str, relation: &'static str) {
+        self.edges.push(Edge {
+            from: from.to_string(),
+            to: to.to_string(),
+            relation,
+        });
+    }
+
+    pub fn write<W: Write>(&self, format: GraphFormat, mut writer: W) -> Result<(), Error> {
+        match format {
+            GraphFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self).map_err(std::io::Error::other)?;
+                writeln!(writer)?;
+            }
+            GraphFormat::Dot => {
+                writeln!(writer, "digraph provenance {{")?;
+                writeln!(writer, "    node [shape=box];")?;
+                for node in &self.nodes {
+                    let mut label = node.kind.to_string();
+                    for (key, value) in &node.attributes {
+                        label.push_str(&format!("\n{key}: {value}"));
+                    }
+                    writeln!(writer, "    \"{}\" [label=\"{}\"];", escape_dot(&node.id), escape_dot(&label))?;
+                }
+                for edge in &self.edges {
+                    writeln!(
+                        writer,
+                        "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                        escape_dot(&edge.from),
+                        escape_dot(&edge.to),
+                        edge.relation
+                    )?;
+                }
+                writeln!(writer, "}}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Build the derivation graph of an organism or specimen.
+///
+/// The entity can either be the entity id of a specimen or an organism id, in which case
+/// every specimen of the organism is included. Each specimen is linked to the name it was
+/// identified as and to the sequences whose material sample id refers to it. Organisms,
+/// extractions and the other steps of the chain don't have tables here yet so organisms
+/// only appear as their id and the chain ends at the sequences. The coordinates of sensitive
+/// specimens are generalized like any other output.
+pub fn derivation_graph(pool: &PgPool, entity: &str) -> Result<Graph, Error> {
+    use schema::{names, specimens};
+
+    let sensitivity = SensitivityList::load(pool)?;
+    let mut conn = pool.get()?;
+
+    let specimens = specimens::table
+        .filter(specimens::entity_id.eq(entity).or(specimens::organism_id.eq(entity)))
+        .order_by(specimens::record_id)
+        .load::<models::Specimen>(&mut conn)?;
+
+    if specimens.is_empty() {
+        return Err(ParseError::NotFound(format!("specimen or organism {entity}")).into());
+    }
+
+    let mut graph = Graph::default();
+
+    for mut specimen in specimens {
+        generalize_specimen(&sensitivity, &mut specimen);
+        let specimen_node = format!("specimen:{}", specimen.record_id);
+
+        if let Some(organism_id) = &specimen.organism_id {
+            let organism_node = format!("organism:{organism_id}");
+            graph.add_node(Node {
+                id: organism_node.clone(),
+                kind: "organism",
+                attributes: BTreeMap::from([("organism_id".to_string(), organism_id.clone())]),
+            });
+            graph.add_edge(&organism_node, &specimen_node, "collected as");
+        }
+
+        let name = names::table
+            .filter(names::id.eq(specimen.name_id))
+            .select(names::scientific_name)
+            .get_result::<String>(&mut conn)
+            .optional()?;
+
+        if let Some(name) = name {
+            let name_node = format!("name:{}", specimen.name_id);
+            graph.add_node(Node {
+                id: name_node.clone(),
+                kind: "name",
+                attributes: BTreeMap::from([("scientific_name".to_string(), name)]),
+            });
+            graph.add_edge(&specimen_node, &name_node, "identified as");
+        }
+
+        if let Some(material_sample_id) = &specimen.material_sample_id {
+            for sequence in sequences_of(&mut conn, material_sample_id)? {
+                graph.add_edge(&specimen_node, &sequence.id, "sequenced as");
+                graph.add_node(sequence);
+            }
+        }
+
+        graph.add_node(specimen_node_of(specimen_node.clone(), specimen));
+    }
+
+    info!(entity, nodes = graph.nodes.len(), edges = graph.edges.len(), "Built derivation graph");
+    Ok(graph)
+}
+
+
+fn specimen_node_of(id: String, specimen: models::Specimen) -> Node {
+    let mut attributes = BTreeMap::new();
+    let mut insert = |key: &str, value: Option<String>| {
+        if let Some(value) = value {
+            attributes.insert(key.to_string(), value);
+        }
+    };
+
+    insert("entity_id", specimen.entity_id);
+    insert("record_id", Some(specimen.record_id));
+    insert("material_sample_id", specimen.material_sample_id);
+    insert("institution_code", specimen.institution_code);
+    insert("collection_code", specimen.collection_code);
+    insert("type_status", specimen.type_status);
+    insert("recorded_by", specimen.recorded_by);
+    insert("locality", specimen.locality);
+    insert("country", specimen.country);
+    insert("latitude", specimen.latitude.map(|value| value.to_string()));
+    insert("longitude", specimen.longitude.map(|value| value.to_string()));
+
+    Node {
+        id,
+        kind: "specimen",
+        attributes,
+    }
+}
+
+
+/// Reduce the sequences that currently refer to the material sample.
+///
+/// Sequences aren't reduced into a table yet so the logs that ever set the material sample
+/// id are reduced here, dropping any sequence that has since been changed to another sample.
+fn sequences_of(conn: &mut PgConnection, material_sample_id: &str) -> Result<Vec<Node>, Error> {
+    use schema::sequence_logs::dsl::*;
+
+    let entity_ids = sequence_logs
+        .filter(sql::<Bool>("atom ->> 'MaterialSampleId' = ").bind::<Text, _>(material_sample_id))
+        .select(entity_id)
+        .distinct()
+        .load::<String>(conn)?;
+
+    let no_merge = no_merge_versions(conn)?;
+    let operations = sequence_logs
+        .filter(entity_id.eq_any(&entity_ids))
+        .filter(dataset_version_id.ne_all(&no_merge))
+        .order(operation_id.asc())
+        .load::<SequenceOperation>(conn)?;
+
+    let mut sequences = Vec::new();
+    for (key, ops) in group_operations(operations, vec![]).into_iter() {
+        let mut map = Map::new(key);
+        map.reduce(&ops);
+
+        let mut attributes = BTreeMap::new();
+        let mut current_sample = None;
+        for atom in map.atoms.into_values() {
+            if let SequenceAtom::MaterialSampleId(value) = &atom {
+                current_sample = Some(value.clone());
+            }
+            if let Some((name, value)) = atom_attribute(&atom) {
+                attributes.insert(name, value);
+            }
+        }
+
+        if current_sample.as_deref() == Some(material_sample_id) {
+            sequences.push(Node {
+                id: format!("sequence:{}", map.entity_id),
+                kind: "sequence",
+                attributes,
+            });
+        }
+    }
+
+    sequences.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(sequences)
+}
+
+
+/// The field name and value of an atom, from its serialized single key object
+fn atom_attribute<A: Serialize>(atom: &A) -> Option<(String, String)> {
+    let serde_json::Value::Object(object) = serde_json::to_value(atom).ok()?
+    else {
+        return None;
+    };
+    let (name, value) = object.into_iter().next()?;
+    let value = match value {
+        serde_json::Value::String(value) => value,
+        value => value.to_string(),
+    };
+    Some((name, value))
+}
+
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,147 @@
+use arga_core::models::LogOperation;
+use diesel::*;
+use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+// the chunks an import has finished only matter to the oplogger so they aren't part of arga_core
+diesel::table! {
+    import_runs (dataset_version_id, chunk_key) {
+        dataset_version_id -> Uuid,
+        chunk_key -> Int8,
+        operations -> Int8,
+        inserted -> Int8,
+        completed_at -> Timestamptz,
+    }
+}
+
+
+/// The chunks of operations that were completely imported into a dataset version.
+///
+/// Every loader inserts with `ON CONFLICT DO NOTHING` on the operation id, but the operation
+/// clock gives a chunk new ids every time it is sent, so a chunk resent by a retried import
+/// doesn't conflict with the operations it already inserted. Instead each chunk is keyed by
+/// the entities and atoms of its operations, which are the same every time the file is read,
+/// and the key is recorded once all of its operations are inserted. Retrying the import of the
+/// same dataset version skips the chunks that have a key and only imports the rest.
+///
+/// A chunk that had an operation rejected by the database isn't recorded so that it is tried
+/// again once the data is fixed.
+#[derive(Clone)]
+pub struct ImportRuns {
+    pool: PgPool,
+}
+
+impl ImportRuns {
+    pub fn new(pool: &PgPool) -> Result<ImportRuns, Error> {
+        let mut conn = pool.get()?;
+        create_table(&mut conn)?;
+        Ok(ImportRuns { pool: pool.clone() })
+    }
+
+    /// Whether the chunk was already imported into the dataset version
+    pub fn completed(&self, version_id: &Uuid, key: i64) -> Result<bool, Error> {
+        use import_runs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        let found = import_runs
+            .filter(dataset_version_id.eq(version_id))
+            .filter(chunk_key.eq(key))
+            .count()
+            .get_result::<i64>(&mut conn)?;
+
+        Ok(found > 0)
+    }
+
+    /// Record that every operation of the chunk was imported into the dataset version
+    pub fn complete(&self, version_id: &Uuid, key: i64, total: usize, total_inserted: usize) -> Result<(), Error> {
+        use import_runs::dsl::*;
+
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(import_runs)
+            .values((
+                dataset_version_id.eq(version_id),
+                chunk_key.eq(key),
+                operations.eq(total as i64),
+                inserted.eq(total_inserted as i64),
+                completed_at.eq(chrono::Utc::now()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&mut conn)?;
+
+        Ok(())
+    }
+}
+
+
+/// The idempotency key of a chunk of operations.
+///
+/// Only the entity and atom of each operation is hashed since the operation ids change every
+/// time the chunk is stamped by the operation clock.
+pub fn chunk_key<A, O>(operations: &[O]) -> i64
+where
+    A: ToString,
+    O: LogOperation<A>,
+{
+    let mut hasher = Xxh3::new();
+    for operation in operations {
+        hasher.update(operation.entity_id().as_bytes());
+        hasher.update(&[0]);
+        hasher.update(operation.atom().to_string().as_bytes());
+        hasher.update(&[0]);
+    }
+
+    // postgres has no unsigned integers so the bits are stored as a bigint
+    hasher.digest() as i64
+}
+
+
+fn create_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS import_runs (
+            dataset_version_id uuid NOT NULL,
+            chunk_key bigint NOT NULL,
+            operations bigint NOT NULL,
+            inserted bigint NOT NULL,
+            completed_at timestamptz NOT NULL,
+            PRIMARY KEY (dataset_version_id, chunk_key)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arga_core::crdt::{DataFrame, Version};
+    use arga_core::models::{TaxonAtom, TaxonOperation};
+
+    use super::*;
+
+    fn operations(names: &[&str]) -> Vec<TaxonOperation> {
+        let mut frame = DataFrame::create("entity".to_string(), Uuid::new_v4(), Version::new());
+        for name in names {
+            frame.push(TaxonAtom::ScientificName(name.to_string()));
+        }
+        frame.collect()
+    }
+
+    #[test]
+    fn chunk_key_ignores_the_operation_ids() {
+        // every frame is created with new operation ids and a new dataset version
+        let first = chunk_key::<TaxonAtom, _>(&operations(&["Aus bus", "Aus cus"]));
+        let second = chunk_key::<TaxonAtom, _>(&operations(&["Aus bus", "Aus cus"]));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn chunk_key_changes_with_the_atoms() {
+        let first = chunk_key::<TaxonAtom, _>(&operations(&["Aus bus", "Aus cus"]));
+        let second = chunk_key::<TaxonAtom, _>(&operations(&["Aus bus", "Aus dus"]));
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,50 @@
+//! The ARGA operation logger as a library.
+//!
+//! The `oplogger` binary is built on this crate, which also lets other teams derive their
+//! own materializations from the operation logs. Implement `reducer::Reducer` for the
+//! record to materialize and `reducer::EntityPager` for the log table to page through, then
+//! write the records with `reducer::update_table` to get the same progress bars, fingerprints
+//! and skipped record handling as the built in updates. Registering the update with
+//! `updates::register` runs it as part of `updates::update_all` after its dependencies.
+//!
+//! Only the public modules make up the library. The command line interface of the binary
+//! lives in the hidden `cli` module and everything else is internal to the crate.
+
+mod archive;
+mod cardinality;
+#[doc(hidden)]
+pub mod cli;
+mod clock;
+mod concept_links;
+pub mod database;
+mod dataset_lock;
+mod determinism;
+mod entity_views;
+pub mod errors;
+mod fingerprints;
+mod frame_digests;
+mod frames;
+mod geodesy;
+mod graph;
+mod import_runs;
+mod journal;
+mod links;
+mod loggers;
+mod maintenance;
+mod minting;
+mod operations;
+mod output;
+mod precedence;
+mod push;
+mod readers;
+pub mod reducer;
+mod relink;
+mod schema_check;
+mod taxonomy;
+pub mod updates;
+mod utils;
+mod vocabulary;
+mod warnings;
+mod watermarks;
+
+use loggers::*;
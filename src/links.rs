@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use tracing::info;
+
+use crate::concept_links;
+use crate::database::PgPool;
+use crate::errors::Error;
+use crate::loggers::{names, taxa};
+use crate::updates::run_in_waves;
+
+
+/// A single link stage that can be run as part of `link all`.
+///
+/// Like the update stages each link declares the stages it depends on. Links read the
+/// rows written by earlier links, for example taxa are linked to names through the
+/// variants linked in the names stage, so the stages are always run one after another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum LinkStage {
+    /// Link name variants to the names of their correct spelling
+    Names,
+    /// Link taxa to their names and parents
+    Taxa,
+    /// Link the taxa of different datasets that are the same concept by their name and authorship
+    Concepts,
+}
+
+impl LinkStage {
+    pub fn all() -> Vec<LinkStage> {
+        use LinkStage::*;
+        vec![Names, Taxa, Concepts]
+    }
+
+    pub fn dependencies(&self) -> Vec<LinkStage> {
+        use LinkStage::*;
+
+        match self {
+            Names => vec![],
+            // taxon names are looked up with the variants linked to their accepted names
+            Taxa => vec![Names],
+            // concepts are matched on the taxa table so they don't need the other links
+            Concepts => vec![],
+        }
+    }
+
+    pub fn run(&self, pool: &PgPool) -> Result<(), Error> {
+        match self {
+            LinkStage::Names => names::link_variants(pool),
+            LinkStage::Taxa => taxa::link(),
+            LinkStage::Concepts => concept_links::link(pool, None),
+        }
+    }
+}
+
+
+/// Run the link stages in dependency order.
+///
+/// When `only` isn't empty just those stages are run, and any stage in `skip` is left out.
+/// Dependencies of a selected stage aren't added implicitly so a single stage can be rerun
+/// without repeating the ones it depends on, but the selected stages still run in an order
+/// where a stage comes after its dependencies. The time each stage took is logged as it finishes.
+pub fn link_all(pool: PgPool, only: &[LinkStage], skip: &[LinkStage]) -> Result<(), Error> {
+    let selected: HashSet<LinkStage> = LinkStage::all()
+        .into_iter()
+        .filter(|stage| only.is_empty() || only.contains(stage))
+        .filter(|stage| !skip.contains(stage))
+        .collect();
+
+    let started = Instant::now();
+
+    // a dependency that isn't selected is treated as already linked
+    let dependencies = |stage: &LinkStage| {
+        let mut dependencies = stage.dependencies();
+        dependencies.retain(|dep| selected.contains(dep));
+        dependencies
+    };
+
+    let stages = LinkStage::all().into_iter().filter(|stage| selected.contains(stage)).collect();
+    run_in_waves(stages, dependencies, |wave| {
+        for stage in wave {
+            info!(?stage, "Running link stage");
+            let stage_started = Instant::now();
+            stage.run(&pool)?;
+            info!(?stage, elapsed = ?stage_started.elapsed(), "Finished link stage");
+        }
+        Ok(())
+    })?;
+
+    info!(stages = selected.len(), elapsed = ?started.elapsed(), "Finished linking all tables");
+    Ok(())
+}
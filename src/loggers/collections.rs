@@ -1,34 +1,121 @@
+use std::collections::HashSet;
 use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use arga_core::crdt::lww::Map;
 use arga_core::crdt::DataFrame;
 use arga_core::models::{self, LogOperation, SpecimenAtom, SpecimenOperation};
 use arga_core::schema;
+use chrono::{DateTime, Utc};
 use diesel::*;
 use rayon::prelude::*;
-use serde::Deserialize;
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::database::{dataset_lookup, name_lookup, FrameLoader, PgPool, StringMap};
-use crate::errors::Error;
-use crate::frames::IntoFrame;
+use crate::database::{
+    dataset_lookup,
+    dataset_version_lookup,
+    get_pool,
+    name_lookup,
+    no_merge_versions,
+    FrameLoader,
+    PgPool,
+    StringMap,
+};
+use crate::determinism::EntityRecord;
+use crate::entity_views::{specimen_entities, EntityView};
+use crate::errors::{skip_record, Error};
+use crate::frames::{FrameReader, IntoFrame};
+use crate::geodesy::{to_wgs84, GeodeticDatum};
+use crate::minting;
+use crate::output::OutputSchema;
+use crate::precedence;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::institutions::InstitutionRegistry;
+use crate::readers::mappings::{FieldMappings, Recorded};
+use crate::readers::sensitivity::{generalize, SensitivityList};
+use crate::readers::xlsx::SheetOptions;
 use crate::readers::{meta, OperationLoader};
 use crate::reducer::{DatabaseReducer, EntityPager, Reducer};
-use crate::utils::{new_progress_bar, titleize_first_word};
-use crate::{frame_push_opt, import_compressed_csv_stream, FrameProgress};
+use crate::utils::{
+    decode_list,
+    empty_as_none,
+    encode_list,
+    geodetic_datum_from_str_opt,
+    list_from_str,
+    new_progress_bar,
+    new_spinner,
+    recorded_from_str,
+    titleize_first_word,
+};
+use crate::{frame_push_opt, import_compressed_csv_stream, import_frames_from_stream, FrameProgress};
 
 type SpecimenFrame = DataFrame<SpecimenAtom>;
 
 
+// the specimens table only has a text column for agents so the individual people are kept here
+diesel::table! {
+    specimen_agents (entity_id, role, position) {
+        entity_id -> Text,
+        role -> Text,
+        position -> Int4,
+        name -> Text,
+    }
+}
+
+// the precision that the coordinates of a sensitive specimen have to be generalized to when they leave
+// the specimens table, which keeps the original coordinates
+diesel::table! {
+    specimen_generalizations (entity_id) {
+        entity_id -> Text,
+        precision -> Float8,
+    }
+}
+
+// the institution code and name as the dataset had them before the code was normalized
+diesel::table! {
+    specimen_verbatim_institutions (entity_id) {
+        entity_id -> Text,
+        institution_code -> Nullable<Text>,
+        institution_name -> Nullable<Text>,
+    }
+}
+
+// the coordinates and datum as the dataset had them before they were converted to WGS84
+diesel::table! {
+    specimen_verbatim_coordinates (entity_id) {
+        entity_id -> Text,
+        latitude -> Float8,
+        longitude -> Float8,
+        geodetic_datum -> Text,
+    }
+}
+
+
+/// The verbatim coordinates of the records framed since they were last stored.
+///
+/// Records are framed deep inside the generic readers which don't have a connection, so the
+/// coordinates are collected here and written by `store_verbatim_coordinates` once the
+/// import finishes.
+static VERBATIM_COORDINATES: Mutex<Vec<VerbatimCoordinates>> = Mutex::new(Vec::new());
+
+
 impl OperationLoader for FrameLoader<SpecimenOperation> {
     type Operation = SpecimenOperation;
+    const LOG_TABLE: &'static str = "specimen_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<SpecimenOperation>, Error> {
         use schema::specimen_logs::dsl::*;
         let mut conn = self.pool.get()?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = specimen_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<SpecimenOperation>(&mut conn)?;
 
@@ -57,20 +144,31 @@ impl OperationLoader for FrameLoader<SpecimenOperation> {
 // in order to split them up into different operation logs down the line without having
 // to reprocess the CSV file.
 #[derive(Debug, Clone, Deserialize)]
-struct Record {
-    entity_id: String,
-    record_id: String,
-    scientific_name: String,
-    canonical_name: String,
-    scientific_name_authority: Option<String>,
+pub struct Record {
+    pub entity_id: String,
+    pub record_id: String,
+    pub scientific_name: String,
+    pub canonical_name: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub scientific_name_authority: Option<String>,
 
-    type_status: Option<String>,
-    institution_name: Option<String>,
-    institution_code: Option<String>,
+    #[serde(default, deserialize_with = "recorded_from_str")]
+    pub type_status: Option<Recorded>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub institution_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub institution_code: Option<String>,
+
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    #[serde(default, deserialize_with = "geodetic_datum_from_str_opt")]
+    pub geodetic_datum: Option<GeodeticDatum>,
     // collection_code: Option<String>,
     // catalog_number: Option<String>,
-    // collected_by: Option<String>,
-    // identified_by: Option<String>,
+    #[serde(default, deserialize_with = "list_from_str")]
+    pub collected_by: Vec<String>,
+    #[serde(default, deserialize_with = "list_from_str")]
+    pub identified_by: Vec<String>,
     // identified_date: Option<String>,
     // organism_id: Option<String>,
     // material_sample_id: Option<String>,
@@ -85,8 +183,6 @@ struct Record {
     // state_province: Option<String>,
     // county: Option<String>,
     // municipality: Option<String>,
-    // latitude: Option<f64>,
-    // longitude: Option<f64>,
     // // verbatim_lat_long: Option<String>,
     // elevation: Option<f64>,
     // depth: Option<f64>,
@@ -129,6 +225,15 @@ struct Record {
 impl IntoFrame for Record {
     type Atom = SpecimenAtom;
 
+    const RECORD_TYPE: &'static str = "collections";
+    const UNKNOWN_FIELDS: &'static [&'static str] = &["type_status"];
+
+    fn set_unknown(&mut self, field: &str) {
+        if field == "type_status" {
+            self.type_status = Some(Recorded::Unknown);
+        }
+    }
+
     fn entity_hashable(&self) -> &[u8] {
         self.entity_id.as_bytes()
     }
@@ -140,27 +245,122 @@ impl IntoFrame for Record {
         frame.push(ScientificName(titleize_first_word(&self.scientific_name)));
         frame.push(CanonicalName(titleize_first_word(&self.canonical_name)));
         frame_push_opt!(frame, Authorship, self.scientific_name_authority);
-        frame_push_opt!(frame, TypeStatus, self.type_status);
+        frame_push_opt!(frame, TypeStatus, self.type_status.map(String::from));
         frame_push_opt!(frame, InstitutionName, self.institution_name);
         frame_push_opt!(frame, InstitutionCode, self.institution_code);
+
+        if !self.collected_by.is_empty() {
+            frame.push(RecordedBy(encode_list(&self.collected_by)));
+        }
+        if !self.identified_by.is_empty() {
+            frame.push(IdentifiedBy(encode_list(&self.identified_by)));
+        }
+
+        // coordinates are always logged as WGS84 so that records from different datums can be
+        // compared. the verbatim coordinates and datum are kept in a side table for auditing
+        if let (Some(latitude), Some(longitude)) = (self.latitude, self.longitude) {
+            let (latitude, longitude) = match self.geodetic_datum {
+                Some(datum) => {
+                    keep_verbatim_coordinates(VerbatimCoordinates {
+                        entity_id: xxh3_64(self.entity_hashable()).to_string(),
+                        latitude,
+                        longitude,
+                        geodetic_datum: datum.name().to_string(),
+                    });
+                    to_wgs84(latitude, longitude, datum)
+                }
+                None => (latitude, longitude),
+            };
+            frame.push(Latitude(latitude));
+            frame.push(Longitude(longitude));
+        }
+
         frame
     }
 }
 
 
-pub fn import_archive<S: Read + FrameProgress>(stream: S, dataset: &meta::Dataset) -> Result<(), Error> {
-    import_compressed_csv_stream::<S, Record, SpecimenOperation>(stream, dataset)
+/// Import frames of specimens from the stream
+pub fn import_frames<R>(reader: R, pool: PgPool) -> Result<(), Error>
+where
+    R: FrameReader<Atom = SpecimenAtom> + FrameProgress,
+    R: Iterator<Item = Result<DataFrame<R::Atom>, Error>>,
+{
+    import_frames_from_stream::<SpecimenOperation, R>(reader, pool.clone())?;
+    store_verbatim_coordinates(&pool)
 }
 
 
-pub fn update() -> Result<(), Error> {
-    let mut pool = crate::database::get_pool()?;
+pub fn import_archive<S: Read + FrameProgress>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error> {
+    import_compressed_csv_stream::<S, Record, SpecimenOperation>(stream, dataset, mappings)?;
+    store_verbatim_coordinates(&get_pool()?)
+}
 
+
+pub struct Collections {
+    pub path: PathBuf,
+    pub dataset_version_id: Uuid,
+    pub sheet: SheetOptions,
+    /// The timestamp column to skip rows older than the dataset watermark by
+    pub since: Option<String>,
+    /// The sentinels of the columns recorded as unknown
+    pub mappings: FieldMappings,
+}
+
+impl Collections {
+    /// Import the CSV or spreadsheet file as specimen operations into the specimen_logs table.
+    ///
+    /// This will parse and decompose the CSV file, merge it with the existing logs
+    /// and then insert them into the database, effectively updating specimen_logs with the
+    /// latest changes from the dataset.
+    pub fn import(&self) -> Result<(), Error> {
+        crate::import_file_as_logs::<Record, SpecimenOperation>(
+            &self.path,
+            &self.dataset_version_id,
+            &self.sheet,
+            &self.mappings,
+            self.since.as_deref(),
+        )?;
+        store_verbatim_coordinates(&get_pool()?)?;
+        info!("Specimen operations import finished");
+        Ok(())
+    }
+
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<Record>()
+    }
+}
+
+
+/// Reduce the specimen logs and update the specimens table.
+///
+/// The collectors and identifiers of each specimen are also written to the `specimen_agents`
+/// table, one row per person in the order they were listed, so that they can be linked to
+/// agents individually. The specimens table gets the same people joined with semicolons.
+///
+/// The specimens table keeps the original coordinates of every specimen. The specimens of a taxon
+/// in the stored sensitivity list get the precision of their taxon recorded in
+/// `specimen_generalizations`, and their coordinates are generalized to the centre of a grid
+/// cell of that size whenever they are output, pushed or exported.
+///
+/// When an institution registry is provided the institution code of every specimen is normalized
+/// against it. The verbatim code and name of a normalized specimen are kept in the
+/// `specimen_verbatim_institutions` table and any code or name that can't be found in the
+/// registry is reported at the end of the update.
+pub fn update(mut pool: PgPool, institutions: Option<InstitutionRegistry>) -> Result<(), Error> {
     let lookups = Lookups {
         names: name_lookup(&mut pool)?,
         datasets: dataset_lookup(&mut pool)?,
     };
 
+    let sensitivity = SensitivityList::load(&pool)?;
+
+    EntityView::Specimens.refresh(&pool)?;
     let pager: FrameLoader<SpecimenOperation> = FrameLoader::new(pool.clone());
     let bar = new_progress_bar(pager.total()? as usize, "Updating specimens");
 
@@ -170,22 +370,53 @@ pub fn update() -> Result<(), Error> {
     let total_entities = pager.total()?;
     info!(total_entities, "Reducing specimens");
 
-    let reducer: DatabaseReducer<models::Specimen, _, _> = DatabaseReducer::new(pager, lookups);
+    let mut reducer: DatabaseReducer<ReducedSpecimen, _, _> = DatabaseReducer::new(pager, lookups);
     let mut conn = pool.get()?;
+    create_agents_table(&mut conn)?;
+    create_generalizations_table(&mut conn)?;
+    create_verbatim_institutions_table(&mut conn)?;
+    let mut unmatched_institutions = HashSet::new();
+
+    while let Some(records) = reducer.next() {
+        let started = Instant::now();
 
-    for records in reducer.into_iter() {
         for chunk in records.chunks(1000) {
             use diesel::upsert::excluded;
             use schema::specimens::dsl::*;
 
             let mut valid_records = Vec::new();
+            let mut agents = Vec::new();
             for record in chunk {
                 match record {
-                    Ok(record) => valid_records.push(record),
-                    Err(err) => error!(?err),
+                    Ok(record) => {
+                        valid_records.push(record.specimen.clone());
+                        agents.extend(record.agents.iter().cloned());
+                    }
+                    Err(err) => skip_record(err),
                 }
             }
 
+            let mut verbatim_institutions = Vec::new();
+            if let Some(registry) = &institutions {
+                for record in valid_records.iter_mut() {
+                    match normalize_institution(registry, record) {
+                        Ok(Some(verbatim)) => verbatim_institutions.push(verbatim),
+                        Ok(None) => {}
+                        Err(unmatched) => {
+                            unmatched_institutions.insert(unmatched);
+                        }
+                    }
+                }
+            }
+
+            let generalizations: Vec<SpecimenGeneralization> = valid_records
+                .iter()
+                .filter_map(|record| sensitive_precision(&sensitivity, record))
+                .collect();
+
+            let entity_ids: Vec<String> = valid_records.iter().filter_map(|r| r.entity_id.clone()).collect();
+            let entity_ids: Vec<&String> = entity_ids.iter().collect();
+
             // postgres always creates a new row version so we cant get
             // an actual figure of the amount of records changed
             diesel::insert_into(specimens)
@@ -224,11 +455,228 @@ pub fn update() -> Result<(), Error> {
                 ))
                 .execute(&mut conn)?;
 
+            replace_agents(&mut conn, &entity_ids, &agents)?;
+            replace_generalizations(&mut conn, &entity_ids, &generalizations)?;
+            replace_verbatim_institutions(&mut conn, &entity_ids, &verbatim_institutions)?;
+
             bar.inc(chunk.len() as u64);
         }
+
+        reducer.record_write(started.elapsed());
     }
 
     bar.finish();
+    reducer.finish();
+
+    if !unmatched_institutions.is_empty() {
+        let mut unmatched: Vec<String> = unmatched_institutions.into_iter().collect();
+        unmatched.sort();
+        warn!(total = unmatched.len(), ?unmatched, "Institutions not found in the registry");
+    }
+
+    minting::mint_new_entities(&pool, "specimens")?;
+    Ok(())
+}
+
+
+/// Replace the agents of the specimens with the ones from the latest reduction.
+///
+/// Lists can shrink between dataset versions so the existing agents are removed first
+/// rather than upserted, otherwise a person dropped from a list would linger.
+fn replace_agents(conn: &mut PgConnection, entity_ids: &[&String], agents: &[SpecimenAgent]) -> Result<(), Error> {
+    use specimen_agents::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::delete(specimen_agents.filter(entity_id.eq_any(entity_ids))).execute(conn)?;
+        diesel::insert_into(specimen_agents).values(agents).execute(conn)?;
+        Ok(())
+    })
+}
+
+
+fn create_agents_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS specimen_agents (
+            entity_id text NOT NULL,
+            role text NOT NULL,
+            position integer NOT NULL,
+            name text NOT NULL,
+            PRIMARY KEY (entity_id, role, position)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+/// The precision to generalize the coordinates of a specimen to if its taxon is in the sensitivity list
+fn sensitive_precision(sensitivity: &SensitivityList, record: &models::Specimen) -> Option<SpecimenGeneralization> {
+    let precision = sensitivity.precision(&record.name_id)?;
+    let entity_id = record.entity_id.clone()?;
+    Some(SpecimenGeneralization { entity_id, precision })
+}
+
+
+/// Generalize the coordinates of a specimen from the specimens table if its taxon is in the
+/// sensitivity list. Anything that outputs specimens from the table has to call this
+pub fn generalize_specimen(sensitivity: &SensitivityList, specimen: &mut models::Specimen) {
+    if let Some(precision) = sensitivity.precision(&specimen.name_id) {
+        specimen.latitude = specimen.latitude.map(|latitude| generalize(latitude, precision));
+        specimen.longitude = specimen.longitude.map(|longitude| generalize(longitude, precision));
+    }
+}
+
+
+/// Replace the generalizations of the specimens with the ones from the latest reduction.
+///
+/// A taxon can be removed from the sensitivity list so the existing rows are removed first
+/// in the same way as the agents.
+fn replace_generalizations(
+    conn: &mut PgConnection,
+    entity_ids: &[&String],
+    generalizations: &[SpecimenGeneralization],
+) -> Result<(), Error> {
+    use specimen_generalizations::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::delete(specimen_generalizations.filter(entity_id.eq_any(entity_ids))).execute(conn)?;
+        diesel::insert_into(specimen_generalizations)
+            .values(generalizations)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+
+pub fn create_generalizations_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS specimen_generalizations (
+            entity_id text PRIMARY KEY,
+            precision double precision NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+/// Replace the institution code with the normalized code from the registry.
+///
+/// The institution code is preferred but we fall back to the institution name when the code
+/// can't be matched, since some providers only supply the full name or use a code of their own.
+/// The verbatim code and name are returned when the code was normalized so they can be kept.
+/// If neither can be matched the record is left untouched and the verbatim value is returned
+/// as an error so that it can be reported.
+fn normalize_institution(
+    registry: &InstitutionRegistry,
+    record: &mut models::Specimen,
+) -> Result<Option<VerbatimInstitution>, String> {
+    let code = record.institution_code.as_ref().and_then(|code| registry.normalize(code));
+    let name = record.institution_name.as_ref().and_then(|name| registry.normalize(name));
+
+    let normalized = match code.or(name) {
+        Some(normalized) => normalized.clone(),
+        None => {
+            return match record.institution_code.as_ref().or(record.institution_name.as_ref()) {
+                Some(verbatim) => Err(verbatim.clone()),
+                None => Ok(None),
+            };
+        }
+    };
+
+    let verbatim = record.entity_id.clone().map(|entity_id| VerbatimInstitution {
+        entity_id,
+        institution_code: record.institution_code.clone(),
+        institution_name: record.institution_name.clone(),
+    });
+
+    record.institution_code = Some(normalized);
+    Ok(verbatim)
+}
+
+
+/// Replace the verbatim institutions of the specimens with the ones from the latest reduction.
+///
+/// The registry can change or be left out of an update so the existing rows are removed first
+/// in the same way as the generalizations.
+fn replace_verbatim_institutions(
+    conn: &mut PgConnection,
+    entity_ids: &[&String],
+    institutions: &[VerbatimInstitution],
+) -> Result<(), Error> {
+    use specimen_verbatim_institutions::dsl::*;
+
+    conn.transaction(|conn| {
+        diesel::delete(specimen_verbatim_institutions.filter(entity_id.eq_any(entity_ids))).execute(conn)?;
+        diesel::insert_into(specimen_verbatim_institutions)
+            .values(institutions)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+
+fn create_verbatim_institutions_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS specimen_verbatim_institutions (
+            entity_id text PRIMARY KEY,
+            institution_code text,
+            institution_name text
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+fn keep_verbatim_coordinates(coordinates: VerbatimCoordinates) {
+    VERBATIM_COORDINATES
+        .lock()
+        .expect("Verbatim coordinates lock poisoned")
+        .push(coordinates);
+}
+
+
+/// Store the verbatim coordinates collected while framing the imported records.
+///
+/// Only the records with a datum are converted so the others are already verbatim in the
+/// logs. A later import of the same specimen replaces its verbatim coordinates.
+pub fn store_verbatim_coordinates(pool: &PgPool) -> Result<(), Error> {
+    use diesel::upsert::excluded;
+    use specimen_verbatim_coordinates::dsl::*;
+
+    let collected = std::mem::take(&mut *VERBATIM_COORDINATES.lock().expect("Verbatim coordinates lock poisoned"));
+    let mut conn = pool.get()?;
+    create_verbatim_coordinates_table(&mut conn)?;
+
+    for chunk in collected.chunks(10_000) {
+        diesel::insert_into(specimen_verbatim_coordinates)
+            .values(chunk)
+            .on_conflict(entity_id)
+            .do_update()
+            .set((
+                latitude.eq(excluded(latitude)),
+                longitude.eq(excluded(longitude)),
+                geodetic_datum.eq(excluded(geodetic_datum)),
+            ))
+            .execute(&mut conn)?;
+    }
+
+    info!(total = collected.len(), "Verbatim coordinates stored");
+    Ok(())
+}
+
+
+fn create_verbatim_coordinates_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS specimen_verbatim_coordinates (
+            entity_id text PRIMARY KEY,
+            latitude double precision NOT NULL,
+            longitude double precision NOT NULL,
+            geodetic_datum text NOT NULL
+        )",
+    )
+    .execute(conn)?;
     Ok(())
 }
 
@@ -239,7 +687,66 @@ struct Lookups {
 }
 
 
-impl Reducer<Lookups> for models::Specimen {
+/// A single person in a specimen's list of collectors or identifiers
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = specimen_agents)]
+struct SpecimenAgent {
+    entity_id: String,
+    role: String,
+    position: i32,
+    name: String,
+}
+
+/// The precision in decimal degrees that a specimen's coordinates are generalized to
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = specimen_generalizations)]
+struct SpecimenGeneralization {
+    entity_id: String,
+    precision: f64,
+}
+
+/// The institution of a specimen as it was before the code was normalized against the registry
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = specimen_verbatim_institutions)]
+struct VerbatimInstitution {
+    entity_id: String,
+    institution_code: Option<String>,
+    institution_name: Option<String>,
+}
+
+/// The coordinates of a specimen as they were before they were converted to WGS84
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = specimen_verbatim_coordinates)]
+struct VerbatimCoordinates {
+    entity_id: String,
+    latitude: f64,
+    longitude: f64,
+    geodetic_datum: String,
+}
+
+/// A reduced specimen along with the individual people in its agent lists
+#[derive(Debug, Clone)]
+struct ReducedSpecimen {
+    specimen: models::Specimen,
+    agents: Vec<SpecimenAgent>,
+}
+
+
+fn agents_from_names(entity_id: &str, role: &str, names: &[String]) -> Vec<SpecimenAgent> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(position, name)| SpecimenAgent {
+            entity_id: entity_id.to_string(),
+            role: role.to_string(),
+            position: position as i32,
+            name: name.clone(),
+        })
+        .collect()
+}
+
+
+impl Reducer<Lookups> for ReducedSpecimen {
     type Atom = SpecimenAtom;
 
     fn reduce(frame: Map<Self::Atom>, lookups: &Lookups) -> Result<Self, Error> {
@@ -288,8 +795,8 @@ impl Reducer<Lookups> for models::Specimen {
                 InstitutionName(value) => institution_name = Some(value),
                 InstitutionCode(value) => institution_code = Some(value),
                 CollectionCode(value) => collection_code = Some(value),
-                RecordedBy(value) => recorded_by = Some(value),
-                IdentifiedBy(value) => identified_by = Some(value),
+                RecordedBy(value) => recorded_by = Some(decode_list(&value)),
+                IdentifiedBy(value) => identified_by = Some(decode_list(&value)),
                 IdentifiedDate(value) => identified_date = Some(value),
                 TypeStatus(value) => type_status = Some(value),
                 Locality(value) => locality = Some(value),
@@ -311,6 +818,12 @@ impl Reducer<Lookups> for models::Specimen {
             }
         }
 
+        let recorded_by = recorded_by.unwrap_or_default();
+        let identified_by = identified_by.unwrap_or_default();
+
+        let mut agents = agents_from_names(&frame.entity_id, "collector", &recorded_by);
+        agents.extend(agents_from_names(&frame.entity_id, "identifier", &identified_by));
+
         let record = models::Specimen {
             id: uuid::Uuid::new_v4(),
             entity_id: Some(frame.entity_id),
@@ -330,8 +843,8 @@ impl Reducer<Lookups> for models::Specimen {
             institution_name,
             institution_code,
             collection_code,
-            recorded_by,
-            identified_by,
+            recorded_by: join_agents(&recorded_by),
+            identified_by: join_agents(&identified_by),
             identified_date,
             type_status,
             locality,
@@ -352,10 +865,158 @@ impl Reducer<Lookups> for models::Specimen {
             identification_remarks,
         };
 
-        Ok(record)
+        Ok(ReducedSpecimen {
+            specimen: record,
+            agents,
+        })
+    }
+}
+
+
+fn join_agents(names: &[String]) -> Option<String> {
+    if names.is_empty() {
+        None
+    }
+    else {
+        Some(names.join("; "))
+    }
+}
+
+
+/// A reduced specimen as written out by the reduce command.
+///
+/// Unlike the specimens table this doesn't resolve the name or dataset, so specimens whose
+/// names haven't been imported yet are still written out.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SpecimenRecord {
+    pub entity_id: String,
+    pub dataset_id: String,
+    pub dataset_uuid: Uuid,
+    pub record_id: Option<String>,
+    pub scientific_name: Option<String>,
+    pub canonical_name: Option<String>,
+    pub authorship: Option<String>,
+    pub type_status: Option<Recorded>,
+    pub institution_name: Option<String>,
+    pub institution_code: Option<String>,
+    pub recorded_by: Option<String>,
+    pub identified_by: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl From<Map<SpecimenAtom>> for SpecimenRecord {
+    fn from(value: Map<SpecimenAtom>) -> Self {
+        use SpecimenAtom::*;
+
+        let mut record = SpecimenRecord {
+            entity_id: value.entity_id,
+            ..Default::default()
+        };
+
+        for atom in value.atoms.into_values() {
+            match atom {
+                RecordId(value) => record.record_id = Some(value),
+                ScientificName(value) => record.scientific_name = Some(value),
+                CanonicalName(value) => record.canonical_name = Some(value),
+                Authorship(value) => record.authorship = Some(value),
+                TypeStatus(value) => record.type_status = Some(Recorded::from(value)),
+                InstitutionName(value) => record.institution_name = Some(value),
+                InstitutionCode(value) => record.institution_code = Some(value),
+                RecordedBy(value) => record.recorded_by = join_agents(&decode_list(&value)),
+                IdentifiedBy(value) => record.identified_by = join_agents(&decode_list(&value)),
+                Latitude(value) => record.latitude = Some(value),
+                Longitude(value) => record.longitude = Some(value),
+                _ => {}
+            }
+        }
+
+        record
     }
 }
 
+impl EntityRecord for SpecimenRecord {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for SpecimenRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "entity_id",
+        "dataset_id",
+        "dataset_uuid",
+        "record_id",
+        "scientific_name",
+        "canonical_name",
+        "authorship",
+        "type_status",
+        "institution_name",
+        "institution_code",
+        "recorded_by",
+        "identified_by",
+        "latitude",
+        "longitude",
+    ];
+    const NAME: &'static str = "specimens";
+    const VERSION: u32 = 2;
+}
+
+
+/// Reduce the specimen logs into records without updating the database.
+///
+/// The coordinates of sensitive taxa are generalized in the same way as the pushed specimens.
+/// When a cutoff is provided only the operations imported at or before it are reduced.
+pub fn reduce(mut pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<SpecimenRecord>, Error> {
+    use schema::dataset_versions;
+    use schema::specimen_logs::dsl::*;
+
+    let names = name_lookup(&mut pool)?;
+    let versions = dataset_version_lookup(&mut pool)?;
+    let sensitivity = SensitivityList::load(&pool)?;
+    let mut conn = pool.get()?;
+
+    let spinner = new_spinner("Loading specimen logs");
+    let no_merge = no_merge_versions(&mut conn)?;
+    let mut query = specimen_logs
+        .filter(dataset_version_id.ne_all(no_merge))
+        .order(operation_id.asc())
+        .into_boxed();
+    if let Some(cutoff) = as_of {
+        let imported = dataset_versions::table
+            .filter(dataset_versions::imported_at.le(cutoff))
+            .select(dataset_versions::id);
+        query = query.filter(dataset_version_id.eq_any(imported));
+    }
+    let operations = query.load::<SpecimenOperation>(&mut conn)?;
+    spinner.finish();
+
+    let spinner = new_spinner("Reducing specimen logs");
+    let entities = crate::operations::group_operations(precedence::apply(operations), vec![]);
+    let mut records = Vec::new();
+
+    for (key, ops) in entities.into_iter() {
+        let mut map = Map::new(key);
+        map.reduce(&ops);
+
+        // specimens don't log their dataset so it is the last dataset to change the entity
+        let mut record = SpecimenRecord::from(map);
+        if let Some(dataset) = ops.last().and_then(|op| versions.get(&op.dataset_version_id)) {
+            (record.dataset_id, record.dataset_uuid) = dataset.clone();
+        }
+
+        let name_id = record.scientific_name.as_ref().and_then(|name| names.get(name));
+        if let Some(precision) = name_id.and_then(|name_id| sensitivity.precision(name_id)) {
+            record.latitude = record.latitude.map(|latitude| generalize(latitude, precision));
+            record.longitude = record.longitude.map(|longitude| generalize(longitude, precision));
+        }
+        records.push(record);
+    }
+    spinner.finish();
+
+    Ok(records)
+}
+
 
 impl EntityPager for FrameLoader<SpecimenOperation> {
     type Operation = models::SpecimenOperation;
@@ -363,13 +1024,7 @@ impl EntityPager for FrameLoader<SpecimenOperation> {
     fn total(&self) -> Result<i64, Error> {
         let mut conn = self.pool.get()?;
 
-        let total = {
-            use diesel::dsl::count_distinct;
-            use schema::specimen_logs::dsl::*;
-            specimen_logs
-                .select(count_distinct(entity_id))
-                .get_result::<i64>(&mut conn)?
-        };
+        let total = specimen_entities::table.count().get_result::<i64>(&mut conn)?;
 
         Ok(total)
     }
@@ -381,19 +1036,20 @@ impl EntityPager for FrameLoader<SpecimenOperation> {
         let limit = 10_000;
         let offset = page as i64 * limit;
 
-        let entity_ids = specimen_logs
-            .select(entity_id)
-            .group_by(entity_id)
-            .order_by(entity_id)
+        let entity_ids = specimen_entities::table
+            .select(specimen_entities::entity_id)
+            .order_by(specimen_entities::entity_id)
             .offset(offset)
             .limit(limit)
             .into_boxed();
 
+        let no_merge = no_merge_versions(&mut conn)?;
         let operations = specimen_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order_by((entity_id, operation_id))
             .load::<SpecimenOperation>(&mut conn)?;
 
-        Ok(operations)
+        Ok(precedence::apply(operations))
     }
 }
@@ -1,12 +1,26 @@
-use arga_core::schema::datasets;
+use arga_core::schema::{dataset_versions, datasets};
 use diesel::*;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-use crate::database::{get_pool, source_lookup};
+use crate::database::{get_pool, source_lookup, PgPool};
+use crate::determinism::EntityRecord;
 use crate::errors::Error;
-use crate::utils::{access_pill_status_from_str, content_type_from_str, data_reuse_status_from_str};
+use crate::output::OutputSchema;
+use crate::readers::describe::{describe_record, Column};
+use crate::utils::{
+    access_pill_status_from_str,
+    access_pill_status_to_str,
+    content_type_from_str,
+    content_type_to_str,
+    data_reuse_status_from_str,
+    data_reuse_status_to_str,
+    doi_from_str,
+    empty_as_none,
+};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tracing::info;
 use uuid::Uuid;
 
 use crate::errors::LookupError;
@@ -15,8 +29,21 @@ use arga_core::models::DataReuseStatus;
 use arga_core::models::Dataset;
 use arga_core::models::SourceContentType;
 
+// attribution details the frontend needs that aren't part of the arga_core datasets table
+diesel::table! {
+    dataset_attributions (dataset_id) {
+        dataset_id -> Uuid,
+        doi -> Nullable<Text>,
+        citation_template -> Nullable<Text>,
+        attribution_url -> Nullable<Text>,
+    }
+}
+
+
 pub struct Datasets {
     pub path: PathBuf,
+    /// Check that every DOI resolves at doi.org before importing it
+    pub check_doi: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -24,11 +51,17 @@ struct CSVRecord {
     source_name: String,
     global_id: String,
     name: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
     short_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     url: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     citation: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     description: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     license: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     rights_holder: Option<String>,
     created_at: DateTime<Utc>,
     updated_at: DateTime<Utc>,
@@ -39,6 +72,21 @@ struct CSVRecord {
     publication_year: Option<i16>,
     #[serde(deserialize_with = "content_type_from_str")]
     content_type: Option<SourceContentType>,
+    #[serde(default, deserialize_with = "doi_from_str")]
+    doi: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    citation_template: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    attribution_url: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = dataset_attributions)]
+struct DatasetAttribution {
+    dataset_id: Uuid,
+    doi: Option<String>,
+    citation_template: Option<String>,
+    attribution_url: Option<String>,
 }
 
 impl From<CSVRecord> for Dataset {
@@ -67,6 +115,11 @@ impl From<CSVRecord> for Dataset {
 impl Datasets {
     /// Import datasets if they are not already in the table. This is an upsert and will
     /// update the data if it matches on dataset name.
+    ///
+    /// The optional `doi`, `citation_template` and `attribution_url` columns are stored
+    /// alongside the dataset in the `dataset_attributions` table. DOIs are normalised to
+    /// their bare form and the import fails on the first one that isn't a valid DOI. When
+    /// `check_doi` is set each DOI is also looked up at doi.org, which is a request per dataset.
     pub fn import(&self) -> Result<(), Error> {
         use diesel::upsert::excluded;
 
@@ -77,12 +130,20 @@ impl Datasets {
         let mut conn = pool.get()?;
 
         let sources = source_lookup(&mut pool)?;
+        create_attributions_table(&mut conn)?;
 
         for result in records {
             let record: CSVRecord = result?;
 
+            if self.check_doi {
+                if let Some(doi) = &record.doi {
+                    resolve_doi(doi)?;
+                }
+            }
+
             // Borrow the source_name before moving record
             let source_name = record.source_name.clone();
+            let attribution = (record.doi.clone(), record.citation_template.clone(), record.attribution_url.clone());
 
             let mut dataset_record = Dataset::from(record);
 
@@ -93,7 +154,7 @@ impl Datasets {
                 return Err(Error::Lookup(LookupError::Source(source_name.to_string())));
             }
 
-            diesel::insert_into(datasets::table)
+            let dataset_id = diesel::insert_into(datasets::table)
                 .values(&dataset_record)
                 .on_conflict(datasets::global_id)
                 .do_update()
@@ -113,9 +174,200 @@ impl Datasets {
                     datasets::publication_year.eq(excluded(datasets::publication_year)),
                     datasets::content_type.eq(excluded(datasets::content_type)),
                 ))
-                .execute(&mut conn)?;
+                .returning(datasets::id)
+                .get_result::<Uuid>(&mut conn)?;
+
+            let (doi, citation_template, attribution_url) = attribution;
+            let attribution = DatasetAttribution {
+                dataset_id,
+                doi,
+                citation_template,
+                attribution_url,
+            };
+            upsert_attribution(&mut conn, &attribution)?;
         }
 
         Ok(())
     }
+
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<CSVRecord>()
+    }
+}
+
+
+/// A dataset as it appears in the registry export.
+///
+/// The columns are the same as the import CSV, including the attribution details, so
+/// that an export can be imported again. The global id is used as the entity id.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetRecord {
+    pub source_name: String,
+    pub global_id: String,
+    pub name: String,
+    pub short_name: Option<String>,
+    pub url: Option<String>,
+    pub citation: Option<String>,
+    pub description: Option<String>,
+    pub license: Option<String>,
+    pub rights_holder: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub reuse_pill: Option<&'static str>,
+    pub access_pill: Option<&'static str>,
+    pub publication_year: Option<i16>,
+    pub content_type: Option<&'static str>,
+    pub doi: Option<String>,
+    pub citation_template: Option<String>,
+    pub attribution_url: Option<String>,
+}
+
+impl EntityRecord for DatasetRecord {
+    fn entity_id(&self) -> &str {
+        &self.global_id
+    }
+}
+
+impl OutputSchema for DatasetRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "source_name",
+        "global_id",
+        "name",
+        "short_name",
+        "url",
+        "citation",
+        "description",
+        "license",
+        "rights_holder",
+        "created_at",
+        "updated_at",
+        "reuse_pill",
+        "access_pill",
+        "publication_year",
+        "content_type",
+        "doi",
+        "citation_template",
+        "attribution_url",
+    ];
+    const NAME: &'static str = "datasets";
+    const VERSION: u32 = 1;
+}
+
+
+/// Load every dataset with the name of its source and its attribution details, ordered by global id.
+///
+/// Datasets aren't versioned, so with a cutoff only the datasets that had a version imported
+/// at or before it are loaded, as they are as of now.
+pub fn reduce(mut pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<DatasetRecord>, Error> {
+    let mut conn = pool.get()?;
+    create_attributions_table(&mut conn)?;
+
+    let source_names: HashMap<Uuid, String> =
+        source_lookup(&mut pool)?.into_iter().map(|(name, id)| (id, name)).collect();
+
+    let attributions: HashMap<Uuid, DatasetAttribution> = dataset_attributions::table
+        .load::<(Uuid, Option<String>, Option<String>, Option<String>)>(&mut conn)?
+        .into_iter()
+        .map(|(dataset_id, doi, citation_template, attribution_url)| {
+            let attribution = DatasetAttribution {
+                dataset_id,
+                doi,
+                citation_template,
+                attribution_url,
+            };
+            (dataset_id, attribution)
+        })
+        .collect();
+
+    let mut query = datasets::table.order(datasets::global_id.asc()).into_boxed();
+    if let Some(cutoff) = as_of {
+        let imported = dataset_versions::table
+            .filter(dataset_versions::imported_at.le(cutoff))
+            .select(dataset_versions::dataset_id);
+        query = query.filter(datasets::id.eq_any(imported));
+    }
+
+    let records = query.load::<Dataset>(&mut conn)?;
+
+    let mut exported = Vec::with_capacity(records.len());
+    for dataset in records {
+        let source_name = match source_names.get(&dataset.source_id) {
+            Some(name) => name.clone(),
+            None => return Err(Error::Lookup(LookupError::Source(dataset.source_id.to_string()))),
+        };
+        let attribution = attributions.get(&dataset.id);
+
+        exported.push(DatasetRecord {
+            source_name,
+            global_id: dataset.global_id,
+            name: dataset.name,
+            short_name: dataset.short_name,
+            url: dataset.url,
+            citation: dataset.citation,
+            description: dataset.description,
+            license: dataset.license,
+            rights_holder: dataset.rights_holder,
+            created_at: dataset.created_at,
+            updated_at: dataset.updated_at,
+            reuse_pill: dataset.reuse_pill.as_ref().map(data_reuse_status_to_str),
+            access_pill: dataset.access_pill.as_ref().map(access_pill_status_to_str),
+            publication_year: dataset.publication_year,
+            content_type: dataset.content_type.as_ref().map(content_type_to_str),
+            doi: attribution.and_then(|attr| attr.doi.clone()),
+            citation_template: attribution.and_then(|attr| attr.citation_template.clone()),
+            attribution_url: attribution.and_then(|attr| attr.attribution_url.clone()),
+        });
+    }
+
+    info!(total = exported.len(), "Loaded datasets");
+    Ok(exported)
+}
+
+
+/// Check that the DOI is registered by asking the doi.org resolver for it.
+///
+/// The resolver redirects registered DOIs to their landing page and responds with a 404
+/// otherwise. The redirect isn't followed since publisher sites often refuse requests
+/// without a browser user agent, which would make a registered DOI look unresolvable.
+fn resolve_doi(doi: &str) -> Result<(), Error> {
+    let agent = ureq::AgentBuilder::new().redirects(0).build();
+
+    match agent.head(&format!("https://doi.org/{doi}")).call() {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(404, _)) => Err(Error::Lookup(LookupError::Doi(doi.to_string()))),
+        Err(err) => Err(Error::Lookup(LookupError::Doi(format!("{doi} ({err})")))),
+    }
+}
+
+
+fn upsert_attribution(conn: &mut PgConnection, attribution: &DatasetAttribution) -> Result<(), Error> {
+    use dataset_attributions::dsl::*;
+    use diesel::upsert::excluded;
+
+    diesel::insert_into(dataset_attributions)
+        .values(attribution)
+        .on_conflict(dataset_id)
+        .do_update()
+        .set((
+            doi.eq(excluded(doi)),
+            citation_template.eq(excluded(citation_template)),
+            attribution_url.eq(excluded(attribution_url)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+
+fn create_attributions_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS dataset_attributions (
+            dataset_id uuid PRIMARY KEY REFERENCES datasets ON DELETE CASCADE,
+            doi text,
+            citation_template text,
+            attribution_url text
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
 }
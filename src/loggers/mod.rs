@@ -9,30 +9,88 @@ pub mod taxa;
 pub mod taxonomic_acts;
 
 
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
 
 use arga_core::crdt::{DataFrame, DataFrameOperation};
 use arga_core::models::{self, LogOperation};
 use arga_core::schema;
+pub use collections::Collections;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use diesel::*;
 use indicatif::ProgressBarIter;
+use memmap2::{Advice, Mmap};
 pub use nomenclatural_acts::NomenclaturalActs;
 use rayon::prelude::*;
 pub use sequences::Sequences;
 use serde::de::DeserializeOwned;
 pub use taxonomic_acts::TaxonomicActs;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::cardinality::AtomCardinality;
+use crate::clock::OperationClock;
 use crate::database::{create_dataset_version, get_pool, FrameLoader, PgPool};
-use crate::errors::Error;
-use crate::frames::{FrameReader, Framer, IntoFrame};
+use crate::errors::{skip_record, Error};
+use crate::frame_digests::FrameDigests;
+use crate::frames::{FrameReader, Framer, Frames, IntoFrame};
+use crate::import_runs::{chunk_key, ImportRuns};
 use crate::operations::distinct_changes;
 use crate::readers::csv::CsvReader;
+use crate::readers::mappings::FieldMappings;
+use crate::readers::records::RecordReader;
+use crate::readers::xlsx::{self, SheetOptions};
 use crate::readers::{meta, OperationLoader};
 use crate::utils::FrameImportBars;
+use crate::watermarks::Watermark;
+
+
+/// The amount of operations to deduplicate against the database at once.
+///
+/// Loading existing operations uses `= ANY($1)` with a single array parameter for
+/// the entity ids, so this is only bound by memory rather than the parameter limit.
+const LOAD_CHUNK_SIZE: usize = 50_000;
+
+/// The amount of operations to insert at once.
+///
+/// Inserts bind a parameter for every column of every operation and postgres
+/// only allows 65535 parameters in a single statement.
+const UPSERT_CHUNK_SIZE: usize = 10_000;
+
+/// The amount of parsed chunks that can be waiting on the import worker.
+///
+/// Each chunk holds the operations of 20,000 frames so this bounds how far parsing
+/// can get ahead of the database without holding the whole file in memory.
+const PIPELINE_DEPTH: usize = 2;
+
+/// Appended to the version of a dataset imported with `set_no_merge` so that it stands out from the normal imports
+pub const NO_MERGE_VERSION_SUFFIX: &str = "+no-merge";
+
+
+/// Whether the imports of this process append their operations without merging, set once from the command line
+static NO_MERGE: OnceLock<bool> = OnceLock::new();
+
+
+/// Append every operation of the imports run by this process to the logs as-is.
+///
+/// This is only meant for loading a provider file exactly as it was delivered to investigate
+/// it. The operations aren't merged with the existing logs and unchanged rows aren't skipped,
+/// so operations that change nothing are appended along with the rest. Only the first call
+/// has any effect.
+pub fn set_no_merge() {
+    let _ = NO_MERGE.set(true);
+}
+
+/// Returns true if the imports skip the merge with the existing logs
+pub fn is_no_merge() -> bool {
+    NO_MERGE.get().copied().unwrap_or_default()
+}
 
 
 pub trait FrameProgress {
@@ -81,28 +139,126 @@ impl<S: Read> Read for ProgressStream<S> {
 ///
 /// The Reader (<R>) must implement the IntoFrame trait and be deserializable from a CSV file.
 /// The Operation (<Op>) must implement the OperationLoader trait
-pub fn import_csv_as_logs<T, Op>(path: &PathBuf, dataset_version_id: &Uuid) -> Result<(), Error>
+pub fn import_csv_as_logs<T, Op>(
+    path: &PathBuf,
+    dataset_version_id: &Uuid,
+    mappings: &FieldMappings,
+    since: Option<&str>,
+) -> Result<(), Error>
 where
     Op: Sync,
     T: DeserializeOwned + IntoFrame,
     T::Atom: Default + Clone + ToString + PartialEq,
     FrameLoader<Op>: OperationLoader + Clone,
     <FrameLoader<Op> as OperationLoader>::Operation:
-        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Sync,
+        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Send + Sync,
 {
     let file = File::open(path)?;
     let size = file.metadata()?.size();
-    let stream = ProgressStream::new(file, size as usize);
-    import_csv_from_stream::<T, Op, _>(stream, dataset_version_id)?;
+
+    match map_file(&file) {
+        Some(mmap) => {
+            let stream = ProgressStream::new(&mmap[..], size as usize);
+            import_csv_from_stream::<T, Op, _>(stream, dataset_version_id, mappings.clone(), since)?;
+        }
+        None => {
+            let stream = ProgressStream::new(file, size as usize);
+            import_csv_from_stream::<T, Op, _>(stream, dataset_version_id, mappings.clone(), since)?;
+        }
+    }
     Ok(())
 }
 
 
+/// Map a local file into memory for parsing.
+///
+/// The CSV parser reads a mapped file straight out of the page cache rather than through
+/// read calls on a buffered file, which is where most of the time goes when importing the
+/// larger taxonomy backbones. Files that can't be mapped, like pipes and empty files, return
+/// None and are streamed instead. Compressed and archived inputs are always streamed.
+fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return None;
+    }
+
+    // SAFETY: reading a mapped file that is truncated by another process is undefined
+    // behaviour. dataset files are never modified while they are being imported
+    let mmap = unsafe { Mmap::map(file) }.ok()?;
+
+    // rows are only read once and in order so let the kernel read ahead aggressively
+    let _ = mmap.advise(Advice::Sequential);
+    Some(mmap)
+}
+
+
+/// Import a spreadsheet or CSV file as operation logs.
+///
+/// Spreadsheets are detected by their file extension and read with `import_xlsx_as_logs`,
+/// every other file is treated as a CSV file. The sheet options are only used for spreadsheets,
+/// and the watermark column in `since` only for CSV files. The mappings apply to both.
+pub fn import_file_as_logs<T, Op>(
+    path: &PathBuf,
+    dataset_version_id: &Uuid,
+    sheet: &SheetOptions,
+    mappings: &FieldMappings,
+    since: Option<&str>,
+) -> Result<(), Error>
+where
+    Op: Sync,
+    T: DeserializeOwned + IntoFrame,
+    T::Atom: Default + Clone + ToString + PartialEq,
+    FrameLoader<Op>: OperationLoader + Clone,
+    <FrameLoader<Op> as OperationLoader>::Operation:
+        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Send + Sync,
+{
+    match xlsx::is_spreadsheet(path) {
+        true => {
+            if let Some(column) = since {
+                warn!(column, "Watermarks only apply to CSV files, importing every row of the spreadsheet");
+            }
+            import_xlsx_as_logs::<T, Op>(path, dataset_version_id, sheet, mappings)
+        }
+        false => import_csv_as_logs::<T, Op>(path, dataset_version_id, mappings, since),
+    }
+}
+
+
+/// Import a single sheet of a spreadsheet as operation logs.
+///
+/// Spreadsheets are small enough to read in full so every row is deserialized up front, which
+/// also means a malformed row fails the import before any operations are inserted. The records
+/// then go through the same frame pipeline as a CSV import.
+pub fn import_xlsx_as_logs<T, Op>(
+    path: &PathBuf,
+    dataset_version_id: &Uuid,
+    sheet: &SheetOptions,
+    mappings: &FieldMappings,
+) -> Result<(), Error>
+where
+    Op: Sync,
+    T: DeserializeOwned + IntoFrame,
+    T::Atom: Default + Clone + ToString + PartialEq,
+    FrameLoader<Op>: OperationLoader + Clone,
+    <FrameLoader<Op> as OperationLoader>::Operation:
+        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Send + Sync,
+{
+    let records = xlsx::read_records::<T>(path, sheet, mappings.clone())?;
+    let reader = RecordReader::new(records.into_iter(), *dataset_version_id);
+    import_frames_from_stream::<Op, _>(reader, get_pool()?)
+}
+
+
 /// Imports a CSV stream that has been compressed.
 ///
 /// This will use the brotli decompressor before passing the stream on to `import_csv_from_stream` where
-/// it will proceed as if it was an extracted CSV file
-pub fn import_compressed_csv_stream<S, T, Op>(stream: S, dataset: &meta::Dataset) -> Result<(), Error>
+/// it will proceed as if it was an extracted CSV file. The field mappings are the dataset specific
+/// value overrides found in the archive, if any.
+pub fn import_compressed_csv_stream<S, T, Op>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error>
 where
     S: Read + FrameProgress,
     Op: Sync,
@@ -110,11 +266,11 @@ where
     T::Atom: Default + Clone + ToString + PartialEq,
     FrameLoader<Op>: OperationLoader + Clone,
     <FrameLoader<Op> as OperationLoader>::Operation:
-        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Sync,
+        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Send + Sync,
 {
     let input = brotli::Decompressor::new(stream, 4096);
     let dataset_version = create_dataset_version(&dataset.id, &dataset.version, &dataset.published_at.to_string())?;
-    import_csv_from_stream::<T, Op, _>(input, &dataset_version.id)?;
+    import_csv_from_stream::<T, Op, _>(input, &dataset_version.id, mappings.clone(), None)?;
     Ok(())
 }
 
@@ -128,7 +284,15 @@ where
 /// The Record (<T>) must implement the IntoFrame trait and be deserializable from a CSV file.
 /// The Operation (<Op>) must implement the OperationLoader trait
 /// The Reader (<R>) only needs to implement std::io::Read
-pub fn import_csv_from_stream<T, Op, R>(reader: R, dataset_version_id: &Uuid) -> Result<(), Error>
+///
+/// When `since` names a timestamp column only the rows newer than the watermark of the
+/// dataset are imported, and the watermark is advanced once every row has been imported.
+pub fn import_csv_from_stream<T, Op, R>(
+    reader: R,
+    dataset_version_id: &Uuid,
+    mappings: FieldMappings,
+    since: Option<&str>,
+) -> Result<(), Error>
 where
     R: Read + FrameProgress,
     Op: Sync,
@@ -136,7 +300,7 @@ where
     T::Atom: Default + Clone + ToString + PartialEq,
     FrameLoader<Op>: OperationLoader + Clone,
     <FrameLoader<Op> as OperationLoader>::Operation:
-        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Sync,
+        LogOperation<T::Atom> + From<DataFrameOperation<T::Atom>> + Clone + Send + Sync,
 {
     let bars = reader.bars();
 
@@ -145,35 +309,36 @@ where
     // us to conveniently get chunks of frames from the reader and sets us up for easy parallelization.
     // and the third is the frame loader which allows us to query the database to deduplicate and
     // pull out unique operations, as well as upsert the new operations.
-    let reader = CsvReader::<T, R>::from_reader(reader, *dataset_version_id)?;
-    let framer = Framer::new(reader);
-    let loader = FrameLoader::<Op>::new(get_pool()?);
-
-    // parse and convert big chunks of rows. this is an IO bound task but for each
-    // chunk we need to query the database and then insert into the database, so
-    // we parallelize the frame merging and inserting instead since it is an order of
-    // magnitude slower than the parsing
-    for frames in framer.chunks(20_000) {
-        let total_frames = frames.len();
-
-        // we flatten out all the frames into operations and process them in chunks of 10k.
-        // postgres has a parameter limit and by chunking it we can query the database to
-        // filter to distinct changes and import it in bulk without triggering any errors.
-        frames.operations()?.par_chunks(10_000).try_for_each(|slice| {
-            let total = slice.len();
+    let pool = get_pool()?;
+    let mut reader = CsvReader::<T, R>::from_reader_with_mappings(reader, *dataset_version_id, mappings)?;
+
+    // a raw append frames every row, including the ones that haven't changed since the last import
+    let digests = match is_no_merge() {
+        true => None,
+        false => Some(FrameDigests::load(pool.clone(), T::RECORD_TYPE, dataset_version_id)?),
+    };
+    if let Some(digests) = &digests {
+        reader = reader.with_digests(digests.clone());
+    }
 
-            // compare the ops with previously imported ops and only return actual changes
-            let changes = distinct_changes(slice.to_vec(), &loader)?;
-            let inserted = loader.upsert_operations(&changes)?;
+    let watermark = match since {
+        Some(column) => Some(Watermark::load(pool.clone(), dataset_version_id, column)?),
+        None => None,
+    };
+    if let Some(watermark) = &watermark {
+        reader = reader.with_watermark(watermark.clone())?;
+    }
 
-            bars.inserted.inc(inserted as u64);
-            bars.operations.inc(total as u64);
-            Ok::<(), Error>(())
-        })?;
+    let framer = Framer::new(reader);
+    let loader = FrameLoader::<Op>::new(pool);
 
-        bars.frames.inc(total_frames as u64);
+    import_frame_chunks::<T::Atom, Op, _>(framer.chunks(20_000), &loader, &bars)?;
+    if let Some(digests) = digests {
+        digests.commit(&bars.rejected.lock().expect("Rejected entities lock poisoned"))?;
+    }
+    if let Some(watermark) = watermark {
+        watermark.advance(&bars.rejected.lock().expect("Rejected entities lock poisoned"))?;
     }
-
     bars.finish();
     Ok(())
 }
@@ -196,7 +361,7 @@ where
     Op: Sync,
     FrameLoader<Op>: OperationLoader + Clone,
     <FrameLoader<Op> as OperationLoader>::Operation:
-        LogOperation<R::Atom> + From<DataFrameOperation<R::Atom>> + Clone + Sync,
+        LogOperation<R::Atom> + From<DataFrameOperation<R::Atom>> + Clone + Send + Sync,
 {
     let bars = reader.bars();
 
@@ -208,32 +373,247 @@ where
     let framer = Framer::new(reader);
     let loader = FrameLoader::<Op>::new(pool);
 
-    // parse and convert big chunks of rows. this is an IO bound task but for each
-    // chunk we need to query the database and then insert into the database, so
-    // we parallelize the frame merging and inserting instead since it is an order of
-    // magnitude slower than the parsing
-    for frames in framer.chunks(20_000) {
-        let total_frames = frames.len();
-
-        // we flatten out all the frames into operations and process them in chunks of 10k.
-        // postgres has a parameter limit and by chunking it we can query the database to
-        // filter to distinct changes and import it in bulk without triggering any errors.
-        frames.operations()?.par_chunks(10_000).try_for_each(|slice| {
-            let total = slice.len();
+    import_frame_chunks::<R::Atom, Op, _>(framer.chunks(20_000), &loader, &bars)?;
+    bars.finish();
+    Ok(())
+}
 
-            // compare the ops with previously imported ops and only return actual changes
-            let changes = distinct_changes(slice.to_vec(), &loader)?;
-            let inserted = loader.upsert_operations(&changes)?;
 
-            bars.inserted.inc(inserted as u64);
-            bars.operations.inc(total as u64);
+/// Import chunks of frames with the parsing and the database work overlapping.
+///
+/// The chunks are parsed and flattened into operations on the calling thread and handed to
+/// a dedicated worker thread over a bounded channel. The worker deduplicates and upserts
+/// each chunk with rayon while the next chunk is being parsed, so a compressed stream keeps
+/// decoding instead of waiting on the database. Parsing stays on the calling thread because
+/// the archive entries being read can't be sent to another thread.
+///
+/// If the worker fails the channel is closed and parsing stops early, if parsing fails
+/// the worker finishes the chunks already sent before the error is returned. Once every chunk
+/// is in the amount of atoms per frame is compared with the previous imports into the logs.
+fn import_frame_chunks<A, Op, I>(chunks: I, loader: &FrameLoader<Op>, bars: &FrameImportBars) -> Result<(), Error>
+where
+    I: Iterator<Item = Frames<A>>,
+    A: Default + Clone + ToString + PartialEq,
+    Op: Sync,
+    FrameLoader<Op>: OperationLoader,
+    <FrameLoader<Op> as OperationLoader>::Operation:
+        LogOperation<A> + From<DataFrameOperation<A>> + Clone + Send + Sync,
+{
+    let mut clock = OperationClock::new(&loader.pool)?;
+    let runs = &ImportRuns::new(&loader.pool)?;
+    let skipped = &AtomicU64::new(0);
+
+    let (sender, receiver) =
+        sync_channel::<(usize, Vec<<FrameLoader<Op> as OperationLoader>::Operation>)>(PIPELINE_DEPTH);
+
+    std::thread::scope(|scope| {
+        let worker = scope.spawn(move || {
+            for (total_frames, operations) in receiver {
+                // we process the operations in large chunks. loading existing operations binds the
+                // entity ids as a single array so it isn't affected by the postgres parameter limit,
+                // but inserting binds every column of every operation so the changes are upserted
+                // in smaller chunks.
+                operations.par_chunks(LOAD_CHUNK_SIZE).try_for_each(|slice| {
+                    let total = slice.len();
+
+                    // a retried import skips the chunks an earlier run of the version finished
+                    let version_id = *slice[0].dataset_version_id();
+                    let key = chunk_key::<A, _>(slice);
+                    if runs.completed(&version_id, key)? {
+                        skipped.fetch_add(1, Ordering::Relaxed);
+                        bars.operations.inc(total as u64);
+                        return Ok(());
+                    }
+
+                    // compare the ops with previously imported ops and only return actual changes
+                    let changes = match is_no_merge() {
+                        true => slice.to_vec(),
+                        false => distinct_changes(slice.to_vec(), loader)?,
+                    };
+
+                    let mut inserted = 0;
+                    for chunk in changes.chunks(UPSERT_CHUNK_SIZE) {
+                        let upserted = upsert_chunk::<_, A>(loader, chunk, &bars.rejected)?;
+                        bars.inserted.inc(upserted as u64);
+                        inserted += upserted;
+                    }
+
+                    // a chunk with rejected operations is imported again on the next run
+                    let rejected = {
+                        let rejected = bars.rejected.lock().expect("Rejected entities lock poisoned");
+                        slice.iter().any(|op| rejected.contains(op.entity_id()))
+                    };
+                    if !rejected {
+                        runs.complete(&version_id, key, total, inserted)?;
+                    }
+
+                    bars.operations.inc(total as u64);
+                    Ok::<(), Error>(())
+                })?;
+
+                bars.frames.inc(total_frames as u64);
+            }
             Ok::<(), Error>(())
-        })?;
+        });
 
-        bars.frames.inc(total_frames as u64);
+        let parsed = send_frame_chunks(chunks, sender, &mut clock, &bars.atoms);
+        worker.join().expect("The import worker panicked")?;
+        parsed
+    })?;
+
+    let skipped = skipped.load(Ordering::Relaxed);
+    if skipped > 0 {
+        info!(skipped, "Skipped the chunks an earlier run of the import finished");
     }
 
-    bars.finish();
+    bars.atoms.check(&loader.pool, FrameLoader::<Op>::LOG_TABLE)?;
+    Ok(())
+}
+
+
+/// Upsert the operations, narrowing a rejected chunk down to the operations causing it.
+///
+/// A chunk is inserted with a single statement so one operation that the database rejects,
+/// like a value over a column limit, fails every other operation in the chunk with it. When
+/// that happens the chunk is split in half and each half retried until the rejected
+/// operations are isolated, which are skipped and reported with the entity they belong to.
+/// Operations are inserted with `ON CONFLICT DO NOTHING` so retrying a half is harmless.
+/// Errors that aren't caused by the data, like a lost connection, fail the import as before.
+/// The entities of rejected operations are added to `rejected` so their rows aren't digested.
+fn upsert_chunk<L, A>(
+    loader: &L,
+    operations: &[L::Operation],
+    rejected: &Mutex<HashSet<String>>,
+) -> Result<usize, Error>
+where
+    L: OperationLoader,
+    L::Operation: LogOperation<A>,
+{
+    let mut refusals = Vec::new();
+    let inserted = upsert_bisect::<L, A>(loader, operations, &mut refusals)?;
+
+    // an error outside of class 23 can come from the statement rather than the values, like a
+    // missing column, in which case every operation in the chunk is refused with it
+    if refusals.len() == operations.len() {
+        if let Some(idx) = refusals.iter().position(|refusal| refusal.class == Refused::Unclassified) {
+            return Err(Error::Database(refusals.swap_remove(idx).err));
+        }
+    }
+
+    let mut rejected = rejected.lock().expect("Rejected entities lock poisoned");
+    for refusal in refusals {
+        rejected.insert(refusal.entity_id.clone());
+        skip_record(&Error::RejectedOperation(refusal.entity_id, refusal.operation_id, refusal.err));
+    }
+
+    Ok(inserted)
+}
+
+
+/// An operation the database refused to insert on its own
+struct Refusal {
+    entity_id: String,
+    operation_id: String,
+    class: Refused,
+    err: DieselError,
+}
+
+/// The SQLSTATE class of a database error that the values of a statement could have caused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Refused {
+    /// Class 23, the values broke an integrity constraint
+    IntegrityViolation,
+    /// Any class diesel doesn't keep, which includes the class 22 data exceptions
+    Unclassified,
+}
+
+/// Bisect the operations until every operation the database refuses is isolated.
+///
+/// Only errors that the values could have caused are bisected, anything else is returned
+/// straight away.
+fn upsert_bisect<L, A>(loader: &L, operations: &[L::Operation], refusals: &mut Vec<Refusal>) -> Result<usize, Error>
+where
+    L: OperationLoader,
+    L::Operation: LogOperation<A>,
+{
+    match loader.upsert_operations(operations) {
+        Ok(inserted) => Ok(inserted),
+        Err(Error::Database(err)) => {
+            let Some(class) = refused_class(&err)
+            else {
+                return Err(Error::Database(err));
+            };
+
+            match operations {
+                [operation] => {
+                    refusals.push(Refusal {
+                        entity_id: operation.entity_id().clone(),
+                        operation_id: operation.id().to_string(),
+                        class,
+                        err,
+                    });
+                    Ok(0)
+                }
+                operations => {
+                    let (left, right) = operations.split_at(operations.len() / 2);
+                    Ok(upsert_bisect::<L, A>(loader, left, refusals)? + upsert_bisect::<L, A>(loader, right, refusals)?)
+                }
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The SQLSTATE class of a database error if the values of the statement could have caused it.
+///
+/// Diesel keeps the codes of the class 23 integrity constraint violations as their own kinds
+/// and every code it doesn't know as `Unknown`, which is where the class 22 data exceptions end
+/// up along with errors in the statement itself. Kinds from any other class, like a lost
+/// connection or a serialization failure, are never caused by the values.
+fn refused_class(err: &DieselError) -> Option<Refused> {
+    match err {
+        DieselError::DatabaseError(kind, _) => match kind {
+            DatabaseErrorKind::UniqueViolation
+            | DatabaseErrorKind::ForeignKeyViolation
+            | DatabaseErrorKind::NotNullViolation
+            | DatabaseErrorKind::CheckViolation => Some(Refused::IntegrityViolation),
+            DatabaseErrorKind::Unknown => Some(Refused::Unclassified),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+
+/// Parse the chunks and send them to the import worker.
+///
+/// Every chunk is restamped with ids reserved from the operation clock before it is sent so
+/// that its operations are newer than everything imported before them. The sender is consumed so that the channel
+/// is closed when parsing finishes or fails, otherwise the worker would wait on it forever.
+fn send_frame_chunks<A, O, I>(
+    chunks: I,
+    sender: SyncSender<(usize, Vec<O>)>,
+    clock: &mut OperationClock,
+    atoms: &AtomCardinality,
+) -> Result<(), Error>
+where
+    I: Iterator<Item = Frames<A>>,
+    A: Default,
+    O: LogOperation<A> + From<DataFrameOperation<A>>,
+{
+    for frames in chunks {
+        let total_frames = frames.len();
+        let (mut operations, atoms_per_frame): (Vec<DataFrameOperation<A>>, Vec<usize>) =
+            frames.operations_per_frame()?;
+        atoms.record(&atoms_per_frame);
+        clock.stamp(&mut operations)?;
+        let operations: Vec<O> = operations.into_iter().map(O::from).collect();
+
+        // the worker only hangs up when it failed, and it returns that error itself
+        if sender.send((total_frames, operations)).is_err() {
+            break;
+        }
+    }
     Ok(())
 }
 
@@ -1,10 +1,291 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+use arga_core::models::TaxonomicStatus;
 use arga_core::{models, schema};
+use diesel::sql_types::BigInt;
 use diesel::*;
-use tracing::info;
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::{get_pool, name_lookup, PgPool, StringMap};
+use crate::errors::{skip_record, Error};
+use crate::maintenance::NAME_KEY;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::mappings::FieldMappings;
+use crate::utils::{empty_as_none, new_progress_bar};
+
+
+/// Words that start an authorship but are lowercase, like the `de` in `de Candolle`.
+/// Particles ending in an apostrophe are usually joined to the name, like `d'Orbigny`
+const AUTHOR_PARTICLES: [&str; 14] =
+    ["d'", "da", "de", "del", "der", "di", "du", "ex", "l'", "la", "le", "van", "von", "zu"];
+
+/// Lowercase words that qualify the use of a name rather than being part of it, like the
+/// `sensu` in `Aus bus sensu Smith`. They are kept with the authorship that follows them
+const NAME_QUALIFIERS: [&str; 12] = [
+    "auct.", "auctt.", "emend.", "fide", "nec", "nom.", "non", "p.p.", "s.l.", "s.s.", "s.str.", "sensu",
+];
+
+
+// variants are specific to the oplogger's name handling so they aren't part of arga_core yet
+diesel::table! {
+    name_variants (id) {
+        id -> Uuid,
+        dataset_id -> Uuid,
+        scientific_name -> Text,
+        canonical_name -> Text,
+        status -> Text,
+        name_id -> Nullable<Uuid>,
+    }
+}
+
+
+// the transformer identifies names by a hash of the scientific name. the names table in
+// arga_core has no column for it so the hashes are kept alongside it
+diesel::table! {
+    name_entities (entity_id) {
+        entity_id -> Text,
+        name_id -> Uuid,
+    }
+}
+
+
+// ranks and codes aren't columns of the names table in arga_core so they are kept alongside it.
+// either is null when the taxa with the name disagree on it
+diesel::table! {
+    name_classifications (name_id) {
+        name_id -> Uuid,
+        rank -> Nullable<Text>,
+        nomenclatural_code -> Nullable<Text>,
+    }
+}
+
+
+#[derive(QueryableByName)]
+struct AmbiguousClassifications {
+    #[diesel(sql_type = BigInt)]
+    ranks: i64,
+    #[diesel(sql_type = BigInt)]
+    codes: i64,
+}
+
+
+#[derive(QueryableByName)]
+struct DuplicateNames {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+
+/// A spelling of a name that isn't the correct spelling.
+///
+/// Orthographic variants and misspellings refer to the same name as the correct spelling
+/// so rather than getting their own names row they are recorded here and linked to the
+/// names row of the correct spelling once it is known.
+#[derive(Debug, Clone)]
+pub struct NameVariant {
+    pub dataset_id: Uuid,
+    pub scientific_name: String,
+    pub canonical_name: String,
+    pub status: TaxonomicStatus,
+}
+
+impl NameVariant {
+    /// Returns true if a taxon with the status is a spelling variant of another name
+    pub fn is_variant(status: &TaxonomicStatus) -> bool {
+        matches!(status, TaxonomicStatus::OrthographicVariant | TaxonomicStatus::Misspelled)
+    }
+}
+
+
+/// A name as it appears in the names CSV exported by the transformer
+#[derive(Debug, Deserialize)]
+struct Record {
+    /// The hash the transformer identifies the name with
+    entity_id: String,
+    /// The full name. Includes the authorship when there is one
+    scientific_name: String,
+    /// The name without the authorship. Parsed from the scientific name when empty
+    #[serde(default, deserialize_with = "empty_as_none")]
+    canonical_name: Option<String>,
+    /// The authorship of the name. Parsed from the scientific name when empty
+    #[serde(default, deserialize_with = "empty_as_none")]
+    scientific_name_authorship: Option<String>,
+}
+
+impl From<Record> for models::Name {
+    fn from(value: Record) -> models::Name {
+        let (canonical_name, authorship) = parse_authorship(&value.scientific_name, value.canonical_name.as_deref());
+
+        models::Name {
+            id: Uuid::new_v4(),
+            scientific_name: value.scientific_name,
+            canonical_name,
+            authorship: value.scientific_name_authorship.or(authorship),
+        }
+    }
+}
+
+
+/// Import names from a names CSV rather than from the taxa of a dataset.
+///
+/// Names aren't logged so they don't belong to a dataset version, which lets datasets
+/// that are only a list of names be loaded without any taxonomy.
+pub struct Names {
+    pub path: PathBuf,
+}
+
+impl Names {
+    pub fn import(&self) -> Result<(), Error> {
+        let reader = std::fs::File::open(&self.path)?;
+        import_csv(get_pool()?, reader, &FieldMappings::default())
+    }
+
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<Record>()
+    }
+}
+
+
+/// Import the brotli compressed names CSV of a dataset archive
+pub fn import_archive<S: Read>(stream: S, mappings: &FieldMappings) -> Result<(), Error> {
+    let input = brotli::Decompressor::new(stream, 4096);
+    import_csv(get_pool()?, input, mappings)
+}
+
+
+/// Read every name in a names CSV and import them.
+///
+/// The transformer derives the entity hash from the scientific name so two rows with the
+/// same hash but a different name point to a problem with the export. They are imported
+/// as separate names and a warning is logged for each, with the hash kept for the first name.
+fn import_csv<R: Read>(pool: PgPool, reader: R, mappings: &FieldMappings) -> Result<(), Error> {
+    // names don't have any columns that can be recorded as unknown
+    let mut mappings = mappings.clone();
+    mappings.retain_unknowns(&[]);
+
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = mappings.headers(reader.headers()?);
+
+    let mut entities: HashMap<String, String> = HashMap::new();
+    let mut names = Vec::new();
+
+    for row in reader.records() {
+        let row = mappings.apply(&headers, &row?);
+        let record: Record = match row.deserialize(Some(&headers)) {
+            Ok(record) => record,
+            Err(err) => {
+                skip_record(&Error::from(err));
+                continue;
+            }
+        };
+
+        match entities.get(&record.entity_id) {
+            Some(name) if name != &record.scientific_name => {
+                let (entity_id, other) = (&record.entity_id, &record.scientific_name);
+                warn!(entity_id, name, other, "Entity hash used by another name");
+            }
+            Some(_) => {}
+            None => {
+                entities.insert(record.entity_id.clone(), record.scientific_name.clone());
+            }
+        }
+
+        names.push(models::Name::from(record));
+    }
+
+    names.sort_by(|a, b| a.scientific_name.cmp(&b.scientific_name));
+    names.dedup_by(|a, b| a.scientific_name.eq(&b.scientific_name));
+    import(pool.clone(), &names)?;
+    import_entities(pool, &entities)
+}
+
+
+/// Store the entity hash of each name with the names row it was imported as
+fn import_entities(mut pool: PgPool, entities: &HashMap<String, String>) -> Result<(), Error> {
+    use diesel::upsert::excluded;
+    use name_entities::dsl::*;
+
+    let names = name_lookup(&mut pool)?;
+    let mut conn = pool.get()?;
+    create_entities_table(&mut conn)?;
+
+    let mut values = Vec::with_capacity(entities.len());
+    for (hash, name) in entities {
+        match names.get(name) {
+            Some(uuid) => values.push((entity_id.eq(hash), name_id.eq(*uuid))),
+            None => warn!(entity_id = hash, name, "Imported name not found"),
+        }
+    }
+
+    for chunk in values.chunks(10_000) {
+        diesel::insert_into(name_entities)
+            .values(chunk)
+            .on_conflict(entity_id)
+            .do_update()
+            .set(name_id.eq(excluded(name_id)))
+            .execute(&mut conn)?;
+    }
+
+    info!(total = values.len(), "Name entities imported");
+    Ok(())
+}
+
+
+/// Split a scientific name into its canonical name and authorship.
+///
+/// With a canonical name the authorship is whatever follows it in the scientific name. Without
+/// one the canonical name is taken to be the uninomial followed by the lowercase epithets and
+/// rank markers, skipping a subgenus in parentheses, and the authorship starts at the first
+/// word after that. So `Aus (Bus) cus var. dus (L., 1758) Smith` splits into `Aus cus var. dus`
+/// and `(L., 1758) Smith`. Lowercase author particles like `van` or `d'Orbigny` and qualifiers
+/// like `sensu` or `auct.` start the authorship rather than being taken as epithets.
+pub fn parse_authorship(scientific_name: &str, canonical_name: Option<&str>) -> (String, Option<String>) {
+    let words: Vec<&str> = scientific_name.split_whitespace().collect();
+
+    if let Some(canonical) = canonical_name {
+        let canonical: Vec<&str> = canonical.split_whitespace().collect();
+        let authorship = words.strip_prefix(canonical.as_slice()).map(|rest| rest.join(" "));
+        return (canonical.join(" "), authorship.filter(|authorship| !authorship.is_empty()));
+    }
+
+    let Some((uninomial, mut rest)) = words.split_first()
+    else {
+        return (String::new(), None);
+    };
+
+    let is_epithet = |word: &str| {
+        let lowercase = word.starts_with(|c: char| c.is_lowercase()) || word == "×";
+        let particle = AUTHOR_PARTICLES.iter().any(|particle| match particle.ends_with('\'') {
+            true => word.starts_with(particle),
+            false => word == *particle,
+        });
+        lowercase && !particle && !NAME_QUALIFIERS.contains(&word)
+    };
+
+    if let [subgenus, next, ..] = rest {
+        if subgenus.starts_with('(') && subgenus.ends_with(')') && is_epithet(*next) {
+            rest = &rest[1..];
+        }
+    }
+
+    let mut canonical = vec![*uninomial];
+    while let [word, tail @ ..] = rest {
+        if !is_epithet(*word) {
+            break;
+        }
+        canonical.push(*word);
+        rest = tail;
+    }
+
+    let authorship = Some(rest.join(" ")).filter(|authorship| !authorship.is_empty());
+    (canonical.join(" "), authorship)
+}
 
-use crate::database::PgPool;
-use crate::errors::Error;
-use crate::utils::new_progress_bar;
 
 /// Import names if they are not already in the table. This is an upsert and will
 /// update the data if it matches on scientific name
@@ -31,5 +312,247 @@ pub fn import(pool: PgPool, records: &[models::Name]) -> Result<(), Error> {
 
     bar.finish();
     info!(total = records.len(), total_imported, "Name import finished");
+
+    warn_duplicates(&mut conn)?;
+    Ok(())
+}
+
+
+/// Report names that differ from another name only in case or whitespace.
+///
+/// The names table is keyed on the exact scientific name so these can't be caught by the
+/// upsert. They are left in place since the spelling to keep isn't known here and can be
+/// merged afterwards with the merge-names maintenance command.
+fn warn_duplicates(conn: &mut PgConnection) -> Result<(), Error> {
+    let duplicates = sql_query(format!(
+        "SELECT count(*) AS total FROM (
+            SELECT {NAME_KEY} FROM names GROUP BY 1 HAVING count(*) > 1
+         ) AS duplicates"
+    ))
+    .get_result::<DuplicateNames>(conn)?;
+
+    if duplicates.total > 0 {
+        warn!(total = duplicates.total, "Names differing only in case or whitespace, run maintenance merge-names");
+    }
+    Ok(())
+}
+
+
+/// Import the spelling variants found in a dataset.
+///
+/// This is an upsert on the dataset and variant spelling, leaving any link to
+/// the correct name that has already been made in place.
+pub fn import_variants(pool: &PgPool, variants: &[NameVariant]) -> Result<(), Error> {
+    use diesel::upsert::excluded;
+    use name_variants::dsl::*;
+
+    let mut conn = pool.get()?;
+    create_variants_table(&mut conn)?;
+
+    for chunk in variants.chunks(10_000) {
+        let values: Vec<_> = chunk
+            .iter()
+            .map(|variant| {
+                (
+                    id.eq(Uuid::new_v4()),
+                    dataset_id.eq(variant.dataset_id),
+                    scientific_name.eq(&variant.scientific_name),
+                    canonical_name.eq(&variant.canonical_name),
+                    status.eq(format!("{:?}", variant.status)),
+                )
+            })
+            .collect();
+
+        diesel::insert_into(name_variants)
+            .values(values)
+            .on_conflict((dataset_id, scientific_name))
+            .do_update()
+            .set((canonical_name.eq(excluded(canonical_name)), status.eq(excluded(status))))
+            .execute(&mut conn)?;
+    }
+
+    Ok(())
+}
+
+
+/// Link spelling variants to the names row of the correct spelling.
+///
+/// Taxa don't record the correct spelling of a variant, that comes from the taxonomic
+/// act for the variant in the same dataset, so this has to run after the taxonomic acts
+/// have been updated. Variants without an act are left unlinked until one arrives.
+pub fn link_variants(pool: &PgPool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    create_variants_table(&mut conn)?;
+
+    let linked = sql_query(
+        "UPDATE name_variants SET name_id = names.id
+         FROM taxa variant
+         JOIN taxonomic_acts acts ON acts.taxon_id = variant.id
+         JOIN taxa accepted ON accepted.id = acts.accepted_taxon_id
+         JOIN names ON names.scientific_name = accepted.scientific_name
+         WHERE variant.dataset_id = name_variants.dataset_id
+         AND variant.scientific_name = name_variants.scientific_name",
+    )
+    .execute(&mut conn)?;
+
+    info!(linked, "Linked name variants");
     Ok(())
 }
+
+
+/// A map of variant spellings to the names row of their correct spelling.
+///
+/// Only variants that have been linked are included.
+pub fn variant_lookup(pool: &PgPool) -> Result<StringMap, Error> {
+    use name_variants::dsl::*;
+
+    let mut conn = pool.get()?;
+    create_variants_table(&mut conn)?;
+
+    let results = name_variants
+        .filter(name_id.is_not_null())
+        .select((scientific_name, name_id.assume_not_null()))
+        .load::<(String, Uuid)>(&mut conn)?;
+
+    let mut map = StringMap::new();
+    for (lookup, uuid) in results {
+        map.insert(lookup, uuid);
+    }
+    Ok(map)
+}
+
+
+/// Record the rank and nomenclatural code of each name from the taxa linked to it.
+///
+/// The names table only has the name and its authorship so filtering names by rank means
+/// joining the taxa of every dataset. A name gets the rank and code that all of the taxa linked
+/// to it in `taxon_names` agree on, and is left null for whichever of the two the datasets
+/// disagree on, since picking one dataset's rank over another's isn't something an index should
+/// do silently. The table is rebuilt from the taxa every time so names that lose their taxa are
+/// dropped from it.
+pub fn classify(pool: &PgPool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    create_classifications_table(&mut conn)?;
+
+    let classified = conn.transaction::<_, Error, _>(|conn| {
+        diesel::delete(name_classifications::table).execute(conn)?;
+        let classified = sql_query(
+            "INSERT INTO name_classifications (name_id, rank, nomenclatural_code)
+             SELECT taxon_names.name_id,
+                    CASE WHEN count(DISTINCT taxa.rank) = 1 THEN min(taxa.rank::text) END,
+                    CASE WHEN count(DISTINCT NULLIF(taxa.nomenclatural_code, '')) = 1
+                         THEN min(NULLIF(taxa.nomenclatural_code, '')) END
+             FROM taxon_names
+             JOIN taxa ON taxa.id = taxon_names.taxon_id
+             GROUP BY taxon_names.name_id",
+        )
+        .execute(conn)?;
+        Ok(classified)
+    })?;
+
+    let ambiguous = sql_query(
+        "SELECT count(*) FILTER (WHERE rank IS NULL) AS ranks,
+                count(*) FILTER (WHERE nomenclatural_code IS NULL) AS codes
+         FROM name_classifications",
+    )
+    .get_result::<AmbiguousClassifications>(&mut conn)?;
+
+    info!(
+        classified,
+        ambiguous_ranks = ambiguous.ranks,
+        ambiguous_codes = ambiguous.codes,
+        "Classified names"
+    );
+    Ok(())
+}
+
+
+fn create_entities_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS name_entities (
+            entity_id text PRIMARY KEY,
+            name_id uuid NOT NULL REFERENCES names ON DELETE CASCADE
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+pub fn create_classifications_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS name_classifications (
+            name_id uuid PRIMARY KEY REFERENCES names ON DELETE CASCADE,
+            rank text,
+            nomenclatural_code text
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+fn create_variants_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS name_variants (
+            id uuid PRIMARY KEY,
+            dataset_id uuid NOT NULL REFERENCES datasets ON DELETE CASCADE,
+            scientific_name text NOT NULL,
+            canonical_name text NOT NULL,
+            status text NOT NULL,
+            name_id uuid REFERENCES names ON DELETE SET NULL,
+            UNIQUE (dataset_id, scientific_name)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_authorship_splits_names_without_a_canonical_name() {
+        let cases = [
+            ("Aus", "Aus", None),
+            ("Aus bus", "Aus bus", None),
+            ("Aus bus L.", "Aus bus", Some("L.")),
+            ("Aus bus Linnaeus, 1758", "Aus bus", Some("Linnaeus, 1758")),
+            ("Aus bus (L., 1758) Smith", "Aus bus", Some("(L., 1758) Smith")),
+            ("Aus (Bus) cus var. dus (L., 1758) Smith", "Aus cus var. dus", Some("(L., 1758) Smith")),
+            ("Aus × bus Smith", "Aus × bus", Some("Smith")),
+            ("Aus bus de Candolle", "Aus bus", Some("de Candolle")),
+            ("Aus bus van der Wulp", "Aus bus", Some("van der Wulp")),
+            ("Aus bus d'Orbigny, 1839", "Aus bus", Some("d'Orbigny, 1839")),
+            ("Aus bus l'Héritier", "Aus bus", Some("l'Héritier")),
+            ("Aus bus sensu Smith", "Aus bus", Some("sensu Smith")),
+            ("Aus bus auct. non L.", "Aus bus", Some("auct. non L.")),
+            ("Aus bus nom. nud.", "Aus bus", Some("nom. nud.")),
+            ("Aus bus s.l.", "Aus bus", Some("s.l.")),
+            ("", "", None),
+        ];
+
+        for (scientific_name, canonical_name, authorship) in cases {
+            let expected = (canonical_name.to_string(), authorship.map(str::to_string));
+            assert_eq!(parse_authorship(scientific_name, None), expected, "{scientific_name}");
+        }
+    }
+
+    #[test]
+    fn parse_authorship_uses_the_canonical_name_when_given() {
+        let cases = [
+            ("Aus bus L.", "Aus bus", Some("L.")),
+            ("Aus bus d'Orbigny", "Aus bus", Some("d'Orbigny")),
+            ("Aus bus", "Aus bus", None),
+            // the canonical name is kept even when the scientific name doesn't start with it
+            ("Aus cus L.", "Aus bus", None),
+        ];
+
+        for (scientific_name, canonical_name, authorship) in cases {
+            let expected = (canonical_name.to_string(), authorship.map(str::to_string));
+            assert_eq!(parse_authorship(scientific_name, Some(canonical_name)), expected, "{scientific_name}");
+        }
+    }
+}
@@ -5,18 +5,33 @@ use arga_core::crdt::lww::Map;
 use arga_core::crdt::DataFrame;
 use arga_core::models::{self, NomenclaturalActAtom, NomenclaturalActOperation, NomenclaturalActType};
 use arga_core::schema;
+use chrono::{DateTime, Utc};
 use diesel::*;
 use indicatif::ProgressIterator;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 use uuid::Uuid;
 
-use crate::database::{get_pool, name_lookup, publication_lookup, FrameLoader, PgPool};
+use crate::database::{
+    dataset_version_lookup,
+    get_pool,
+    name_lookup,
+    no_merge_versions,
+    publication_lookup,
+    FrameLoader,
+    PgPool,
+};
+use crate::determinism::EntityRecord;
 use crate::errors::Error;
 use crate::frames::{FrameReader, IntoFrame};
 use crate::operations::group_operations;
+use crate::output::OutputSchema;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::mappings::FieldMappings;
+use crate::readers::records::RecordReader;
+use crate::readers::xlsx::SheetOptions;
 use crate::readers::{meta, OperationLoader};
-use crate::utils::{new_progress_bar, new_spinner, nomenclatural_act_from_str};
+use crate::utils::{empty_as_none, new_progress_bar, new_spinner, nomenclatural_act_from_str};
 use crate::{frame_push_opt, import_compressed_csv_stream, import_frames_from_stream, FrameProgress};
 
 type NomenclaturalActFrame = DataFrame<NomenclaturalActAtom>;
@@ -24,13 +39,16 @@ type NomenclaturalActFrame = DataFrame<NomenclaturalActAtom>;
 
 impl OperationLoader for FrameLoader<NomenclaturalActOperation> {
     type Operation = NomenclaturalActOperation;
+    const LOG_TABLE: &'static str = "nomenclatural_act_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<NomenclaturalActOperation>, Error> {
         use schema::nomenclatural_act_logs::dsl::*;
         let mut conn = self.pool.get()?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = nomenclatural_act_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<NomenclaturalActOperation>(&mut conn)?;
 
@@ -67,15 +85,21 @@ pub struct Record {
     /// The name of the taxon without the author
     pub canonical_name: String,
     /// The authorship of the name
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub scientific_name_authorship: Option<String>,
 
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub authority_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub authority_year: Option<String>,
 
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub base_authority_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub base_authority_year: Option<String>,
 
     /// The name of the taxon currently accepted. Should include author when possible
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub acted_on: Option<String>,
 
     /// The status of the taxon. Refer to TaxonomicStatus for all options
@@ -84,11 +108,14 @@ pub struct Record {
     pub act: NomenclaturalActType,
 
     #[serde(alias = "nomenclatural_act_publication")]
-    pub publication: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub publication: Option<String>,
     #[serde(alias = "year_of_act")]
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub publication_date: Option<String>,
 
-    pub source_url: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub source_url: Option<String>,
     // citation: Option<String>,
 
     // /// The timestamp of when the record was created at the data source
@@ -102,6 +129,8 @@ pub struct Record {
 impl IntoFrame for Record {
     type Atom = NomenclaturalActAtom;
 
+    const RECORD_TYPE: &'static str = "nomenclatural_acts";
+
     fn entity_hashable(&self) -> &[u8] {
         // the nomenclatural act id should be an externally unique value that all datasets
         // reference if they are describing this particular datum
@@ -114,15 +143,15 @@ impl IntoFrame for Record {
         frame.push(ScientificName(self.scientific_name));
         frame.push(CanonicalName(self.canonical_name));
         frame.push(Act(self.act));
-        frame.push(SourceUrl(self.source_url));
-        frame.push(Publication(self.publication));
         frame_push_opt!(frame, Authorship, self.scientific_name_authorship);
         frame_push_opt!(frame, AuthorityName, self.authority_name);
         frame_push_opt!(frame, AuthorityYear, self.authority_year);
         frame_push_opt!(frame, BasionymAuthorityName, self.base_authority_name);
         frame_push_opt!(frame, BasionymAuthorityYear, self.base_authority_year);
         frame_push_opt!(frame, ActedOn, self.acted_on);
+        frame_push_opt!(frame, Publication, self.publication);
         frame_push_opt!(frame, PublicationDate, self.publication_date);
+        frame_push_opt!(frame, SourceUrl, self.source_url);
         frame
     }
 }
@@ -138,8 +167,30 @@ where
 }
 
 
-pub fn import_archive<S: Read + FrameProgress>(stream: S, dataset: &meta::Dataset) -> Result<(), Error> {
-    import_compressed_csv_stream::<S, Record, NomenclaturalActOperation>(stream, dataset)
+/// Infer nomenclatural acts from the reduced taxa and import them as operations.
+///
+/// Many providers only supply taxa, but the act that established a name can be inferred from its
+/// authorship. The dataset version should belong to a dataset dedicated to inferred acts so that the
+/// provenance of these operations is clearly distinct from acts supplied by a provider.
+pub fn infer_from_taxa(pool: PgPool, dataset_version_id: Uuid) -> Result<(), Error> {
+    let taxa = super::taxa::reduce(pool.clone(), None)?;
+    let records: Vec<Record> = taxa
+        .iter()
+        .filter_map(|taxon| taxon.infer_nomenclatural_act())
+        .collect();
+    info!(taxa = taxa.len(), inferred = records.len(), "Inferred nomenclatural acts from taxa");
+
+    let reader = RecordReader::new(records.into_iter(), dataset_version_id);
+    import_frames(reader, pool)
+}
+
+
+pub fn import_archive<S: Read + FrameProgress>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error> {
+    import_compressed_csv_stream::<S, Record, NomenclaturalActOperation>(stream, dataset, mappings)
 }
 
 
@@ -151,6 +202,11 @@ pub struct NomenclaturalAct {
     /// The id of this record entity in the taxonomic act logs
     entity_id: String,
 
+    /// The external identifier of the dataset that last changed the act
+    dataset_id: String,
+    /// The internal identifier of the dataset that last changed the act
+    dataset_uuid: Uuid,
+
     /// The name of the taxon. Should include author when possible
     scientific_name: String,
     /// The authorship of the name
@@ -175,41 +231,95 @@ pub struct NomenclaturalAct {
     source_url: String,
     citation: Option<String>,
 }
+impl EntityRecord for NomenclaturalAct {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for NomenclaturalAct {
+    const COLUMNS: &'static [&'static str] = &[
+        "entity_id",
+        "dataset_id",
+        "dataset_uuid",
+        "scientific_name",
+        "scientific_name_authorship",
+        "canonical_name",
+        "authority_name",
+        "authority_year",
+        "base_authority_name",
+        "base_authority_year",
+        "acted_on",
+        "act",
+        "publication",
+        "publication_date",
+        "source_url",
+        "citation",
+    ];
+    const NAME: &'static str = "nomenclatural_acts";
+    const VERSION: u32 = 2;
+}
 
 pub struct NomenclaturalActs {
     pub path: PathBuf,
     pub dataset_version_id: Uuid,
+    pub sheet: SheetOptions,
+    /// The timestamp column to skip rows older than the dataset watermark by
+    pub since: Option<String>,
+    /// The sentinels of the columns recorded as unknown
+    pub mappings: FieldMappings,
 }
 
 impl NomenclaturalActs {
-    /// Import the CSV file as taxonomic act operations into the taxonomic_act_logs table.
+    /// Import the CSV or spreadsheet file as taxonomic act operations into the taxonomic_act_logs table.
     ///
     /// This will parse and decompose the CSV file, merge it with the existing taxonomic act logs
     /// and then insert them into the database, effectively updating taxonomic_act_logs with the
     /// latest changes from the dataset.
     pub fn import(&self) -> Result<(), Error> {
-        crate::import_csv_as_logs::<Record, NomenclaturalActOperation>(&self.path, &self.dataset_version_id)?;
+        crate::import_file_as_logs::<Record, NomenclaturalActOperation>(
+            &self.path,
+            &self.dataset_version_id,
+            &self.sheet,
+            &self.mappings,
+            self.since.as_deref(),
+        )?;
         info!("Nomenclatural act logs imported");
         Ok(())
     }
 
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<Record>()
+    }
+
     /// Reduce the entire taxonomic_act_logs table into an ARGA CSV file.
     ///
     /// This will generate a snapshot of every taxonomic act built from all datasets
     /// using the last-write-win CRDT map. The snapshot output is a reproducible
     /// dataset that should be imported into the ARGA database and used by the application.
-    pub fn reduce() -> Result<Vec<NomenclaturalAct>, Error> {
+    /// When a cutoff is provided only the operations imported at or before it are reduced.
+    pub fn reduce(as_of: Option<DateTime<Utc>>) -> Result<Vec<NomenclaturalAct>, Error> {
+        use schema::dataset_versions;
         use schema::nomenclatural_act_logs::dsl::*;
 
-        let pool = get_pool()?;
+        let mut pool = get_pool()?;
+        let versions = dataset_version_lookup(&mut pool)?;
         let mut conn = pool.get()?;
 
         let spinner = new_spinner("Loading nomenclatural act logs");
-        let ops = nomenclatural_act_logs
-            // .inner_join(dataset_versions::table.on(dataset_version_id.eq(dataset_versions::id)))
-            // .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+        let no_merge = no_merge_versions(&mut conn)?;
+        let mut query = nomenclatural_act_logs
+            .filter(dataset_version_id.ne_all(no_merge))
             .order(operation_id.asc())
-            .load::<NomenclaturalActOperation>(&mut conn)?;
+            .into_boxed();
+        if let Some(cutoff) = as_of {
+            let imported = dataset_versions::table
+                .filter(dataset_versions::imported_at.le(cutoff))
+                .select(dataset_versions::id);
+            query = query.filter(dataset_version_id.eq_any(imported));
+        }
+        let ops = query.load::<NomenclaturalActOperation>(&mut conn)?;
         spinner.finish();
 
         let spinner = new_spinner("Grouping nomenclatural act logs");
@@ -223,10 +333,13 @@ impl NomenclaturalActs {
             let mut map = Map::new(key);
             map.reduce(&ops);
 
-            // include the dataset global id in the reduced output to
+            // include the dataset in the reduced output to
             // allow for multiple taxonomic systems
-            let record = NomenclaturalAct::from(map);
-            if let Some(_op) = ops.first() {
+            let mut record = NomenclaturalAct::from(map);
+            if let Some(op) = ops.last() {
+                if let Some(dataset) = versions.get(&op.dataset_version_id) {
+                    (record.dataset_id, record.dataset_uuid) = dataset.clone();
+                }
                 records.push(record);
             }
         }
@@ -234,18 +347,17 @@ impl NomenclaturalActs {
         Ok(records)
     }
 
-    pub fn update() -> Result<(), Error> {
+    pub fn update(mut pool: PgPool) -> Result<(), Error> {
         use diesel::upsert::excluded;
         use schema::nomenclatural_acts::dsl::*;
 
-        let mut pool = get_pool()?;
         let mut conn = pool.get()?;
 
         // reduce the logs and convert the record to the model equivalent. because taxa
         // are unique per dataset we need to have a dataset lookup and scope the taxa
         // lookup to the appropriate dataset, this ensures that taxonomic acts are applied
         // to the correct taxon for that system, rather than attaching an act across systems
-        let reduced = Self::reduce()?;
+        let reduced = Self::reduce(None)?;
 
         // import all the names in case they don't already exist. we use names to
         // hang data on including the names that a nomenclatural act describes or acts on
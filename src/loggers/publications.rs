@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::io::Read;
 
 use arga_core::crdt::lww::Map;
@@ -7,26 +8,44 @@ use arga_core::schema;
 use chrono::{DateTime, Utc};
 use diesel::*;
 use rayon::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::database::{FrameLoader, PgPool};
+use crate::database::{dataset_version_lookup, no_merge_versions, FrameLoader, PgPool};
+use crate::determinism::EntityRecord;
 use crate::errors::Error;
 use crate::frames::{FrameReader, IntoFrame};
+use crate::output::OutputSchema;
+use crate::readers::mappings::FieldMappings;
 use crate::readers::{meta, OperationLoader};
+use crate::utils::{empty_as_none, new_spinner};
 use crate::{frame_push_opt, import_compressed_csv_stream, import_frames_from_stream, FrameProgress};
 
 type PublicationFrame = DataFrame<PublicationAtom>;
 
 
+// equivalents aren't part of arga_core as they are only recorded by the oplogger when merging
+// duplicate publications. the canonical entity is the publication the duplicate was merged into
+diesel::table! {
+    publication_equivalents (entity_id) {
+        entity_id -> Varchar,
+        canonical_entity_id -> Varchar,
+    }
+}
+
+
 impl OperationLoader for FrameLoader<PublicationOperation> {
     type Operation = PublicationOperation;
+    const LOG_TABLE: &'static str = "publication_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<PublicationOperation>, Error> {
         use schema::publication_logs::dsl::*;
         let mut conn = self.pool.get()?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = publication_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<PublicationOperation>(&mut conn)?;
 
@@ -63,12 +82,16 @@ pub struct Record {
     #[serde(alias = "publication_url")]
     pub source_url: String,
     pub published_date: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub language: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub publisher: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub doi: Option<String>,
 
     pub publication_type: Option<PublicationType>,
     #[serde(alias = "full_citation")]
+    #[serde(default, deserialize_with = "empty_as_none")]
     pub citation: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
@@ -77,6 +100,8 @@ pub struct Record {
 impl IntoFrame for Record {
     type Atom = PublicationAtom;
 
+    const RECORD_TYPE: &'static str = "publications";
+
     fn entity_hashable(&self) -> &[u8] {
         // the sequence id should be an externally unique value that all datasets
         // reference if they are describing this particular datum
@@ -113,17 +138,21 @@ where
 }
 
 
-pub fn import_archive<S: Read + FrameProgress>(stream: S, dataset: &meta::Dataset) -> Result<(), Error> {
-    import_compressed_csv_stream::<S, Record, PublicationOperation>(stream, dataset)
+pub fn import_archive<S: Read + FrameProgress>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error> {
+    import_compressed_csv_stream::<S, Record, PublicationOperation>(stream, dataset, mappings)
 }
 
 
-pub fn update() -> Result<(), Error> {
+pub fn update(pool: PgPool) -> Result<(), Error> {
     use diesel::dsl::count_distinct;
     use schema::publication_logs::dsl::*;
 
-    let pool = crate::database::get_pool()?;
     let mut conn = pool.get()?;
+    create_publication_equivalents_table(&mut conn)?;
 
     // get the total amount of distinct entities in the log table. this allows
     // us to split up the reduction into many threads without loading all operations
@@ -164,8 +193,10 @@ pub fn reduce_and_update(offset: i64, limit: i64, pool: crate::database::PgPool)
 
     // get the operations for the entities making sure to order by operation id so that
     // the CRDT structs can do their thing
+    let no_merge = no_merge_versions(&mut conn)?;
     let operations = publication_logs
         .filter(entity_id.eq_any(entity_ids))
+        .filter(dataset_version_id.ne_all(&no_merge))
         .order_by((entity_id, operation_id))
         .load::<PublicationOperation>(&mut conn)?;
 
@@ -173,9 +204,22 @@ pub fn reduce_and_update(offset: i64, limit: i64, pool: crate::database::PgPool)
     let entities = crate::operations::group_operations(operations, vec![]);
     let mut records: Vec<models::Publication> = Vec::new();
 
+    // publications merged into another one stay in the logs but aren't written back
+    let keys: Vec<&String> = entities.keys().collect();
+    let merged: HashSet<String> = publication_equivalents::table
+        .filter(publication_equivalents::entity_id.eq_any(keys))
+        .select(publication_equivalents::entity_id)
+        .load::<String>(&mut conn)?
+        .into_iter()
+        .collect();
+
     // reduce all the operations by applying them to an empty record
     // as per the last write wins policy
     for (key, ops) in entities.into_iter() {
+        if merged.contains(&key) {
+            continue;
+        }
+
         let mut map = Map::new(key);
         map.reduce(&ops);
 
@@ -211,6 +255,147 @@ pub fn reduce_and_update(offset: i64, limit: i64, pool: crate::database::PgPool)
 }
 
 
+/// A reduced publication as written out by the reduce command
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicationRecord {
+    pub entity_id: String,
+    pub dataset_id: String,
+    pub dataset_uuid: Uuid,
+    pub title: String,
+    pub authors: Option<String>,
+    pub published_year: i32,
+    pub source_url: String,
+    pub published_date: Option<DateTime<Utc>>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub doi: Option<String>,
+    pub publication_type: Option<String>,
+    pub citation: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl From<Record> for PublicationRecord {
+    fn from(value: Record) -> Self {
+        PublicationRecord {
+            entity_id: value.entity_id,
+            dataset_id: String::new(),
+            dataset_uuid: Uuid::nil(),
+            title: value.title,
+            authors: value.authors.map(|authors| authors.join("; ")),
+            published_year: value.published_year,
+            source_url: value.source_url,
+            published_date: value.published_date,
+            language: value.language,
+            publisher: value.publisher,
+            doi: value.doi,
+            publication_type: value.publication_type.map(|kind| format!("{kind:?}")),
+            citation: value.citation,
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+        }
+    }
+}
+
+impl EntityRecord for PublicationRecord {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for PublicationRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "entity_id",
+        "dataset_id",
+        "dataset_uuid",
+        "title",
+        "authors",
+        "published_year",
+        "source_url",
+        "published_date",
+        "language",
+        "publisher",
+        "doi",
+        "publication_type",
+        "citation",
+        "created_at",
+        "updated_at",
+    ];
+    const NAME: &'static str = "publications";
+    const VERSION: u32 = 2;
+}
+
+
+/// Reduce the publication logs into records without updating the database.
+///
+/// Publications merged into another one are left out the same way they are when updating.
+/// When a cutoff is provided only the operations imported at or before it are reduced.
+pub fn reduce(mut pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<PublicationRecord>, Error> {
+    use schema::dataset_versions;
+    use schema::publication_logs::dsl::*;
+
+    let versions = dataset_version_lookup(&mut pool)?;
+    let mut conn = pool.get()?;
+    create_publication_equivalents_table(&mut conn)?;
+
+    let spinner = new_spinner("Loading publication logs");
+    let no_merge = no_merge_versions(&mut conn)?;
+    let mut query = publication_logs
+        .filter(dataset_version_id.ne_all(no_merge))
+        .order(operation_id.asc())
+        .into_boxed();
+    if let Some(cutoff) = as_of {
+        let imported = dataset_versions::table
+            .filter(dataset_versions::imported_at.le(cutoff))
+            .select(dataset_versions::id);
+        query = query.filter(dataset_version_id.eq_any(imported));
+    }
+    let operations = query.load::<PublicationOperation>(&mut conn)?;
+    spinner.finish();
+
+    let merged: HashSet<String> = publication_equivalents::table
+        .select(publication_equivalents::entity_id)
+        .load::<String>(&mut conn)?
+        .into_iter()
+        .collect();
+
+    let spinner = new_spinner("Reducing publication logs");
+    let entities = crate::operations::group_operations(operations, vec![]);
+    let mut records = Vec::new();
+
+    for (key, ops) in entities.into_iter() {
+        if merged.contains(&key) {
+            continue;
+        }
+
+        let mut map = Map::new(key);
+        map.reduce(&ops);
+
+        // publications don't log their dataset so it is the last dataset to change the entity
+        let mut record = PublicationRecord::from(Record::from(map));
+        if let Some(dataset) = ops.last().and_then(|op| versions.get(&op.dataset_version_id)) {
+            (record.dataset_id, record.dataset_uuid) = dataset.clone();
+        }
+        records.push(record);
+    }
+    spinner.finish();
+
+    Ok(records)
+}
+
+
+pub fn create_publication_equivalents_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS publication_equivalents (
+            entity_id varchar PRIMARY KEY,
+            canonical_entity_id varchar NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
 /// Converts a LWW CRDT map of name publication atoms to a record for serialisation
 impl From<Map<PublicationAtom>> for Record {
     fn from(value: Map<PublicationAtom>) -> Self {
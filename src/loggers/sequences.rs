@@ -1,31 +1,44 @@
+use std::collections::{BTreeMap, HashSet};
 use std::path::PathBuf;
 
+use arga_core::crdt::lww::Map;
 use arga_core::crdt::DataFrame;
 use arga_core::models::{SequenceAtom, SequenceOperation};
 use arga_core::schema;
+use chrono::{DateTime, Utc};
 use diesel::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::info;
 use uuid::Uuid;
 
-use crate::database::FrameLoader;
+use crate::database::{dataset_version_lookup, no_merge_versions, FrameLoader, PgPool};
+use crate::determinism::EntityRecord;
 use crate::errors::Error;
 use crate::frame_push_opt;
 use crate::frames::IntoFrame;
+use crate::operations::group_operations;
+use crate::output::OutputSchema;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::mappings::FieldMappings;
+use crate::readers::xlsx::SheetOptions;
 use crate::readers::OperationLoader;
+use crate::utils::{empty_as_none, new_spinner};
 
 type SequenceFrame = DataFrame<SequenceAtom>;
 
 
 impl OperationLoader for FrameLoader<SequenceOperation> {
     type Operation = SequenceOperation;
+    const LOG_TABLE: &'static str = "sequence_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<SequenceOperation>, Error> {
         use schema::sequence_logs::dsl::*;
         let mut conn = self.pool.get_timeout(std::time::Duration::from_secs(1))?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = sequence_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<SequenceOperation>(&mut conn)?;
 
@@ -52,38 +65,50 @@ impl OperationLoader for FrameLoader<SequenceOperation> {
 /// This is deserializeable with the serde crate and enforces expectations
 /// about what fields are mandatory and the format they should be in.
 #[derive(Debug, Clone, Deserialize)]
-struct Record {
+pub struct Record {
     /// The record id assigned by the dataset
     sequence_id: String,
     /// The record id of the dna extraction that was sequenced
     dna_extract_id: String,
 
     /// The date the sequence occurred
+    #[serde(default, deserialize_with = "empty_as_none")]
     event_date: Option<String>,
     /// The time the sequence occurred
+    #[serde(default, deserialize_with = "empty_as_none")]
     event_time: Option<String>,
     /// Who carried out the sequencing
+    #[serde(default, deserialize_with = "empty_as_none")]
     sequenced_by: Option<String>,
     /// An external reference id to the material that was sequenced
+    #[serde(default, deserialize_with = "empty_as_none")]
     material_sample_id: Option<String>,
 
     /// The concentration used for the sequencing
+    #[serde(default, deserialize_with = "empty_as_none")]
     concentration: Option<String>,
     amplicon_size: Option<i64>,
     /// The basepair size of the sequence. eg 140 bp
+    #[serde(default, deserialize_with = "empty_as_none")]
     estimated_size: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     bait_set_name: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     bait_set_reference: Option<String>,
 
     /// The gene being sequenced. eg COI-5P
+    #[serde(default, deserialize_with = "empty_as_none")]
     target_gene: Option<String>,
     /// The sequence data. eg ACTGTTGGCAC
+    #[serde(default, deserialize_with = "empty_as_none")]
     dna_sequence: Option<String>,
 }
 
 impl IntoFrame for Record {
     type Atom = SequenceAtom;
 
+    const RECORD_TYPE: &'static str = "sequences";
+
     fn entity_hashable(&self) -> &[u8] {
         // the sequence id should be an externally unique value that all datasets
         // reference if they are describing this particular datum
@@ -114,17 +139,152 @@ impl IntoFrame for Record {
 pub struct Sequences {
     pub path: PathBuf,
     pub dataset_version_id: Uuid,
+    pub sheet: SheetOptions,
+    /// The timestamp column to skip rows older than the dataset watermark by
+    pub since: Option<String>,
+    /// The sentinels of the columns recorded as unknown
+    pub mappings: FieldMappings,
 }
 
 impl Sequences {
-    /// Import the CSV file as sequence operations into the sequence_logs table.
+    /// Import the CSV or spreadsheet file as sequence operations into the sequence_logs table.
     ///
     /// This will parse and decompose the CSV file, merge it with the existing logs
     /// and then insert them into the database, effectively updating sequence_logs with the
     /// latest changes from the dataset.
     pub fn import(&self) -> Result<(), Error> {
-        crate::import_csv_as_logs::<Record, SequenceOperation>(&self.path, &self.dataset_version_id)?;
+        crate::import_file_as_logs::<Record, SequenceOperation>(
+            &self.path,
+            &self.dataset_version_id,
+            &self.sheet,
+            &self.mappings,
+            self.since.as_deref(),
+        )?;
         info!("Sequence operations import finished");
         Ok(())
     }
+
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<Record>()
+    }
+}
+
+
+/// A reference from a sequence to another record and whether it resolves.
+///
+/// The reference is the name of the column holding the foreign id and the target is the
+/// table it's resolved against. There is one row for every reference a sequence makes.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequenceLink {
+    pub entity_id: String,
+    /// The global id of the dataset that last changed the sequence
+    pub dataset_id: String,
+    /// The uuid of the same dataset
+    pub dataset_uuid: Uuid,
+    pub reference: String,
+    pub target: String,
+    pub value: String,
+    pub resolved: bool,
+}
+
+impl EntityRecord for SequenceLink {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for SequenceLink {
+    const COLUMNS: &'static [&'static str] =
+        &["entity_id", "dataset_id", "dataset_uuid", "reference", "target", "value", "resolved"];
+    const NAME: &'static str = "sequence_links";
+    const VERSION: u32 = 2;
+}
+
+
+/// Reduce the sequence logs into their foreign references and check if they resolve.
+///
+/// Material sample ids are resolved against the specimens table. The dna extract id is
+/// left out until there is an extractions table to resolve it against. A summary of the
+/// resolved and unresolved references of each dataset is logged so that linkage can be
+/// tracked between imports. When a cutoff is provided only the sequence operations imported at
+/// or before it are reduced, though they are still resolved against the current specimens.
+pub fn links(mut pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<SequenceLink>, Error> {
+    let versions = dataset_version_lookup(&mut pool)?;
+    let mut conn = pool.get()?;
+
+    let spinner = new_spinner("Loading sequence logs");
+    let no_merge = no_merge_versions(&mut conn)?;
+    let operations = {
+        use schema::dataset_versions;
+        use schema::sequence_logs::dsl::*;
+
+        let mut query = sequence_logs
+            .filter(dataset_version_id.ne_all(&no_merge))
+            .order(operation_id.asc())
+            .into_boxed();
+        if let Some(cutoff) = as_of {
+            let imported = dataset_versions::table
+                .filter(dataset_versions::imported_at.le(cutoff))
+                .select(dataset_versions::id);
+            query = query.filter(dataset_version_id.eq_any(imported));
+        }
+        query.load::<SequenceOperation>(&mut conn)?
+    };
+
+    let material_samples: HashSet<String> = {
+        use schema::specimens::dsl::*;
+        specimens
+            .filter(material_sample_id.is_not_null())
+            .select(material_sample_id.assume_not_null())
+            .load::<String>(&mut conn)?
+            .into_iter()
+            .collect()
+    };
+    spinner.finish();
+
+    let mut links = Vec::new();
+    for (key, ops) in group_operations(operations, vec![]).into_iter() {
+        // the last dataset to change an entity is the one responsible for its references
+        let (dataset_id, dataset_uuid) =
+            ops.last().and_then(|op| versions.get(&op.dataset_version_id)).cloned().unwrap_or_default();
+
+        let mut map = Map::new(key);
+        map.reduce(&ops);
+
+        for atom in map.atoms.into_values() {
+            if let SequenceAtom::MaterialSampleId(value) = atom {
+                links.push(SequenceLink {
+                    entity_id: map.entity_id.clone(),
+                    dataset_id: dataset_id.clone(),
+                    dataset_uuid,
+                    reference: "material_sample_id".to_string(),
+                    target: "specimens".to_string(),
+                    resolved: material_samples.contains(&value),
+                    value,
+                });
+            }
+        }
+    }
+
+    report_links(&links);
+    Ok(links)
+}
+
+
+fn report_links(links: &[SequenceLink]) {
+    let mut summary: BTreeMap<(&str, &str), (usize, usize)> = BTreeMap::new();
+    for link in links {
+        let (resolved, unresolved) = summary.entry((&link.dataset_id, &link.reference)).or_default();
+        if link.resolved {
+            *resolved += 1;
+        }
+        else {
+            *unresolved += 1;
+        }
+    }
+
+    for ((dataset_id, reference), (resolved, unresolved)) in summary {
+        info!(dataset_id, reference, resolved, unresolved, "Sequence links");
+    }
 }
@@ -4,13 +4,25 @@ use arga_core::models::AccessRightsStatus;
 use arga_core::models::DataReuseStatus;
 use arga_core::models::Source;
 use arga_core::models::SourceContentType;
-use arga_core::schema::sources;
+use arga_core::schema::{dataset_versions, datasets, sources};
+use chrono::{DateTime, Utc};
 use diesel::*;
 
-use crate::database::get_pool;
+use crate::database::{get_pool, PgPool};
+use crate::determinism::EntityRecord;
 use crate::errors::Error;
-use crate::utils::{access_pill_status_from_str, content_type_from_str, data_reuse_status_from_str};
-use serde::Deserialize;
+use crate::output::OutputSchema;
+use crate::readers::describe::{describe_record, Column};
+use crate::utils::{
+    access_pill_status_from_str,
+    access_pill_status_to_str,
+    content_type_from_str,
+    content_type_to_str,
+    data_reuse_status_from_str,
+    data_reuse_status_to_str,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
 use uuid::Uuid;
 
 pub struct Sources {
@@ -87,4 +99,87 @@ impl Sources {
 
         Ok(())
     }
+
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<CSVRecord>()
+    }
+}
+
+
+/// A source as it appears in the registry export.
+///
+/// The columns are the same as the import CSV so that an export can be imported again.
+/// The source name is unique and used as the entity id.
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceRecord {
+    pub name: String,
+    pub author: String,
+    pub license: String,
+    pub reuse_pill: Option<&'static str>,
+    pub access_rights: String,
+    pub access_pill: Option<&'static str>,
+    pub rights_holder: String,
+    pub content_type: Option<&'static str>,
+}
+
+impl From<Source> for SourceRecord {
+    fn from(value: Source) -> SourceRecord {
+        SourceRecord {
+            name: value.name,
+            author: value.author,
+            license: value.license,
+            reuse_pill: value.reuse_pill.as_ref().map(data_reuse_status_to_str),
+            access_rights: value.access_rights,
+            access_pill: value.access_pill.as_ref().map(access_pill_status_to_str),
+            rights_holder: value.rights_holder,
+            content_type: value.content_type.as_ref().map(content_type_to_str),
+        }
+    }
+}
+
+impl EntityRecord for SourceRecord {
+    fn entity_id(&self) -> &str {
+        &self.name
+    }
+}
+
+impl OutputSchema for SourceRecord {
+    const COLUMNS: &'static [&'static str] = &[
+        "name",
+        "author",
+        "license",
+        "reuse_pill",
+        "access_rights",
+        "access_pill",
+        "rights_holder",
+        "content_type",
+    ];
+    const NAME: &'static str = "sources";
+    const VERSION: u32 = 1;
+}
+
+
+/// Load every source in the registry ordered by name.
+///
+/// Sources aren't versioned, so with a cutoff only the sources that had a dataset version
+/// imported at or before it are loaded, as they are as of now.
+pub fn reduce(pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<SourceRecord>, Error> {
+    let mut conn = pool.get()?;
+
+    let mut query = sources::table.order(sources::name.asc()).into_boxed();
+    if let Some(cutoff) = as_of {
+        let imported = dataset_versions::table
+            .filter(dataset_versions::imported_at.le(cutoff))
+            .select(dataset_versions::dataset_id);
+        let sources_imported = datasets::table
+            .filter(datasets::id.eq_any(imported))
+            .select(datasets::source_id);
+        query = query.filter(sources::id.eq_any(sources_imported));
+    }
+
+    let records = query.load::<Source>(&mut conn)?;
+    info!(total = records.len(), "Loaded sources");
+
+    Ok(records.into_iter().map(SourceRecord::from).collect())
 }
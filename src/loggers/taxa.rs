@@ -1,20 +1,34 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io::Read;
+use std::path::Path;
+use std::time::Instant;
 
 use arga_core::crdt::lww::Map;
 use arga_core::crdt::DataFrame;
-use arga_core::models::{self, TaxonAtom, TaxonOperation, TaxonOperationWithDataset, TaxonomicRank, TaxonomicStatus};
+use arga_core::models::{
+    self,
+    NomenclaturalActType,
+    TaxonAtom,
+    TaxonOperation,
+    TaxonOperationWithDataset,
+    TaxonomicRank,
+    TaxonomicStatus,
+};
 use arga_core::schema;
+use chrono::{DateTime, Utc};
 use diesel::*;
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::database::{
     dataset_lookup,
     get_pool,
     name_lookup,
+    no_merge_versions,
+    reduced_dataset,
     refresh_materialized_view,
     taxon_lookup,
     FrameLoader,
@@ -23,26 +37,54 @@ use crate::database::{
     StringMap,
     UuidStringMap,
 };
-use crate::errors::{Error, LookupError, ReduceError};
+use crate::determinism::EntityRecord;
+use crate::entity_views::{taxa_entities, EntityView};
+use crate::errors::{skip_record, Error, LookupError, ParseError, ReduceError};
+use crate::fingerprints::{self, Fingerprints};
 use crate::frames::IntoFrame;
+use crate::loggers::names::NameVariant;
+use crate::minting;
 use crate::operations::group_operations;
+use crate::output::OutputSchema;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::edits::EditReader;
+use crate::readers::mappings::FieldMappings;
 use crate::readers::{meta, OperationLoader};
 use crate::reducer::{DatabaseReducer, EntityPager, Reducer};
-use crate::utils::{taxonomic_rank_from_str, taxonomic_status_from_str, titleize_first_word, UpdateBars};
-use crate::{frame_push_opt, import_compressed_csv_stream, FrameProgress};
+use crate::taxonomy;
+use crate::utils::{
+    empty_as_none,
+    str_to_taxonomic_rank,
+    str_to_taxonomic_status,
+    taxonomic_rank_from_str,
+    taxonomic_status_from_str,
+    titleize_first_word,
+    UpdateBars,
+};
+use crate::{
+    frame_push_opt,
+    import_compressed_csv_stream,
+    import_frames_from_stream,
+    nomenclatural_acts,
+    precedence,
+    FrameProgress,
+};
 
 type TaxonFrame = DataFrame<TaxonAtom>;
 
 
 impl OperationLoader for FrameLoader<TaxonOperation> {
     type Operation = TaxonOperation;
+    const LOG_TABLE: &'static str = "taxa_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<TaxonOperation>, Error> {
         use schema::taxa_logs::dsl::*;
         let mut conn = self.pool.get()?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = taxa_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<TaxonOperation>(&mut conn)?;
 
@@ -80,11 +122,13 @@ struct Record {
     /// The record id assigned by the dataset
     taxon_id: String,
     /// The scientific name of the parent taxon to link up in a tree
+    #[serde(default, deserialize_with = "empty_as_none")]
     parent_taxon: Option<String>,
 
     /// The name of the taxon. Should include author when possible
     scientific_name: String,
     /// The authorship of the taxon
+    #[serde(default, deserialize_with = "empty_as_none")]
     scientific_name_authorship: Option<String>,
 
     /// The name of the taxon without the author
@@ -100,14 +144,19 @@ struct Record {
     /// The code used to define the taxon. Eg. ICZN
     nomenclatural_code: String,
 
+    #[serde(default, deserialize_with = "empty_as_none")]
     citation: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     references: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     last_updated: Option<String>,
 }
 
 impl IntoFrame for Record {
     type Atom = TaxonAtom;
 
+    const RECORD_TYPE: &'static str = "taxa";
+
     fn entity_hashable(&self) -> &[u8] {
         // because arga supports multiple taxonomic systems we use the entity_id
         // field which should be salted with a unique dataset_id to ensure that
@@ -140,13 +189,17 @@ impl IntoFrame for Record {
 /// The ARGA taxon CSV record output
 /// This is the record in a CSV after reducing the taxa logs
 /// from multiple datasets.
-#[derive(Clone, Debug, Default, Serialize)]
+///
+/// It can also be deserialized from a reduced CSV so that curated edits of the
+/// output can be imported back as operations.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Taxon {
     /// The id of this record entity in the taxa logs
     entity_id: String,
     /// The id of the taxon as determined by the source dataset
     taxon_id: String,
     /// The scientific name of the parent taxon. Useful for taxonomy trees
+    #[serde(default, deserialize_with = "empty_as_none")]
     parent_taxon: Option<String>,
     /// The external identifier of the source dataset as determined by ARGA
     dataset_id: String,
@@ -157,6 +210,7 @@ pub struct Taxon {
     /// The name of the taxon. Should include author when possible
     scientific_name: String,
     /// The authorship of the taxon
+    #[serde(default, deserialize_with = "empty_as_none")]
     scientific_name_authorship: Option<String>,
     /// The name of the taxon without the author
     canonical_name: String,
@@ -164,15 +218,144 @@ pub struct Taxon {
     nomenclatural_code: String,
 
     /// The rank of the taxon. Refer to TaxonomicRank for all options
+    #[serde(deserialize_with = "taxonomic_rank_from_str")]
     taxon_rank: TaxonomicRank,
     /// The status of the taxon. Refer to TaxonomicStatus for all options
+    #[serde(deserialize_with = "taxonomic_status_from_str")]
     taxonomic_status: TaxonomicStatus,
 
+    #[serde(default, deserialize_with = "empty_as_none")]
     citation: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     references: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
     last_updated: Option<String>,
 }
 
+impl Taxon {
+    /// The atoms of the fields that a curator can edit in the reduced output
+    fn curated_atoms(&self) -> Vec<TaxonAtom> {
+        use TaxonAtom::*;
+
+        let mut atoms = vec![
+            TaxonId(self.taxon_id.clone()),
+            ScientificName(self.scientific_name.clone()),
+            CanonicalName(self.canonical_name.clone()),
+            NomenclaturalCode(self.nomenclatural_code.clone()),
+            TaxonomicRank(self.taxon_rank.clone()),
+            TaxonomicStatus(self.taxonomic_status.clone()),
+        ];
+
+        let optional = [
+            self.parent_taxon.clone().map(ParentTaxon),
+            self.scientific_name_authorship.clone().map(Authorship),
+            self.citation.clone().map(Citation),
+            self.references.clone().map(References),
+            self.last_updated.clone().map(LastUpdated),
+        ];
+        atoms.extend(optional.into_iter().flatten());
+        atoms
+    }
+}
+
+impl EntityRecord for Taxon {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for Taxon {
+    const COLUMNS: &'static [&'static str] = &[
+        "entity_id",
+        "taxon_id",
+        "parent_taxon",
+        "dataset_id",
+        "dataset_uuid",
+        "scientific_name",
+        "scientific_name_authorship",
+        "canonical_name",
+        "nomenclatural_code",
+        "taxon_rank",
+        "taxonomic_status",
+        "citation",
+        "references",
+        "last_updated",
+    ];
+    const NAME: &'static str = "taxa";
+    const VERSION: u32 = 1;
+}
+
+impl Taxon {
+    /// Infer the nomenclatural act that established this name from the authorship.
+    ///
+    /// A name with a parenthesised authority has been moved from its original genus, so it
+    /// is considered a new combination with the basionym authority in the parentheses and
+    /// the combination authority following it, eg. `(L.) Mill.`. Any other authorship is
+    /// considered to be the original description of the name. Names without an authorship
+    /// can't be inferred.
+    ///
+    /// An original description acts on the name itself. A new combination acts on the basionym,
+    /// which the taxon doesn't name, so it's left out rather than guessed.
+    pub fn infer_nomenclatural_act(&self) -> Option<nomenclatural_acts::Record> {
+        let authorship = self.scientific_name_authorship.as_ref()?.trim();
+        if authorship.is_empty() {
+            return None;
+        }
+
+        let mut record = nomenclatural_acts::Record {
+            entity_id: format!("{}:inferred_act", self.entity_id),
+            scientific_name: self.scientific_name.clone(),
+            canonical_name: self.canonical_name.clone(),
+            scientific_name_authorship: Some(authorship.to_string()),
+            authority_name: None,
+            authority_year: None,
+            base_authority_name: None,
+            base_authority_year: None,
+            acted_on: Some(self.scientific_name.clone()),
+            act: NomenclaturalActType::OriginalDescription,
+            publication: self.citation.clone(),
+            publication_date: None,
+            source_url: self.references.clone(),
+        };
+
+        match authorship.strip_prefix('(').and_then(|rest| rest.split_once(')')) {
+            Some((basionym, combination)) => {
+                let (name, year) = split_authority(basionym);
+                record.act = NomenclaturalActType::CombinatioNova;
+                record.acted_on = None;
+                record.base_authority_name = name;
+                record.base_authority_year = year;
+
+                let (name, year) = split_authority(combination);
+                record.authority_name = name;
+                record.authority_year = year.clone();
+                record.publication_date = year;
+            }
+            None => {
+                let (name, year) = split_authority(authorship);
+                record.authority_name = name;
+                record.authority_year = year.clone();
+                record.publication_date = year;
+            }
+        }
+
+        Some(record)
+    }
+}
+
+/// Split an authority like `Linnaeus, 1758` into the name and year
+fn split_authority(authority: &str) -> (Option<String>, Option<String>) {
+    let authority = authority.trim();
+    let (name, year) = match authority.rsplit_once(',') {
+        Some((name, year)) if year.trim().chars().all(|c| c.is_ascii_digit()) => (name.trim(), Some(year.trim())),
+        _ => (authority, None),
+    };
+
+    let name = Some(name.to_string()).filter(|name| !name.is_empty());
+    let year = year.map(|year| year.to_string()).filter(|year| !year.is_empty());
+    (name, year)
+}
+
 pub struct TaxonLink {
     dataset_id: Uuid,
     name_id: Uuid,
@@ -181,8 +364,18 @@ pub struct TaxonLink {
 }
 
 
-pub fn import<S: Read + FrameProgress>(stream: S, dataset: &meta::Dataset) -> Result<(), Error> {
-    import_compressed_csv_stream::<S, Record, TaxonOperation>(stream, dataset)
+pub fn import<S: Read + FrameProgress>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error> {
+    import_compressed_csv_stream::<S, Record, TaxonOperation>(stream, dataset, mappings)
+}
+
+
+/// The columns of a taxa CSV as the import deserializes them
+pub fn describe() -> Vec<Column> {
+    describe_record::<Record>()
 }
 
 
@@ -207,7 +400,42 @@ pub fn update2() -> Result<(), Error> {
     Ok(())
 }
 
-fn reduce_chunk(pool: PgPool, offset: i64, limit: i64) -> Result<Vec<Taxon>, Error> {
+/// Reduce the entire taxa_logs table into taxon records.
+///
+/// The entities are reduced in parallel chunks so the order of the returned
+/// records is not guaranteed. When a cutoff is provided only the operations
+/// imported at or before it are reduced, producing a historical snapshot.
+pub fn reduce(mut pool: PgPool, as_of: Option<DateTime<Utc>>) -> Result<Vec<Taxon>, Error> {
+    let datasets = dataset_lookup(&mut pool)?;
+    let mut conn = pool.get()?;
+
+    let total = {
+        use diesel::dsl::count_distinct;
+        use schema::taxa_logs::dsl::*;
+
+        taxa_logs
+            .select(count_distinct(entity_id))
+            .get_result::<i64>(&mut conn)?
+    };
+
+    let limit = 10_000;
+    let offsets: Vec<i64> = (0..total).step_by(limit as usize).collect();
+
+    let chunks = offsets
+        .into_par_iter()
+        .map(|offset| reduce_chunk(pool.clone(), offset, limit, as_of, &datasets))
+        .collect::<Result<Vec<Vec<Taxon>>, Error>>()?;
+
+    Ok(chunks.into_iter().flatten().collect())
+}
+
+fn reduce_chunk(
+    pool: PgPool,
+    offset: i64,
+    limit: i64,
+    as_of: Option<DateTime<Utc>>,
+    datasets: &StringMap,
+) -> Result<Vec<Taxon>, Error> {
     let mut conn = pool.get()?;
 
     let operations = {
@@ -228,14 +456,27 @@ fn reduce_chunk(pool: PgPool, offset: i64, limit: i64) -> Result<Vec<Taxon>, Err
 
         // get the operations for the entities making sure to order by operation id so that
         // the CRDT structs can do their thing
-        taxa_logs
+        let no_merge = no_merge_versions(&mut conn)?;
+        let mut query = taxa_logs
             .inner_join(dataset_versions::table.on(dataset_version_id.eq(dataset_versions::id)))
             .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order_by((entity_id, operation_id))
-            .load::<TaxonOperationWithDataset>(&mut conn)?
+            .into_boxed();
+
+        if let Some(cutoff) = as_of {
+            query = query.filter(dataset_versions::imported_at.le(cutoff));
+        }
+
+        query.load::<TaxonOperationWithDataset>(&mut conn)?
     };
 
+    Ok(reduce_operations(precedence::apply(operations), datasets))
+}
+
+/// Reduce the operations of a set of entities into taxon records
+fn reduce_operations(operations: Vec<TaxonOperationWithDataset>, datasets: &StringMap) -> Vec<Taxon> {
     // group the entity operations up and preparing it for use in the LWW map
     let entities = group_operations(operations, vec![]);
     let mut reduced_records = Vec::new();
@@ -246,11 +487,12 @@ fn reduce_chunk(pool: PgPool, offset: i64, limit: i64) -> Result<Vec<Taxon>, Err
         let mut map = Map::new(key);
         map.reduce(&ops);
 
-        // include the dataset uuid in the reduced output to
+        // include the dataset in the reduced output to
         // allow for multiple taxonomic systems
         let mut record = Taxon::from(map);
         if let Some(op) = ops.first() {
-            record.dataset_uuid.clone_from(&op.dataset.id);
+            let logged_by = (op.dataset.global_id.as_str(), op.dataset.id);
+            (record.dataset_id, record.dataset_uuid) = reduced_dataset(&record.dataset_id, logged_by, datasets);
             reduced_records.push(record);
         }
     }
@@ -265,12 +507,339 @@ fn reduce_chunk(pool: PgPool, offset: i64, limit: i64) -> Result<Vec<Taxon>, Err
             .then_with(|| a.scientific_name.cmp(&b.scientific_name))
     });
     reduced_records.dedup_by(|a, b| a.scientific_name == b.scientific_name && a.dataset_id == b.dataset_id);
+    reduced_records
+}
 
-    Ok(reduced_records)
+
+/// Import curated edits of a reduced taxa CSV as operations.
+///
+/// The edited file is compared with the taxa reduced at the base cutoff, which should be the
+/// same point the file was exported at, so that only the cells a curator changed become
+/// operations. Only the entities in the file are reduced for the comparison. The operations
+/// are logged under the curation dataset version and win over the source datasets like any
+/// newer change would. Rows for entities that don't exist in the base are skipped, and a
+/// cleared cell is reported but not imported as there is no operation to unset a field.
+pub fn curate(pool: PgPool, path: &Path, base: DateTime<Utc>, dataset_version_id: Uuid) -> Result<(), Error> {
+    let mut rows = Vec::new();
+    let mut reader = csv::Reader::from_path(path)?;
+    for row in reader.deserialize::<Taxon>() {
+        match row {
+            Ok(edited) => rows.push(edited),
+            Err(err) => skip_record(&err.into()),
+        }
+    }
+
+    let entity_ids: Vec<String> = rows.iter().map(|taxon| taxon.entity_id.clone()).collect();
+    let snapshot: HashMap<String, Taxon> = reduce_entities(pool.clone(), &entity_ids, Some(base))?
+        .into_iter()
+        .map(|taxon| (taxon.entity_id.clone(), taxon))
+        .collect();
+
+    let mut edits = Vec::new();
+    let mut cleared = 0;
+
+    for edited in rows {
+        let Some(exported) = snapshot.get(&edited.entity_id)
+        else {
+            let missing = format!("taxon entity {} in the base snapshot", edited.entity_id);
+            skip_record(&ParseError::NotFound(missing).into());
+            continue;
+        };
+
+        let base_atoms = exported.curated_atoms();
+        let edited_atoms = edited.curated_atoms();
+
+        // an atom of the base without one of the same field in the edit is a cleared cell
+        let fields: Vec<_> = edited_atoms.iter().map(std::mem::discriminant).collect();
+        cleared += base_atoms
+            .iter()
+            .filter(|atom| !fields.contains(&std::mem::discriminant(*atom)))
+            .count();
+
+        let changes: Vec<TaxonAtom> = edited_atoms.into_iter().filter(|atom| !base_atoms.contains(atom)).collect();
+        if !changes.is_empty() {
+            edits.push((edited.entity_id, changes));
+        }
+    }
+
+    if cleared > 0 {
+        warn!(cleared, "Cleared cells can't be imported as operations and were ignored");
+    }
+
+    info!(edited = edits.len(), "Importing curated taxa");
+    let reader = EditReader::new(edits.into_iter(), dataset_version_id);
+    import_frames_from_stream::<TaxonOperation, _>(reader, pool)
 }
 
-pub fn reduce_and_update(pool: PgPool, offset: i64, limit: i64) -> Result<(), Error> {
-    let reduced_records = reduce_chunk(pool.clone(), offset, limit)?;
+
+/// Reduce only the taxa matching an entity id or a name straight from the logs.
+///
+/// Names are matched against the scientific and canonical names in the taxa table to find
+/// the entity ids, so a name is only found if it existed at the last update. The records
+/// themselves are always reduced from the current logs and nothing is written.
+pub fn query(pool: PgPool, entity: Option<&str>, name: Option<&str>) -> Result<Vec<Taxon>, Error> {
+    use schema::taxa;
+
+    let mut entity_ids: Vec<String> = entity.map(|id| vec![id.to_string()]).unwrap_or_default();
+    if let Some(name) = name {
+        let mut conn = pool.get()?;
+        let matched = taxa::table
+            .filter(taxa::scientific_name.eq(name).or(taxa::canonical_name.eq(name)))
+            .select(taxa::entity_id)
+            .load::<Option<String>>(&mut conn)?;
+        entity_ids.extend(matched.into_iter().flatten());
+    }
+
+    reduce_entities(pool, &entity_ids, None)
+}
+
+/// Reduce the taxa of a set of entity ids, optionally only from the operations imported at or
+/// before a cutoff. The ids are queried in chunks to stay under the bind parameter limit
+fn reduce_entities(mut pool: PgPool, entity_ids: &[String], as_of: Option<DateTime<Utc>>) -> Result<Vec<Taxon>, Error> {
+    use schema::{dataset_versions, datasets, taxa_logs};
+
+    let dataset_ids = dataset_lookup(&mut pool)?;
+    let mut conn = pool.get()?;
+    let no_merge = no_merge_versions(&mut conn)?;
+
+    let mut operations = Vec::new();
+    for chunk in entity_ids.chunks(10_000) {
+        let mut query = taxa_logs::table
+            .inner_join(dataset_versions::table.on(taxa_logs::dataset_version_id.eq(dataset_versions::id)))
+            .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+            .filter(taxa_logs::entity_id.eq_any(chunk))
+            .filter(taxa_logs::dataset_version_id.ne_all(&no_merge))
+            .order_by((taxa_logs::entity_id, taxa_logs::operation_id))
+            .into_boxed();
+
+        if let Some(cutoff) = as_of {
+            query = query.filter(dataset_versions::imported_at.le(cutoff));
+        }
+
+        operations.extend(query.load::<TaxonOperationWithDataset>(&mut conn)?);
+    }
+
+    info!(entities = entity_ids.len(), operations = operations.len(), "Reducing matching taxa");
+    Ok(reduce_operations(precedence::apply(operations), &dataset_ids))
+}
+
+/// Merge the reduced taxa from every dataset into a single row per name.
+///
+/// Each dataset reduces to its own taxon for a name, which is what a taxonomic system needs,
+/// but a backbone view wants one row per name. The same canonical name can be a different
+/// taxon under another nomenclatural code, or a homonym with a different author, so the rows
+/// are grouped by the canonical name, code, and authorship. Rows without an authorship join
+/// the authored group of their name and code when there is only one, otherwise they are
+/// merged on their own. The rows of each group are ordered by the position of their dataset in
+/// `precedence`, with unlisted datasets ordered after them by their dataset id. The first row
+/// is used as the consensus and any fields it is missing are filled in from the following rows.
+/// The merged rows are ordered by entity id so that the output is the same between runs.
+pub fn consensus(taxa: Vec<Taxon>, precedence: &[String]) -> Vec<Taxon> {
+    let rank = |dataset_id: &str| {
+        precedence
+            .iter()
+            .position(|id| id == dataset_id)
+            .unwrap_or(precedence.len())
+    };
+
+    let mut names: BTreeMap<(String, String, String), Vec<Taxon>> = BTreeMap::new();
+    for taxon in taxa {
+        names.entry(consensus_key(&taxon)).or_default().push(taxon);
+    }
+
+    let (unauthored, authored): (Vec<_>, Vec<_>) = names.keys().cloned().partition(|key| key.2.is_empty());
+    for key in unauthored {
+        let mut matches = authored.iter().filter(|other| other.0 == key.0 && other.1 == key.1);
+        if let (Some(other), None) = (matches.next(), matches.next()) {
+            let rows = names.remove(&key).unwrap_or_default();
+            names.entry(other.clone()).or_default().extend(rows);
+        }
+    }
+
+    let mut merged = Vec::with_capacity(names.len());
+    for (_name, mut taxa) in names {
+        taxa.sort_by(|a, b| {
+            rank(&a.dataset_id)
+                .cmp(&rank(&b.dataset_id))
+                .then_with(|| a.dataset_id.cmp(&b.dataset_id))
+                .then_with(|| a.entity_id.cmp(&b.entity_id))
+        });
+
+        let mut rows = taxa.into_iter();
+        if let Some(mut consensus) = rows.next() {
+            for other in rows {
+                consensus.parent_taxon = consensus.parent_taxon.or(other.parent_taxon);
+                consensus.scientific_name_authorship = consensus
+                    .scientific_name_authorship
+                    .or(other.scientific_name_authorship);
+                consensus.citation = consensus.citation.or(other.citation);
+                consensus.references = consensus.references.or(other.references);
+                consensus.last_updated = consensus.last_updated.or(other.last_updated);
+            }
+            merged.push(consensus);
+        }
+    }
+
+    merged.sort_by(|a, b| a.entity_id.cmp(&b.entity_id));
+    merged
+}
+
+
+/// A single taxon in an external backbone CSV such as the ALA backbone.
+///
+/// Both the snake case columns used by ARGA and the camel case Darwin Core terms are accepted.
+#[derive(Debug, Clone, Deserialize)]
+struct BackboneTaxon {
+    #[serde(alias = "canonicalName")]
+    canonical_name: String,
+    #[serde(default, alias = "scientificNameAuthorship")]
+    scientific_name_authorship: Option<String>,
+    #[serde(alias = "taxonRank")]
+    taxon_rank: String,
+    #[serde(alias = "taxonomicStatus")]
+    taxonomic_status: String,
+}
+
+/// A difference between the reduced taxa and an external backbone.
+///
+/// Additions are names found in the reduced taxa but not the backbone and removals are the
+/// reverse. Conflicts are names found in both with a different rank or status.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackboneDifference {
+    pub kind: String,
+    pub canonical_name: String,
+    pub scientific_name_authorship: Option<String>,
+    pub dataset_id: Option<String>,
+    pub reduced: Option<String>,
+    pub backbone: Option<String>,
+}
+
+// differences aren't entities so they are ordered by name, with the rest of the row breaking ties
+impl EntityRecord for BackboneDifference {
+    fn entity_id(&self) -> &str {
+        &self.canonical_name
+    }
+}
+
+impl OutputSchema for BackboneDifference {
+    const COLUMNS: &'static [&'static str] = &[
+        "kind",
+        "canonical_name",
+        "scientific_name_authorship",
+        "dataset_id",
+        "reduced",
+        "backbone",
+    ];
+    const NAME: &'static str = "backbone_differences";
+    const VERSION: u32 = 1;
+}
+
+
+/// Compare the consensus of the reduced taxa against an external backbone.
+///
+/// Names are matched on their canonical name and authorship, ignoring case and whitespace.
+/// Backbone values for rank and status are parsed with the same rules as the importer so that
+/// spelling differences like `species` and `SPECIES` aren't reported as conflicts. Values that
+/// can't be parsed are compared verbatim and will always conflict.
+pub fn compare_backbone(
+    taxa: Vec<Taxon>,
+    precedence: &[String],
+    backbone_path: &Path,
+) -> Result<Vec<BackboneDifference>, Error> {
+    let mut backbone = BTreeMap::new();
+    let mut reader = csv::Reader::from_path(backbone_path)?;
+    for row in reader.deserialize() {
+        let taxon: BackboneTaxon = row?;
+        let key = backbone_key(&taxon.canonical_name, taxon.scientific_name_authorship.as_deref());
+        backbone.insert(key, taxon);
+    }
+
+    let mut reduced = BTreeMap::new();
+    for taxon in consensus(taxa, precedence) {
+        let key = backbone_key(&taxon.canonical_name, taxon.scientific_name_authorship.as_deref());
+        reduced.insert(key, taxon);
+    }
+
+    let mut differences = Vec::new();
+    for (key, taxon) in &reduced {
+        let Some(external) = backbone.get(key)
+        else {
+            differences.push(BackboneDifference {
+                kind: "addition".to_string(),
+                canonical_name: taxon.canonical_name.clone(),
+                scientific_name_authorship: taxon.scientific_name_authorship.clone(),
+                dataset_id: Some(taxon.dataset_id.clone()),
+                reduced: None,
+                backbone: None,
+            });
+            continue;
+        };
+
+        let rank = format!("{:?}", taxon.taxon_rank);
+        let external_rank = match str_to_taxonomic_rank(&external.taxon_rank) {
+            Ok(value) => format!("{value:?}"),
+            Err(_) => external.taxon_rank.clone(),
+        };
+
+        let status = format!("{:?}", taxon.taxonomic_status);
+        let external_status = match str_to_taxonomic_status(&external.taxonomic_status) {
+            Ok(value) => format!("{value:?}"),
+            Err(_) => external.taxonomic_status.clone(),
+        };
+
+        for (kind, ours, theirs) in [
+            ("rank_conflict", rank, external_rank),
+            ("status_conflict", status, external_status),
+        ] {
+            if ours != theirs {
+                differences.push(BackboneDifference {
+                    kind: kind.to_string(),
+                    canonical_name: taxon.canonical_name.clone(),
+                    scientific_name_authorship: taxon.scientific_name_authorship.clone(),
+                    dataset_id: Some(taxon.dataset_id.clone()),
+                    reduced: Some(ours),
+                    backbone: Some(theirs),
+                });
+            }
+        }
+    }
+
+    for (key, external) in &backbone {
+        if !reduced.contains_key(key) {
+            differences.push(BackboneDifference {
+                kind: "removal".to_string(),
+                canonical_name: external.canonical_name.clone(),
+                scientific_name_authorship: external.scientific_name_authorship.clone(),
+                dataset_id: None,
+                reduced: None,
+                backbone: None,
+            });
+        }
+    }
+
+    info!(
+        reduced = reduced.len(),
+        backbone = backbone.len(),
+        differences = differences.len(),
+        "Backbone comparison finished"
+    );
+    Ok(differences)
+}
+
+
+fn consensus_key(taxon: &Taxon) -> (String, String, String) {
+    let (name, authorship) = backbone_key(&taxon.canonical_name, taxon.scientific_name_authorship.as_deref());
+    (name, taxon.nomenclatural_code.trim().to_lowercase(), authorship)
+}
+
+fn backbone_key(canonical_name: &str, authorship: Option<&str>) -> (String, String) {
+    let normalize = |value: &str| value.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase();
+    (normalize(canonical_name), normalize(authorship.unwrap_or_default()))
+}
+
+pub fn reduce_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<(), Error> {
+    let datasets = dataset_lookup(&mut pool)?;
+    let reduced_records = reduce_chunk(pool.clone(), offset, limit, None, &datasets)?;
 
     let mut names = Vec::new();
     let mut records = Vec::new();
@@ -372,7 +941,8 @@ pub fn link2() -> Result<(), Error> {
 }
 
 pub fn link_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<(), Error> {
-    let reduced_records = reduce_chunk(pool.clone(), offset, limit)?;
+    let datasets = dataset_lookup(&mut pool)?;
+    let reduced_records = reduce_chunk(pool.clone(), offset, limit, None, &datasets)?;
 
     let mut dataset_ids: Vec<Uuid> = reduced_records.iter().map(|r| r.dataset_uuid).collect();
     dataset_ids.sort();
@@ -477,8 +1047,9 @@ impl From<Map<TaxonAtom>> for Taxon {
                 // generation but we don't need to actually use it
                 EntityId(_value) => {}
 
+                DatasetId(value) => taxon.dataset_id = value,
+
                 // fields currently not supported
-                DatasetId(_value) => {}
                 AcceptedNameUsageId(_value) => {}
                 ParentNameUsageId(_value) => {}
                 AcceptedNameUsage(_value) => {}
@@ -495,13 +1066,16 @@ impl From<Map<TaxonAtom>> for Taxon {
 }
 
 
-pub fn update() -> Result<(), Error> {
-    let mut pool = crate::database::get_pool()?;
-
+/// Reduce the taxa logs and upsert the entities that changed since the last update.
+///
+/// Entities with the same winning atoms as the last time they were written are skipped.
+/// A full update clears the fingerprints first so that every entity is written again.
+pub fn update(mut pool: PgPool, full: bool) -> Result<(), Error> {
     let lookups = Lookups {
         datasets: dataset_lookup(&mut pool)?,
     };
 
+    EntityView::Taxa.refresh(&pool)?;
     let pager: FrameLoader<TaxonOperation> = FrameLoader::new(pool.clone());
 
     // get the total amount of distinct entities in the log table. this allows
@@ -513,10 +1087,18 @@ pub fn update() -> Result<(), Error> {
 
     info!(total_entities, "Reducing taxa");
 
-    let reducer: DatabaseReducer<models::Taxon, _, _> = DatabaseReducer::new(pager, lookups);
+    if full {
+        fingerprints::clear(&pool, "taxa")?;
+    }
+
+    let fingerprints = Fingerprints::new(pool.clone(), "taxa")?;
+    let mut reducer: DatabaseReducer<models::Taxon, _, _> =
+        DatabaseReducer::new(pager, lookups).with_fingerprints(fingerprints);
     let mut conn = pool.get()?;
 
-    for records in reducer.into_iter() {
+    while let Some(records) = reducer.next() {
+        let started = Instant::now();
+
         for chunk in records.chunks(1000) {
             use diesel::upsert::excluded;
             use schema::names;
@@ -526,15 +1108,35 @@ pub fn update() -> Result<(), Error> {
             for record in chunk {
                 match record {
                     Ok(record) => valid_records.push(record.clone()),
-                    Err(err) => error!(?err),
+                    Err(err) => skip_record(err),
                 }
             }
 
-            // insert the names as well as they'll need to be used for linking later
-            let mut names: Vec<models::Name> = valid_records.iter().map(|r| models::Name::from(r.clone())).collect();
+            // insert the names as well as they'll need to be used for linking later. spelling
+            // variants are recorded separately so that they don't duplicate the correct name
+            let (variant_records, name_records): (Vec<&models::Taxon>, Vec<&models::Taxon>) =
+                valid_records.iter().partition(|r| NameVariant::is_variant(&r.status));
+
+            let mut names: Vec<models::Name> = name_records
+                .into_iter()
+                .map(|r| models::Name::from(r.clone()))
+                .collect();
             names.sort_by(|a, b| a.scientific_name.cmp(&b.scientific_name));
             names.dedup_by(|a, b| a.scientific_name.eq(&b.scientific_name));
 
+            let mut variants: Vec<NameVariant> = variant_records
+                .into_iter()
+                .map(|r| NameVariant {
+                    dataset_id: r.dataset_id,
+                    scientific_name: r.scientific_name.clone(),
+                    canonical_name: r.canonical_name.clone(),
+                    status: r.status.clone(),
+                })
+                .collect();
+            variants.sort_by(|a, b| (a.dataset_id, &a.scientific_name).cmp(&(b.dataset_id, &b.scientific_name)));
+            variants.dedup_by(|a, b| a.dataset_id.eq(&b.dataset_id) && a.scientific_name.eq(&b.scientific_name));
+            super::names::import_variants(&pool, &variants)?;
+
             diesel::insert_into(names::table)
                 .values(names)
                 .on_conflict(names::scientific_name)
@@ -573,15 +1175,35 @@ pub fn update() -> Result<(), Error> {
 
             bars.records.inc(chunk.len() as u64);
         }
+
+        reducer.commit_fingerprints()?;
+        reducer.record_write(started.elapsed());
     }
 
     bars.finish();
+    reducer.finish();
     info!("Finished reducing and updating taxa");
 
+    // taxonomic acts may have already been updated so try to link any new variants
+    super::names::link_variants(&pool)?;
+
+    minting::mint_new_entities(&pool, "taxa")?;
+    taxonomy::report(&pool)?;
     Ok(())
 }
 
 
+/// Trace the reduction of a single taxon entity without updating the taxa table
+pub fn explain(mut pool: PgPool, entity_id: &str) -> Result<(), Error> {
+    let lookups = Lookups {
+        datasets: dataset_lookup(&mut pool)?,
+    };
+
+    let loader: FrameLoader<TaxonOperation> = FrameLoader::new(pool);
+    crate::reducer::explain::<models::Taxon, _, _>(&loader, entity_id, &lookups)
+}
+
+
 pub fn link() -> Result<(), Error> {
     let mut pool = crate::database::get_pool()?;
 
@@ -590,10 +1212,11 @@ pub fn link() -> Result<(), Error> {
 
     let lookups = LinkLookups {
         datasets,
-        names: name_lookup(&mut pool)?,
+        names: name_and_variant_lookup(&mut pool)?,
         taxa: taxon_lookup(&mut pool, &dataset_ids)?,
     };
 
+    EntityView::Taxa.refresh(&pool)?;
     let pager: FrameLoader<TaxonOperation> = FrameLoader::new(pool.clone());
 
     // get the total amount of distinct entities in the log table. this allows
@@ -615,7 +1238,7 @@ pub fn link() -> Result<(), Error> {
         for record in chunk {
             match record {
                 Ok(record) => records.push(record),
-                Err(err) => error!(?err),
+                Err(err) => skip_record(&err),
             }
         }
 
@@ -630,6 +1253,8 @@ pub fn link() -> Result<(), Error> {
         }
     }
 
+    taxonomy::break_cycles(&mut pool.get()?, &mut links)?;
+
     let name_bar = bars.add_progress_bar(total_entities, "Updating name links");
     let parent_bar = bars.add_progress_bar(links.len(), "Updating parent links");
 
@@ -675,10 +1300,30 @@ pub fn link() -> Result<(), Error> {
     }
 
     bars.finish();
+
+    // cycles are broken before the links are applied so one found here was made by another
+    // process. it breaks the DAG views built on the parent links so fail loudly
+    let cycles = taxonomy::report(&pool)?;
+    if cycles > 0 {
+        return Err(ReduceError::TaxonomyCycles(cycles).into());
+    }
     Ok(())
 }
 
 
+/// A map of names that also resolves spelling variants to the correct name.
+///
+/// Variants don't get their own names row so without this their taxa would
+/// have nothing to link to.
+fn name_and_variant_lookup(pool: &mut PgPool) -> Result<StringMap, Error> {
+    let mut names = name_lookup(pool)?;
+    for (variant, name_id) in super::names::variant_lookup(pool)? {
+        names.entry(variant).or_insert(name_id);
+    }
+    Ok(names)
+}
+
+
 struct Lookups {
     datasets: StringMap,
 }
@@ -843,13 +1488,7 @@ impl EntityPager for FrameLoader<TaxonOperation> {
     fn total(&self) -> Result<i64, Error> {
         let mut conn = self.pool.get()?;
 
-        let total = {
-            use diesel::dsl::count_distinct;
-            use schema::taxa_logs::dsl::*;
-            taxa_logs
-                .select(count_distinct(entity_id))
-                .get_result::<i64>(&mut conn)?
-        };
+        let total = taxa_entities::table.count().get_result::<i64>(&mut conn)?;
 
         Ok(total)
     }
@@ -861,19 +1500,120 @@ impl EntityPager for FrameLoader<TaxonOperation> {
         let limit = 10_000;
         let offset = page as i64 * limit;
 
-        let entity_ids = taxa_logs
-            .select(entity_id)
-            .group_by(entity_id)
-            .order_by(entity_id)
+        let entity_ids = taxa_entities::table
+            .select(taxa_entities::entity_id)
+            .order_by(taxa_entities::entity_id)
             .offset(offset)
             .limit(limit)
             .into_boxed();
 
+        let no_merge = no_merge_versions(&mut conn)?;
         let operations = taxa_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order_by((entity_id, operation_id))
             .load::<TaxonOperation>(&mut conn)?;
 
-        Ok(operations)
+        Ok(precedence::apply(operations))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arga_core::crdt::Version;
+
+    use super::*;
+
+    fn reduce_taxon(atoms: Vec<TaxonAtom>) -> Taxon {
+        let mut frame = DataFrame::create("entity".to_string(), Uuid::new_v4(), Version::new());
+        for atom in atoms {
+            frame.push(atom);
+        }
+        let operations: Vec<TaxonOperation> = frame.collect();
+
+        let mut map = Map::new("entity".to_string());
+        map.reduce(&operations);
+        Taxon::from(map)
+    }
+
+    #[test]
+    fn reduced_dataset_resolves_the_dataset_id_atom() {
+        let named = Uuid::new_v4();
+        let logged_by = Uuid::new_v4();
+        let datasets = StringMap::from([
+            ("ARGA:TL:1".to_string(), named),
+            ("ARGA:TL:2".to_string(), logged_by),
+        ]);
+
+        let taxon = reduce_taxon(vec![
+            TaxonAtom::DatasetId("ARGA:TL:1".to_string()),
+            TaxonAtom::ScientificName("Aus bus".to_string()),
+        ]);
+        let dataset = reduced_dataset(&taxon.dataset_id, ("ARGA:TL:2", logged_by), &datasets);
+        assert_eq!(dataset, ("ARGA:TL:1".to_string(), named));
+    }
+
+    #[test]
+    fn reduced_dataset_falls_back_to_the_logging_dataset() {
+        let logged_by = Uuid::new_v4();
+        let datasets = StringMap::from([("ARGA:TL:2".to_string(), logged_by)]);
+
+        // a dataset id that was never imported
+        let taxon = reduce_taxon(vec![
+            TaxonAtom::DatasetId("ARGA:TL:9".to_string()),
+            TaxonAtom::ScientificName("Aus bus".to_string()),
+        ]);
+        let dataset = reduced_dataset(&taxon.dataset_id, ("ARGA:TL:2", logged_by), &datasets);
+        assert_eq!(dataset, ("ARGA:TL:2".to_string(), logged_by));
+
+        let taxon = reduce_taxon(vec![TaxonAtom::ScientificName("Aus bus".to_string())]);
+        let dataset = reduced_dataset(&taxon.dataset_id, ("ARGA:TL:2", logged_by), &datasets);
+        assert_eq!(dataset, ("ARGA:TL:2".to_string(), logged_by));
+    }
+
+    fn taxon(entity_id: &str, dataset_id: &str, code: &str, authorship: Option<&str>) -> Taxon {
+        Taxon {
+            entity_id: entity_id.to_string(),
+            dataset_id: dataset_id.to_string(),
+            scientific_name: "Morus alba".to_string(),
+            canonical_name: "Morus alba".to_string(),
+            nomenclatural_code: code.to_string(),
+            scientific_name_authorship: authorship.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn consensus_keeps_homonyms_from_different_codes_apart() {
+        // the white mulberry and a gannet share the name under the botanical and zoological codes
+        let taxa = vec![
+            taxon("1", "ARGA:TL:1", "ICN", Some("L.")),
+            taxon("2", "ARGA:TL:2", "ICZN", Some("(Linnaeus, 1758)")),
+            taxon("3", "ARGA:TL:2", "ICN", None),
+        ];
+
+        let merged = consensus(taxa, &["ARGA:TL:2".to_string()]);
+        assert_eq!(merged.len(), 2);
+
+        // the unauthored botanical row joins the only authored botanical row and takes precedence
+        assert_eq!(merged[0].entity_id, "2");
+        assert_eq!(merged[0].nomenclatural_code, "ICZN");
+        assert_eq!(merged[1].entity_id, "3");
+        assert_eq!(merged[1].nomenclatural_code, "ICN");
+        assert_eq!(merged[1].scientific_name_authorship.as_deref(), Some("L."));
+    }
+
+    #[test]
+    fn consensus_keeps_homonyms_with_different_authors_apart() {
+        let taxa = vec![
+            taxon("1", "ARGA:TL:1", "ICZN", Some("Smith, 1900")),
+            taxon("2", "ARGA:TL:2", "ICZN", Some("Jones, 1950")),
+            taxon("3", "ARGA:TL:2", "ICZN", Some("smith,  1900")),
+        ];
+
+        let merged = consensus(taxa, &[]);
+        let ids: Vec<&str> = merged.iter().map(|taxon| taxon.entity_id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2"]);
     }
 }
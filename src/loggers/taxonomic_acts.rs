@@ -17,22 +17,40 @@ use diesel::*;
 use indicatif::ProgressIterator;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::database::{dataset_lookup, get_pool, taxon_lookup, FrameLoader, PgPool, StringMap, UuidStringMap};
+use crate::database::{
+    dataset_lookup,
+    get_pool,
+    no_merge_versions,
+    reduced_dataset,
+    taxon_lookup,
+    FrameLoader,
+    PgPool,
+    StringMap,
+    UuidStringMap,
+};
+use crate::determinism::EntityRecord;
+use crate::entity_views::{taxonomic_act_entities, EntityView};
 use crate::errors::{Error, LookupError, ReduceError};
 use crate::frames::IntoFrame;
+use crate::minting;
 use crate::operations::group_operations;
+use crate::output::OutputSchema;
+use crate::precedence;
+use crate::readers::describe::{describe_record, Column};
+use crate::readers::mappings::FieldMappings;
+use crate::readers::xlsx::SheetOptions;
 use crate::readers::{meta, OperationLoader};
-use crate::reducer::{DatabaseReducer, EntityPager, Reducer};
+use crate::reducer::{update_table, EntityPager, Reducer};
 use crate::utils::{
     date_time_from_str_opt,
+    empty_as_none,
     new_progress_bar,
     new_spinner,
     taxonomic_status_from_str,
     titleize_first_word,
-    UpdateBars,
 };
 use crate::{frame_push_opt, import_compressed_csv_stream, FrameProgress};
 
@@ -41,13 +59,16 @@ type TaxonomicActFrame = DataFrame<TaxonomicActAtom>;
 
 impl OperationLoader for FrameLoader<TaxonomicActOperation> {
     type Operation = TaxonomicActOperation;
+    const LOG_TABLE: &'static str = "taxonomic_act_logs";
 
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<TaxonomicActOperation>, Error> {
         use schema::taxonomic_act_logs::dsl::*;
         let mut conn = self.pool.get()?;
+        let no_merge = no_merge_versions(&mut conn)?;
 
         let ops = taxonomic_act_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order(operation_id.asc())
             .load::<TaxonomicActOperation>(&mut conn)?;
 
@@ -195,7 +216,7 @@ impl OperationLoader for FrameLoader<TaxonomicActOperation> {
 /// This is deserializeable with the serde crate and enforces expectations
 /// about what fields are mandatory and the format they should be in.
 #[derive(Debug, Clone, Deserialize, Default)]
-struct Record {
+pub struct Record {
     /// Any value that uniquely identifies this record through its lifetime.
     /// This is a kind of global permanent identifier
     entity_id: String,
@@ -206,6 +227,7 @@ struct Record {
     /// The name of the taxon. Should include author when possible
     scientific_name: String,
     /// The name of the taxon currently accepted. Should include author when possible
+    #[serde(default, deserialize_with = "empty_as_none")]
     accepted_usage_taxon: Option<String>,
 
     /// The timestamp of when the record was created at the data source
@@ -215,12 +237,15 @@ struct Record {
     #[serde(deserialize_with = "date_time_from_str_opt")]
     updated_at: Option<DateTime<Utc>>,
 
+    #[serde(default, deserialize_with = "empty_as_none")]
     references: Option<String>,
 }
 
 impl IntoFrame for Record {
     type Atom = TaxonomicActAtom;
 
+    const RECORD_TYPE: &'static str = "taxonomic_acts";
+
     fn entity_hashable(&self) -> &[u8] {
         // the nomenclatural act id should be an externally unique value that all datasets
         // reference if they are describing this particular datum
@@ -272,9 +297,36 @@ pub struct TaxonomicAct {
     source_url: Option<String>,
 }
 
+impl EntityRecord for TaxonomicAct {
+    fn entity_id(&self) -> &str {
+        &self.entity_id
+    }
+}
+
+impl OutputSchema for TaxonomicAct {
+    const COLUMNS: &'static [&'static str] = &[
+        "entity_id",
+        "dataset_id",
+        "dataset_uuid",
+        "taxon",
+        "accepted_taxon",
+        "data_created_at",
+        "data_updated_at",
+        "publication",
+        "publication_date",
+        "source_url",
+    ];
+    const NAME: &'static str = "taxonomic_acts";
+    const VERSION: u32 = 1;
+}
+
 
-pub fn import<S: Read + FrameProgress>(stream: S, dataset: &meta::Dataset) -> Result<(), Error> {
-    import_compressed_csv_stream::<S, Record, TaxonomicActOperation>(stream, dataset)
+pub fn import<S: Read + FrameProgress>(
+    stream: S,
+    dataset: &meta::Dataset,
+    mappings: &FieldMappings,
+) -> Result<(), Error> {
+    import_compressed_csv_stream::<S, Record, TaxonomicActOperation>(stream, dataset, mappings)
 }
 
 pub fn update2() -> Result<(), Error> {
@@ -306,6 +358,7 @@ pub fn update2() -> Result<(), Error> {
 }
 
 pub fn reduce_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<(), Error> {
+    let datasets = dataset_lookup(&mut pool)?;
     let mut conn = pool.get()?;
 
     let operations = {
@@ -326,10 +379,12 @@ pub fn reduce_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<()
 
         // get the operations for the entities making sure to order by operation id so that
         // the CRDT structs can do their thing
+        let no_merge = no_merge_versions(&mut conn)?;
         taxonomic_act_logs
             .inner_join(dataset_versions::table.on(dataset_version_id.eq(dataset_versions::id)))
             .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order_by((entity_id, operation_id))
             .load::<TaxonomicActOperationWithDataset>(&mut conn)?
     };
@@ -346,7 +401,8 @@ pub fn reduce_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<()
 
         let mut record = TaxonomicAct::from(map);
         if let Some(op) = ops.first() {
-            record.dataset_uuid.clone_from(&op.dataset.id);
+            let logged_by = (op.dataset.global_id.as_str(), op.dataset.id);
+            (record.dataset_id, record.dataset_uuid) = reduced_dataset(&record.dataset_id, logged_by, &datasets);
             reduced_records.push(record);
         }
     }
@@ -416,42 +472,68 @@ pub fn reduce_and_update(mut pool: PgPool, offset: i64, limit: i64) -> Result<()
 pub struct TaxonomicActs {
     pub path: PathBuf,
     pub dataset_version_id: Uuid,
+    pub sheet: SheetOptions,
+    /// The timestamp column to skip rows older than the dataset watermark by
+    pub since: Option<String>,
+    /// The sentinels of the columns recorded as unknown
+    pub mappings: FieldMappings,
 }
 
 impl TaxonomicActs {
-    /// Import the CSV file as taxonomic act operations into the taxonomic_act_logs table.
+    /// Import the CSV or spreadsheet file as taxonomic act operations into the taxonomic_act_logs table.
     ///
     /// This will parse and decompose the CSV file, merge it with the existing taxonomic act logs
     /// and then insert them into the database, effectively updating taxonomic_act_logs with the
     /// latest changes from the dataset.
     pub fn import(&self) -> Result<(), Error> {
-        crate::import_csv_as_logs::<Record, TaxonomicActOperation>(&self.path, &self.dataset_version_id)?;
+        crate::import_file_as_logs::<Record, TaxonomicActOperation>(
+            &self.path,
+            &self.dataset_version_id,
+            &self.sheet,
+            &self.mappings,
+            self.since.as_deref(),
+        )?;
         info!("Taxonomic act logs imported");
         Ok(())
     }
 
+    /// The columns of the CSV as the import deserializes them
+    pub fn describe() -> Vec<Column> {
+        describe_record::<Record>()
+    }
+
     /// Reduce the entire taxonomic_act_logs table into an ARGA CSV file.
     ///
     /// This will generate a snapshot of every taxonomic act built from all datasets
     /// using the last-write-win CRDT map. The snapshot output is a reproducible
     /// dataset that should be imported into the ARGA database and used by the application.
-    pub fn reduce() -> Result<Vec<TaxonomicAct>, Error> {
+    /// When a cutoff is provided only the operations imported at or before it are reduced.
+    pub fn reduce(as_of: Option<DateTime<Utc>>) -> Result<Vec<TaxonomicAct>, Error> {
         use schema::taxonomic_act_logs::dsl::*;
         use schema::{dataset_versions, datasets};
 
-        let pool = get_pool()?;
+        let mut pool = get_pool()?;
+        let dataset_ids = dataset_lookup(&mut pool)?;
         let mut conn = pool.get()?;
 
         let spinner = new_spinner("Loading taxonomic act logs");
-        let ops = taxonomic_act_logs
+        let no_merge = no_merge_versions(&mut conn)?;
+        let mut query = taxonomic_act_logs
             .inner_join(dataset_versions::table.on(dataset_version_id.eq(dataset_versions::id)))
             .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+            .filter(dataset_version_id.ne_all(no_merge))
             .order(operation_id.asc())
-            .load::<TaxonomicActOperationWithDataset>(&mut conn)?;
+            .into_boxed();
+
+        if let Some(cutoff) = as_of {
+            query = query.filter(dataset_versions::imported_at.le(cutoff));
+        }
+
+        let ops = query.load::<TaxonomicActOperationWithDataset>(&mut conn)?;
         spinner.finish();
 
         let spinner = new_spinner("Grouping taxonomic act logs");
-        let entities = group_operations(ops, vec![]);
+        let entities = group_operations(precedence::apply(ops), vec![]);
         spinner.finish();
 
         let mut records = Vec::new();
@@ -461,11 +543,12 @@ impl TaxonomicActs {
             let mut map = Map::new(key);
             map.reduce(&ops);
 
-            // include the dataset global id in the reduced output to
+            // include the dataset in the reduced output to
             // allow for multiple taxonomic systems
             let mut record = TaxonomicAct::from(map);
             if let Some(op) = ops.first() {
-                record.dataset_id.clone_from(&op.dataset.global_id);
+                let logged_by = (op.dataset.global_id.as_str(), op.dataset.id);
+                (record.dataset_id, record.dataset_uuid) = reduced_dataset(&record.dataset_id, logged_by, &dataset_ids);
                 records.push(record);
             }
         }
@@ -484,7 +567,7 @@ impl TaxonomicActs {
         // are unique per dataset we need to have a dataset lookup and scope the taxa
         // lookup to the appropriate dataset, this ensures that taxonomic acts are applied
         // to the correct taxon for that system, rather than attaching an act across systems
-        let reduced = Self::reduce()?;
+        let reduced = Self::reduce(None)?;
 
         // get all the dataset uuids in the record list first to scope on
         let datasets = dataset_lookup(&mut pool)?;
@@ -575,7 +658,7 @@ impl From<Map<TaxonomicActAtom>> for TaxonomicAct {
                 // we want this atom for provenance and reproduction with the hash
                 // generation but we don't need to actually use it
                 EntityId(_value) => {}
-                DatasetId(_value) => {}
+                DatasetId(value) => act.dataset_id = value,
             }
         }
 
@@ -584,9 +667,11 @@ impl From<Map<TaxonomicActAtom>> for TaxonomicAct {
 }
 
 
-pub fn update() -> Result<(), Error> {
-    let mut pool = crate::database::get_pool()?;
-
+/// Reduce the taxonomic acts logs and upsert the entities that changed since the last update.
+///
+/// Entities with the same winning atoms as the last time they were written are skipped.
+/// A full update clears the fingerprints first so that every entity is written again.
+pub fn update(mut pool: PgPool, full: bool) -> Result<(), Error> {
     let datasets = dataset_lookup(&mut pool)?;
     let dataset_ids: Vec<Uuid> = datasets.values().map(|id| id.clone()).collect();
 
@@ -595,56 +680,51 @@ pub fn update() -> Result<(), Error> {
         taxa: taxon_lookup(&mut pool, &dataset_ids)?,
     };
 
+    EntityView::TaxonomicActs.refresh(&pool)?;
     let pager: FrameLoader<TaxonomicActOperation> = FrameLoader::new(pool.clone());
 
-    // get the total amount of distinct entities in the log table. this allows
-    // us to split up the reduction into many threads without loading all operations
-    // into memory
-    let total_entities = pager.total()? as usize;
-    let bars = UpdateBars::new(total_entities);
+    update_table::<models::TaxonomicAct, _, _, _>(&pool, "taxonomic_acts", pager, lookups, full, |conn, records| {
+        use diesel::upsert::excluded;
+        use schema::taxonomic_acts::dsl::*;
 
-    info!(total_entities, "Reducing taxonomic acts");
+        // postgres always creates a new row version so we cant get
+        // an actual figure of the amount of records changed
+        diesel::insert_into(taxonomic_acts)
+            .values(records)
+            .on_conflict(entity_id)
+            .do_update()
+            .set((
+                taxon_id.eq(excluded(taxon_id)),
+                accepted_taxon_id.eq(excluded(accepted_taxon_id)),
+                source_url.eq(excluded(source_url)),
+                updated_at.eq(excluded(updated_at)),
+                data_created_at.eq(excluded(data_created_at)),
+                data_updated_at.eq(excluded(data_updated_at)),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })?;
 
-    let reducer: DatabaseReducer<models::TaxonomicAct, _, _> = DatabaseReducer::new(pager, lookups);
-    let mut conn = pool.get()?;
+    // the acts link spelling variants to the correct spelling of their name
+    super::names::link_variants(&pool)?;
 
-    for records in reducer.into_iter() {
-        for chunk in records.chunks(1000) {
-            use diesel::upsert::excluded;
-            use schema::taxonomic_acts::dsl::*;
-
-            let mut valid_records = Vec::new();
-            for record in chunk {
-                match record {
-                    Ok(record) => valid_records.push(record),
-                    Err(err) => error!(?err),
-                }
-            }
+    minting::mint_new_entities(&pool, "taxonomic_acts")?;
+    Ok(())
+}
 
-            // postgres always creates a new row version so we cant get
-            // an actual figure of the amount of records changed
-            diesel::insert_into(taxonomic_acts)
-                .values(valid_records)
-                .on_conflict(entity_id)
-                .do_update()
-                .set((
-                    taxon_id.eq(excluded(taxon_id)),
-                    accepted_taxon_id.eq(excluded(accepted_taxon_id)),
-                    source_url.eq(excluded(source_url)),
-                    updated_at.eq(excluded(updated_at)),
-                    data_created_at.eq(excluded(data_created_at)),
-                    data_updated_at.eq(excluded(data_updated_at)),
-                ))
-                .execute(&mut conn)?;
 
-            bars.records.inc(chunk.len() as u64);
-        }
-    }
+/// Trace the reduction of a single taxonomic act entity without updating the taxonomic acts table
+pub fn explain(mut pool: PgPool, entity_id: &str) -> Result<(), Error> {
+    let datasets = dataset_lookup(&mut pool)?;
+    let dataset_ids: Vec<Uuid> = datasets.values().map(|id| id.clone()).collect();
 
-    bars.finish();
-    info!("Finished reducing and updating taxonomic acts");
+    let lookups = Lookups {
+        datasets,
+        taxa: taxon_lookup(&mut pool, &dataset_ids)?,
+    };
 
-    Ok(())
+    let loader: FrameLoader<TaxonomicActOperation> = FrameLoader::new(pool);
+    crate::reducer::explain::<models::TaxonomicAct, _, _>(&loader, entity_id, &lookups)
 }
 
 
@@ -654,13 +734,7 @@ impl EntityPager for FrameLoader<TaxonomicActOperation> {
     fn total(&self) -> Result<i64, Error> {
         let mut conn = self.pool.get()?;
 
-        let total = {
-            use diesel::dsl::count_distinct;
-            use schema::taxonomic_act_logs::dsl::*;
-            taxonomic_act_logs
-                .select(count_distinct(entity_id))
-                .get_result::<i64>(&mut conn)?
-        };
+        let total = taxonomic_act_entities::table.count().get_result::<i64>(&mut conn)?;
 
         Ok(total)
     }
@@ -672,20 +746,21 @@ impl EntityPager for FrameLoader<TaxonomicActOperation> {
         let limit = 10_000;
         let offset = page as i64 * limit;
 
-        let entity_ids = taxonomic_act_logs
-            .select(entity_id)
-            .group_by(entity_id)
-            .order_by(entity_id)
+        let entity_ids = taxonomic_act_entities::table
+            .select(taxonomic_act_entities::entity_id)
+            .order_by(taxonomic_act_entities::entity_id)
             .offset(offset)
             .limit(limit)
             .into_boxed();
 
+        let no_merge = no_merge_versions(&mut conn)?;
         let operations = taxonomic_act_logs
             .filter(entity_id.eq_any(entity_ids))
+            .filter(dataset_version_id.ne_all(&no_merge))
             .order_by((entity_id, operation_id))
             .load::<TaxonomicActOperation>(&mut conn)?;
 
-        Ok(operations)
+        Ok(precedence::apply(operations))
     }
 }
 
@@ -729,8 +804,6 @@ impl Reducer<Lookups> for models::TaxonomicAct {
             .clone();
 
         let taxon = taxon.ok_or(ReduceError::MissingAtom(frame.entity_id.clone(), "Taxon".to_string()))?;
-        let accepted_taxon =
-            accepted_taxon.ok_or(ReduceError::MissingAtom(frame.entity_id.clone(), "AcceptedTaxon".to_string()))?;
 
         let taxon_key = (dataset_id, taxon.clone());
         let taxon_id = lookups
@@ -739,18 +812,25 @@ impl Reducer<Lookups> for models::TaxonomicAct {
             .ok_or(LookupError::Name(taxon.clone()))?
             .clone();
 
-        let accepted_taxon_key = (dataset_id, accepted_taxon.clone());
-        let accepted_taxon_id = lookups
-            .taxa
-            .get(&accepted_taxon_key)
-            .ok_or(LookupError::Name(accepted_taxon.clone()))?
-            .clone();
+        // accepted names don't have an accepted usage so only look it up when there is one
+        let accepted_taxon_id = match accepted_taxon {
+            Some(accepted_taxon) => {
+                let accepted_taxon_key = (dataset_id, accepted_taxon.clone());
+                let accepted_taxon_id = lookups
+                    .taxa
+                    .get(&accepted_taxon_key)
+                    .ok_or(LookupError::Name(accepted_taxon))?
+                    .clone();
+                Some(accepted_taxon_id)
+            }
+            None => None,
+        };
 
         let record = models::TaxonomicAct {
             id: Uuid::new_v4(),
             entity_id: frame.entity_id,
             taxon_id,
-            accepted_taxon_id: Some(accepted_taxon_id),
+            accepted_taxon_id,
             source_url,
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
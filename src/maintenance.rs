@@ -0,0 +1,625 @@
+use std::collections::BTreeMap;
+
+use arga_core::schema::{
+    dataset_versions,
+    datasets,
+    nomenclatural_act_logs,
+    publication_logs,
+    sequence_logs,
+    specimen_logs,
+    taxa_logs,
+    taxonomic_act_logs,
+};
+use chrono::{Duration, Utc};
+use clap::ValueEnum;
+use diesel::dsl::{exists, not};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::sql_types::{BigInt, Text};
+use diesel::*;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+use crate::loggers::publications;
+
+
+/// Dataset versions younger than this are never collected.
+///
+/// A dataset version is created before any of its operations are inserted, so a version
+/// that is still being imported would otherwise look empty.
+const GRACE_PERIOD_HOURS: i64 = 24;
+
+/// The log tables with atoms that can hold an empty value
+pub const LOG_TABLES: [&str; 6] = [
+    "taxa_logs",
+    "taxonomic_act_logs",
+    "nomenclatural_act_logs",
+    "publication_logs",
+    "specimen_logs",
+    "sequence_logs",
+];
+
+/// Matches atoms whose value is an empty or whitespace only string.
+///
+/// An atom is a single key object with the field name as the key, and json path only
+/// walks the values, so the field name itself is never matched.
+const EMPTY_ATOM: &str = r#"jsonb_path_exists(atom, '$.** ? (@.type() == "string" && @ like_regex "^\\s*$")')"#;
+
+
+/// The amount of legacy rows given an entity id in one statement
+const BACKFILL_CHUNK_SIZE: i64 = 10_000;
+
+
+/// The key that names differing only in case or whitespace share
+pub const NAME_KEY: &str = r#"lower(regexp_replace(btrim(names.scientific_name), '\s+', ' ', 'g'))"#;
+
+/// The DOI of a publication without case or a resolver prefix like `https://doi.org/` or `doi:`
+const DOI_KEY: &str = r#"lower(regexp_replace(btrim(doi), '^(https?://(dx\.)?doi\.org/|doi:)\s*', '', 'i'))"#;
+
+/// The title and year of a publication without case, whitespace or punctuation
+const TITLE_KEY: &str = r#"lower(regexp_replace(title, '[^[:alnum:]]+', '', 'g')) || ':' || published_year"#;
+
+
+#[derive(QueryableByName)]
+struct EmptyAtoms {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+#[derive(QueryableByName)]
+struct RowCount {
+    #[diesel(sql_type = BigInt)]
+    total: i64,
+}
+
+#[derive(QueryableByName)]
+struct NameMerge {
+    #[diesel(sql_type = Text)]
+    duplicate: String,
+    #[diesel(sql_type = Text)]
+    survivor: String,
+}
+
+#[derive(QueryableByName)]
+struct PublicationMerge {
+    #[diesel(sql_type = Text)]
+    duplicate: String,
+    #[diesel(sql_type = Text)]
+    survivor: String,
+    #[diesel(sql_type = Text)]
+    matched_by: String,
+}
+
+#[derive(QueryableByName)]
+struct Reference {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+#[derive(QueryableByName)]
+struct UniqueColumn {
+    #[diesel(sql_type = Text)]
+    index_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+
+/// Find and remove dataset versions that no operation log refers to.
+///
+/// Deduplication means that re-importing a dataset creates a new dataset version that often
+/// contributes no operations at all, leaving behind versions with nothing to attribute. Other
+/// tables can still refer to a version without operations so each version is removed on its
+/// own and any that are still referenced are kept and reported, in the same way as the
+/// datasets. When `include_datasets` is set any dataset left without a version is removed as
+/// well, unless another table still refers to it. With `dry_run` the candidates are only listed.
+pub fn collect_dataset_versions(pool: &PgPool, dry_run: bool, include_datasets: bool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    let cutoff = Utc::now() - Duration::hours(GRACE_PERIOD_HOURS);
+
+    let empty = dataset_versions::table
+        .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+        .filter(dataset_versions::imported_at.lt(cutoff))
+        .filter(not(exists(taxa_logs::table.filter(taxa_logs::dataset_version_id.eq(dataset_versions::id)))))
+        .filter(not(exists(
+            taxonomic_act_logs::table.filter(taxonomic_act_logs::dataset_version_id.eq(dataset_versions::id)),
+        )))
+        .filter(not(exists(
+            nomenclatural_act_logs::table.filter(nomenclatural_act_logs::dataset_version_id.eq(dataset_versions::id)),
+        )))
+        .filter(not(exists(
+            publication_logs::table.filter(publication_logs::dataset_version_id.eq(dataset_versions::id)),
+        )))
+        .filter(not(exists(specimen_logs::table.filter(specimen_logs::dataset_version_id.eq(dataset_versions::id)))))
+        .filter(not(exists(sequence_logs::table.filter(sequence_logs::dataset_version_id.eq(dataset_versions::id)))))
+        .select((dataset_versions::id, datasets::global_id, dataset_versions::version))
+        .order_by((datasets::global_id, dataset_versions::imported_at))
+        .load::<(Uuid, String, String)>(&mut conn)?;
+
+    for (id, dataset, version) in &empty {
+        info!(?id, dataset, version, "Empty dataset version");
+    }
+
+    if dry_run {
+        info!(total = empty.len(), "Dry run, no dataset versions removed");
+    }
+    else {
+        let mut removed = 0;
+        let mut kept = 0;
+        for (id, dataset, version) in &empty {
+            match diesel::delete(dataset_versions::table.filter(dataset_versions::id.eq(id))).execute(&mut conn) {
+                Ok(_) => removed += 1,
+                Err(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                    warn!(?id, dataset, version, "Empty dataset version is still referenced, keeping it");
+                    kept += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        info!(removed, kept, "Removed empty dataset versions");
+    }
+
+    if include_datasets {
+        collect_datasets(&mut conn, dry_run)?;
+    }
+
+    Ok(())
+}
+
+
+/// Remove datasets that have no dataset versions.
+///
+/// Reduced tables such as taxa and specimens also refer to datasets, so each dataset is
+/// removed on its own and any that are still referenced are kept and reported.
+fn collect_datasets(conn: &mut PgConnection, dry_run: bool) -> Result<(), Error> {
+    let empty = datasets::table
+        .filter(not(exists(dataset_versions::table.filter(dataset_versions::dataset_id.eq(datasets::id)))))
+        .select((datasets::id, datasets::global_id))
+        .order_by(datasets::global_id)
+        .load::<(Uuid, String)>(conn)?;
+
+    let mut removed = 0;
+    for (id, dataset) in empty {
+        if dry_run {
+            info!(?id, dataset, "Dataset without versions");
+            continue;
+        }
+
+        match diesel::delete(datasets::table.filter(datasets::id.eq(id))).execute(conn) {
+            Ok(_) => removed += 1,
+            Err(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                warn!(?id, dataset, "Dataset without versions is still referenced, keeping it");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    info!(removed, dry_run, "Finished removing datasets without versions");
+    Ok(())
+}
+
+
+/// Remove operations that set an atom to an empty string.
+///
+/// Imports used to log blank cells as atoms with an empty value, which show up as changes
+/// between dataset versions and hide the previous value of the field. Blank values are now
+/// skipped when importing, so removing the existing ones makes the logs reduce the same way
+/// a fresh import would. With `dry_run` the operations are only counted.
+pub fn clean_logs(pool: &PgPool, dry_run: bool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+
+    for table in LOG_TABLES {
+        if dry_run {
+            let found = sql_query(format!("SELECT count(*) AS total FROM {table} WHERE {EMPTY_ATOM}"))
+                .get_result::<EmptyAtoms>(&mut conn)?;
+            info!(table, total = found.total, "Empty value operations");
+        }
+        else {
+            let removed = sql_query(format!("DELETE FROM {table} WHERE {EMPTY_ATOM}")).execute(&mut conn)?;
+            info!(table, removed, "Removed empty value operations");
+        }
+    }
+
+    Ok(())
+}
+
+
+/// Merge names that only differ in case or whitespace.
+///
+/// Each group of names sharing a normalized key is merged into the name with the most
+/// taxon links, falling back to the first scientific name in sort order. Every foreign key
+/// that refers to the names table is found in the catalog and repointed to the surviving
+/// name before the duplicates are removed, all within one transaction. Rows that would break
+/// a unique key of their table once repointed are removed first, so a taxon linked to both
+/// the duplicate and the survivor keeps the one link. With `dry_run` the merges are only
+/// listed.
+pub fn merge_names(pool: &PgPool, dry_run: bool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+
+    conn.transaction::<_, Error, _>(|conn| {
+        sql_query(format!(
+            "CREATE TEMPORARY TABLE name_merges ON COMMIT DROP AS
+             WITH keyed AS (
+                SELECT names.id, names.scientific_name, {NAME_KEY} AS key,
+                       (SELECT count(*) FROM taxon_names WHERE taxon_names.name_id = names.id) AS links
+                FROM names
+             ),
+             ranked AS (
+                SELECT id, scientific_name, key,
+                       first_value(id) OVER (PARTITION BY key ORDER BY links DESC, scientific_name) AS survivor_id,
+                       count(*) OVER (PARTITION BY key) AS variants
+                FROM keyed
+             )
+             SELECT id AS duplicate_id, survivor_id FROM ranked
+             WHERE variants > 1 AND id <> survivor_id"
+        ))
+        .execute(conn)?;
+
+        let merges = sql_query(
+            "SELECT duplicate.scientific_name AS duplicate, survivor.scientific_name AS survivor
+             FROM name_merges
+             JOIN names duplicate ON duplicate.id = name_merges.duplicate_id
+             JOIN names survivor ON survivor.id = name_merges.survivor_id
+             ORDER BY survivor.scientific_name, duplicate.scientific_name",
+        )
+        .load::<NameMerge>(conn)?;
+
+        for merge in &merges {
+            info!(duplicate = merge.duplicate, survivor = merge.survivor, "Duplicate name");
+        }
+
+        if dry_run {
+            info!(total = merges.len(), "Dry run, no names merged");
+            return Ok(());
+        }
+
+        for reference in references(conn, "names")? {
+            let removed = dedupe_references(conn, "name_merges", &reference)?;
+            let Reference { table_name, column_name } = reference;
+            if removed > 0 {
+                info!(table_name, column_name, removed, "Removed references already held by the surviving names");
+            }
+
+            let repointed = sql_query(format!(
+                "UPDATE \"{table_name}\" SET \"{column_name}\" = name_merges.survivor_id
+                 FROM name_merges
+                 WHERE \"{table_name}\".\"{column_name}\" = name_merges.duplicate_id"
+            ))
+            .execute(conn)?;
+            info!(table_name, column_name, repointed, "Repointed name references");
+        }
+
+        let removed =
+            sql_query("DELETE FROM names USING name_merges WHERE names.id = name_merges.duplicate_id").execute(conn)?;
+        info!(removed, "Merged duplicate names");
+        Ok(())
+    })
+}
+
+
+/// Merge publications imported from more than one source into a single publication.
+///
+/// Publications are matched on their DOI, compared without case or a resolver prefix.
+/// Publications without a DOI fall back to matching on their title, ignoring case and
+/// punctuation, and published year, but only when every publication with that title and
+/// year shares the same DOI so that two editions aren't merged by their title. Each group
+/// is merged into the publication with a DOI that was created first.
+///
+/// The duplicates are recorded in `publication_equivalents` so that updates stop writing
+/// them back from the logs, and every foreign key that refers to the publications table is
+/// repointed to the survivor before the duplicates are removed, all within one transaction.
+/// Rows that would break a unique key of their table once repointed are removed first, in the
+/// same way as when merging names. With `dry_run` the merges are only listed.
+pub fn merge_publications(pool: &PgPool, dry_run: bool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    publications::create_publication_equivalents_table(&mut conn)?;
+
+    conn.transaction::<_, Error, _>(|conn| {
+        sql_query(format!(
+            "CREATE TEMPORARY TABLE publication_merges ON COMMIT DROP AS
+             WITH keyed AS (
+                SELECT id, created_at,
+                       NULLIF({DOI_KEY}, '') AS doi_key,
+                       {TITLE_KEY} AS title_key
+                FROM publications
+             ),
+             by_doi AS (
+                SELECT id, first_value(id) OVER (PARTITION BY doi_key ORDER BY created_at, id) AS survivor_id
+                FROM keyed WHERE doi_key IS NOT NULL
+             ),
+             titles AS (
+                SELECT title_key FROM keyed GROUP BY title_key HAVING count(DISTINCT doi_key) <= 1
+             ),
+             by_title AS (
+                SELECT keyed.id, keyed.doi_key,
+                       first_value(keyed.id) OVER (
+                          PARTITION BY keyed.title_key ORDER BY keyed.doi_key IS NULL, keyed.created_at, keyed.id
+                       ) AS survivor_id
+                FROM keyed JOIN titles ON titles.title_key = keyed.title_key
+             ),
+             merges AS (
+                SELECT id AS duplicate_id, survivor_id, 'doi' AS matched_by FROM by_doi
+                UNION ALL
+                SELECT by_title.id, COALESCE(by_doi.survivor_id, by_title.survivor_id), 'title'
+                FROM by_title LEFT JOIN by_doi ON by_doi.id = by_title.survivor_id
+                WHERE by_title.doi_key IS NULL
+             )
+             SELECT * FROM merges WHERE duplicate_id <> survivor_id"
+        ))
+        .execute(conn)?;
+
+        let merges = sql_query(
+            "SELECT duplicate.title AS duplicate, survivor.title AS survivor, matched_by
+             FROM publication_merges
+             JOIN publications duplicate ON duplicate.id = publication_merges.duplicate_id
+             JOIN publications survivor ON survivor.id = publication_merges.survivor_id
+             ORDER BY survivor.title, duplicate.title",
+        )
+        .load::<PublicationMerge>(conn)?;
+
+        for merge in &merges {
+            info!(
+                duplicate = merge.duplicate,
+                survivor = merge.survivor,
+                matched_by = merge.matched_by,
+                "Duplicate publication"
+            );
+        }
+
+        if dry_run {
+            info!(total = merges.len(), "Dry run, no publications merged");
+            return Ok(());
+        }
+
+        // duplicates that were merged into a publication which is now a duplicate itself follow it
+        let recorded = sql_query(
+            "INSERT INTO publication_equivalents (entity_id, canonical_entity_id)
+             SELECT duplicate.entity_id, survivor.entity_id
+             FROM publication_merges
+             JOIN publications duplicate ON duplicate.id = publication_merges.duplicate_id
+             JOIN publications survivor ON survivor.id = publication_merges.survivor_id
+             ON CONFLICT (entity_id) DO UPDATE SET canonical_entity_id = excluded.canonical_entity_id",
+        )
+        .execute(conn)?;
+        let followed = sql_query(
+            "UPDATE publication_equivalents SET canonical_entity_id = merged.canonical_entity_id
+             FROM publication_equivalents merged
+             WHERE publication_equivalents.canonical_entity_id = merged.entity_id",
+        )
+        .execute(conn)?;
+        info!(recorded, followed, "Recorded publication equivalents");
+
+        for reference in references(conn, "publications")? {
+            let removed = dedupe_references(conn, "publication_merges", &reference)?;
+            let Reference { table_name, column_name } = reference;
+            if removed > 0 {
+                info!(table_name, column_name, removed, "Removed references held by the surviving publications");
+            }
+
+            let repointed = sql_query(format!(
+                "UPDATE \"{table_name}\" SET \"{column_name}\" = publication_merges.survivor_id
+                 FROM publication_merges
+                 WHERE \"{table_name}\".\"{column_name}\" = publication_merges.duplicate_id"
+            ))
+            .execute(conn)?;
+            info!(table_name, column_name, repointed, "Repointed publication references");
+        }
+
+        let removed = sql_query(
+            "DELETE FROM publications USING publication_merges WHERE publications.id = publication_merges.duplicate_id",
+        )
+        .execute(conn)?;
+        info!(removed, "Merged duplicate publications");
+        Ok(())
+    })
+}
+
+
+/// Every column with a foreign key to the table, found in the catalog
+fn references(conn: &mut PgConnection, table: &str) -> Result<Vec<Reference>, Error> {
+    let references = sql_query(
+        "SELECT cl.relname::text AS table_name, att.attname::text AS column_name
+         FROM pg_constraint con
+         JOIN pg_class cl ON cl.oid = con.conrelid
+         JOIN pg_attribute att ON att.attrelid = con.conrelid AND att.attnum = ANY(con.conkey)
+         WHERE con.contype = 'f'
+         AND con.confrelid = $1::regclass
+         ORDER BY table_name, column_name",
+    )
+    .bind::<Text, _>(table)
+    .load::<Reference>(conn)?;
+
+    Ok(references)
+}
+
+
+/// Delete the rows that would break a unique key of their table once the reference is repointed
+/// from the duplicates in the merges table to their survivors.
+///
+/// A row conflicts with another when they have the same values in the rest of a unique key that
+/// includes the referencing column, and both refer to the same survivor once merged. The row
+/// already referring to the survivor is kept, otherwise the first of the duplicates is. Keys
+/// with an expression or a predicate are skipped as their conflicts can't be matched by column.
+fn dedupe_references(conn: &mut PgConnection, merges: &str, reference: &Reference) -> Result<usize, Error> {
+    let Reference { table_name, column_name } = reference;
+
+    let columns = sql_query(
+        "SELECT ic.relname::text AS index_name, att.attname::text AS column_name
+         FROM pg_index i
+         JOIN pg_class ic ON ic.oid = i.indexrelid
+         JOIN pg_attribute att ON att.attrelid = i.indrelid AND att.attnum = ANY(i.indkey)
+         WHERE i.indisunique AND i.indpred IS NULL AND i.indexprs IS NULL
+         AND i.indrelid = $1::regclass
+         ORDER BY index_name, column_name",
+    )
+    .bind::<Text, _>(table_name)
+    .load::<UniqueColumn>(conn)?;
+
+    let mut keys: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for column in columns {
+        keys.entry(column.index_name).or_default().push(column.column_name);
+    }
+
+    let mut removed = 0;
+    for key in keys.values().filter(|key| key.contains(column_name)) {
+        let matched: String = key
+            .iter()
+            .filter(|column| *column != column_name)
+            .map(|column| format!(" AND other.\"{column}\" = t.\"{column}\""))
+            .collect();
+
+        removed += sql_query(format!(
+            "DELETE FROM \"{table_name}\" t
+             USING {merges} m
+             WHERE t.\"{column_name}\" = m.duplicate_id
+             AND EXISTS (
+                SELECT 1 FROM \"{table_name}\" other
+                LEFT JOIN {merges} om ON om.duplicate_id = other.\"{column_name}\"
+                WHERE COALESCE(om.survivor_id, other.\"{column_name}\") = m.survivor_id
+                AND (other.\"{column_name}\" = m.survivor_id OR other.ctid < t.ctid){matched}
+             )"
+        ))
+        .execute(conn)?;
+    }
+
+    Ok(removed)
+}
+
+
+/// A reduced table with rows inserted before the entity model
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LegacyTable {
+    Taxa,
+    Specimens,
+}
+
+impl LegacyTable {
+    fn table_name(&self) -> &'static str {
+        match self {
+            LegacyTable::Taxa => "taxa",
+            LegacyTable::Specimens => "specimens",
+        }
+    }
+
+    /// Every log entity that a legacy row could have been reduced from.
+    ///
+    /// The entity id of a reduced row is the log entity id, which is the hash of the entity
+    /// id in the source record, so it is found by matching the row against the latest value
+    /// of the atoms the source entity id was built from. Taxa are unique to a scientific name
+    /// within a dataset and specimens to their record id.
+    fn candidates(&self) -> &'static str {
+        match self {
+            LegacyTable::Taxa => {
+                "SELECT taxa.id AS row_id, names.entity_id
+                 FROM taxa
+                 JOIN datasets ON datasets.id = taxa.dataset_id
+                 JOIN (
+                    SELECT DISTINCT ON (entity_id) entity_id, atom ->> 'ScientificName' AS scientific_name
+                    FROM taxa_logs WHERE atom ? 'ScientificName'
+                    ORDER BY entity_id, operation_id DESC
+                 ) names ON names.scientific_name = taxa.scientific_name
+                 JOIN (
+                    SELECT DISTINCT ON (entity_id) entity_id, atom ->> 'DatasetId' AS dataset_id
+                    FROM taxa_logs WHERE atom ? 'DatasetId'
+                    ORDER BY entity_id, operation_id DESC
+                 ) logged_datasets ON logged_datasets.entity_id = names.entity_id
+                    AND logged_datasets.dataset_id = datasets.global_id
+                 WHERE taxa.entity_id IS NULL"
+            }
+            LegacyTable::Specimens => {
+                "SELECT specimens.id AS row_id, records.entity_id
+                 FROM specimens
+                 JOIN (
+                    SELECT DISTINCT ON (entity_id) entity_id, atom ->> 'RecordId' AS record_id
+                    FROM specimen_logs WHERE atom ? 'RecordId'
+                    ORDER BY entity_id, operation_id DESC
+                 ) records ON records.record_id = specimens.record_id
+                 WHERE specimens.entity_id IS NULL"
+            }
+        }
+    }
+}
+
+
+/// Give the rows of a reduced table that predate the entity model their entity id.
+///
+/// The entity id of each legacy row is recomputed from the logs and stored in a temporary
+/// table, then written to the reduced table in chunks so that a large backfill doesn't hold
+/// a lock on the whole table. Rows that match more than one entity, and entities that would
+/// end up on more than one row, are left without an entity id and counted so they can be
+/// looked at by hand. With `dry_run` the rows are only counted.
+pub fn backfill_entity_ids(pool: &PgPool, table: LegacyTable, dry_run: bool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    let table_name = table.table_name();
+
+    let legacy = sql_query(format!("SELECT count(*) AS total FROM {table_name} WHERE entity_id IS NULL"))
+        .get_result::<RowCount>(&mut conn)?
+        .total;
+    info!(table = table_name, legacy, "Recomputing entity ids of legacy rows");
+
+    sql_query("DROP TABLE IF EXISTS entity_backfill").execute(&mut conn)?;
+    sql_query(format!(
+        "CREATE TEMPORARY TABLE entity_backfill AS
+         SELECT row_id, min(entity_id) AS entity_id
+         FROM ({}) candidates
+         GROUP BY row_id
+         HAVING count(DISTINCT entity_id) = 1",
+        table.candidates()
+    ))
+    .execute(&mut conn)?;
+
+    // an entity already on a row, or matched by several legacy rows, is a duplicate of that row
+    let duplicates = sql_query(format!(
+        "DELETE FROM entity_backfill
+         WHERE EXISTS (SELECT 1 FROM {table_name} WHERE {table_name}.entity_id = entity_backfill.entity_id)
+         OR entity_id IN (SELECT entity_id FROM entity_backfill GROUP BY entity_id HAVING count(*) > 1)"
+    ))
+    .execute(&mut conn)?;
+
+    let matched = sql_query("SELECT count(*) AS total FROM entity_backfill")
+        .get_result::<RowCount>(&mut conn)?
+        .total;
+
+    info!(
+        table = table_name,
+        matched,
+        duplicates,
+        unmatched = legacy - matched - duplicates as i64,
+        "Recomputed entity ids"
+    );
+
+    if dry_run {
+        sql_query("DROP TABLE entity_backfill").execute(&mut conn)?;
+        info!("Dry run, no entity ids backfilled");
+        return Ok(());
+    }
+
+    let mut total = 0;
+    loop {
+        let updated = sql_query(format!(
+            "WITH chunk AS (
+                DELETE FROM entity_backfill
+                WHERE row_id IN (SELECT row_id FROM entity_backfill LIMIT {BACKFILL_CHUNK_SIZE})
+                RETURNING row_id, entity_id
+             )
+             UPDATE {table_name} SET entity_id = chunk.entity_id
+             FROM chunk
+             WHERE {table_name}.id = chunk.row_id"
+        ))
+        .execute(&mut conn)?;
+
+        if updated == 0 {
+            break;
+        }
+
+        total += updated;
+        info!(table = table_name, total, "Backfilled entity ids");
+    }
+
+    sql_query("DROP TABLE entity_backfill").execute(&mut conn)?;
+    info!(table = table_name, total, "Finished backfilling entity ids");
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use diesel::sql_types::Text;
+use diesel::*;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+/// How many entities are sent to the identifier service in one request
+const MINT_CHUNK_SIZE: i64 = 500;
+
+
+// minted identifiers aren't part of arga_core so they are recorded alongside the reduced
+// tables, keyed by the table and the entity id of the row they were minted for
+diesel::table! {
+    minted_identifiers (table_name, entity_id) {
+        table_name -> Varchar,
+        entity_id -> Varchar,
+        identifier -> Varchar,
+    }
+}
+
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = minted_identifiers)]
+struct MintedIdentifier {
+    table_name: String,
+    entity_id: String,
+    identifier: String,
+}
+
+#[derive(QueryableByName)]
+struct Unminted {
+    #[diesel(sql_type = Text)]
+    entity_id: String,
+}
+
+
+/// A service that registers new entities and hands back an ARGA identifier for each of them
+pub trait IdMinter {
+    /// Mint an identifier for every entity id, returned as a map of entity id to identifier
+    fn mint(&self, table: &str, entity_ids: &[String]) -> Result<HashMap<String, String>, Error>;
+}
+
+
+#[derive(Serialize)]
+struct MintRequest<'a> {
+    kind: &'a str,
+    entity_ids: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct MintResponse {
+    identifiers: HashMap<String, String>,
+}
+
+
+/// An identifier service reached over HTTP.
+///
+/// The entity ids are posted as JSON along with the name of the reduced table as the kind
+/// of entity, and the service responds with an `identifiers` object mapping each entity id
+/// to its minted identifier.
+pub struct HttpMinter {
+    url: String,
+    token: Option<String>,
+}
+
+impl HttpMinter {
+    /// Configure the minter with ID_MINTER_URL and ID_MINTER_TOKEN. Minting is disabled when the url isn't set
+    pub fn from_env() -> Option<HttpMinter> {
+        let url = std::env::var("ID_MINTER_URL").ok().filter(|url| !url.is_empty())?;
+        let token = std::env::var("ID_MINTER_TOKEN").ok().filter(|token| !token.is_empty());
+        Some(HttpMinter { url, token })
+    }
+}
+
+impl IdMinter for HttpMinter {
+    fn mint(&self, table: &str, entity_ids: &[String]) -> Result<HashMap<String, String>, Error> {
+        let mut request = ureq::post(&self.url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+
+        let response = request
+            .send_json(MintRequest {
+                kind: table,
+                entity_ids,
+            })
+            .map_err(|err| Error::Minting(err.to_string()))?;
+
+        let response: MintResponse = response.into_json()?;
+        Ok(response.identifiers)
+    }
+}
+
+
+/// Mint identifiers for the entities of a reduced table that don't have one yet.
+///
+/// This is run at the end of an update so any entity that entered the table during the
+/// update is registered with the identifier service. Nothing is minted when the service
+/// isn't configured.
+pub fn mint_new_entities(pool: &PgPool, table: &'static str) -> Result<(), Error> {
+    match HttpMinter::from_env() {
+        Some(minter) => mint_missing(pool, &minter, table),
+        None => Ok(()),
+    }
+}
+
+
+/// Mint identifiers with the minter for every entity in the table without one.
+///
+/// The identifiers are stored in chunks as they are minted so an update that fails part
+/// way through only mints the remaining entities the next time it runs. A response that
+/// leaves out an entity is treated as an error since the entity would be sent forever.
+pub fn mint_missing<M: IdMinter>(pool: &PgPool, minter: &M, table: &'static str) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+    create_minted_identifiers_table(&mut conn)?;
+
+    info!(table, "Minting identifiers for new entities");
+    let mut total = 0;
+
+    loop {
+        let unminted = sql_query(format!(
+            "SELECT DISTINCT entity_id::text AS entity_id FROM {table}
+             WHERE entity_id IS NOT NULL
+             AND NOT EXISTS (
+                SELECT 1 FROM minted_identifiers
+                WHERE minted_identifiers.table_name = $1
+                AND minted_identifiers.entity_id = {table}.entity_id
+             )
+             ORDER BY entity_id
+             LIMIT {MINT_CHUNK_SIZE}"
+        ))
+        .bind::<Text, _>(table)
+        .load::<Unminted>(&mut conn)?;
+
+        if unminted.is_empty() {
+            break;
+        }
+
+        let entity_ids: Vec<String> = unminted.into_iter().map(|row| row.entity_id).collect();
+        let mut identifiers = minter.mint(table, &entity_ids)?;
+
+        let mut minted = Vec::with_capacity(entity_ids.len());
+        for entity_id in entity_ids {
+            let Some(identifier) = identifiers.remove(&entity_id)
+            else {
+                return Err(Error::Minting(format!("no identifier was minted for {table} entity {entity_id}")));
+            };
+
+            minted.push(MintedIdentifier {
+                table_name: table.to_string(),
+                entity_id,
+                identifier,
+            });
+        }
+
+        // an identifier minted twice by the service violates the unique constraint
+        // rather than being skipped, which would leave the entity unminted forever
+        diesel::insert_into(minted_identifiers::table)
+            .values(&minted)
+            .on_conflict((minted_identifiers::table_name, minted_identifiers::entity_id))
+            .do_nothing()
+            .execute(&mut conn)?;
+
+        total += minted.len();
+    }
+
+    info!(table, total, "Minted identifiers");
+    Ok(())
+}
+
+
+fn create_minted_identifiers_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS minted_identifiers (
+            table_name varchar NOT NULL,
+            entity_id varchar NOT NULL,
+            identifier varchar NOT NULL UNIQUE,
+            PRIMARY KEY (table_name, entity_id)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
@@ -69,7 +69,12 @@ where
 {
     // grab all the entity ids in the chunk because we need to check for existing
     // operations in the database for the operation merge
-    let entity_ids: Vec<&String> = ops.iter().map(|op| op.entity_id()).collect();
+    let mut entity_ids: Vec<&String> = ops.iter().map(|op| op.entity_id()).collect();
+
+    // an entity has many operations in a chunk so we dedup the ids to keep the array
+    // parameter sent to the database as small as possible
+    entity_ids.sort();
+    entity_ids.dedup();
 
     // load the existing operations by looking for the entity ids present in the frame chunk
     // this allows us to group and compare operations in bulk without using all the memory
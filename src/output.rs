@@ -0,0 +1,273 @@
+use std::io::{Stdout, Write};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::determinism::EntityRecord;
+use crate::errors::{Error, ReduceError};
+
+
+/// How many rows are written before flushing the output
+const FLUSH_CHUNK_SIZE: usize = 10_000;
+
+
+/// The explicit column layout of a reduced CSV output.
+///
+/// Serde derives the columns from the order of the struct fields which makes it too easy
+/// to reorder the output when refactoring a record. Each reduced record instead declares
+/// the columns it outputs and the writer places the serialized fields in that order. The
+/// version must be bumped whenever the columns change so that consumers diffing two
+/// snapshots know that a difference in layout was intended.
+pub trait OutputSchema: EntityRecord {
+    const NAME: &'static str;
+    const VERSION: u32;
+    const COLUMNS: &'static [&'static str];
+}
+
+
+/// A CSV writer that enforces the column schema of a reduced output.
+///
+/// Rows are sorted by entity id and written in chunks with a fixed set of quoting rules,
+/// so two reductions of the same logs produce byte identical outputs regardless of the
+/// order the reducer emitted them in.
+pub struct SchemaWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> SchemaWriter<W> {
+    pub fn new(output: W) -> SchemaWriter<W> {
+        let writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .quote_style(csv::QuoteStyle::Necessary)
+            .terminator(csv::Terminator::Any(b'\n'))
+            .from_writer(output);
+
+        SchemaWriter { writer }
+    }
+
+    /// Flush any buffered rows and return the underlying writer
+    pub fn into_inner(self) -> Result<W, Error> {
+        Ok(self.writer.into_inner().map_err(|err| err.into_error())?)
+    }
+
+    pub fn write_all<R: OutputSchema>(&mut self, records: Vec<R>) -> Result<(), Error> {
+        info!(schema = R::NAME, version = R::VERSION, total = records.len(), "Writing reduced output");
+
+        self.writer.write_record(R::COLUMNS)?;
+
+        // the column positions are determined from the first record since every record
+        // of the same type serializes its fields in the same order
+        let positions = match records.first() {
+            Some(record) => column_positions::<R>(&serialize_with_headers(record)?.0)?,
+            None => return Ok(self.writer.flush()?),
+        };
+
+        let mut rows = Vec::with_capacity(records.len());
+        for record in &records {
+            let (_, row) = serialize_with_headers(record)?;
+            let ordered: Vec<String> = positions.iter().map(|idx| row[*idx].to_string()).collect();
+            rows.push((record.entity_id(), ordered));
+        }
+
+        // an entity can produce more than one row so ties are ordered by the row itself
+        rows.sort();
+
+        for chunk in rows.chunks(FLUSH_CHUNK_SIZE) {
+            for (_, row) in chunk {
+                self.writer.write_record(row)?;
+            }
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Serialize a record into its field names and values using the record's serde impl
+fn serialize_with_headers<R: OutputSchema>(record: &R) -> Result<(csv::StringRecord, csv::StringRecord), Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.serialize(record)?;
+    writer.flush()?;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(writer.get_ref().as_slice());
+
+    let headers = reader.headers()?.clone();
+    let row = reader.records().next().transpose()?.unwrap_or_default();
+    Ok((headers, row))
+}
+
+
+/// Map each column in the schema to the position of the serialized field.
+///
+/// A field that isn't in the schema or a column without a field means the record
+/// has changed without updating its schema, which is treated as an error rather than
+/// silently dropping or inventing a column.
+fn column_positions<R: OutputSchema>(headers: &csv::StringRecord) -> Result<Vec<usize>, Error> {
+    if let Some(field) = headers.iter().find(|field| !R::COLUMNS.contains(field)) {
+        return Err(ReduceError::SchemaMismatch(R::NAME.to_string(), field.to_string()).into());
+    }
+
+    let mut positions = Vec::with_capacity(R::COLUMNS.len());
+    for column in R::COLUMNS {
+        match headers.iter().position(|field| field == *column) {
+            Some(idx) => positions.push(idx),
+            None => return Err(ReduceError::SchemaMismatch(R::NAME.to_string(), column.to_string()).into()),
+        }
+    }
+
+    Ok(positions)
+}
+
+
+/// How to print records for a person to read
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum PrintFormat {
+    /// Each record as a block of aligned column names and values
+    Table,
+    /// A pretty printed JSON array of records
+    Json,
+}
+
+
+/// Print records to stdout for inspection rather than as a reduced output
+pub fn print_records<R: Serialize>(records: &[R], format: PrintFormat) -> Result<(), Error> {
+    match format {
+        PrintFormat::Json => {
+            let json = serde_json::to_string_pretty(records).map_err(std::io::Error::other)?;
+            println!("{json}");
+        }
+        PrintFormat::Table => {
+            for record in records {
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.serialize(record)?;
+                writer.flush()?;
+
+                let mut reader = csv::Reader::from_reader(writer.get_ref().as_slice());
+                let headers = reader.headers()?.clone();
+                let row = reader.records().next().transpose()?.unwrap_or_default();
+
+                let width = headers.iter().map(|header| header.len()).max().unwrap_or_default();
+                for (header, value) in headers.iter().zip(row.iter()) {
+                    println!("{header:width$}  {value}");
+                }
+                println!();
+            }
+        }
+    }
+
+    info!(total = records.len(), "Matching records");
+    Ok(())
+}
+
+
+/// How to write an exported record set
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A CSV with the record's column schema
+    Csv,
+    /// A pretty printed JSON array of records in the same order as the CSV
+    Json,
+}
+
+
+/// How to compress a reduced output
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Compression {
+    /// The same compression used for the CSVs in a dataset archive
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+
+/// Stdout, optionally compressed as the output is written.
+///
+/// Encoders only write their trailing frame when finished, so `finish` must be called
+/// once everything is written rather than relying on the encoder being dropped.
+pub enum OutputWriter {
+    Plain(Stdout),
+    Brotli(Box<brotli::CompressorWriter<Stdout>>),
+    Zstd(zstd::Encoder<'static, Stdout>),
+    Gzip(flate2::write::GzEncoder<Stdout>),
+}
+
+impl OutputWriter {
+    pub fn new(compression: Option<Compression>) -> Result<OutputWriter, Error> {
+        let stdout = std::io::stdout();
+
+        Ok(match compression {
+            None => OutputWriter::Plain(stdout),
+            Some(Compression::Brotli) => {
+                OutputWriter::Brotli(Box::new(brotli::CompressorWriter::new(stdout, 4096, 9, 22)))
+            }
+            Some(Compression::Zstd) => OutputWriter::Zstd(zstd::Encoder::new(stdout, 0)?),
+            Some(Compression::Gzip) => {
+                OutputWriter::Gzip(flate2::write::GzEncoder::new(stdout, flate2::Compression::default()))
+            }
+        })
+    }
+
+    /// Write the end of the compressed stream and flush stdout
+    pub fn finish(self) -> Result<(), Error> {
+        let mut stdout = match self {
+            OutputWriter::Plain(stdout) => stdout,
+            OutputWriter::Brotli(writer) => writer.into_inner(),
+            OutputWriter::Zstd(writer) => writer.finish()?,
+            OutputWriter::Gzip(writer) => writer.finish()?,
+        };
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputWriter::Plain(writer) => writer.write(buf),
+            OutputWriter::Brotli(writer) => writer.write(buf),
+            OutputWriter::Zstd(writer) => writer.write(buf),
+            OutputWriter::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(writer) => writer.flush(),
+            OutputWriter::Brotli(writer) => writer.flush(),
+            OutputWriter::Zstd(writer) => writer.flush(),
+            OutputWriter::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+
+/// Write a reduced output to stdout with its column schema
+pub fn write_records<R: OutputSchema>(records: Vec<R>, compression: Option<Compression>) -> Result<(), Error> {
+    let mut writer = SchemaWriter::new(OutputWriter::new(compression)?);
+    writer.write_all(records)?;
+    writer.into_inner()?.finish()
+}
+
+
+/// Write records to stdout sorted by entity id so that two exports can be diffed directly
+pub fn export_records<R: OutputSchema>(
+    mut records: Vec<R>,
+    format: ExportFormat,
+    compression: Option<Compression>,
+) -> Result<(), Error> {
+    match format {
+        ExportFormat::Csv => write_records(records, compression),
+        ExportFormat::Json => {
+            info!(schema = R::NAME, version = R::VERSION, total = records.len(), "Writing export");
+            records.sort_by(|a, b| a.entity_id().cmp(b.entity_id()));
+
+            let mut writer = OutputWriter::new(compression)?;
+            serde_json::to_writer_pretty(&mut writer, &records).map_err(std::io::Error::other)?;
+            writer.write_all(b"\n")?;
+            writer.finish()
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use arga_core::models::{self, LogOperation};
+use arga_core::schema::{dataset_versions, datasets};
+use diesel::sql_types::{Array, Text};
+use diesel::*;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::PgPool;
+use crate::errors::{Error, ParseError};
+
+
+/// The trust rules used by the updates and reduces, set once from the stored rules
+static PRECEDENCE: OnceLock<AtomPrecedence> = OnceLock::new();
+
+
+/// Use these rules for every update run by this process. Only the first call has any effect
+pub fn set_precedence(precedence: AtomPrecedence) {
+    let _ = PRECEDENCE.set(precedence);
+}
+
+
+/// Drop the operations that lose to a more trusted dataset under the rules set with `set_precedence`
+pub fn apply<Op, A>(operations: Vec<Op>) -> Vec<Op>
+where
+    Op: LogOperation<A> + DatasetVersioned,
+    A: Serialize,
+{
+    match PRECEDENCE.get() {
+        Some(precedence) => precedence.apply(operations),
+        None => operations,
+    }
+}
+
+
+/// An operation that knows the dataset version it was logged under
+pub trait DatasetVersioned {
+    fn dataset_version_id(&self) -> &Uuid;
+}
+
+macro_rules! dataset_versioned {
+    ($($operation:ty),*) => {
+        $(impl DatasetVersioned for $operation {
+            fn dataset_version_id(&self) -> &Uuid {
+                &self.dataset_version_id
+            }
+        })*
+    };
+}
+
+dataset_versioned!(models::TaxonOperation, models::TaxonomicActOperation, models::SpecimenOperation);
+
+macro_rules! dataset_versioned_with_dataset {
+    ($($operation:ty),*) => {
+        $(impl DatasetVersioned for $operation {
+            fn dataset_version_id(&self) -> &Uuid {
+                &self.operation.dataset_version_id
+            }
+        })*
+    };
+}
+
+dataset_versioned_with_dataset!(models::TaxonOperationWithDataset, models::TaxonomicActOperationWithDataset);
+
+
+// the rules are stored so that every update and reduce applies the same ones, not
+// only the runs given the rules file
+diesel::table! {
+    atom_trust_rules (position) {
+        position -> Integer,
+        atoms -> Array<Text>,
+        datasets -> Array<Text>,
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+/// The datasets trusted for a set of atoms, from most to least trusted
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    /// The names of the atoms the rule applies to. A `*` matches any part of the name, eg. `Identified*`
+    pub atoms: Vec<String>,
+    /// The global ids of the datasets
+    pub datasets: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, atom: &str) -> bool {
+        self.atoms.iter().any(|pattern| glob_matches(pattern, atom))
+    }
+}
+
+
+/// Field level dataset precedence.
+///
+/// The LWW map picks the latest operation of an atom regardless of where it came from, so the
+/// only way to prefer one dataset for coordinates and another for identifications on the same
+/// entity used to be changing the timestamps. A rule lists the datasets trusted for some atoms
+/// in order. For each atom of an entity only the operations of the most trusted dataset that
+/// logged one are reduced, and the latest of those wins as usual. Datasets that aren't listed
+/// rank below every listed dataset, and atoms that no rule matches are left to the LWW map.
+///
+/// The first rule matching an atom is the one used. The rules are read from a TOML file and
+/// stored with `store_rules`, replacing the previous rules. A file without rules clears them:
+///
+/// ```toml
+/// [[rule]]
+/// atoms = ["Latitude", "Longitude", "CoordinateUncertainty*"]
+/// datasets = ["ARGA:TL:0001000", "ARGA:TL:0001011"]
+/// ```
+#[derive(Debug, Clone)]
+pub struct AtomPrecedence {
+    rules: Vec<Rule>,
+    /// The global id of the dataset each version belongs to
+    versions: HashMap<Uuid, String>,
+}
+
+impl AtomPrecedence {
+    /// Load the stored trust rules. Without any stored rules the operations are left to the LWW map
+    pub fn load(pool: &PgPool) -> Result<AtomPrecedence, Error> {
+        let mut conn = pool.get()?;
+        create_trust_rules_table(&mut conn)?;
+
+        let rules = atom_trust_rules::table
+            .select((atom_trust_rules::atoms, atom_trust_rules::datasets))
+            .order_by(atom_trust_rules::position)
+            .load::<(Vec<String>, Vec<String>)>(&mut conn)?
+            .into_iter()
+            .map(|(atoms, datasets)| Rule { atoms, datasets })
+            .collect::<Vec<Rule>>();
+
+        let versions: HashMap<Uuid, String> = dataset_versions::table
+            .inner_join(datasets::table.on(dataset_versions::dataset_id.eq(datasets::id)))
+            .select((dataset_versions::id, datasets::global_id))
+            .load::<(Uuid, String)>(&mut conn)?
+            .into_iter()
+            .collect();
+
+        for rule in &rules {
+            for dataset in &rule.datasets {
+                if !versions.values().any(|global_id| global_id == dataset) {
+                    warn!(dataset, atoms = ?rule.atoms, "Trust rule lists a dataset that hasn't been imported");
+                }
+            }
+        }
+
+        if !rules.is_empty() {
+            info!(rules = rules.len(), "Loaded atom trust rules");
+        }
+        Ok(AtomPrecedence { rules, versions })
+    }
+
+    /// The position of the operation's dataset in the rule matching the atom. None if no rule matches
+    fn rank(&self, atom: &str, dataset_version_id: &Uuid) -> Option<usize> {
+        let rule = self.rules.iter().find(|rule| rule.matches(atom))?;
+        let dataset = self.versions.get(dataset_version_id);
+        let position = rule.datasets.iter().position(|id| Some(id) == dataset);
+        Some(position.unwrap_or(usize::MAX))
+    }
+
+    /// Keep the operations of each entity atom that come from its most trusted dataset
+    pub fn apply<Op, A>(&self, operations: Vec<Op>) -> Vec<Op>
+    where
+        Op: LogOperation<A> + DatasetVersioned,
+        A: Serialize,
+    {
+        let ranked: Vec<Option<(String, usize)>> = operations
+            .iter()
+            .map(|op| {
+                let atom = atom_name(op.atom())?;
+                let rank = self.rank(&atom, op.dataset_version_id())?;
+                Some((atom, rank))
+            })
+            .collect();
+
+        let mut best: HashMap<(&String, &String), usize> = HashMap::new();
+        for (op, ranked) in operations.iter().zip(&ranked) {
+            if let Some((atom, rank)) = ranked {
+                let entry = best.entry((op.entity_id(), atom)).or_insert(*rank);
+                *entry = (*entry).min(*rank);
+            }
+        }
+
+        let keep: Vec<bool> = operations
+            .iter()
+            .zip(&ranked)
+            .map(|(op, ranked)| match ranked {
+                Some((atom, rank)) => best.get(&(op.entity_id(), atom)) == Some(rank),
+                None => true,
+            })
+            .collect();
+
+        operations
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(op, keep)| keep.then_some(op))
+            .collect()
+    }
+}
+
+
+/// Replace the stored trust rules with the rules in a TOML file
+pub fn store_rules(pool: &PgPool, path: &Path) -> Result<(), Error> {
+    use atom_trust_rules::dsl::*;
+
+    let file: RulesFile = toml::from_str(&std::fs::read_to_string(path)?).map_err(ParseError::from)?;
+
+    let rows: Vec<_> = file
+        .rule
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            (
+                position.eq(index as i32),
+                atoms.eq(rule.atoms.clone()),
+                datasets.eq(rule.datasets.clone()),
+            )
+        })
+        .collect();
+
+    let mut conn = pool.get()?;
+    create_trust_rules_table(&mut conn)?;
+
+    conn.transaction(|conn| {
+        diesel::delete(atom_trust_rules).execute(conn)?;
+        diesel::insert_into(atom_trust_rules).values(&rows).execute(conn)?;
+        Ok::<(), Error>(())
+    })?;
+
+    info!(rules = rows.len(), "Atom trust rules stored");
+    Ok(())
+}
+
+
+fn create_trust_rules_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS atom_trust_rules (
+            position integer PRIMARY KEY,
+            atoms text[] NOT NULL,
+            datasets text[] NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+/// The variant name of an atom, which is how atoms are keyed in the log tables
+fn atom_name<A: Serialize>(atom: &A) -> Option<String> {
+    match serde_json::to_value(atom).ok()? {
+        serde_json::Value::String(name) => Some(name),
+        serde_json::Value::Object(map) => map.keys().next().cloned(),
+        _ => None,
+    }
+}
+
+
+/// Match a name against a pattern where `*` matches any amount of characters
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first)
+    else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last()
+    else {
+        // no wildcard so the whole name has to match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use arga_core::crdt::{DataFrame, Version};
+    use arga_core::models::{TaxonAtom, TaxonOperation};
+
+    use super::*;
+
+    #[test]
+    fn glob_matches_wildcards() {
+        let cases = [
+            ("Latitude", "Latitude", true),
+            ("Latitude", "Longitude", false),
+            ("Latitude", "LatitudeAccuracy", false),
+            ("Identified*", "IdentifiedBy", true),
+            ("Identified*", "Identified", true),
+            ("Identified*", "DateIdentified", false),
+            ("*Uncertainty", "CoordinateUncertainty", true),
+            ("*Uncertainty", "UncertaintyMeters", false),
+            ("Coordinate*Meters", "CoordinateUncertaintyMeters", true),
+            ("Coordinate*Meters", "CoordinatePrecision", false),
+            ("*", "Anything", true),
+            ("A*B*C", "AxxBxxC", true),
+            ("A*B*C", "AxxCxxB", false),
+        ];
+
+        for (pattern, name, expected) in cases {
+            assert_eq!(glob_matches(pattern, name), expected, "{pattern} against {name}");
+        }
+    }
+
+    fn operations(entity_id: &str, dataset_version_id: Uuid, atoms: Vec<TaxonAtom>) -> Vec<TaxonOperation> {
+        let mut frame = DataFrame::create(entity_id.to_string(), dataset_version_id, Version::new());
+        for atom in atoms {
+            frame.push(atom);
+        }
+        frame.collect()
+    }
+
+    #[test]
+    fn apply_keeps_the_most_trusted_dataset_of_matching_atoms() {
+        let trusted = Uuid::new_v4();
+        let untrusted = Uuid::new_v4();
+        let unlisted = Uuid::new_v4();
+
+        let precedence = AtomPrecedence {
+            rules: vec![Rule {
+                atoms: vec!["Scientific*".to_string()],
+                datasets: vec!["ARGA:TL:1".to_string(), "ARGA:TL:2".to_string()],
+            }],
+            versions: HashMap::from([
+                (trusted, "ARGA:TL:1".to_string()),
+                (untrusted, "ARGA:TL:2".to_string()),
+                (unlisted, "ARGA:TL:3".to_string()),
+            ]),
+        };
+
+        let mut ops = Vec::new();
+        for (version, name) in [(unlisted, "Unlisted"), (untrusted, "Untrusted"), (trusted, "Trusted")] {
+            ops.extend(operations("entity", version, vec![
+                TaxonAtom::ScientificName(name.to_string()),
+                TaxonAtom::CanonicalName(name.to_string()),
+            ]));
+        }
+        // the trusted dataset didn't log the atom for this entity so the next one down wins
+        ops.extend(operations("other", untrusted, vec![TaxonAtom::ScientificName("Untrusted".to_string())]));
+        ops.extend(operations("other", unlisted, vec![TaxonAtom::ScientificName("Unlisted".to_string())]));
+
+        let kept = precedence.apply(ops);
+        let atoms = |entity_id: &str| -> Vec<TaxonAtom> {
+            kept.iter()
+                .filter(|op| op.entity_id() == entity_id)
+                .map(|op| op.atom().clone())
+                .filter(|atom| matches!(atom, TaxonAtom::ScientificName(_) | TaxonAtom::CanonicalName(_)))
+                .collect()
+        };
+
+        assert_eq!(atoms("entity"), vec![
+            TaxonAtom::CanonicalName("Unlisted".to_string()),
+            TaxonAtom::CanonicalName("Untrusted".to_string()),
+            TaxonAtom::ScientificName("Trusted".to_string()),
+            TaxonAtom::CanonicalName("Trusted".to_string()),
+        ]);
+        assert_eq!(atoms("other"), vec![TaxonAtom::ScientificName("Untrusted".to_string())]);
+    }
+
+    #[test]
+    fn apply_without_rules_keeps_every_operation() {
+        let precedence = AtomPrecedence {
+            rules: vec![],
+            versions: HashMap::new(),
+        };
+
+        let ops = operations("entity", Uuid::new_v4(), vec![
+            TaxonAtom::ScientificName("Aus bus".to_string()),
+            TaxonAtom::CanonicalName("Aus bus".to_string()),
+        ]);
+        let total = ops.len();
+        assert_eq!(precedence.apply(ops).len(), total);
+    }
+}
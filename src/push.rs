@@ -0,0 +1,386 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Instant;
+
+use arga_core::schema;
+use diesel::pg::CopyFormat;
+use diesel::sql_types::Text;
+use diesel::*;
+use tracing::{info, warn};
+
+use crate::database::PgPool;
+use crate::errors::Error;
+use crate::loggers::{collections, names};
+use crate::updates::UpdateStage;
+
+
+/// A table written by an update that can be pushed to the web database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushTable {
+    Sources,
+    Datasets,
+    Names,
+    Taxa,
+    TaxonNames,
+    NameClassifications,
+    TaxonomicActs,
+    Publications,
+    NomenclaturalActs,
+    Specimens,
+}
+
+impl PushTable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PushTable::Sources => "sources",
+            PushTable::Datasets => "datasets",
+            PushTable::Names => "names",
+            PushTable::Taxa => "taxa",
+            PushTable::TaxonNames => "taxon_names",
+            PushTable::NameClassifications => "name_classifications",
+            PushTable::TaxonomicActs => "taxonomic_acts",
+            PushTable::Publications => "publications",
+            PushTable::NomenclaturalActs => "nomenclatural_acts",
+            PushTable::Specimens => "specimens",
+        }
+    }
+
+    /// The columns that identify a row in both databases, which are the same columns the
+    /// local updates upsert on. The ids are generated by each database so they can't be used.
+    ///
+    /// The rows are upserted with `ON CONFLICT` on these columns so the web table needs a
+    /// unique constraint or index on exactly them, which is checked before the table is pushed
+    pub fn natural_key(&self) -> &'static [&'static str] {
+        match self {
+            PushTable::Sources => &["name"],
+            PushTable::Datasets => &["global_id"],
+            PushTable::Names => &["scientific_name"],
+            PushTable::Taxa => &["scientific_name", "dataset_id"],
+            PushTable::TaxonNames => &["taxon_id", "name_id"],
+            PushTable::NameClassifications => &["name_id"],
+            PushTable::TaxonomicActs => &["entity_id"],
+            PushTable::Publications => &["entity_id"],
+            PushTable::NomenclaturalActs => &["entity_id"],
+            PushTable::Specimens => &["entity_id"],
+        }
+    }
+
+    /// Whether the table has a generated `id` column that other rows can refer to
+    pub fn has_id(&self) -> bool {
+        !matches!(self, PushTable::TaxonNames | PushTable::NameClassifications)
+    }
+
+    /// The columns that refer to the ids of other pushed tables. These are mapped to the web ids
+    /// even if the web schema doesn't declare them as foreign keys, since a link table like
+    /// taxon_names is nothing but those ids
+    pub fn references(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            PushTable::TaxonNames => &[("taxon_id", "taxa"), ("name_id", "names")],
+            _ => &[],
+        }
+    }
+
+    /// The tables an update stage writes, in the order they have to be pushed for their foreign keys
+    pub fn written_by(stage: &UpdateStage) -> Vec<PushTable> {
+        match stage {
+            UpdateStage::Taxa => vec![
+                PushTable::Names,
+                PushTable::Taxa,
+                PushTable::TaxonNames,
+                PushTable::NameClassifications,
+            ],
+            UpdateStage::TaxonomicActs => vec![PushTable::TaxonomicActs],
+            UpdateStage::Publications => vec![PushTable::Publications],
+            UpdateStage::NomenclaturalActs => vec![PushTable::NomenclaturalActs],
+            UpdateStage::Collections => vec![PushTable::Specimens],
+            UpdateStage::Custom(_) => vec![],
+        }
+    }
+}
+
+
+#[derive(QueryableByName)]
+struct ColumnName {
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+#[derive(QueryableByName)]
+struct IndexColumn {
+    #[diesel(sql_type = Text)]
+    index_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+#[derive(QueryableByName)]
+struct ForeignKey {
+    #[diesel(sql_type = Text)]
+    column_name: String,
+    #[diesel(sql_type = Text)]
+    foreign_table: String,
+}
+
+
+/// Stream every row of a table from the local database into the same table of the web database.
+///
+/// Diesel only copies into tables it has a definition for, so the rows are copied into a temporary
+/// table with the same name, which shadows the real table as postgres searches pg_temp first.
+macro_rules! copy_rows {
+    ($local:expr, $web:expr, $($table:ident)::+) => {{
+        let rows = diesel::copy_to($($table)::+::table)
+            .with_format(CopyFormat::Binary)
+            .load_raw($local)?;
+        let rows = RefCell::new(rows);
+
+        diesel::copy_from($($table)::+::table)
+            .from_raw_data($($table)::+::table, |copy| {
+                std::io::copy(&mut *rows.borrow_mut(), copy)?;
+                Ok::<_, Error>(())
+            })
+            .with_format(CopyFormat::Binary)
+            .execute($web)?
+    }};
+}
+
+
+/// Copy the tables written by the update stages into the ARGA web database.
+///
+/// The web app otherwise imports the reduced CSVs by hand. Each table is streamed with COPY
+/// into a staging table and upserted on its natural key within a single transaction, so the
+/// web app sees either all of the table's changes or none of them. Rows deleted from the local
+/// table are left in the web database. Custom updates don't declare the tables they write and
+/// aren't pushed.
+///
+/// The two databases generate their own ids for the same rows, so the local ids of a pushed
+/// row are swapped for the web ids of the row with the same natural key, and so are the foreign
+/// keys pointing at tables pushed earlier. The sources and datasets are pushed first for this.
+pub fn push(pool: &PgPool, web_url: &str, stages: &[UpdateStage]) -> Result<(), Error> {
+    let mut local = pool.get()?;
+    let mut web = PgConnection::establish(web_url)?;
+
+    // the local id of every pushed row and the id it has in the web database. it is kept for the
+    // whole session so that the foreign keys of later tables can be mapped to the web ids
+    sql_query(
+        "CREATE TEMPORARY TABLE push_ids (
+            table_name text NOT NULL,
+            local_id uuid NOT NULL,
+            web_id uuid NOT NULL,
+            PRIMARY KEY (table_name, local_id)
+        )",
+    )
+    .execute(&mut web)?;
+
+    let mut tables = Vec::new();
+    for stage in UpdateStage::all() {
+        if !stages.contains(&stage) {
+            continue;
+        }
+        if let UpdateStage::Custom(name) = stage {
+            warn!(name, "Custom updates aren't pushed to the web database");
+        }
+        tables.extend(PushTable::written_by(&stage));
+    }
+    if !tables.is_empty() {
+        tables.splice(0..0, [PushTable::Sources, PushTable::Datasets]);
+    }
+
+    for table in tables {
+        let started = Instant::now();
+        let rows = web.transaction(|web| push_table(&mut local, web, table))?;
+        info!(table = table.name(), rows, elapsed = ?started.elapsed(), "Pushed table to the web database");
+    }
+
+    Ok(())
+}
+
+
+fn push_table(local: &mut PgConnection, web: &mut PgConnection, table: PushTable) -> Result<usize, Error> {
+    let name = table.name();
+
+    // the classifications are a side table of the oplogger so it might not exist yet in either database
+    if table == PushTable::NameClassifications {
+        names::create_classifications_table(local)?;
+        names::create_classifications_table(web)?;
+    }
+
+    check_natural_key(web, table)?;
+
+    sql_query(format!("CREATE TEMPORARY TABLE {name} (LIKE public.{name} INCLUDING DEFAULTS) ON COMMIT DROP"))
+        .execute(web)?;
+
+    let copied = match table {
+        PushTable::Sources => copy_rows!(local, web, schema::sources),
+        PushTable::Datasets => copy_rows!(local, web, schema::datasets),
+        PushTable::Names => copy_rows!(local, web, schema::names),
+        PushTable::Taxa => copy_rows!(local, web, schema::taxa),
+        PushTable::TaxonNames => copy_rows!(local, web, schema::taxon_names),
+        PushTable::NameClassifications => copy_rows!(local, web, names::name_classifications),
+        PushTable::TaxonomicActs => copy_rows!(local, web, schema::taxonomic_acts),
+        PushTable::Publications => copy_rows!(local, web, schema::publications),
+        PushTable::NomenclaturalActs => copy_rows!(local, web, schema::nomenclatural_acts),
+        PushTable::Specimens => copy_rows!(local, web, schema::specimens),
+    };
+
+    // the specimens table keeps the original coordinates so the sensitive ones are generalized
+    // before they reach the web database
+    if table == PushTable::Specimens {
+        generalize_specimens(local, web)?;
+    }
+
+    let mut foreign_keys = sql_query(
+        "SELECT kcu.column_name::text AS column_name, ccu.table_name::text AS foreign_table
+         FROM information_schema.table_constraints tc
+         JOIN information_schema.key_column_usage kcu
+           ON kcu.constraint_name = tc.constraint_name AND kcu.table_schema = tc.table_schema
+         JOIN information_schema.constraint_column_usage ccu
+           ON ccu.constraint_name = tc.constraint_name AND ccu.table_schema = tc.table_schema
+         WHERE tc.constraint_type = 'FOREIGN KEY' AND tc.table_schema = 'public' AND tc.table_name = $1",
+    )
+    .bind::<Text, _>(name)
+    .load::<ForeignKey>(web)?;
+
+    // the natural key can include a foreign key, like the dataset of a taxon, so the keys
+    // to other tables are mapped before the rows are matched with the web rows
+    for (column, foreign_table) in table.references() {
+        if !foreign_keys.iter().any(|key| key.column_name == *column) {
+            foreign_keys.push(ForeignKey {
+                column_name: column.to_string(),
+                foreign_table: foreign_table.to_string(),
+            });
+        }
+    }
+
+    let (own, others): (Vec<ForeignKey>, Vec<ForeignKey>) =
+        foreign_keys.into_iter().partition(|key| key.foreign_table == name);
+    for key in &others {
+        map_ids(web, name, &key.column_name, &key.foreign_table)?;
+    }
+
+    let matched = table
+        .natural_key()
+        .iter()
+        .map(|column| format!("s.\"{column}\" IS NOT DISTINCT FROM t.\"{column}\""))
+        .collect::<Vec<String>>()
+        .join(" AND ");
+
+    // name_classifications is keyed by its name so it has no id of its own to map
+    if table.has_id() {
+        sql_query(format!(
+            "INSERT INTO push_ids
+             SELECT '{name}', s.id, COALESCE(t.id, s.id) FROM pg_temp.{name} s
+             LEFT JOIN public.{name} t ON {matched}
+             ON CONFLICT (table_name, local_id) DO UPDATE SET web_id = EXCLUDED.web_id"
+        ))
+        .execute(web)?;
+
+        for key in &own {
+            map_ids(web, name, &key.column_name, name)?;
+        }
+        map_ids(web, name, "id", name)?;
+    }
+
+    let columns = sql_query(
+        "SELECT column_name::text FROM information_schema.columns
+         WHERE table_schema = 'public' AND table_name = $1 AND column_name <> 'id'
+         ORDER BY ordinal_position",
+    )
+    .bind::<Text, _>(name)
+    .load::<ColumnName>(web)?;
+
+    let key = table.natural_key();
+    let updates: Vec<String> = columns
+        .iter()
+        .filter(|column| !key.contains(&column.column_name.as_str()))
+        .map(|column| format!("\"{0}\" = EXCLUDED.\"{0}\"", column.column_name))
+        .collect();
+
+    // a link table like taxon_names is only its key so there is nothing to update
+    let conflict = match updates.is_empty() {
+        true => "DO NOTHING".to_string(),
+        false => format!("DO UPDATE SET {}", updates.join(", ")),
+    };
+
+    sql_query(format!(
+        "INSERT INTO public.{name} SELECT * FROM pg_temp.{name}
+         ON CONFLICT ({}) {conflict}",
+        key.join(", "),
+    ))
+    .execute(web)?;
+
+    Ok(copied)
+}
+
+
+/// Make sure the web table has a unique constraint or index on exactly the natural key.
+///
+/// Postgres only accepts `ON CONFLICT` on columns that a non-partial unique index covers, and
+/// without one the upsert fails after the whole table has been copied. This catches a web
+/// schema that has drifted from the local one before anything is copied
+fn check_natural_key(web: &mut PgConnection, table: PushTable) -> Result<(), Error> {
+    let name = table.name();
+
+    let columns = sql_query(
+        "SELECT i.indexrelid::regclass::text AS index_name, a.attname::text AS column_name
+         FROM pg_index i
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+         WHERE i.indrelid = ('public.' || $1)::regclass AND i.indisunique AND i.indpred IS NULL",
+    )
+    .bind::<Text, _>(name)
+    .load::<IndexColumn>(web)?;
+
+    let mut indexes: HashMap<String, BTreeSet<String>> = HashMap::new();
+    for column in columns {
+        indexes.entry(column.index_name).or_default().insert(column.column_name);
+    }
+
+    let key: BTreeSet<String> = table.natural_key().iter().map(|column| column.to_string()).collect();
+    if indexes.values().any(|columns| *columns == key) {
+        return Ok(());
+    }
+
+    Err(Error::SchemaDrift(format!(
+        "the web table {name} has no unique constraint on ({}) to push on",
+        table.natural_key().join(", ")
+    )))
+}
+
+
+/// Generalize the coordinates of the copied specimens to the precision recorded for them in
+/// `specimen_generalizations`, in the same way as `sensitivity::generalize`.
+fn generalize_specimens(local: &mut PgConnection, web: &mut PgConnection) -> Result<(), Error> {
+    collections::create_generalizations_table(local)?;
+    sql_query(
+        "CREATE TEMPORARY TABLE specimen_generalizations (
+            entity_id text PRIMARY KEY,
+            precision double precision NOT NULL
+        ) ON COMMIT DROP",
+    )
+    .execute(web)?;
+    copy_rows!(local, web, collections::specimen_generalizations);
+
+    let generalized = sql_query(
+        "UPDATE pg_temp.specimens s
+         SET latitude = round(((floor(s.latitude / g.precision) + 0.5) * g.precision)::numeric, 6)::double precision,
+             longitude = round(((floor(s.longitude / g.precision) + 0.5) * g.precision)::numeric, 6)::double precision
+         FROM pg_temp.specimen_generalizations g
+         WHERE g.entity_id = s.entity_id",
+    )
+    .execute(web)?;
+
+    info!(generalized, "Generalized the coordinates of sensitive specimens");
+    Ok(())
+}
+
+
+/// Swap the local ids in a column of the staging table for the web ids of the rows they refer to.
+/// Ids of rows that weren't pushed are left as they are
+fn map_ids(web: &mut PgConnection, name: &str, column: &str, foreign_table: &str) -> Result<(), Error> {
+    sql_query(format!(
+        "UPDATE pg_temp.{name} s SET \"{column}\" = m.web_id FROM push_ids m
+         WHERE m.table_name = $1 AND m.local_id = s.\"{column}\""
+    ))
+    .bind::<Text, _>(foreign_table)
+    .execute(web)?;
+    Ok(())
+}
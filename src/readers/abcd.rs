@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use tracing::info;
+use uuid::Uuid;
+
+use super::plazi::document::xml_files;
+use super::records::RecordReader;
+use crate::collections::{self, Record};
+use crate::errors::{skip_record, Error, ParseError};
+use crate::readers::mappings::Recorded;
+use crate::utils::str_to_geodetic_datum;
+
+
+/// Import ABCD (Access to Biological Collections Data) XML documents as specimen logs.
+///
+/// The input can either be a single document or a directory of them, such as the paged
+/// responses of a BioCASe provider. Every unit becomes a specimen identified by the ABCD
+/// triple of its source institution, source and unit id.
+pub fn import_all(input: PathBuf, dataset_version: Uuid) -> Result<(), Error> {
+    let pool = crate::database::get_pool()?;
+
+    let files = match input.is_dir() {
+        true => {
+            info!("Enumerating files in '{input:?}'");
+            xml_files(input)?
+        }
+        false => vec![input],
+    };
+
+    for (idx, file) in files.iter().enumerate() {
+        info!("Reading file {idx}: {file:?}");
+        let records = read_units(BufReader::new(File::open(file)?))?;
+
+        info!(units = records.len(), "Importing units");
+        let reader = RecordReader::new(records.into_iter(), dataset_version);
+        collections::import_frames(reader, pool.clone())?;
+    }
+
+    info!("Imported {} ABCD documents", files.len());
+    Ok(())
+}
+
+
+/// Read every unit in an ABCD document as a specimen record.
+///
+/// Elements are matched on their local name so that documents work regardless of the
+/// namespace prefix the provider uses, which is typically `abcd` but can be anything.
+/// Units that can't be mapped to a specimen, like those without an identification, are
+/// skipped and reported rather than failing the whole document.
+pub fn read_units<R: BufRead>(reader: R) -> Result<Vec<Record>, Error> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut unit: Option<Unit> = None;
+    let mut records = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                match name.as_str() {
+                    "Unit" => unit = Some(Unit::default()),
+                    "Identification" => {
+                        if let Some(unit) = unit.as_mut() {
+                            unit.identifications.push(Identification::default());
+                        }
+                    }
+                    _ => {}
+                }
+                path.push(name);
+            }
+            Event::End(e) => {
+                path.pop();
+                if e.local_name().as_ref() == b"Unit" {
+                    match unit.take().map(Unit::into_record) {
+                        Some(Ok(record)) => records.push(record),
+                        Some(Err(err)) => skip_record(&err),
+                        None => {}
+                    }
+                }
+            }
+            Event::Text(e) => {
+                if let Some(current) = unit.as_mut() {
+                    let text = e.unescape()?;
+                    // a unit with an unparseable value is dropped along with the rest of its elements
+                    if let Err(err) = current.set(&path, text.trim()) {
+                        skip_record(&err);
+                        unit = None;
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(records)
+}
+
+
+/// The values of a unit block used for a specimen
+#[derive(Debug, Default)]
+struct Unit {
+    source_institution_id: Option<String>,
+    source_id: Option<String>,
+    unit_id: Option<String>,
+    type_status: Option<String>,
+    identifications: Vec<Identification>,
+
+    // gathering block
+    collected_by: Vec<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    spatial_datum: Option<String>,
+}
+
+/// A single identification block of a unit
+#[derive(Debug, Default)]
+struct Identification {
+    preferred: bool,
+    full_name: Option<String>,
+    genus: Option<String>,
+    epithet: Option<String>,
+    infraspecific_epithet: Option<String>,
+    author_team: Option<String>,
+    author_team_parenthesis: Option<String>,
+    identified_by: Vec<String>,
+}
+
+impl Unit {
+    /// Set the value of the element at the end of the path
+    fn set(&mut self, path: &[String], text: &str) -> Result<(), Error> {
+        let Some((element, ancestors)) = path.split_last()
+        else {
+            return Ok(());
+        };
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let parent = ancestors.last().map(String::as_str);
+        let within = |name: &str| ancestors.iter().any(|ancestor| ancestor == name);
+
+        if within("Identification") {
+            if let Some(identification) = self.identifications.last_mut() {
+                identification.set(element, text, within("Identifiers"));
+            }
+            return Ok(());
+        }
+
+        if within("Gathering") {
+            match element.as_str() {
+                "FullName" if within("GatheringAgent") => self.collected_by.push(text.to_string()),
+                "GatheringAgentsText" => self.collected_by.push(text.to_string()),
+                // only the first set of site coordinates is used
+                "LatitudeDecimal" if self.latitude.is_none() => self.latitude = Some(parse_decimal(text)?),
+                "LongitudeDecimal" if self.longitude.is_none() => self.longitude = Some(parse_decimal(text)?),
+                "SpatialDatum" if self.spatial_datum.is_none() => self.spatial_datum = Some(text.to_string()),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match (parent, element.as_str()) {
+            (Some("Unit"), "SourceInstitutionID") => self.source_institution_id = Some(text.to_string()),
+            (Some("Unit"), "SourceID") => self.source_id = Some(text.to_string()),
+            (Some("Unit"), "UnitID") => self.unit_id = Some(text.to_string()),
+            (_, "TypeStatus") if self.type_status.is_none() => self.type_status = Some(text.to_string()),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn into_record(self) -> Result<Record, Error> {
+        let unit_id = self.unit_id.ok_or_else(|| ParseError::NotFound("UnitID".to_string()))?;
+
+        // use the identification flagged as preferred, otherwise the first one listed
+        let mut identifications = self.identifications;
+        let idx = identifications.iter().position(|ident| ident.preferred).unwrap_or(0);
+        if idx >= identifications.len() {
+            return Err(ParseError::NotFound(format!("Identification of unit {unit_id}")).into());
+        }
+        let identification = identifications.swap_remove(idx);
+
+        let authorship = identification.authorship();
+        let canonical_name = identification.canonical_name().or(identification.full_name.clone());
+        let Some(canonical_name) = canonical_name
+        else {
+            return Err(ParseError::NotFound(format!("ScientificName of unit {unit_id}")).into());
+        };
+
+        let scientific_name = match (identification.full_name, &authorship) {
+            (Some(full_name), _) => full_name,
+            (None, Some(authorship)) => format!("{canonical_name} {authorship}"),
+            (None, None) => canonical_name.clone(),
+        };
+
+        let geodetic_datum = match &self.spatial_datum {
+            Some(datum) => str_to_geodetic_datum(datum)?,
+            None => None,
+        };
+
+        // the ABCD triple id is what makes a unit globally unique
+        let triple = [self.source_institution_id.as_ref(), self.source_id.as_ref(), Some(&unit_id)];
+        let entity_id = triple.into_iter().flatten().cloned().collect::<Vec<String>>().join(":");
+
+        Ok(Record {
+            entity_id,
+            record_id: unit_id,
+            scientific_name,
+            canonical_name,
+            scientific_name_authority: authorship,
+            type_status: self.type_status.map(Recorded::Value),
+            institution_name: None,
+            institution_code: self.source_institution_id,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            geodetic_datum,
+            collected_by: self.collected_by,
+            identified_by: identification.identified_by,
+        })
+    }
+}
+
+impl Identification {
+    fn set(&mut self, element: &str, text: &str, within_identifiers: bool) {
+        let value = Some(text.to_string());
+
+        match element {
+            "PreferredFlag" => self.preferred = matches!(text.to_lowercase().as_str(), "true" | "1"),
+            "FullScientificNameString" => self.full_name = value,
+            "GenusOrMonomial" => self.genus = value,
+            // botanical and zoological names atomise the epithets differently
+            "FirstEpithet" | "SpeciesEpithet" => self.epithet = value,
+            "InfraspecificEpithet" | "SubspeciesEpithet" => self.infraspecific_epithet = value,
+            "AuthorTeam" | "AuthorTeamOriginalAndYear" => self.author_team = value,
+            "AuthorTeamParenthesis" | "AuthorTeamParenthesisAndYear" => self.author_team_parenthesis = value,
+            "FullName" | "IdentifiersText" if within_identifiers => self.identified_by.push(text.to_string()),
+            _ => {}
+        }
+    }
+
+    /// The name without authorship built from the atomised name parts
+    fn canonical_name(&self) -> Option<String> {
+        let genus = self.genus.as_ref()?;
+        let parts = [Some(genus), self.epithet.as_ref(), self.infraspecific_epithet.as_ref()];
+        Some(parts.into_iter().flatten().cloned().collect::<Vec<String>>().join(" "))
+    }
+
+    fn authorship(&self) -> Option<String> {
+        match (&self.author_team_parenthesis, &self.author_team) {
+            (Some(parenthesis), Some(team)) => Some(format!("({parenthesis}) {team}")),
+            (Some(parenthesis), None) => Some(format!("({parenthesis})")),
+            (None, Some(team)) => Some(team.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+
+fn parse_decimal(text: &str) -> Result<f64, Error> {
+    text.parse()
+        .map_err(|_| ParseError::InvalidValue(format!("invalid decimal coordinate: {text}")).into())
+}
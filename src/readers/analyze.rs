@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use tracing::{info, warn};
+
+use crate::errors::Error;
+use crate::frames::IntoFrame;
+use crate::readers::mappings::{clear_fields, FieldMappings};
+use crate::readers::xlsx;
+
+
+/// The amount of rows to sample from the start of the file
+const SAMPLE_ROWS: usize = 100_000;
+
+/// The amount of distinct invalid values to show for each column
+const MAX_EXAMPLES: usize = 5;
+
+
+/// The values of a column that couldn't be parsed into the record's type for it
+#[derive(Debug, Default)]
+pub struct ColumnIssue {
+    /// The parser error of the first invalid value, describing what was expected
+    pub error: String,
+    pub invalid_rows: usize,
+    pub examples: BTreeSet<String>,
+}
+
+
+/// Sample a CSV file and report the columns with values that can't be parsed, without importing it
+pub fn analyze_file<T: DeserializeOwned + IntoFrame>(path: &Path, mappings: FieldMappings) -> Result<(), Error> {
+    analyze_csv::<T>(path, mappings)?;
+    Ok(())
+}
+
+
+/// Sample a CSV file and report the columns with values that don't match the record.
+///
+/// Serde stops at the first field that fails to deserialize so a row with more than one
+/// invalid value is only counted against the first of them. That is still enough to find
+/// the columns that would otherwise fail the import one row at a time. Each issue comes
+/// with a suggested field mappings entry that can be filled in to replace the values.
+///
+/// The rows are mapped the same way the importer maps them, so values that the mappings
+/// replace or mark as unknown aren't reported.
+pub fn analyze_csv<T: DeserializeOwned + IntoFrame>(
+    path: &Path,
+    mut mappings: FieldMappings,
+) -> Result<BTreeMap<String, ColumnIssue>, Error> {
+    if xlsx::is_spreadsheet(path) {
+        info!(?path, "Spreadsheets are deserialized in full before importing, skipping analysis");
+        return Ok(BTreeMap::new());
+    }
+
+    mappings.retain_unknowns(T::UNKNOWN_FIELDS);
+    let mut reader = csv::Reader::from_path(path)?;
+    let headers = mappings.headers(reader.headers()?);
+    let mut issues: BTreeMap<String, ColumnIssue> = BTreeMap::new();
+    let mut sampled = 0;
+
+    for row in reader.records().take(SAMPLE_ROWS) {
+        let row = row?;
+        sampled += 1;
+
+        let unknown = mappings.unknown_fields(&headers, &row);
+        let row = clear_fields(&headers, &mappings.apply(&headers, &row), &unknown);
+
+        let err = match row.deserialize::<T>(Some(&headers)) {
+            Ok(_) => continue,
+            Err(err) => err,
+        };
+
+        let (column, value, error) = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => {
+                let field = err.field().map(|idx| idx as usize);
+                let column = field.and_then(|idx| headers.get(idx)).unwrap_or("<row>");
+                let value = field.and_then(|idx| row.get(idx)).unwrap_or_default();
+                (column.to_string(), value.to_string(), err.kind().to_string())
+            }
+            _ => ("<row>".to_string(), String::new(), err.to_string()),
+        };
+
+        let issue = issues.entry(column).or_insert_with(|| ColumnIssue {
+            error,
+            ..Default::default()
+        });
+        issue.invalid_rows += 1;
+        if issue.examples.len() < MAX_EXAMPLES {
+            issue.examples.insert(value);
+        }
+    }
+
+    report(sampled, &issues);
+    Ok(issues)
+}
+
+
+fn report(sampled: usize, issues: &BTreeMap<String, ColumnIssue>) {
+    if issues.is_empty() {
+        info!(sampled, "No invalid values found");
+        return;
+    }
+
+    for (column, issue) in issues {
+        warn!(column, invalid_rows = issue.invalid_rows, error = issue.error, examples = ?issue.examples, "Invalid values");
+    }
+
+    // a field mappings file replaces verbatim values so it is usually the quickest fix
+    let mut suggestion = String::new();
+    for (column, issue) in issues.iter().filter(|(column, _)| column.as_str() != "<row>") {
+        suggestion.push_str(&format!("[{column}]\n"));
+        for example in &issue.examples {
+            // quote the value as a TOML string since the examples can contain anything
+            let key = toml::Value::String(example.clone());
+            suggestion.push_str(&format!("{key} = \"\"\n"));
+        }
+        suggestion.push('\n');
+    }
+
+    info!(sampled, columns = issues.len(), "Finished analysing the file");
+    if !suggestion.is_empty() {
+        println!("# Suggested field mappings, fill in the replacement values\n\n{suggestion}");
+    }
+}
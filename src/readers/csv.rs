@@ -1,12 +1,16 @@
 use std::io::Read;
 
 use arga_core::crdt::{DataFrame, Version};
+use csv::StringRecord;
 use serde::de::DeserializeOwned;
 use uuid::Uuid;
 use xxhash_rust::xxh3::Xxh3;
 
-use crate::errors::Error;
+use crate::errors::{Error, ParseError};
+use crate::frame_digests::{row_digest, FrameDigests};
 use crate::frames::{FrameReader, IntoFrame};
+use crate::readers::mappings::{clear_fields, FieldMappings};
+use crate::watermarks::Watermark;
 
 
 impl<T, R> FrameReader for CsvReader<T, R>
@@ -29,6 +33,10 @@ pub struct CsvReader<T, R: Read> {
     pub total_rows: usize,
     last_version: Version,
     reader: csv::Reader<R>,
+    headers: StringRecord,
+    mappings: FieldMappings,
+    digests: Option<FrameDigests>,
+    watermark: Option<(usize, Watermark)>,
     phantom_record: std::marker::PhantomData<T>,
 }
 
@@ -39,33 +47,120 @@ where
     R: Read,
 {
     pub fn from_reader(reader: R, dataset_version_id: Uuid) -> Result<CsvReader<T, R>, Error> {
+        Self::from_reader_with_mappings(reader, dataset_version_id, FieldMappings::default())
+    }
+
+    /// Create a reader that replaces field values with the dataset specific overrides
+    /// and defaults before deserializing the row into a record
+    pub fn from_reader_with_mappings(
+        reader: R,
+        dataset_version_id: Uuid,
+        mut mappings: FieldMappings,
+    ) -> Result<CsvReader<T, R>, Error> {
+        mappings.retain_unknowns(T::UNKNOWN_FIELDS);
+        let mut reader = csv::Reader::from_reader(reader);
+        let headers = mappings.headers(reader.headers()?);
+
         Ok(CsvReader {
-            reader: csv::Reader::from_reader(reader),
+            reader,
+            headers,
+            mappings,
             total_rows: 0,
             last_version: Version::new(),
             dataset_version_id,
+            digests: None,
+            watermark: None,
             phantom_record: std::marker::PhantomData,
         })
     }
 
+    /// Skip the rows that are unchanged since the last import of the dataset
+    pub fn with_digests(mut self, digests: FrameDigests) -> CsvReader<T, R> {
+        self.digests = Some(digests);
+        self
+    }
+
+    /// Skip the rows older than the watermark of the dataset
+    pub fn with_watermark(mut self, watermark: Watermark) -> Result<CsvReader<T, R>, Error> {
+        let position = self.headers.iter().position(|header| header == watermark.column);
+        match position {
+            Some(position) => self.watermark = Some((position, watermark)),
+            None => return Err(ParseError::MissingColumn(watermark.column).into()),
+        }
+        Ok(self)
+    }
+
     pub fn next_frame(&mut self) -> Option<Result<DataFrame<T::Atom>, Error>> {
-        let row = self.reader.deserialize::<T>().next();
-        match row {
-            Some(Err(err)) => Some(Err(err.into())),
-            Some(Ok(record)) => {
-                // We hash the entity_id to save on storage in the column
-                let mut hasher = Xxh3::new();
-                hasher.update(record.entity_hashable());
-                let hash = hasher.digest().to_string();
-
-                let frame = DataFrame::create(hash, self.dataset_version_id, self.last_version);
-                let frame = record.into_frame(frame);
-                self.last_version = frame.last_version();
-                Some(Ok(frame))
+        loop {
+            let (record, digest, stamp) = match self.next_record()? {
+                Ok(row) => row,
+                Err(err) => return Some(Err(err)),
+            };
+
+            // We hash the entity_id to save on storage in the column
+            let mut hasher = Xxh3::new();
+            hasher.update(record.entity_hashable());
+            let hash = hasher.digest().to_string();
+
+            if let (Some((_, watermark)), Some(stamp)) = (&self.watermark, stamp) {
+                watermark.track(&hash, &stamp);
             }
-            None => None,
+
+            if let (Some(digests), Some(digest)) = (&self.digests, digest) {
+                if digests.unchanged(&hash, digest) {
+                    continue;
+                }
+            }
+
+            let frame = DataFrame::create(hash, self.dataset_version_id, self.last_version);
+            let frame = record.into_frame(frame);
+            self.last_version = frame.last_version();
+            return Some(Ok(frame));
         }
     }
+
+    /// Read the next record, along with the digest of its row when skipping unchanged rows
+    /// and the value of its watermark column when skipping old rows
+    fn next_record(&mut self) -> Option<Result<(T, Option<i64>, Option<String>), Error>> {
+        let mut row = StringRecord::new();
+        let mut unknown = Vec::new();
+        loop {
+            match self.reader.read_record(&mut row) {
+                Err(err) => return Some(Err(err.into())),
+                Ok(false) => return None,
+                Ok(true) => {}
+            }
+
+            // only rebuild the row when there are overrides since it is a hot path. the
+            // watermark is checked after so that its position and value both come from the
+            // mapped headers
+            if !self.mappings.is_empty() {
+                unknown = self.mappings.unknown_fields(&self.headers, &row);
+                row = self.mappings.apply(&self.headers, &row);
+            }
+
+            match &self.watermark {
+                Some((position, watermark)) if !watermark.is_new(row.get(*position).unwrap_or_default()) => continue,
+                _ => break,
+            }
+        }
+
+        let stamp = self.watermark.as_ref().map(|(position, _)| row.get(*position).unwrap_or_default().to_string());
+        let digest = self.digests.as_ref().map(|_| row_digest(&self.headers, &row));
+
+        // the sentinels are part of the digest so that a value becoming unknown is a change,
+        // but they are emptied before deserializing so that typed columns don't have to parse them
+        if !unknown.is_empty() {
+            row = clear_fields(&self.headers, &row, &unknown);
+        }
+        let record = row.deserialize::<T>(Some(&self.headers)).map_err(|err| err.into());
+        Some(record.map(|mut record| {
+            for field in unknown {
+                record.set_unknown(field);
+            }
+            (record, digest, stamp)
+        }))
+    }
 }
 
 impl<T, R> Iterator for CsvReader<T, R>
@@ -0,0 +1,370 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::Write;
+
+use clap::ValueEnum;
+use serde::de::value::SeqDeserializer;
+use serde::de::{DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::{forward_to_deserialize_any, Deserializer, Serialize};
+
+use crate::errors::Error;
+use crate::utils::{
+    ACCESS_RIGHTS_STATUSES,
+    CONTENT_TYPES,
+    DATA_REUSE_STATUSES,
+    NOMENCLATURAL_ACTS,
+    TAXONOMIC_RANKS,
+    TAXONOMIC_STATUSES,
+};
+
+
+/// A value that no column with a vocabulary or a format accepts
+const PROBE_VALUE: &str = "\u{1}describe\u{1}";
+
+
+/// How to write a record schema
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SchemaFormat {
+    /// A markdown table for the published spec
+    Markdown,
+    /// A pretty printed JSON array of columns
+    Json,
+}
+
+
+/// A column of a CSV record as its deserializer expects it
+#[derive(Debug, Serialize)]
+pub struct Column {
+    pub name: String,
+    /// The type the value is parsed as. eg. string, integer, decimal
+    pub kind: &'static str,
+    /// Whether the column has to have a value
+    pub required: bool,
+    /// Whether the value is parsed further and can be rejected, like a date or a vocabulary term
+    pub validated: bool,
+    /// The name of the vocabulary that the accepted values come from
+    pub vocabulary: Option<&'static str>,
+    /// The values that are accepted, matched case insensitively for vocabularies
+    pub accepted: Vec<&'static str>,
+}
+
+
+/// Describe the columns of a CSV record from its deserializer.
+///
+/// Serde has no reflection so the record is deserialized from a probe for each of its fields
+/// instead, which records the type the field asks for and whether it rejects a nonsense value.
+/// A rejecting field is checked against every vocabulary and the one it accepts every term of
+/// is listed. This runs the same code as an import, so the description can't drift from what
+/// an import actually accepts.
+pub fn describe_record<T: DeserializeOwned>() -> Vec<Column> {
+    let fields = match T::deserialize(FieldNames) {
+        Err(ProbeError::Fields(fields)) => fields,
+        _ => &[],
+    };
+
+    let vocabularies = vocabularies();
+    let mut columns = Vec::with_capacity(fields.len());
+
+    for field in fields {
+        let (hints, accepts_any) = probe::<T>(field, PROBE_VALUE);
+
+        let (vocabulary, accepted) = match hints.variants {
+            Some(variants) => (None, variants.to_vec()),
+            None if accepts_any => (None, vec![]),
+            None => vocabularies
+                .iter()
+                .find(|(_, terms)| terms.iter().all(|term| probe::<T>(field, term).1))
+                .map(|(name, terms)| (Some(*name), terms.clone()))
+                .unwrap_or_default(),
+        };
+
+        columns.push(Column {
+            name: field.to_string(),
+            kind: hints.kind.unwrap_or("string"),
+            required: !hints.optional,
+            validated: !accepts_any,
+            vocabulary,
+            accepted,
+        });
+    }
+
+    columns
+}
+
+
+pub fn write_schema<W: Write>(columns: &[Column], format: SchemaFormat, mut writer: W) -> Result<(), Error> {
+    match format {
+        SchemaFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, columns).map_err(std::io::Error::other)?;
+            writeln!(writer)?;
+        }
+        SchemaFormat::Markdown => {
+            writeln!(writer, "| Column | Type | Required | Accepted values |")?;
+            writeln!(writer, "| ------ | ---- | -------- | --------------- |")?;
+            for column in columns {
+                let accepted = match (column.vocabulary, column.validated) {
+                    (Some(vocabulary), _) => format!("{vocabulary}: {}", column.accepted.join(", ")),
+                    (None, _) if !column.accepted.is_empty() => column.accepted.join(", "),
+                    (None, true) => "parsed on import".to_string(),
+                    (None, false) => String::new(),
+                };
+                let required = if column.required { "yes" } else { "no" };
+                writeln!(writer, "| {} | {} | {required} | {accepted} |", column.name, column.kind)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// The vocabularies that a column can be restricted to
+fn vocabularies() -> Vec<(&'static str, Vec<&'static str>)> {
+    vec![
+        ("taxonomic rank", terms(TAXONOMIC_RANKS)),
+        ("taxonomic status", terms(TAXONOMIC_STATUSES)),
+        ("nomenclatural act", terms(NOMENCLATURAL_ACTS)),
+        ("data reuse status", terms(DATA_REUSE_STATUSES)),
+        ("access rights status", terms(ACCESS_RIGHTS_STATUSES)),
+        ("content type", terms(CONTENT_TYPES)),
+    ]
+}
+
+fn terms<T>(table: &[(&'static str, T)]) -> Vec<&'static str> {
+    table.iter().map(|(term, _)| *term).filter(|term| !term.is_empty()).collect()
+}
+
+
+/// Deserialize the record with the value in the field, returning what the field asked for
+/// and whether it accepted the value
+fn probe<T: DeserializeOwned>(field: &'static str, value: &str) -> (Hints, bool) {
+    let hints = RefCell::new(Hints::default());
+    let result = T::deserialize(FieldProbe {
+        field,
+        value: ValueProbe { value, hints: &hints },
+    });
+
+    // the other fields are missing so a record is only deserialized when none are required
+    let accepted = matches!(result, Ok(_) | Err(ProbeError::MissingField));
+    (hints.into_inner(), accepted)
+}
+
+
+#[derive(Debug)]
+enum ProbeError {
+    /// The field names of the struct, returned instead of deserializing it
+    Fields(&'static [&'static str]),
+    MissingField,
+    Rejected(String),
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::Fields(fields) => write!(f, "struct with fields {fields:?}"),
+            ProbeError::MissingField => write!(f, "missing field"),
+            ProbeError::Rejected(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl serde::de::Error for ProbeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        ProbeError::Rejected(msg.to_string())
+    }
+
+    fn missing_field(_field: &'static str) -> Self {
+        ProbeError::MissingField
+    }
+}
+
+
+/// What a field asked the deserializer for
+#[derive(Debug, Default)]
+struct Hints {
+    kind: Option<&'static str>,
+    optional: bool,
+    variants: Option<&'static [&'static str]>,
+}
+
+
+/// Captures the field names of a struct without deserializing it
+struct FieldNames;
+
+impl<'de> Deserializer<'de> for FieldNames {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeError> {
+        Err(ProbeError::Rejected("records have to be a struct".to_string()))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        Err(ProbeError::Fields(fields))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+
+/// Deserializes a struct with a single field set to the probe value
+struct FieldProbe<'a> {
+    field: &'static str,
+    value: ValueProbe<'a>,
+}
+
+impl<'de> Deserializer<'de> for FieldProbe<'_> {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeError> {
+        Err(ProbeError::Rejected("records have to be a struct".to_string()))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        visitor.visit_map(SingleField {
+            field: self.field,
+            value: Some(self.value),
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+
+struct SingleField<'a> {
+    field: &'static str,
+    value: Option<ValueProbe<'a>>,
+}
+
+impl<'de> MapAccess<'de> for SingleField<'_> {
+    type Error = ProbeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, ProbeError> {
+        match self.value {
+            Some(_) => seed.deserialize(self.field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, ProbeError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(ProbeError::Rejected("value without a key".to_string())),
+        }
+    }
+}
+
+
+/// Hands a field whatever type it asks for, recording the first type asked for
+#[derive(Clone, Copy)]
+struct ValueProbe<'a> {
+    value: &'a str,
+    hints: &'a RefCell<Hints>,
+}
+
+impl ValueProbe<'_> {
+    fn hint(&self, kind: &'static str) {
+        let mut hints = self.hints.borrow_mut();
+        if hints.kind.is_none() {
+            hints.kind = Some(kind);
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for ValueProbe<'_> {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hint("string");
+        visitor.visit_str(self.value)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hint("boolean");
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hint("integer");
+        visitor.visit_i64(0)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hint("decimal");
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hints.borrow_mut().optional = true;
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeError> {
+        self.hint("list");
+        visitor.visit_seq(SeqDeserializer::new(std::iter::empty::<&str>()))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ProbeError> {
+        self.hint("string");
+        self.hints.borrow_mut().variants = Some(variants);
+        visitor.visit_enum(self.value.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct tuple tuple_struct
+        map struct identifier ignored_any
+    }
+}
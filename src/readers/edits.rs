@@ -0,0 +1,70 @@
+use arga_core::crdt::{DataFrame, Version};
+use uuid::Uuid;
+
+use crate::errors::Error;
+use crate::frames::FrameReader;
+use crate::utils::FrameImportBars;
+use crate::FrameProgress;
+
+
+/// A reader that converts edits of existing entities into frames.
+///
+/// Unlike the `RecordReader` the entity ids are the ones already in the logs rather than
+/// a value to hash, so an edit becomes a frame of the same entity holding only the atoms
+/// that changed. Each edit is considered a separate frame.
+pub struct EditReader<A, I> {
+    pub dataset_version_id: Uuid,
+    last_version: Version,
+    edits: I,
+    bars: FrameImportBars,
+    phantom_atom: std::marker::PhantomData<A>,
+}
+
+impl<A, I> EditReader<A, I>
+where
+    A: Default + Clone + ToString + PartialEq,
+    I: Iterator<Item = (String, Vec<A>)>,
+{
+    pub fn new(edits: I, dataset_version_id: Uuid) -> EditReader<A, I> {
+        EditReader {
+            dataset_version_id,
+            last_version: Version::new(),
+            edits,
+            bars: FrameImportBars::new(0),
+            phantom_atom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn next_frame(&mut self) -> Option<DataFrame<A>> {
+        let (entity_id, atoms) = self.edits.next()?;
+
+        let mut frame = DataFrame::create(entity_id, self.dataset_version_id, self.last_version);
+        for atom in atoms {
+            frame.push(atom);
+        }
+        self.last_version = frame.last_version();
+        Some(frame)
+    }
+}
+
+impl<A, I> FrameReader for EditReader<A, I> {
+    type Atom = A;
+}
+
+impl<A, I> Iterator for EditReader<A, I>
+where
+    A: Default + Clone + ToString + PartialEq,
+    I: Iterator<Item = (String, Vec<A>)>,
+{
+    type Item = Result<DataFrame<A>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().map(Ok)
+    }
+}
+
+impl<A, I> FrameProgress for EditReader<A, I> {
+    fn bars(&self) -> FrameImportBars {
+        self.bars.clone()
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use tracing::info;
+
+use crate::errors::Error;
+
+
+/// A single institution in a registry CSV file.
+///
+/// The code is the normalized institution code as it appears in registries like GRSciColl
+/// or Index Herbariorum. Alternatives are a pipe separated list of other codes and names
+/// that providers use for the same institution. eg. `AMS|Australian Museum`
+#[derive(Debug, Clone, Deserialize)]
+struct Record {
+    code: String,
+    name: String,
+    alternatives: Option<String>,
+}
+
+
+/// A registry of institution codes used to normalize the verbatim codes found in datasets.
+///
+/// Matching is case insensitive and ignores surrounding whitespace. The code, the name, and
+/// all alternatives of an institution will resolve to the normalized code.
+#[derive(Debug, Clone, Default)]
+pub struct InstitutionRegistry {
+    codes: HashMap<String, String>,
+}
+
+impl InstitutionRegistry {
+    pub fn from_path(path: &PathBuf) -> Result<InstitutionRegistry, Error> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let mut codes = HashMap::new();
+
+        for row in reader.deserialize() {
+            let record: Record = row?;
+            let code = record.code.trim().to_string();
+
+            codes.insert(normalize_key(&record.code), code.clone());
+            codes.insert(normalize_key(&record.name), code.clone());
+
+            for alternative in record.alternatives.unwrap_or_default().split('|') {
+                if !alternative.trim().is_empty() {
+                    codes.insert(normalize_key(alternative), code.clone());
+                }
+            }
+        }
+
+        info!(total = codes.len(), "Institution registry loaded");
+        Ok(InstitutionRegistry { codes })
+    }
+
+    /// Get the normalized code for a verbatim institution code or name
+    pub fn normalize(&self, value: &str) -> Option<&String> {
+        self.codes.get(&normalize_key(value))
+    }
+}
+
+
+fn normalize_key(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+
+use csv::StringRecord;
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::warn;
+
+
+/// A free-text value that was either provided or recorded by the provider as unknown.
+///
+/// An unknown value is distinct from a value that wasn't provided, which is left out of the
+/// record altogether. Atoms only hold text so an unknown value is logged as `[unknown]` and
+/// read back into `Unknown` when the logs are reduced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recorded {
+    Value(String),
+    Unknown,
+}
+
+impl Recorded {
+    const UNKNOWN: &'static str = "[unknown]";
+}
+
+impl From<String> for Recorded {
+    fn from(value: String) -> Self {
+        match value == Recorded::UNKNOWN {
+            true => Recorded::Unknown,
+            false => Recorded::Value(value),
+        }
+    }
+}
+
+impl From<Recorded> for String {
+    fn from(value: Recorded) -> Self {
+        match value {
+            Recorded::Value(value) => value,
+            Recorded::Unknown => Recorded::UNKNOWN.to_string(),
+        }
+    }
+}
+
+impl Serialize for Recorded {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Recorded::Value(value) => serializer.serialize_str(value),
+            Recorded::Unknown => serializer.serialize_str(Recorded::UNKNOWN),
+        }
+    }
+}
+
+
+/// Per-dataset overrides for field values.
+///
+/// Some providers use their own codes for values that we otherwise parse into enums, such
+/// as "AoU" for an accepted taxonomic status. Rather than adding these to the global parsers
+/// in utils a dataset can include a mapping file that replaces the verbatim value with one
+/// that the parsers understand. The file is keyed by the CSV column name and then by the
+/// verbatim value, for example:
+///
+/// ```toml
+/// [taxonomic_status]
+/// AoU = "accepted"
+///
+/// [taxon_rank]
+/// sp = "species"
+/// ```
+///
+/// Only exact matches are replaced, every other value is passed through untouched.
+///
+/// The mappings can also carry dataset level defaults for columns that are constant across
+/// the whole dataset and are therefore left out of the CSV. A default is added as a column
+/// when the file doesn't have it and fills in empty values when it does, so an explicit
+/// value in a row always takes precedence.
+///
+/// Some providers distinguish a value that was measured but is unknown from one that wasn't
+/// provided at all. The sentinel values they use for the former can be configured per column
+/// and are emptied with `clear_fields` before the row is deserialized, leaving the record to
+/// mark the field as `Recorded::Unknown`. Sentinels are matched ignoring case and surrounding whitespace, and
+/// only apply to the columns listed in the `UNKNOWN_FIELDS` of the record.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct FieldMappings {
+    values: HashMap<String, HashMap<String, String>>,
+    #[serde(skip)]
+    defaults: HashMap<String, String>,
+    #[serde(skip)]
+    unknowns: HashMap<String, HashSet<String>>,
+}
+
+impl FieldMappings {
+    /// Use the column defaults when applying the mappings
+    pub fn with_defaults(mut self, defaults: HashMap<String, String>) -> FieldMappings {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Treat the sentinel values of a column as unknown when applying the mappings
+    pub fn with_unknowns(mut self, unknowns: HashMap<String, Vec<String>>) -> FieldMappings {
+        self.unknowns = unknowns
+            .into_iter()
+            .map(|(field, sentinels)| (field, sentinels.iter().map(|value| normalize_sentinel(value)).collect()))
+            .collect();
+        self
+    }
+
+    /// Stop treating the sentinels of the columns that the record can't mark as unknown as
+    /// unknown, which leaves them to be imported as is
+    pub fn retain_unknowns(&mut self, fields: &[&str]) {
+        self.unknowns.retain(|field, _| {
+            let supported = fields.contains(&field.as_str());
+            if !supported {
+                warn!(field, "The column can't be recorded as unknown, importing its sentinel values as is");
+            }
+            supported
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.defaults.is_empty() && self.unknowns.is_empty()
+    }
+
+    /// Returns true if the value is a sentinel for an unknown value of the field
+    pub fn is_unknown(&self, field: &str, value: &str) -> bool {
+        self.unknowns
+            .get(field)
+            .is_some_and(|sentinels| sentinels.contains(&normalize_sentinel(value)))
+    }
+
+    /// The columns of a CSV row that hold a sentinel for an unknown value.
+    ///
+    /// This is checked before the mappings are applied so that a sentinel can't be mapped away.
+    pub fn unknown_fields<'a>(&self, headers: &'a StringRecord, row: &StringRecord) -> Vec<&'a str> {
+        if self.unknowns.is_empty() {
+            return Vec::new();
+        }
+
+        headers
+            .iter()
+            .zip(row.iter())
+            .filter(|(field, value)| self.is_unknown(field, value))
+            .map(|(field, _)| field)
+            .collect()
+    }
+
+    /// Get the mapped value for a field if there is an override for it
+    pub fn get(&self, field: &str, value: &str) -> Option<&String> {
+        self.values.get(field).and_then(|values| values.get(value))
+    }
+
+    /// The headers of a CSV file with a column added for every default that it is missing.
+    ///
+    /// The added columns are sorted by name so that the order doesn't depend on the
+    /// order of the defaults in the hash map.
+    pub fn headers(&self, headers: &StringRecord) -> StringRecord {
+        let mut missing: Vec<&String> = self
+            .defaults
+            .keys()
+            .filter(|field| !headers.iter().any(|header| header == field.as_str()))
+            .collect();
+        missing.sort();
+
+        let mut headers = headers.clone();
+        for field in missing {
+            headers.push_field(field);
+        }
+        headers
+    }
+
+    /// Apply the overrides to a CSV row, returning a new row with the mapped values.
+    ///
+    /// The headers must be the ones returned by `headers` so that the row is extended
+    /// with the default values of the missing columns.
+    pub fn apply(&self, headers: &StringRecord, row: &StringRecord) -> StringRecord {
+        let values = row.iter().map(Some).chain(std::iter::repeat(None));
+
+        headers
+            .iter()
+            .zip(values)
+            .map(|(field, value)| match value {
+                Some(value) if !value.is_empty() || !self.defaults.contains_key(field) => {
+                    self.get(field, value).map(|v| v.as_str()).unwrap_or(value)
+                }
+                _ => self.default_value(field).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// The default value of a field, mapped like any other value
+    fn default_value(&self, field: &str) -> Option<&str> {
+        let value = self.defaults.get(field)?;
+        Some(self.get(field, value).unwrap_or(value))
+    }
+}
+
+
+/// Empty the cells of the fields in a CSV row
+pub fn clear_fields(headers: &StringRecord, row: &StringRecord, fields: &[&str]) -> StringRecord {
+    headers
+        .iter()
+        .zip(row.iter())
+        .map(|(field, value)| match fields.contains(&field) {
+            true => "",
+            false => value,
+        })
+        .collect()
+}
+
+
+fn normalize_sentinel(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings() -> FieldMappings {
+        let sentinels = vec!["Not Determined".to_string(), "?".to_string()];
+        FieldMappings::default().with_unknowns(HashMap::from([("type_status".to_string(), sentinels)]))
+    }
+
+    #[test]
+    fn sentinels_match_ignoring_case_and_whitespace() {
+        let mappings = mappings();
+        assert!(mappings.is_unknown("type_status", "not determined"));
+        assert!(mappings.is_unknown("type_status", " ? "));
+        assert!(!mappings.is_unknown("type_status", "holotype"));
+        assert!(!mappings.is_unknown("institution_code", "?"));
+    }
+
+    #[test]
+    fn unknown_fields_are_cleared_before_deserializing() {
+        let mappings = mappings();
+        let headers = StringRecord::from(vec!["entity_id", "type_status", "institution_code"]);
+        let row = StringRecord::from(vec!["1", "?", "?"]);
+
+        let unknown = mappings.unknown_fields(&headers, &row);
+        assert_eq!(unknown, vec!["type_status"]);
+
+        // the sentinel is kept by the mappings so that it is part of the row digest
+        let row = mappings.apply(&headers, &row);
+        assert_eq!(row.get(1), Some("?"));
+
+        let row = clear_fields(&headers, &row, &unknown);
+        assert_eq!(row, StringRecord::from(vec!["1", "", "?"]));
+    }
+
+    #[test]
+    fn unsupported_unknowns_are_dropped() {
+        let mut mappings = mappings();
+        mappings.retain_unknowns(&["sex"]);
+
+        let headers = StringRecord::from(vec!["type_status"]);
+        let row = StringRecord::from(vec!["?"]);
+        assert!(mappings.unknown_fields(&headers, &row).is_empty());
+        assert!(mappings.is_empty());
+    }
+
+    #[test]
+    fn recorded_values_round_trip_through_the_logs() {
+        assert_eq!(Recorded::from(String::from(Recorded::Unknown)), Recorded::Unknown);
+
+        let value = Recorded::Value("holotype".to_string());
+        assert_eq!(Recorded::from(String::from(value.clone())), value);
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use arga_core::models;
 use chrono::Utc;
 use serde::Deserialize;
@@ -10,6 +12,9 @@ pub struct Meta {
     pub changelog: Changelog,
     pub attribution: Attribution,
     pub collection: Collection,
+    /// Other datasets that must be imported before this one
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -21,6 +26,32 @@ pub struct Dataset {
     /// RFC 3339
     pub published_at: toml::value::Datetime,
     pub url: String,
+    /// The path to a field mappings file within the archive. See `FieldMappings`
+    pub mappings: Option<String>,
+    /// Values for columns that are the same for every row in the dataset, keyed by the
+    /// CSV column name. Values in a row take precedence over these
+    #[serde(default)]
+    pub defaults: HashMap<String, String>,
+    /// Values that mean the column was recorded but is unknown, keyed by the CSV column name.
+    /// These are imported as an explicit unknown value rather than being left out
+    #[serde(default)]
+    pub unknowns: HashMap<String, Vec<String>>,
+    /// The amount of rows in each file as counted by the provider, keyed by the file name
+    /// with or without the compression extension. Used to catch truncated uploads
+    #[serde(default)]
+    pub expected_rows: HashMap<String, u64>,
+}
+
+/// A dataset that an archive relies on, such as the taxonomy its specimens refer to
+#[derive(Debug, Clone, Deserialize)]
+pub struct Dependency {
+    /// The global id of the dataset
+    pub dataset_id: String,
+    /// The oldest version of the dataset that satisfies the dependency. Any version does when not set
+    pub min_version: Option<String>,
+    /// Warn instead of failing the import when the dependency isn't met
+    #[serde(default)]
+    pub optional: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -46,6 +77,16 @@ pub struct Collection {
 }
 
 
+impl Meta {
+    /// The row count declared for a file in the archive, if any
+    pub fn expected_rows(&self, path: &str) -> Option<u64> {
+        let rows = &self.dataset.expected_rows;
+        rows.get(path)
+            .or_else(|| rows.get(path.trim_end_matches(".br")))
+            .copied()
+    }
+}
+
 impl From<Meta> for models::Source {
     fn from(meta: Meta) -> Self {
         models::Source {
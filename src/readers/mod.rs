@@ -1,12 +1,31 @@
 use crate::errors::Error;
 
+pub mod abcd;
+pub mod analyze;
 pub mod csv;
+pub mod describe;
+pub mod edits;
+pub mod institutions;
+pub mod mappings;
 pub mod meta;
 pub mod plazi;
+pub mod records;
+pub mod sensitivity;
+pub mod xlsx;
 
 
 pub trait OperationLoader {
     type Operation;
+
+    /// The log table the operations are stored in. This is used to key the statistics kept
+    /// about imports into the logs so it has to stay the same across releases.
+    const LOG_TABLE: &'static str;
+
+    /// Load all existing operations for the entities.
+    ///
+    /// Implementations should filter with `eq_any` on a slice which diesel binds as a
+    /// single array parameter (`entity_id = ANY($1)`) on postgres, allowing any amount
+    /// of entity ids to be loaded in one round-trip.
     fn load_operations(&self, entity_ids: &[&String]) -> Result<Vec<Self::Operation>, Error>;
     fn upsert_operations(&self, operations: &[Self::Operation]) -> Result<usize, Error>;
 }
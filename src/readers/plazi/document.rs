@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, Read};
 use std::path::PathBuf;
 
 use arga_core::crdt::{DataFrame, Version};
@@ -14,46 +14,111 @@ use xxhash_rust::xxh3::Xxh3;
 use super::parsing::{end_eq, parse_attribute, parse_attribute_opt, start_eq, ParseSection};
 use super::sections::prelude::*;
 use super::sections::treatment::Treatment;
+use crate::database::PgPool;
 use crate::errors::{Error, ParseError};
 use crate::frames::{FrameReader, IntoFrame};
 use crate::utils::FrameImportBars;
 use crate::{nomenclatural_acts, publications, FrameProgress};
 
 
-pub fn import_all(input_dir: PathBuf, dataset_version: Uuid) -> Result<(), Error> {
+/// Import plazi treatment bank XML files.
+///
+/// The input can either be a directory of extracted XML files or a zip or tar archive
+/// of them. Archives are read entry by entry so that the millions of small files in a
+/// plazi dump never have to be extracted onto the filesystem.
+pub fn import_all(input: PathBuf, dataset_version: Uuid) -> Result<(), Error> {
+    let pool = crate::database::get_pool()?;
+
+    if input.is_dir() {
+        return import_dir(input, dataset_version, pool);
+    }
+
+    match input.extension().and_then(|ext| ext.to_str()) {
+        Some("zip") => import_zip(input, dataset_version, pool),
+        Some("tar") => import_tar(input, dataset_version, pool),
+        _ => Err(ParseError::InvalidValue(format!("unsupported plazi input: {input:?}")).into()),
+    }
+}
+
+fn import_dir(input_dir: PathBuf, dataset_version: Uuid, pool: PgPool) -> Result<(), Error> {
     info!("Enumerating files in '{input_dir:?}'");
     let files = xml_files(input_dir)?;
 
-    let pool = crate::database::get_pool()?;
-
     for (idx, file) in files.iter().enumerate() {
         info!("Reading file {idx}: {file:?}");
+        let document = std::fs::read(file)?;
+        import_document(&document, dataset_version, &pool)?;
+    }
 
-        // TODO:
-        // this is not very efficient at all as it results in reading and parsing the file twice
-        // for both types of records. but since the importer logic and utilities are still in flux
-        // it will do for now. ideally we can move away from an abstraction of frames and move
-        // to a 'record + IntoFrame' to enable better chunking at the reader as well as at the
-        // importer stages.
-        {
-            let fh = File::open(file)?;
-            let reader = BufReader::new(fh);
-            let document = DocumentReader::<publications::Record, _>::from_reader(reader, dataset_version)?;
-            publications::import_frames(document, pool.clone())?;
+    info!("Imported {} XML files", files.len());
+    Ok(())
+}
+
+fn import_zip(path: PathBuf, dataset_version: Uuid, pool: PgPool) -> Result<(), Error> {
+    info!("Reading zip archive '{path:?}'");
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut total = 0;
+
+    for idx in 0..archive.len() {
+        let mut entry = archive.by_index(idx)?;
+        if !entry.is_file() || !entry.name().ends_with(".xml") {
+            continue;
         }
-        {
-            let fh = File::open(file)?;
-            let reader = BufReader::new(fh);
-            let document = DocumentReader::<nomenclatural_acts::Record, _>::from_reader(reader, dataset_version)?;
-            nomenclatural_acts::import_frames(document, pool.clone())?;
+
+        info!("Reading file {idx}: {}", entry.name());
+        let mut document = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut document)?;
+        import_document(&document, dataset_version, &pool)?;
+        total += 1;
+    }
+
+    info!("Imported {total} XML files");
+    Ok(())
+}
+
+fn import_tar(path: PathBuf, dataset_version: Uuid, pool: PgPool) -> Result<(), Error> {
+    info!("Reading tar archive '{path:?}'");
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut total = 0;
+
+    for (idx, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if !entry.header().entry_type().is_file() || !name.ends_with(".xml") {
+            continue;
         }
+
+        info!("Reading file {idx}: {name}");
+        let mut document = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut document)?;
+        import_document(&document, dataset_version, &pool)?;
+        total += 1;
     }
 
-    info!("Importing {} XML files", files.len());
+    info!("Imported {total} XML files");
     Ok(())
 }
 
-fn xml_files(base_dir: PathBuf) -> Result<Vec<PathBuf>, Error> {
+fn import_document(document: &[u8], dataset_version: Uuid, pool: &PgPool) -> Result<(), Error> {
+    // TODO:
+    // this is not very efficient at all as it results in parsing the file twice
+    // for both types of records. but since the importer logic and utilities are still in flux
+    // it will do for now. ideally we can move away from an abstraction of frames and move
+    // to a 'record + IntoFrame' to enable better chunking at the reader as well as at the
+    // importer stages.
+    {
+        let document = DocumentReader::<publications::Record, _>::from_reader(document, dataset_version)?;
+        publications::import_frames(document, pool.clone())?;
+    }
+    {
+        let document = DocumentReader::<nomenclatural_acts::Record, _>::from_reader(document, dataset_version)?;
+        nomenclatural_acts::import_frames(document, pool.clone())?;
+    }
+
+    Ok(())
+}
+
+pub fn xml_files(base_dir: PathBuf) -> Result<Vec<PathBuf>, Error> {
     let mut files = vec![];
 
     // walk the base directory by recursively calling this function
@@ -326,9 +391,9 @@ impl TryIntoRecord<nomenclatural_acts::Record> for (DocumentHeader, Treatment) {
 
         let record = nomenclatural_acts::Record {
             entity_id: document.entity_id,
-            publication: document.title,
+            publication: Some(document.title),
             publication_date: Some(document.date_issued),
-            source_url: document.id,
+            source_url: Some(document.id),
             scientific_name: nomenclature.scientific_name,
             canonical_name: nomenclature.canonical_name,
             scientific_name_authorship: nomenclature.scientific_name_authorship,
@@ -0,0 +1,73 @@
+use arga_core::crdt::{DataFrame, Version};
+use uuid::Uuid;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::errors::Error;
+use crate::frames::{FrameReader, IntoFrame};
+use crate::utils::FrameImportBars;
+use crate::FrameProgress;
+
+
+/// A reader that converts records that were built in memory into frames.
+///
+/// This is useful for operation logs that aren't read from a file, such as records
+/// derived from other reduced data. Each record is considered a separate frame.
+pub struct RecordReader<T, I> {
+    pub dataset_version_id: Uuid,
+    last_version: Version,
+    records: I,
+    bars: FrameImportBars,
+    phantom_record: std::marker::PhantomData<T>,
+}
+
+impl<T, I> RecordReader<T, I>
+where
+    T: IntoFrame,
+    I: Iterator<Item = T>,
+{
+    pub fn new(records: I, dataset_version_id: Uuid) -> RecordReader<T, I> {
+        RecordReader {
+            dataset_version_id,
+            last_version: Version::new(),
+            records,
+            bars: FrameImportBars::new(0),
+            phantom_record: std::marker::PhantomData,
+        }
+    }
+
+    pub fn next_frame(&mut self) -> Option<DataFrame<T::Atom>> {
+        let record = self.records.next()?;
+
+        // We hash the entity_id to save on storage in the column
+        let mut hasher = Xxh3::new();
+        hasher.update(record.entity_hashable());
+        let hash = hasher.digest().to_string();
+
+        let frame = DataFrame::create(hash, self.dataset_version_id, self.last_version);
+        let frame = record.into_frame(frame);
+        self.last_version = frame.last_version();
+        Some(frame)
+    }
+}
+
+impl<T: IntoFrame, I> FrameReader for RecordReader<T, I> {
+    type Atom = T::Atom;
+}
+
+impl<T, I> Iterator for RecordReader<T, I>
+where
+    T: IntoFrame,
+    I: Iterator<Item = T>,
+{
+    type Item = Result<DataFrame<T::Atom>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().map(Ok)
+    }
+}
+
+impl<T, I> FrameProgress for RecordReader<T, I> {
+    fn bars(&self) -> FrameImportBars {
+        self.bars.clone()
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use diesel::*;
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::{PgPool, StringMap};
+use crate::errors::Error;
+
+
+// the sensitivity list isn't part of arga_core as only the oplogger generalizes coordinates. it is
+// stored so that every update of the specimens generalizes them, including `update all`
+diesel::table! {
+    sensitive_taxa (name_id) {
+        name_id -> Uuid,
+        precision -> Float8,
+    }
+}
+
+
+/// A single sensitive taxon in a sensitivity list CSV file.
+///
+/// The taxon is either the scientific name or the id of a name in the names table. The
+/// precision is the size of the grid in decimal degrees that coordinates are generalized to.
+/// eg. `0.1` for roughly 10km
+#[derive(Debug, Clone, Deserialize)]
+struct Record {
+    taxon: String,
+    precision: f64,
+}
+
+
+/// A list of taxa whose coordinates must be generalized before they are made public.
+///
+/// The list is resolved against the names table when it's loaded so that specimens can be
+/// matched by their name id without a name lookup for every record. The resolved list is kept
+/// in the `sensitive_taxa` table and replaced whenever a new list is provided.
+#[derive(Debug, Clone, Default)]
+pub struct SensitivityList {
+    precisions: HashMap<Uuid, f64>,
+}
+
+impl SensitivityList {
+    pub fn from_path(path: &PathBuf, names: &StringMap) -> Result<SensitivityList, Error> {
+        let mut reader = csv::Reader::from_path(path)?;
+        let mut precisions = HashMap::new();
+
+        for row in reader.deserialize() {
+            let record: Record = row?;
+            let taxon = record.taxon.trim();
+
+            let name_id = match Uuid::parse_str(taxon) {
+                Ok(uuid) => Some(uuid),
+                Err(_) => names.get(taxon).copied(),
+            };
+
+            match name_id {
+                Some(name_id) if record.precision > 0.0 => {
+                    // a taxon listed twice uses the coarsest precision
+                    let precision = precisions.entry(name_id).or_insert(record.precision);
+                    *precision = f64::max(*precision, record.precision);
+                }
+                Some(_) => warn!(taxon, precision = record.precision, "Invalid generalization precision"),
+                None => warn!(taxon, "Sensitive taxon not found in names"),
+            }
+        }
+
+        info!(total = precisions.len(), "Sensitivity list loaded");
+        Ok(SensitivityList { precisions })
+    }
+
+    /// Load the stored sensitivity list. It is empty if no list was ever stored
+    pub fn load(pool: &PgPool) -> Result<SensitivityList, Error> {
+        let mut conn = pool.get()?;
+        create_sensitive_taxa_table(&mut conn)?;
+
+        let precisions = sensitive_taxa::table
+            .select((sensitive_taxa::name_id, sensitive_taxa::precision))
+            .load::<(Uuid, f64)>(&mut conn)?
+            .into_iter()
+            .collect::<HashMap<Uuid, f64>>();
+
+        if precisions.is_empty() {
+            warn!("No sensitivity list stored, coordinates will not be generalized");
+        }
+        else {
+            info!(total = precisions.len(), "Stored sensitivity list loaded");
+        }
+
+        Ok(SensitivityList { precisions })
+    }
+
+    /// Replace the stored sensitivity list with this one
+    pub fn store(&self, pool: &PgPool) -> Result<(), Error> {
+        use sensitive_taxa::dsl::*;
+
+        let mut conn = pool.get()?;
+        create_sensitive_taxa_table(&mut conn)?;
+
+        let rows: Vec<_> = self
+            .precisions
+            .iter()
+            .map(|(taxon, generalized)| (name_id.eq(*taxon), precision.eq(*generalized)))
+            .collect();
+
+        conn.transaction(|conn| {
+            diesel::delete(sensitive_taxa).execute(conn)?;
+            for chunk in rows.chunks(10_000) {
+                diesel::insert_into(sensitive_taxa).values(chunk).execute(conn)?;
+            }
+            Ok::<(), Error>(())
+        })?;
+
+        info!(total = rows.len(), "Sensitivity list stored");
+        Ok(())
+    }
+
+    /// Get the generalization precision for a name if it is sensitive
+    pub fn precision(&self, name_id: &Uuid) -> Option<f64> {
+        self.precisions.get(name_id).copied()
+    }
+}
+
+
+fn create_sensitive_taxa_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS sensitive_taxa (
+            name_id uuid PRIMARY KEY,
+            precision double precision NOT NULL
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+
+/// Snap a coordinate to the centre of its grid cell at the given precision in decimal degrees
+pub fn generalize(coordinate: f64, precision: f64) -> f64 {
+    let cell = (coordinate / precision).floor();
+    let centre = (cell + 0.5) * precision;
+
+    // avoid float noise like 12.350000000000001 in the public outputs
+    (centre * 1e6).round() / 1e6
+}
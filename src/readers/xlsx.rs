@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use calamine::{open_workbook_auto, Data, Reader};
+use csv::StringRecord;
+use serde::de::DeserializeOwned;
+use tracing::info;
+
+use crate::errors::{Error, ParseError};
+use crate::frames::IntoFrame;
+use crate::readers::mappings::{clear_fields, FieldMappings};
+
+
+/// Options for selecting the data in a spreadsheet
+#[derive(Debug, Clone, Default)]
+pub struct SheetOptions {
+    /// The name of the sheet to read. Defaults to the first sheet in the workbook
+    pub sheet: Option<String>,
+    /// The zero based row index of the column headers. Rows above it are ignored
+    pub header_row: usize,
+}
+
+
+/// Returns true if the file at the path is a spreadsheet that can be read with `read_records`
+pub fn is_spreadsheet(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("xlsx" | "xlsm" | "xlsb" | "xls" | "ods"))
+}
+
+
+/// Read all rows in a spreadsheet and deserialize them into records.
+///
+/// Each row is converted to a CSV string record before deserializing so that records
+/// behave exactly the same as they would when imported from a CSV file. Cells are
+/// converted to strings without any locale formatting which means dates are always
+/// in an ISO 8601 format and whole numbers don't get a decimal point.
+pub fn read_records<T: DeserializeOwned + IntoFrame>(
+    path: &Path,
+    options: &SheetOptions,
+    mut mappings: FieldMappings,
+) -> Result<Vec<T>, Error> {
+    mappings.retain_unknowns(T::UNKNOWN_FIELDS);
+    let mut workbook = open_workbook_auto(path)?;
+
+    let sheet = match &options.sheet {
+        Some(sheet) => sheet.clone(),
+        None => workbook
+            .sheet_names()
+            .first()
+            .cloned()
+            .ok_or(ParseError::NotFound("worksheet".to_string()))?,
+    };
+
+    info!(sheet, header_row = options.header_row, "Reading spreadsheet");
+    let range = workbook.worksheet_range(&sheet)?;
+
+    // the range starts at the first non-empty cell so we need to offset the header row
+    // by any empty rows at the top of the sheet
+    let start_row = range.start().map(|(row, _)| row as usize).unwrap_or_default();
+    let mut rows = range.rows().skip(options.header_row.saturating_sub(start_row));
+
+    let headers: StringRecord = match rows.next() {
+        Some(row) => row.iter().map(cell_to_string).collect(),
+        None => return Err(ParseError::NotFound("header row".to_string()).into()),
+    };
+    let headers = mappings.headers(&headers);
+
+    let mut records = Vec::new();
+    for row in rows {
+        let row: StringRecord = row.iter().map(cell_to_string).collect();
+
+        // spreadsheets often have formatted but otherwise blank rows at the end
+        if row.iter().all(|value| value.is_empty()) {
+            continue;
+        }
+
+        let unknown = mappings.unknown_fields(&headers, &row);
+        let row = clear_fields(&headers, &mappings.apply(&headers, &row), &unknown);
+
+        let mut record = row.deserialize::<T>(Some(&headers))?;
+        for field in unknown {
+            record.set_unknown(field);
+        }
+        records.push(record);
+    }
+
+    info!(total = records.len(), "Spreadsheet rows read");
+    Ok(records)
+}
+
+
+fn cell_to_string(cell: &Data) -> String {
+    match cell {
+        Data::Empty => String::new(),
+        Data::String(value) => value.trim().to_string(),
+        Data::Int(value) => value.to_string(),
+        Data::Float(value) if value.fract() == 0.0 => format!("{value:.0}"),
+        Data::Float(value) => value.to_string(),
+        Data::Bool(value) => value.to_string(),
+        Data::DateTime(value) => match value.as_datetime() {
+            Some(datetime) if datetime.time() == chrono::NaiveTime::MIN => datetime.format("%Y-%m-%d").to_string(),
+            Some(datetime) => datetime.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            None => value.to_string(),
+        },
+        Data::DateTimeIso(value) => value.clone(),
+        Data::DurationIso(value) => value.clone(),
+        Data::Error(_) => String::new(),
+    }
+}
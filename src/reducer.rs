@@ -1,10 +1,37 @@
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
 use arga_core::crdt::lww::Map;
 use arga_core::models::LogOperation;
+use diesel::PgConnection;
+use serde::Serialize;
+use tracing::{info, warn};
 
 use crate::database::PgPool;
-use crate::errors::Error;
+use crate::errors::{skip_record, Error};
+use crate::fingerprints::{self, fingerprint};
+use crate::utils::UpdateBars;
+
+// the types that the public reducer functions take
+pub use crate::fingerprints::Fingerprints;
+pub use crate::readers::OperationLoader;
+
+
+/// The amount of reduced records written at once by `update_table`
+const WRITE_CHUNK_SIZE: usize = 1000;
+
+/// Entities with more operations than this are reported at the end of an update
+const SLOW_ENTITY_OPERATIONS: usize = 10_000;
+
+/// The amount of entities with the most operations reported at the end of an update
+const SLOW_ENTITIES_REPORTED: usize = 10;
 
 
+/// A record that is reduced from the LWW map of an entity's operations.
+///
+/// The lookups are whatever the record needs to resolve its references into the reduced
+/// tables, such as a map of dataset ids to their uuids, and are loaded once per update.
+/// A record that can't be reduced returns an error and is skipped by the update.
 pub trait Reducer<L>
 where
     Self: Sized,
@@ -15,6 +42,11 @@ where
 }
 
 
+/// Pages through the operations of a log table by entity.
+///
+/// Each page has to contain every operation of the entities in it, ordered by entity and
+/// operation id, and pages have to be ordered consistently so that no entity is missed.
+/// `total` is the amount of distinct entities, which is used for progress reporting.
 pub trait EntityPager {
     type Operation;
 
@@ -23,10 +55,80 @@ pub trait EntityPager {
 }
 
 
+/// The time spent on a single page of entities
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PageTiming {
+    /// Paging the operations out of the log table
+    pub load: Duration,
+    /// Building the LWW maps and reducing them into records
+    pub reduce: Duration,
+    /// Writing the records, as reported by the caller with `record_write`
+    pub write: Duration,
+}
+
+impl PageTiming {
+    fn total(&self) -> Duration {
+        self.load + self.reduce + self.write
+    }
+}
+
+
+/// Where the time of an update went.
+///
+/// A slow update is either slow to page through the logs, slow to reduce, or slow to write
+/// and the progress bars alone don't show which. The time of every page is kept to report
+/// the totals and the slowest page. Entities with more than `SLOW_ENTITY_OPERATIONS` are
+/// tracked as well, since a single entity with a pathological amount of operations can
+/// stall the page it is in, and the ones with the most are reported as warnings.
+#[derive(Debug, Default)]
+pub struct ReduceTimings {
+    pub pages: Vec<PageTiming>,
+    pub slow_entities: Vec<(String, usize)>,
+}
+
+impl ReduceTimings {
+    fn add_entity(&mut self, entity_id: &str, operations: usize) {
+        if operations <= SLOW_ENTITY_OPERATIONS {
+            return;
+        }
+
+        self.slow_entities.push((entity_id.to_string(), operations));
+        if self.slow_entities.len() > SLOW_ENTITIES_REPORTED * 2 {
+            self.keep_slowest();
+        }
+    }
+
+    fn keep_slowest(&mut self) {
+        self.slow_entities.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        self.slow_entities.truncate(SLOW_ENTITIES_REPORTED);
+    }
+
+    /// Log the totals of each stage, the slowest page and the entities with the most operations
+    pub fn report(&mut self) {
+        let load: Duration = self.pages.iter().map(|page| page.load).sum();
+        let reduce: Duration = self.pages.iter().map(|page| page.reduce).sum();
+        let write: Duration = self.pages.iter().map(|page| page.write).sum();
+        info!(pages = self.pages.len(), ?load, ?reduce, ?write, "Update timings");
+
+        let slowest = self.pages.iter().enumerate().max_by_key(|(_, page)| page.total());
+        if let Some((page, timing)) = slowest {
+            info!(page, load = ?timing.load, reduce = ?timing.reduce, write = ?timing.write, "Slowest page");
+        }
+
+        self.keep_slowest();
+        for (entity_id, operations) in &self.slow_entities {
+            warn!(entity_id, operations, "Entity with a pathological amount of operations");
+        }
+    }
+}
+
+
 pub struct DatabaseReducer<R, P, L> {
     pager: P,
     lookups: L,
     current_page: usize,
+    fingerprints: Option<Fingerprints>,
+    timings: ReduceTimings,
     phantom_record: std::marker::PhantomData<R>,
 }
 
@@ -41,28 +143,99 @@ where
             pager,
             lookups,
             current_page: 0,
+            fingerprints: None,
+            timings: ReduceTimings::default(),
             phantom_record: std::marker::PhantomData,
         }
     }
 
-    pub fn next_entity_chunk(&mut self) -> Result<Entities<R>, Error> {
+    /// Skip entities with the same winning atoms as the last time they were written.
+    ///
+    /// The fingerprints of the returned records are queued until `commit_fingerprints`
+    /// is called, which should happen once the chunk has been written.
+    pub fn with_fingerprints(mut self, fingerprints: Fingerprints) -> DatabaseReducer<R, P, L> {
+        self.fingerprints = Some(fingerprints);
+        self
+    }
+
+    pub fn next_entity_chunk(&mut self) -> Result<Entities<R>, Error>
+    where
+        R::Atom: Serialize,
+    {
+        let started = Instant::now();
         let operations = self.pager.load_entity_operations(self.current_page)?;
         self.current_page += 1;
 
+        let load = started.elapsed();
+        let started = Instant::now();
+
         // group up the operations so we can iterate by entity frames
         let entities = crate::operations::group_operations(operations, vec![]);
         let mut records = Vec::new();
 
+        let stored = match &self.fingerprints {
+            Some(fingerprints) => fingerprints.load(&entities.keys().collect::<Vec<_>>())?,
+            None => Default::default(),
+        };
+
         // create an LWW map for each entity and reduce it
         for (key, ops) in entities.into_iter() {
-            let mut map = Map::new(key);
+            self.timings.add_entity(&key, ops.len());
+
+            let mut map = Map::new(key.clone());
             map.reduce(&ops);
+
+            if let Some(fingerprints) = &mut self.fingerprints {
+                let hash = fingerprint(map.atoms.values())?;
+                if stored.get(&key) == Some(&hash) {
+                    fingerprints.skip();
+                    continue;
+                }
+
+                // records that fail to reduce are left without a fingerprint so that
+                // they are tried again on the next update
+                let record = R::reduce(map, &self.lookups);
+                if record.is_ok() {
+                    fingerprints.push(key, hash);
+                }
+                records.push(record);
+                continue;
+            }
+
             let record = R::reduce(map, &self.lookups);
             records.push(record);
         }
 
+        self.timings.pages.push(PageTiming {
+            load,
+            reduce: started.elapsed(),
+            write: Duration::ZERO,
+        });
         Ok(records)
     }
+
+    /// Add the time it took to write the records of the last page to its timing
+    pub fn record_write(&mut self, elapsed: Duration) {
+        if let Some(page) = self.timings.pages.last_mut() {
+            page.write += elapsed;
+        }
+    }
+
+    /// Store the fingerprints of the records returned since the last commit
+    pub fn commit_fingerprints(&mut self) -> Result<(), Error> {
+        match &mut self.fingerprints {
+            Some(fingerprints) => fingerprints.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Log the amount of written and skipped entities and where the time of the update went
+    pub fn finish(&mut self) {
+        if let Some(fingerprints) = &self.fingerprints {
+            fingerprints.finish();
+        }
+        self.timings.report();
+    }
 }
 
 
@@ -74,11 +247,132 @@ where
     R: Reducer<L>,
     P: EntityPager,
     P::Operation: Clone + LogOperation<R::Atom>,
+    R::Atom: Serialize,
 {
     type Item = Entities<R>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let chunk = self.next_entity_chunk().unwrap();
-        if !chunk.is_empty() { Some(chunk) } else { None }
+        // a page where every entity was skipped is empty but isn't the last page
+        loop {
+            let skipped = self.fingerprints.as_ref().map(|fp| fp.skipped).unwrap_or_default();
+            let chunk = self.next_entity_chunk().unwrap();
+            if !chunk.is_empty() {
+                return Some(chunk);
+            }
+
+            let skipped_now = self.fingerprints.as_ref().map(|fp| fp.skipped).unwrap_or_default();
+            if skipped_now == skipped {
+                return None;
+            }
+        }
+    }
+}
+
+
+/// Reduce every entity of a log table and write the records in chunks.
+///
+/// This is the update loop shared by the reduced tables. Entities whose winning atoms haven't
+/// changed since the last update are skipped unless `full` is set, records that fail to reduce
+/// are reported and skipped, and the rest are passed to `write` along with a connection from
+/// the pool. `table` names the fingerprints of the update so it has to be unique to it.
+pub fn update_table<R, P, L, W>(
+    pool: &PgPool,
+    table: &'static str,
+    pager: P,
+    lookups: L,
+    full: bool,
+    mut write: W,
+) -> Result<(), Error>
+where
+    R: Reducer<L>,
+    R::Atom: Serialize,
+    P: EntityPager,
+    P::Operation: Clone + LogOperation<R::Atom>,
+    W: FnMut(&mut PgConnection, &[R]) -> Result<(), Error>,
+{
+    let total_entities = pager.total()? as usize;
+    let bars = UpdateBars::new(total_entities);
+
+    info!(table, total_entities, "Reducing entities");
+
+    if full {
+        fingerprints::clear(pool, table)?;
+    }
+
+    let fingerprints = Fingerprints::new(pool.clone(), table)?;
+    let mut reducer: DatabaseReducer<R, _, _> = DatabaseReducer::new(pager, lookups).with_fingerprints(fingerprints);
+    let mut conn = pool.get()?;
+
+    while let Some(records) = reducer.next() {
+        let mut valid_records = Vec::with_capacity(records.len());
+        for record in records {
+            match record {
+                Ok(record) => valid_records.push(record),
+                Err(err) => skip_record(&err),
+            }
+        }
+
+        let started = Instant::now();
+        for chunk in valid_records.chunks(WRITE_CHUNK_SIZE) {
+            write(&mut conn, chunk)?;
+            bars.records.inc(chunk.len() as u64);
+        }
+
+        reducer.commit_fingerprints()?;
+        reducer.record_write(started.elapsed());
     }
+
+    bars.finish();
+    reducer.finish();
+    info!(table, "Finished reducing and updating");
+    Ok(())
+}
+
+
+/// Trace the reduction of a single entity without updating anything.
+///
+/// This prints every operation logged for the entity, the atoms that the LWW map reduced
+/// them to, and the record that would be upserted with the lookups resolved. When a lookup
+/// fails the error is printed in place of the record since that is usually what needs
+/// explaining. The entity id is the hashed id found in the log tables.
+pub fn explain<R, Lo, L>(loader: &Lo, entity_id: &str, lookups: &L) -> Result<(), Error>
+where
+    R: Reducer<L> + Debug,
+    R::Atom: Debug,
+    Lo: OperationLoader,
+    Lo::Operation: Clone + Debug + LogOperation<R::Atom>,
+{
+    let entity_id = entity_id.to_string();
+    let operations = loader.load_operations(&[&entity_id])?;
+
+    println!("Entity {entity_id}");
+    println!("\nOperations ({})", operations.len());
+    for op in &operations {
+        println!("  {op:?}");
+    }
+
+    if operations.is_empty() {
+        println!("\nNo operations found for the entity");
+        return Ok(());
+    }
+
+    let mut map = Map::new(entity_id.clone());
+    let applied = map.reduce(&operations);
+    println!("\nApplied operations ({})", applied.len());
+    for op in &applied {
+        println!("  {op:?}");
+    }
+
+    println!("\nAtoms");
+    for atom in map.atoms.values() {
+        println!("  {atom:?}");
+    }
+
+    println!("\nRecord");
+    match R::reduce(map, lookups) {
+        Ok(record) => println!("{record:#?}"),
+        Err(err) => println!("  Failed to reduce: {err}\n  {err:?}"),
+    }
+
+    Ok(())
 }
@@ -0,0 +1,74 @@
+use arga_core::schema;
+use diesel::sql_types::Uuid as SqlUuid;
+use diesel::*;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::database::PgPool;
+use crate::errors::{Error, LookupError};
+use crate::loggers::names;
+
+
+/// Recompute the name links of a taxonomy dataset in bulk after it has been re-imported.
+///
+/// `link taxa` reduces every taxon from the logs to resolve its name and parent, which is
+/// wasted work when only the taxa of one dataset changed. This instead rebuilds the
+/// `taxon_names` rows of the dataset with set based updates joined on the scientific name,
+/// including the names of linked variant spellings, and relinks the name variants to the
+/// accepted names of the updated taxonomic acts. Everything runs in a single transaction so
+/// the website never sees a taxon without its names.
+///
+/// Parent links aren't stored in the taxa table so they still require `link taxa`.
+pub fn relink(pool: &PgPool, dataset: &str) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+
+    let dataset_uuid = {
+        use schema::datasets::dsl::*;
+        datasets
+            .filter(global_id.eq(dataset))
+            .select(id)
+            .get_result::<Uuid>(&mut conn)
+            .optional()?
+            .ok_or_else(|| LookupError::Dataset(dataset.to_string()))?
+    };
+
+    info!(dataset, "Relinking taxa");
+
+    // variants are linked to the accepted names first so that the taxon names built from
+    // them below use the latest taxonomic acts
+    names::link_variants(pool)?;
+
+    conn.transaction::<_, Error, _>(|conn| {
+        let removed = sql_query(
+            "DELETE FROM taxon_names
+             USING taxa
+             WHERE taxon_names.taxon_id = taxa.id
+             AND taxa.dataset_id = $1",
+        )
+        .bind::<SqlUuid, _>(dataset_uuid)
+        .execute(conn)?;
+
+        let linked = sql_query(
+            "INSERT INTO taxon_names (taxon_id, name_id)
+             SELECT taxa.id, names.id
+             FROM taxa
+             JOIN names ON names.scientific_name = taxa.scientific_name
+             WHERE taxa.dataset_id = $1
+             UNION
+             SELECT taxa.id, name_variants.name_id
+             FROM taxa
+             JOIN name_variants ON name_variants.dataset_id = taxa.dataset_id
+                AND name_variants.scientific_name = taxa.scientific_name
+             WHERE taxa.dataset_id = $1
+             AND name_variants.name_id IS NOT NULL
+             ON CONFLICT (taxon_id, name_id) DO NOTHING",
+        )
+        .bind::<SqlUuid, _>(dataset_uuid)
+        .execute(conn)?;
+
+        info!(removed, linked, "Relinked taxon names");
+        Ok(())
+    })?;
+
+    Ok(())
+}
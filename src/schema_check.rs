@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+
+use arga_core::schema;
+use diesel::pg::Pg;
+use diesel::query_builder::QueryFragment;
+use diesel::sql_types::Text;
+use diesel::*;
+use tracing::{error, info};
+
+use crate::database::PgPool;
+use crate::errors::Error;
+
+
+/// Get the table name and columns that diesel expects for a table.
+///
+/// Diesel doesn't expose the column names of a table as a list so we instead build the
+/// SQL for selecting all columns and pull the names out of that. The debug output of a
+/// select query looks like `SELECT "taxa"."id", "taxa"."dataset_id" FROM "taxa" -- binds: []`
+fn expected_columns<Q: QueryFragment<Pg>>(query: Q) -> (String, Vec<String>) {
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    let select = sql.trim_start_matches("SELECT ");
+    let (columns, from) = select.split_once(" FROM ").unwrap_or_default();
+
+    let table = from.split_whitespace().next().unwrap_or_default().replace('"', "");
+    let columns = columns
+        .split(", ")
+        .filter_map(|column| column.rsplit_once('.').map(|(_, name)| name.replace('"', "")))
+        .collect();
+
+    (table, columns)
+}
+
+macro_rules! expected_tables {
+    ($($table:ident),* $(,)?) => {
+        vec![$(expected_columns(schema::$table::table.select(schema::$table::all_columns))),*]
+    };
+}
+
+
+#[derive(QueryableByName)]
+struct ColumnInfo {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    column_name: String,
+}
+
+
+/// Compare the tables in arga_core against the live database.
+///
+/// When arga_core is newer than the deployed database an update will fail midway with
+/// an error that doesn't point to the cause, so instead we check the columns of every
+/// table the oplogger reads or writes up front and report exactly what is missing.
+/// Extra columns in the database are fine since we never select them.
+pub fn check_schema(pool: &PgPool) -> Result<(), Error> {
+    let mut conn = pool.get()?;
+
+    let expected = expected_tables![
+        sources,
+        datasets,
+        dataset_versions,
+        names,
+        name_publications,
+        taxa,
+        taxon_names,
+        taxa_logs,
+        taxonomic_acts,
+        taxonomic_act_logs,
+        nomenclatural_acts,
+        nomenclatural_act_logs,
+        publications,
+        publication_logs,
+        specimens,
+        specimen_logs,
+        sequence_logs,
+    ];
+
+    let live = sql_query(
+        "SELECT table_name::text, column_name::text FROM information_schema.columns WHERE table_schema = current_schema()",
+    )
+    .load::<ColumnInfo>(&mut conn)?;
+
+    let mut tables: HashMap<String, HashSet<String>> = HashMap::new();
+    for info in live {
+        tables.entry(info.table_name).or_default().insert(info.column_name);
+    }
+
+    let mut drift = Vec::new();
+    for (table, columns) in expected {
+        match tables.get(&table) {
+            None => drift.push(format!("missing table {table}")),
+            Some(live_columns) => {
+                for column in columns {
+                    if !live_columns.contains(&column) {
+                        drift.push(format!("missing column {table}.{column}"));
+                    }
+                }
+            }
+        }
+    }
+
+    if drift.is_empty() {
+        info!("Database schema matches arga_core");
+        return Ok(());
+    }
+
+    for difference in &drift {
+        error!(difference, "Schema drift");
+    }
+
+    Err(Error::SchemaDrift(drift.join(", ")))
+}
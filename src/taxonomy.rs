@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+
+use arga_core::models::TaxonomicRank;
+use arga_core::schema::{datasets, taxa};
+use diesel::dsl::{count_star, not};
+use diesel::*;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::database::PgPool;
+use crate::errors::{skip_record, Error, ReduceError};
+
+
+/// Ranks that are expected to be at the top of a taxonomy and don't count as orphans
+const ROOT_RANKS: [TaxonomicRank; 3] = [TaxonomicRank::Domain, TaxonomicRank::Superkingdom, TaxonomicRank::Kingdom];
+
+
+/// Report the structure of the taxonomy in the taxa table.
+///
+/// Logs the amount of taxa of each rank in every dataset, the taxa below the root ranks
+/// that have no parent, and any cycles in the parent links. Problems with a taxonomy are
+/// otherwise only found once the DAG views built on it are queried, so this is run after
+/// every update and link. Returns the amount of cycles found.
+pub fn report(pool: &PgPool) -> Result<usize, Error> {
+    let mut conn = pool.get()?;
+
+    let ranks = taxa::table
+        .inner_join(datasets::table.on(taxa::dataset_id.eq(datasets::id)))
+        .group_by((datasets::global_id, taxa::rank))
+        .select((datasets::global_id, taxa::rank, count_star()))
+        .order_by((datasets::global_id, taxa::rank))
+        .load::<(String, TaxonomicRank, i64)>(&mut conn)?;
+
+    for (dataset_id, rank, total) in ranks {
+        info!(dataset_id, ?rank, total, "Taxa by rank");
+    }
+
+    let orphans = taxa::table
+        .inner_join(datasets::table.on(taxa::dataset_id.eq(datasets::id)))
+        .filter(taxa::parent_id.is_null())
+        .filter(not(taxa::rank.eq_any(ROOT_RANKS)))
+        .group_by(datasets::global_id)
+        .select((datasets::global_id, count_star()))
+        .order_by(datasets::global_id)
+        .load::<(String, i64)>(&mut conn)?;
+
+    for (dataset_id, orphans) in orphans {
+        info!(dataset_id, orphans, "Taxa without a parent");
+    }
+
+    let parents = parent_links(&mut conn)?;
+    let cycles = find_cycles(&parents);
+    for cycle in &cycles {
+        warn!(cycle = describe_cycle(&mut conn, cycle)?, "Cycle in the taxonomy parent links");
+    }
+
+    info!(cycles = cycles.len(), "Taxonomy report finished");
+    Ok(cycles.len())
+}
+
+
+/// Remove the parent links that would leave a cycle in the taxonomy.
+///
+/// The new links are laid over the parents already in the taxa table and only the new links
+/// that close a cycle are dropped, leaving those taxa with the parent they already had. Which
+/// link of a cycle is wrong can't be told from the graph alone, so every cycle is reported
+/// along with the dropped link for curators to fix in the source dataset.
+pub fn break_cycles(conn: &mut PgConnection, links: &mut Vec<(Uuid, Uuid)>) -> Result<(), Error> {
+    let parents = parent_links(conn)?;
+    let closing = closing_links(&parents, links);
+    if closing.is_empty() {
+        return Ok(());
+    }
+
+    for ((taxon_id, parent_id), cycle) in &closing {
+        let names = scientific_names(conn, &[*taxon_id, *parent_id])?;
+        let cycle = describe_cycle(conn, cycle)?;
+        let dropped = format!("{} -> {}", names[0], names[1]);
+        skip_record(&ReduceError::CyclicParents(format!("{cycle}, dropped the new link {dropped}")).into());
+    }
+
+    let dropped: HashSet<(Uuid, Uuid)> = closing.into_iter().map(|(link, _)| link).collect();
+    links.retain(|link| !dropped.contains(link));
+
+    info!(dropped = dropped.len(), "Broke cycles in the taxonomy parent links");
+    Ok(())
+}
+
+
+/// Find the new parent links that close a cycle when they are laid over the existing links.
+///
+/// The links are taken to be applied in order so the latest new link in a cycle is the one
+/// that closed it. Dropping it restores the existing parent of that taxon, which can close
+/// another cycle, so the cycles are searched for again until only the existing links are
+/// left in any of them. Cycles made up of existing links alone are left for the taxonomy
+/// report since no new link caused them. Returns the dropped links with the cycle they closed.
+pub fn closing_links(existing: &HashMap<Uuid, Uuid>, links: &[(Uuid, Uuid)]) -> Vec<((Uuid, Uuid), Vec<Uuid>)> {
+    let mut parents = existing.clone();
+    parents.extend(links.iter().copied());
+
+    // a taxon linked more than once ends up with the last link, like the update itself
+    let mut positions: HashMap<Uuid, usize> =
+        links.iter().enumerate().map(|(position, (taxon_id, _))| (*taxon_id, position)).collect();
+
+    let mut closing = Vec::new();
+    loop {
+        let mut dropped = false;
+
+        for cycle in find_cycles(&parents) {
+            let latest = cycle
+                .iter()
+                .filter_map(|taxon_id| positions.get(taxon_id).map(|position| (*position, *taxon_id)))
+                .max();
+
+            let Some((_, taxon_id)) = latest
+            else {
+                continue;
+            };
+
+            let parent_id = parents[&taxon_id];
+            match existing.get(&taxon_id) {
+                Some(previous) => parents.insert(taxon_id, *previous),
+                None => parents.remove(&taxon_id),
+            };
+            positions.remove(&taxon_id);
+
+            closing.push(((taxon_id, parent_id), cycle));
+            dropped = true;
+        }
+
+        if !dropped {
+            return closing;
+        }
+    }
+}
+
+
+/// The parent of every taxon that has one
+fn parent_links(conn: &mut PgConnection) -> Result<HashMap<Uuid, Uuid>, Error> {
+    let links = taxa::table
+        .filter(taxa::parent_id.is_not_null())
+        .select((taxa::id, taxa::parent_id.assume_not_null()))
+        .load::<(Uuid, Uuid)>(conn)?;
+
+    Ok(links.into_iter().collect())
+}
+
+
+/// Find every cycle in a map of taxa to their parent.
+///
+/// Each taxon has at most one parent so following the parents from any taxon either reaches
+/// a root or loops back onto the path walked so far. Taxa are only walked once, and in the
+/// order of their ids so that the same cycles are reported in the same order every time.
+pub fn find_cycles(parents: &HashMap<Uuid, Uuid>) -> Vec<Vec<Uuid>> {
+    let mut starts: Vec<&Uuid> = parents.keys().collect();
+    starts.sort();
+
+    let mut walked: HashSet<Uuid> = HashSet::new();
+    let mut cycles = Vec::new();
+
+    for start in starts {
+        let mut path: Vec<Uuid> = Vec::new();
+        let mut positions: HashMap<Uuid, usize> = HashMap::new();
+        let mut node = *start;
+
+        loop {
+            if walked.contains(&node) {
+                break;
+            }
+            if let Some(position) = positions.get(&node) {
+                cycles.push(path[*position..].to_vec());
+                break;
+            }
+
+            positions.insert(node, path.len());
+            path.push(node);
+
+            match parents.get(&node) {
+                Some(parent) => node = *parent,
+                None => break,
+            }
+        }
+
+        walked.extend(path);
+    }
+
+    cycles
+}
+
+
+/// The scientific names of the taxa in a cycle, leading back to the first one
+pub fn describe_cycle(conn: &mut PgConnection, cycle: &[Uuid]) -> Result<String, Error> {
+    let path: Vec<Uuid> = cycle.iter().chain(cycle.first()).copied().collect();
+    Ok(scientific_names(conn, &path)?.join(" -> "))
+}
+
+
+/// The scientific name of each taxon in order, or its id if it can't be found
+fn scientific_names(conn: &mut PgConnection, taxon_ids: &[Uuid]) -> Result<Vec<String>, Error> {
+    let names: HashMap<Uuid, String> = taxa::table
+        .filter(taxa::id.eq_any(taxon_ids))
+        .select((taxa::id, taxa::scientific_name))
+        .load::<(Uuid, String)>(conn)?
+        .into_iter()
+        .collect();
+
+    Ok(taxon_ids
+        .iter()
+        .map(|id| names.get(id).cloned().unwrap_or_else(|| id.to_string()))
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn taxon(id: u128) -> Uuid {
+        Uuid::from_u128(id)
+    }
+
+    fn parents(links: &[(u128, u128)]) -> HashMap<Uuid, Uuid> {
+        links.iter().map(|(child, parent)| (taxon(*child), taxon(*parent))).collect()
+    }
+
+    #[test]
+    fn find_cycles_ignores_trees() {
+        let parents = parents(&[(2, 1), (3, 1), (4, 2), (5, 4)]);
+        assert!(find_cycles(&parents).is_empty());
+    }
+
+    #[test]
+    fn find_cycles_finds_self_links() {
+        let parents = parents(&[(1, 1), (2, 1)]);
+        assert_eq!(find_cycles(&parents), vec![vec![taxon(1)]]);
+    }
+
+    #[test]
+    fn find_cycles_reports_each_cycle_once() {
+        // 4 and 5 lead into the 1 -> 2 -> 3 cycle but aren't part of it
+        let parents = parents(&[(1, 2), (2, 3), (3, 1), (4, 1), (5, 4), (6, 7), (7, 6)]);
+        let cycles = find_cycles(&parents);
+
+        assert_eq!(cycles, vec![vec![taxon(1), taxon(2), taxon(3)], vec![taxon(6), taxon(7)]]);
+    }
+
+    #[test]
+    fn find_cycles_starts_walking_from_the_lowest_id() {
+        let parents = parents(&[(3, 1), (1, 2), (2, 3)]);
+        assert_eq!(find_cycles(&parents), vec![vec![taxon(1), taxon(2), taxon(3)]]);
+    }
+
+    fn links(links: &[(u128, u128)]) -> Vec<(Uuid, Uuid)> {
+        links.iter().map(|(child, parent)| (taxon(*child), taxon(*parent))).collect()
+    }
+
+    #[test]
+    fn closing_links_keeps_links_without_cycles() {
+        let existing = parents(&[(2, 1)]);
+        let links = links(&[(3, 2), (4, 3)]);
+        assert!(closing_links(&existing, &links).is_empty());
+    }
+
+    #[test]
+    fn closing_links_drops_only_the_latest_link_of_a_cycle() {
+        let existing = parents(&[(2, 1), (3, 2)]);
+        let links = links(&[(4, 3), (1, 4)]);
+        let closing = closing_links(&existing, &links);
+
+        let cycle = vec![taxon(1), taxon(4), taxon(3), taxon(2)];
+        assert_eq!(closing, vec![((taxon(1), taxon(4)), cycle)]);
+    }
+
+    #[test]
+    fn closing_links_ignores_links_replaced_by_new_links() {
+        // 1 is moved away from 2 in the same update so linking 2 to 1 doesn't close a cycle
+        let existing = parents(&[(1, 2)]);
+        let links = links(&[(2, 1), (1, 3)]);
+        assert!(closing_links(&existing, &links).is_empty());
+    }
+
+    #[test]
+    fn closing_links_checks_the_restored_existing_link() {
+        // dropping 2 -> 3 restores 2 -> 1, which closes the cycle with the new 1 -> 2
+        let existing = parents(&[(2, 1)]);
+        let links = links(&[(1, 2), (3, 1), (2, 3)]);
+        let closing = closing_links(&existing, &links);
+
+        assert_eq!(
+            closing,
+            vec![
+                ((taxon(2), taxon(3)), vec![taxon(1), taxon(2), taxon(3)]),
+                ((taxon(1), taxon(2)), vec![taxon(1), taxon(2)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn closing_links_leaves_existing_cycles() {
+        let existing = parents(&[(1, 2), (2, 1)]);
+        let links = links(&[(3, 1)]);
+        assert!(closing_links(&existing, &links).is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::RwLock;
+
+use tracing::info;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+use crate::loggers::{collections, nomenclatural_acts, publications, taxa, taxonomic_acts};
+
+
+/// A single table update that can be run as part of `update all`.
+///
+/// Each stage declares the stages it depends on which lets us build a dependency graph
+/// and run independent stages concurrently. For example, taxonomic acts need the taxa to
+/// be updated first so that the act can be linked to the correct taxon, whereas publications
+/// have no dependencies and can be updated alongside the taxa.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpdateStage {
+    Taxa,
+    TaxonomicActs,
+    Publications,
+    NomenclaturalActs,
+    Collections,
+    /// An update registered with `register`, identified by its name
+    Custom(&'static str),
+}
+
+impl UpdateStage {
+    pub fn all() -> Vec<UpdateStage> {
+        use UpdateStage::*;
+        let mut stages = vec![Taxa, TaxonomicActs, Publications, NomenclaturalActs, Collections];
+        let updates = CUSTOM_UPDATES.read().expect("custom updates poisoned");
+        stages.extend(updates.iter().map(|custom| Custom(custom.name)));
+        stages
+    }
+
+    pub fn dependencies(&self) -> Vec<UpdateStage> {
+        use UpdateStage::*;
+
+        match self {
+            Taxa => vec![],
+            Publications => vec![],
+            // the taxon lookup for acts is scoped to the dataset so all taxa need to exist
+            TaxonomicActs => vec![Taxa],
+            // acts link to both the publication and the names inserted by the taxa update
+            NomenclaturalActs => vec![Taxa, Publications],
+            // specimens are linked to names which are inserted by the taxa update
+            Collections => vec![Taxa],
+            Custom(name) => registered(name).map(|custom| custom.dependencies).unwrap_or_default(),
+        }
+    }
+
+    pub fn run(&self, pool: PgPool) -> Result<(), Error> {
+        match self {
+            UpdateStage::Taxa => taxa::update(pool, false),
+            UpdateStage::TaxonomicActs => taxonomic_acts::update(pool, false),
+            UpdateStage::Publications => publications::update(pool),
+            UpdateStage::NomenclaturalActs => nomenclatural_acts::NomenclaturalActs::update(pool),
+            UpdateStage::Collections => collections::update(pool, None),
+            UpdateStage::Custom(name) => match registered(name) {
+                Some(custom) => (custom.run)(pool),
+                None => Err(Error::UnknownUpdate(name.to_string())),
+            },
+        }
+    }
+}
+
+
+/// An update defined outside of this crate, such as a materialization built with `reducer::update_table`
+#[derive(Clone)]
+pub struct CustomUpdate {
+    /// The unique name of the update, which is how other custom updates depend on it
+    pub name: &'static str,
+    /// The stages that have to finish before this update runs
+    pub dependencies: Vec<UpdateStage>,
+    pub run: fn(PgPool) -> Result<(), Error>,
+}
+
+static CUSTOM_UPDATES: RwLock<Vec<CustomUpdate>> = RwLock::new(Vec::new());
+
+
+/// Register an update to run as part of `update_all`.
+///
+/// Registering an update with the name of one that is already registered replaces it.
+/// Dependencies on stages that aren't registered by the time `update_all` runs are never
+/// completed, which makes `update_all` return an error the same way a dependency cycle does.
+pub fn register(update: CustomUpdate) {
+    let mut updates = CUSTOM_UPDATES.write().expect("custom updates poisoned");
+    updates.retain(|custom| custom.name != update.name);
+    updates.push(update);
+}
+
+
+fn registered(name: &str) -> Option<CustomUpdate> {
+    let updates = CUSTOM_UPDATES.read().expect("custom updates poisoned");
+    updates.iter().find(|custom| custom.name == name).cloned()
+}
+
+
+/// Run all update stages in dependency order.
+///
+/// Stages are run in waves where each wave contains every stage with all of its dependencies
+/// completed. The stages within a wave are independent of each other and are run concurrently,
+/// with at most `parallelism` stages running at the same time. Updates registered with
+/// `register` are run alongside the built in stages. The pool is shared between all stages
+/// so make sure it is large enough to serve the concurrent stages. An error is returned when a
+/// stage depends on one that is never registered or when the stages depend on each other.
+pub fn update_all(pool: PgPool, parallelism: usize) -> Result<(), Error> {
+    run_in_waves(UpdateStage::all(), UpdateStage::dependencies, |wave| {
+        for batch in wave.chunks(parallelism.max(1)) {
+            info!(stages = ?batch, "Running update stages");
+
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|stage| {
+                        let pool = pool.clone();
+                        scope.spawn(move || stage.run(pool))
+                    })
+                    .collect();
+
+                for handle in handles {
+                    handle.join().expect("Update stage panicked")?;
+                }
+
+                Ok::<(), Error>(())
+            })?;
+        }
+        Ok(())
+    })?;
+
+    info!("Finished updating all tables");
+    Ok(())
+}
+
+
+/// Run the stages in waves where each wave is every stage with all of its dependencies completed.
+///
+/// The stages within a wave don't depend on each other so `run_wave` is free to run them in any
+/// order or concurrently. A wave only starts once the previous wave was run. If none of the
+/// remaining stages can run, because they depend on a stage that isn't in `stages` or on each
+/// other, an error is returned instead of waiting forever.
+pub(crate) fn run_in_waves<S, D, R>(stages: Vec<S>, dependencies: D, mut run_wave: R) -> Result<(), Error>
+where
+    S: Copy + Eq + Hash + Debug,
+    D: Fn(&S) -> Vec<S>,
+    R: FnMut(&[S]) -> Result<(), Error>,
+{
+    let mut completed: HashSet<S> = HashSet::new();
+    let mut pending = stages;
+
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<S>, Vec<S>) = pending
+            .into_iter()
+            .partition(|stage| dependencies(stage).iter().all(|dep| completed.contains(dep)));
+
+        if ready.is_empty() {
+            return Err(Error::UnresolvedStages(format!("{waiting:?}")));
+        }
+
+        run_wave(&ready)?;
+        completed.extend(ready);
+        pending = waiting;
+    }
+
+    Ok(())
+}
@@ -1,3 +1,8 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 
 use arga_core::models::{
@@ -10,10 +15,15 @@ use arga_core::models::{
 };
 use chrono::{DateTime, Utc};
 use heck::ToTitleCase;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, WeakProgressBar};
 use serde::Deserialize;
+use tracing::info;
+use xxhash_rust::xxh3::Xxh3;
 
+use crate::cardinality::AtomCardinality;
 use crate::errors::ParseError;
+use crate::geodesy::GeodeticDatum;
+use crate::readers::mappings::Recorded;
 
 pub static PROGRESS_TEMPLATE: &str = "[{elapsed_precise}] {bar:40.cyan/blue} {human_pos:>7}/{human_len:7} {msg}";
 pub static SPINNER_TEMPLATE: &str = "[{elapsed_precise}] {spinner:2.cyan/blue} {msg}";
@@ -29,28 +39,107 @@ macro_rules! frame_push_opt {
     };
 }
 
+/// How progress is reported to the user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// Interactive progress bars drawn to stderr
+    Bars,
+    /// Periodic plain text progress lines in the logs, for when stderr isn't a terminal
+    Plain,
+    /// No progress output at all
+    Hidden,
+}
+
+static PROGRESS_MODE: OnceLock<ProgressMode> = OnceLock::new();
+static PLAIN_BARS: Mutex<Vec<WeakProgressBar>> = Mutex::new(Vec::new());
+static LIST_SEPARATORS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// The interval between plain text progress lines
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set how every progress bar created afterwards reports its progress.
+///
+/// This should be called once at startup before any bars are created. In plain mode
+/// a background thread logs the position of every unfinished bar on an interval.
+pub fn set_progress_mode(mode: ProgressMode) {
+    if PROGRESS_MODE.set(mode).is_ok() && mode == ProgressMode::Plain {
+        std::thread::spawn(report_plain_progress);
+    }
+}
+
+fn progress_mode() -> ProgressMode {
+    *PROGRESS_MODE.get().unwrap_or(&ProgressMode::Bars)
+}
+
+/// Apply the progress mode to a new bar
+fn with_progress_mode(bar: ProgressBar) -> ProgressBar {
+    match progress_mode() {
+        ProgressMode::Bars => {}
+        ProgressMode::Hidden => bar.set_draw_target(ProgressDrawTarget::hidden()),
+        ProgressMode::Plain => {
+            bar.set_draw_target(ProgressDrawTarget::hidden());
+            PLAIN_BARS
+                .lock()
+                .expect("Progress bar registry poisoned")
+                .push(bar.downgrade());
+        }
+    }
+    bar
+}
+
+fn new_multi_progress() -> MultiProgress {
+    match progress_mode() {
+        ProgressMode::Bars => MultiProgress::new(),
+        ProgressMode::Plain | ProgressMode::Hidden => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+    }
+}
+
+fn report_plain_progress() {
+    loop {
+        std::thread::sleep(PLAIN_PROGRESS_INTERVAL);
+
+        let mut bars = PLAIN_BARS.lock().expect("Progress bar registry poisoned");
+        bars.retain(|bar| bar.upgrade().is_some_and(|bar| !bar.is_finished()));
+
+        for bar in bars.iter().filter_map(|bar| bar.upgrade()) {
+            let task = bar.message();
+            let position = bar.position();
+            match bar.length() {
+                Some(total) => info!(task, position, total, "Progress"),
+                None => info!(task, position, "Progress"),
+            }
+        }
+    }
+}
+
+
 pub fn new_spinner(message: &str) -> ProgressBar {
     let style = ProgressStyle::with_template(SPINNER_TEMPLATE).expect("Invalid spinner template");
     let spinner = ProgressBar::new_spinner()
         .with_message(message.to_string())
         .with_style(style);
 
+    let spinner = with_progress_mode(spinner);
     spinner.enable_steady_tick(Duration::from_millis(100));
     spinner
 }
 
 pub fn new_progress_bar(total: usize, message: &str) -> ProgressBar {
     let style = ProgressStyle::with_template(PROGRESS_TEMPLATE).expect("Invalid progress bar template");
-    ProgressBar::new(total as u64)
+    let bar = ProgressBar::new(total as u64)
         .with_message(message.to_string())
-        .with_style(style)
+        .with_style(style);
+
+    with_progress_mode(bar)
 }
 
 pub fn new_progress_bar_bytes(total: usize, message: &str) -> ProgressBar {
     let style = ProgressStyle::with_template(BYTES_PROGRESS_TEMPLATE).expect("Invalid progress bar template");
-    ProgressBar::new(total as u64)
+    let bar = ProgressBar::new(total as u64)
         .with_message(message.to_string())
-        .with_style(style)
+        .with_style(style);
+
+    with_progress_mode(bar)
 }
 
 pub fn new_spinner_totals(message: &str) -> ProgressBar {
@@ -59,6 +148,7 @@ pub fn new_spinner_totals(message: &str) -> ProgressBar {
         .with_message(message.to_string())
         .with_style(style);
 
+    let spinner = with_progress_mode(spinner);
     spinner.enable_steady_tick(Duration::from_millis(100));
     spinner
 }
@@ -70,11 +160,15 @@ pub struct FrameImportBars {
     pub operations: ProgressBar,
     pub inserted: ProgressBar,
     pub frames: ProgressBar,
+    /// The atoms of each frame read, which isn't shown as a bar but summarised once the import finishes
+    pub atoms: AtomCardinality,
+    /// The entities with operations that the database rejected, so that their rows aren't marked as imported
+    pub rejected: Arc<Mutex<HashSet<String>>>,
 }
 
 impl FrameImportBars {
     pub fn new(total_bytes: usize) -> FrameImportBars {
-        let bars = MultiProgress::new();
+        let bars = new_multi_progress();
         let bytes = new_progress_bar_bytes(total_bytes, "Importing");
         let operations = new_spinner_totals("Total operations");
         let inserted = new_spinner_totals("Operations inserted");
@@ -92,6 +186,8 @@ impl FrameImportBars {
             operations,
             inserted,
             frames,
+            atoms: AtomCardinality::default(),
+            rejected: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
@@ -113,7 +209,7 @@ pub struct UpdateBars {
 
 impl UpdateBars {
     pub fn new(total: usize) -> UpdateBars {
-        let bars = MultiProgress::new();
+        let bars = new_multi_progress();
         let records = new_progress_bar(total, "Updating");
         bars.add(records.clone());
 
@@ -194,216 +290,224 @@ where
     str_to_nomenclatural_act(&s).map_err(serde::de::Error::custom)
 }
 
+/// The accepted values of a taxonomic rank, matched case insensitively
+pub const TAXONOMIC_RANKS: &[(&str, TaxonomicRank)] = &[
+    ("domain", TaxonomicRank::Domain),
+    ("superkingdom", TaxonomicRank::Superkingdom),
+    ("kingdom", TaxonomicRank::Kingdom),
+    ("subkingdom", TaxonomicRank::Subkingdom),
+    ("infrakingdom", TaxonomicRank::Infrakingdom),
+    ("superphylum", TaxonomicRank::Superphylum),
+    ("phylum", TaxonomicRank::Phylum),
+    ("subphylum", TaxonomicRank::Subphylum),
+    ("infraphylum", TaxonomicRank::Infraphylum),
+    ("parvphylum", TaxonomicRank::Parvphylum),
+    ("gigaclass", TaxonomicRank::Gigaclass),
+    ("megaclass", TaxonomicRank::Megaclass),
+    ("superclass", TaxonomicRank::Superclass),
+    ("class", TaxonomicRank::Class),
+    ("subclass", TaxonomicRank::Subclass),
+    ("infraclass", TaxonomicRank::Infraclass),
+    ("subterclass", TaxonomicRank::Subterclass),
+    ("superorder", TaxonomicRank::Superorder),
+    ("order", TaxonomicRank::Order),
+    ("hyporder", TaxonomicRank::Hyporder),
+    ("minorder", TaxonomicRank::Minorder),
+    ("suborder", TaxonomicRank::Suborder),
+    ("infraorder", TaxonomicRank::Infraorder),
+    ("parvorder", TaxonomicRank::Parvorder),
+    ("epifamily", TaxonomicRank::Epifamily),
+    ("superfamily", TaxonomicRank::Superfamily),
+    ("family", TaxonomicRank::Family),
+    ("subfamily", TaxonomicRank::Subfamily),
+    ("supertribe", TaxonomicRank::Supertribe),
+    ("tribe", TaxonomicRank::Tribe),
+    ("subtribe", TaxonomicRank::Subtribe),
+    ("genus", TaxonomicRank::Genus),
+    ("subgenus", TaxonomicRank::Subgenus),
+    ("species", TaxonomicRank::Species),
+    ("subspecies", TaxonomicRank::Subspecies),
+    ("variety", TaxonomicRank::Variety),
+    ("subvariety", TaxonomicRank::Subvariety),
+    ("natio", TaxonomicRank::Natio),
+    ("mutatio", TaxonomicRank::Mutatio),
+    ("unranked", TaxonomicRank::Unranked),
+    ("higher taxon", TaxonomicRank::HigherTaxon),
+    ("aggregate genera", TaxonomicRank::AggregateGenera),
+    ("aggregate species", TaxonomicRank::AggregateSpecies),
+    ("supercohort", TaxonomicRank::Supercohort),
+    ("cohort", TaxonomicRank::Cohort),
+    ("subcohort", TaxonomicRank::Subcohort),
+    ("division", TaxonomicRank::Division),
+    ("phylum (division)", TaxonomicRank::Division),
+    ("incertae sedis", TaxonomicRank::IncertaeSedis),
+    ("infragenus", TaxonomicRank::Infragenus),
+    ("section", TaxonomicRank::Section),
+    ("subsection", TaxonomicRank::Subsection),
+    ("subdivision", TaxonomicRank::Subdivision),
+    ("subphylum (subdivision)", TaxonomicRank::Subdivision),
+
+    ("regnum", TaxonomicRank::Regnum),
+    ("familia", TaxonomicRank::Familia),
+    ("classis", TaxonomicRank::Classis),
+    ("ordo", TaxonomicRank::Ordo),
+    ("varietas", TaxonomicRank::Varietas),
+    ("forma", TaxonomicRank::Forma),
+    ("subforma", TaxonomicRank::Subforma),
+    ("subclassis", TaxonomicRank::Subclassis),
+    ("superordo", TaxonomicRank::Superordo),
+    ("sectio", TaxonomicRank::Sectio),
+    ("subsectio", TaxonomicRank::Subsectio),
+    ("nothovarietas", TaxonomicRank::Nothovarietas),
+    ("subvarietas", TaxonomicRank::Subvarietas),
+    ("series", TaxonomicRank::Series),
+    ("subseries", TaxonomicRank::Subseries),
+    ("superspecies", TaxonomicRank::Superspecies),
+    ("infraspecies", TaxonomicRank::Infraspecies),
+    ("subfamilia", TaxonomicRank::Subfamilia),
+    ("subordo", TaxonomicRank::Subordo),
+    ("regio", TaxonomicRank::Regio),
+    ("special form", TaxonomicRank::SpecialForm),
+
+    ("form", TaxonomicRank::Forma),
+    ("subform", TaxonomicRank::Subforma),
+    ("section zoology", TaxonomicRank::Section),
+    ("subsection zoology", TaxonomicRank::Subsection),
+    ("division zoology", TaxonomicRank::Division),
+    ("section botany", TaxonomicRank::Sectio),
+    ("subsection botany", TaxonomicRank::Subsectio),
+    ("nothovariety", TaxonomicRank::Nothovarietas),
+    ("forma specialis", TaxonomicRank::SpecialForm),
+    ("pathovar", TaxonomicRank::Pathovar),
+    ("serovar", TaxonomicRank::Serovar),
+    ("biovar", TaxonomicRank::Biovar),
+    ("species aggregate", TaxonomicRank::AggregateSpecies),
+    ("infraspecific name", TaxonomicRank::Infraspecies),
+    ("other", TaxonomicRank::Unranked),
+
+    ("unplaced to", TaxonomicRank::Unranked),
+    ("", TaxonomicRank::Unranked),
+];
+
 pub fn str_to_taxonomic_rank(value: &str) -> Result<TaxonomicRank, ParseError> {
-    use TaxonomicRank::*;
-
-    match value.to_lowercase().as_str() {
-        "domain" => Ok(Domain),
-        "superkingdom" => Ok(Superkingdom),
-        "kingdom" => Ok(Kingdom),
-        "subkingdom" => Ok(Subkingdom),
-        "infrakingdom" => Ok(Infrakingdom),
-        "superphylum" => Ok(Superphylum),
-        "phylum" => Ok(Phylum),
-        "subphylum" => Ok(Subphylum),
-        "infraphylum" => Ok(Infraphylum),
-        "parvphylum" => Ok(Parvphylum),
-        "gigaclass" => Ok(Gigaclass),
-        "megaclass" => Ok(Megaclass),
-        "superclass" => Ok(Superclass),
-        "class" => Ok(Class),
-        "subclass" => Ok(Subclass),
-        "infraclass" => Ok(Infraclass),
-        "subterclass" => Ok(Subterclass),
-        "superorder" => Ok(Superorder),
-        "order" => Ok(Order),
-        "hyporder" => Ok(Hyporder),
-        "minorder" => Ok(Minorder),
-        "suborder" => Ok(Suborder),
-        "infraorder" => Ok(Infraorder),
-        "parvorder" => Ok(Parvorder),
-        "epifamily" => Ok(Epifamily),
-        "superfamily" => Ok(Superfamily),
-        "family" => Ok(Family),
-        "subfamily" => Ok(Subfamily),
-        "supertribe" => Ok(Supertribe),
-        "tribe" => Ok(Tribe),
-        "subtribe" => Ok(Subtribe),
-        "genus" => Ok(Genus),
-        "subgenus" => Ok(Subgenus),
-        "species" => Ok(Species),
-        "subspecies" => Ok(Subspecies),
-        "variety" => Ok(Variety),
-        "subvariety" => Ok(Subvariety),
-        "natio" => Ok(Natio),
-        "mutatio" => Ok(Mutatio),
-        "unranked" => Ok(Unranked),
-        "higher taxon" => Ok(HigherTaxon),
-        "aggregate genera" => Ok(AggregateGenera),
-        "aggregate species" => Ok(AggregateSpecies),
-        "supercohort" => Ok(Supercohort),
-        "cohort" => Ok(Cohort),
-        "subcohort" => Ok(Subcohort),
-        "division" => Ok(Division),
-        "phylum (division)" => Ok(Division),
-        "incertae sedis" => Ok(IncertaeSedis),
-        "infragenus" => Ok(Infragenus),
-        "section" => Ok(Section),
-        "subsection" => Ok(Subsection),
-        "subdivision" => Ok(Subdivision),
-        "subphylum (subdivision)" => Ok(Subdivision),
-
-        "regnum" => Ok(Regnum),
-        "familia" => Ok(Familia),
-        "classis" => Ok(Classis),
-        "ordo" => Ok(Ordo),
-        "varietas" => Ok(Varietas),
-        "forma" => Ok(Forma),
-        "subforma" => Ok(Subforma),
-        "subclassis" => Ok(Subclassis),
-        "superordo" => Ok(Superordo),
-        "sectio" => Ok(Sectio),
-        "subsectio" => Ok(Subsectio),
-        "nothovarietas" => Ok(Nothovarietas),
-        "subvarietas" => Ok(Subvarietas),
-        "series" => Ok(Series),
-        "subseries" => Ok(Subseries),
-        "superspecies" => Ok(Superspecies),
-        "infraspecies" => Ok(Infraspecies),
-        "subfamilia" => Ok(Subfamilia),
-        "subordo" => Ok(Subordo),
-        "regio" => Ok(Regio),
-        "special form" => Ok(SpecialForm),
-
-        "form" => Ok(Forma),
-        "subform" => Ok(Subforma),
-        "section zoology" => Ok(Section),
-        "subsection zoology" => Ok(Subsection),
-        "division zoology" => Ok(Division),
-        "section botany" => Ok(Sectio),
-        "subsection botany" => Ok(Subsectio),
-        "nothovariety" => Ok(Nothovarietas),
-        "forma specialis" => Ok(SpecialForm),
-        "pathovar" => Ok(Pathovar),
-        "serovar" => Ok(Serovar),
-        "biovar" => Ok(Biovar),
-        "species aggregate" => Ok(AggregateSpecies),
-        "infraspecific name" => Ok(Infraspecies),
-        "other" => Ok(Unranked),
-
-        "unplaced to" => Ok(Unranked),
-        "" => Ok(Unranked),
-
-        val => Err(ParseError::InvalidValue(val.to_string())),
-    }
+    lookup_term(TAXONOMIC_RANKS, value)
 }
 
+/// The accepted values of a taxonomic status, matched case insensitively
+pub const TAXONOMIC_STATUSES: &[(&str, TaxonomicStatus)] = &[
+    ("valid", TaxonomicStatus::Accepted),
+    ("valid name", TaxonomicStatus::Accepted),
+    ("accepted", TaxonomicStatus::Accepted),
+    ("accepted name", TaxonomicStatus::Accepted),
+    ("provisionally accepted", TaxonomicStatus::Accepted),
+
+    ("undescribed", TaxonomicStatus::Undescribed),
+    ("species inquirenda", TaxonomicStatus::SpeciesInquirenda),
+    ("taxon inquirendum", TaxonomicStatus::TaxonInquirendum),
+    ("manuscript name", TaxonomicStatus::ManuscriptName),
+    ("hybrid", TaxonomicStatus::Hybrid),
+
+    ("unassessed", TaxonomicStatus::Unassessed),
+    ("unavailable name", TaxonomicStatus::Unavailable),
+    ("uncertain", TaxonomicStatus::Uncertain),
+    ("unjustified emendation", TaxonomicStatus::UnjustifiedEmendation),
+
+    ("synonym", TaxonomicStatus::Synonym),
+    ("junior synonym", TaxonomicStatus::Synonym),
+    ("junior objective synonym", TaxonomicStatus::Synonym),
+    ("junior subjective synonym", TaxonomicStatus::Synonym),
+    ("later synonym", TaxonomicStatus::Synonym),
+    ("ambiguous synonym", TaxonomicStatus::Synonym),
+
+    ("homonym", TaxonomicStatus::Homonym),
+    ("junior homonym", TaxonomicStatus::Homonym),
+    ("unreplaced junior homonym", TaxonomicStatus::Homonym),
+
+    ("invalid", TaxonomicStatus::Unaccepted),
+    ("invalid name", TaxonomicStatus::Unaccepted),
+    ("unaccepted", TaxonomicStatus::Unaccepted),
+    ("unaccepted name", TaxonomicStatus::Unaccepted),
+    ("informal", TaxonomicStatus::Informal),
+    ("informal name", TaxonomicStatus::Informal),
+
+    ("placeholder", TaxonomicStatus::Placeholder),
+    ("temporary name", TaxonomicStatus::Placeholder),
+
+    ("basionym", TaxonomicStatus::Basionym),
+    ("nomenclatural synonym", TaxonomicStatus::NomenclaturalSynonym),
+    ("taxonomic synonym", TaxonomicStatus::TaxonomicSynonym),
+    ("replaced synonym", TaxonomicStatus::ReplacedSynonym),
+
+    ("incorrect original spelling", TaxonomicStatus::Misspelled),
+    ("misspelling", TaxonomicStatus::Misspelled),
+
+    ("orthographic variant", TaxonomicStatus::OrthographicVariant),
+    ("excluded", TaxonomicStatus::Excluded),
+
+    ("misapplied", TaxonomicStatus::Misapplied),
+    ("misapplication", TaxonomicStatus::Misapplied),
+    ("unsourced misapplied", TaxonomicStatus::Misapplied),
+    ("alternative name", TaxonomicStatus::AlternativeName),
+    ("alternative representation", TaxonomicStatus::AlternativeName),
+
+    ("pro parte misapplied", TaxonomicStatus::ProParteMisapplied),
+    ("unsourced pro parte misapplied", TaxonomicStatus::ProParteMisapplied),
+    ("pro parte taxonomic synonym", TaxonomicStatus::ProParteTaxonomicSynonym),
+
+    ("doubtful misapplied", TaxonomicStatus::DoubtfulMisapplied),
+    ("unsourced doubtful misapplied", TaxonomicStatus::DoubtfulMisapplied),
+    ("doubtful taxonomic synonym", TaxonomicStatus::DoubtfulTaxonomicSynonym),
+    ("doubtful pro parte misapplied", TaxonomicStatus::DoubtfulProParteMisapplied),
+    ("doubtful pro parte taxonomic synonym", TaxonomicStatus::DoubtfulProParteTaxonomicSynonym),
+
+    ("nomen dubium", TaxonomicStatus::NomenDubium),
+    ("nomen nudum", TaxonomicStatus::NomenNudum),
+    ("nomen oblitum", TaxonomicStatus::NomenOblitum),
+
+    ("interim unpublished", TaxonomicStatus::InterimUnpublished),
+    ("superseded combination", TaxonomicStatus::SupersededCombination),
+    ("superseded rank", TaxonomicStatus::SupersededRank),
+    (
+        "incorrect grammatical agreement of specific epithet",
+        TaxonomicStatus::IncorrectGrammaticalAgreementOfSpecificEpithet,
+    ),
+];
+
 pub fn str_to_taxonomic_status(value: &str) -> Result<TaxonomicStatus, ParseError> {
-    use TaxonomicStatus::*;
-
-    match value.to_lowercase().as_str() {
-        "valid" => Ok(Accepted),
-        "valid name" => Ok(Accepted),
-        "accepted" => Ok(Accepted),
-        "accepted name" => Ok(Accepted),
-        "provisionally accepted" => Ok(Accepted),
-
-        "undescribed" => Ok(Undescribed),
-        "species inquirenda" => Ok(SpeciesInquirenda),
-        "taxon inquirendum" => Ok(TaxonInquirendum),
-        "manuscript name" => Ok(ManuscriptName),
-        "hybrid" => Ok(Hybrid),
-
-        "unassessed" => Ok(Unassessed),
-        "unavailable name" => Ok(Unavailable),
-        "uncertain" => Ok(Uncertain),
-        "unjustified emendation" => Ok(UnjustifiedEmendation),
-
-        "synonym" => Ok(Synonym),
-        "junior synonym" => Ok(Synonym),
-        "junior objective synonym" => Ok(Synonym),
-        "junior subjective synonym" => Ok(Synonym),
-        "later synonym" => Ok(Synonym),
-        "ambiguous synonym" => Ok(Synonym),
-
-        "homonym" => Ok(Homonym),
-        "junior homonym" => Ok(Homonym),
-        "unreplaced junior homonym" => Ok(Homonym),
-
-        "invalid" => Ok(Unaccepted),
-        "invalid name" => Ok(Unaccepted),
-        "unaccepted" => Ok(Unaccepted),
-        "unaccepted name" => Ok(Unaccepted),
-        // "excluded" => Ok(Unaccepted),
-        "informal" => Ok(Informal),
-        "informal name" => Ok(Informal),
-
-        "placeholder" => Ok(Placeholder),
-        "temporary name" => Ok(Placeholder),
-
-        "basionym" => Ok(Basionym),
-        "nomenclatural synonym" => Ok(NomenclaturalSynonym),
-        "taxonomic synonym" => Ok(TaxonomicSynonym),
-        "replaced synonym" => Ok(ReplacedSynonym),
-
-        "incorrect original spelling" => Ok(Misspelled),
-        "misspelling" => Ok(Misspelled),
-
-        "orthographic variant" => Ok(OrthographicVariant),
-        "excluded" => Ok(Excluded),
-
-        "misapplied" => Ok(Misapplied),
-        "misapplication" => Ok(Misapplied),
-        "unsourced misapplied" => Ok(Misapplied),
-        "alternative name" => Ok(AlternativeName),
-        "alternative representation" => Ok(AlternativeName),
-
-        "pro parte misapplied" => Ok(ProParteMisapplied),
-        "unsourced pro parte misapplied" => Ok(ProParteMisapplied),
-        "pro parte taxonomic synonym" => Ok(ProParteTaxonomicSynonym),
-
-        "doubtful misapplied" => Ok(DoubtfulMisapplied),
-        "unsourced doubtful misapplied" => Ok(DoubtfulMisapplied),
-        "doubtful taxonomic synonym" => Ok(DoubtfulTaxonomicSynonym),
-        "doubtful pro parte misapplied" => Ok(DoubtfulProParteMisapplied),
-        "doubtful pro parte taxonomic synonym" => Ok(DoubtfulProParteTaxonomicSynonym),
-
-        "nomen dubium" => Ok(NomenDubium),
-        "nomen nudum" => Ok(NomenNudum),
-        "nomen oblitum" => Ok(NomenOblitum),
-
-        "interim unpublished" => Ok(InterimUnpublished),
-        "superseded combination" => Ok(SupersededCombination),
-        "superseded rank" => Ok(SupersededRank),
-        "incorrect grammatical agreement of specific epithet" => Ok(IncorrectGrammaticalAgreementOfSpecificEpithet),
-
-        val => Err(ParseError::InvalidValue(val.to_string())),
-    }
+    lookup_term(TAXONOMIC_STATUSES, value)
 }
 
+/// The accepted values of a nomenclatural act type, matched case insensitively
+pub const NOMENCLATURAL_ACTS: &[(&str, NomenclaturalActType)] = &[
+    ("species_nova", NomenclaturalActType::SpeciesNova),
+    ("subspecies_nova", NomenclaturalActType::SubspeciesNova),
+    ("genus_species_nova", NomenclaturalActType::GenusSpeciesNova),
+    ("combinatio_nova", NomenclaturalActType::CombinatioNova),
+    ("revived_status", NomenclaturalActType::RevivedStatus),
+    ("name_usage", NomenclaturalActType::NameUsage),
+    ("names usage", NomenclaturalActType::NameUsage),
+    ("new_species", NomenclaturalActType::SpeciesNova),
+    ("genus_transfer", NomenclaturalActType::CombinatioNova),
+    ("subgenus_placement", NomenclaturalActType::SubgenusPlacement),
+    ("original description", NomenclaturalActType::OriginalDescription),
+    ("redescription", NomenclaturalActType::Redescription),
+    ("demotion", NomenclaturalActType::Demotion),
+    ("promotion", NomenclaturalActType::Promotion),
+    ("synonymisation", NomenclaturalActType::Synonymisation),
+    ("heterotypic synonymy", NomenclaturalActType::HeterotypicSynonymy),
+    ("homotypic synonymy", NomenclaturalActType::HomotypicSynonymy),
+];
+
 pub fn str_to_nomenclatural_act(value: &str) -> Result<NomenclaturalActType, ParseError> {
-    use NomenclaturalActType::*;
-
-    match value.to_lowercase().as_str() {
-        "species_nova" => Ok(SpeciesNova),
-        "subspecies_nova" => Ok(SubspeciesNova),
-        "genus_species_nova" => Ok(GenusSpeciesNova),
-        "combinatio_nova" => Ok(CombinatioNova),
-        "revived_status" => Ok(RevivedStatus),
-        "name_usage" => Ok(NameUsage),
-        "names usage" => Ok(NameUsage),
-        "new_species" => Ok(SpeciesNova),
-        "genus_transfer" => Ok(CombinatioNova),
-        "subgenus_placement" => Ok(SubgenusPlacement),
-        "original description" => Ok(OriginalDescription),
-        "redescription" => Ok(Redescription),
-        "demotion" => Ok(Demotion),
-        "promotion" => Ok(Promotion),
-        "synonymisation" => Ok(Synonymisation),
-        "heterotypic synonymy" => Ok(HeterotypicSynonymy),
-        "homotypic synonymy" => Ok(HomotypicSynonymy),
-
-        val => Err(ParseError::InvalidValue(val.to_string())),
+    lookup_term(NOMENCLATURAL_ACTS, value)
+}
+
+/// Find the variant of a term in a table of accepted values, ignoring case
+fn lookup_term<T: Clone>(terms: &[(&str, T)], value: &str) -> Result<T, ParseError> {
+    let value = value.to_lowercase();
+    match terms.iter().find(|(term, _)| *term == value) {
+        Some((_, variant)) => Ok(variant.clone()),
+        None => Err(ParseError::InvalidValue(value)),
     }
 }
 
@@ -452,17 +556,18 @@ where
     str_to_data_reuse_status(&s).map_err(serde::de::Error::custom)
 }
 
-pub fn str_to_data_reuse_status(value: &str) -> Result<Option<DataReuseStatus>, ParseError> {
-    // use DataReuseStatus::*;
-
-    match value.to_lowercase().as_str() {
-        "limited" => Ok(Some(DataReuseStatus::Limited)),
-        "unlimited" => Ok(Some(DataReuseStatus::Unlimited)),
-        "none" => Ok(Some(DataReuseStatus::None)),
-        "variable" => Ok(Some(DataReuseStatus::Variable)),
-        "" => Ok(None),
+/// The accepted values of a data reuse status, matched case insensitively
+pub const DATA_REUSE_STATUSES: &[(&str, DataReuseStatus)] = &[
+    ("limited", DataReuseStatus::Limited),
+    ("unlimited", DataReuseStatus::Unlimited),
+    ("none", DataReuseStatus::None),
+    ("variable", DataReuseStatus::Variable),
+];
 
-        val => Err(ParseError::InvalidValue(val.to_string())),
+pub fn str_to_data_reuse_status(value: &str) -> Result<Option<DataReuseStatus>, ParseError> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => lookup_term(DATA_REUSE_STATUSES, value).map(Some),
     }
 }
 
@@ -474,17 +579,18 @@ where
     str_to_access_pill_status(&s).map_err(serde::de::Error::custom)
 }
 
-pub fn str_to_access_pill_status(value: &str) -> Result<Option<AccessRightsStatus>, ParseError> {
-    use AccessRightsStatus::*;
-
-    match value.to_lowercase().as_str() {
-        "open" => Ok(Some(Open)),
-        "restricted" => Ok(Some(Restricted)),
-        "conditional" => Ok(Some(Conditional)),
-        "variable" => Ok(Some(Variable)),
-        "" => Ok(None),
+/// The accepted values of an access rights status, matched case insensitively
+pub const ACCESS_RIGHTS_STATUSES: &[(&str, AccessRightsStatus)] = &[
+    ("open", AccessRightsStatus::Open),
+    ("restricted", AccessRightsStatus::Restricted),
+    ("conditional", AccessRightsStatus::Conditional),
+    ("variable", AccessRightsStatus::Variable),
+];
 
-        val => Err(ParseError::InvalidValue(val.to_string())),
+pub fn str_to_access_pill_status(value: &str) -> Result<Option<AccessRightsStatus>, ParseError> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => lookup_term(ACCESS_RIGHTS_STATUSES, value).map(Some),
     }
 }
 
@@ -496,22 +602,377 @@ where
     str_to_content_type(&s).map_err(serde::de::Error::custom)
 }
 
+/// The accepted values of a source content type, matched case insensitively
+pub const CONTENT_TYPES: &[(&str, SourceContentType)] = &[
+    ("taxonomic backbone", SourceContentType::TaxonomicBackbone),
+    ("ecological traits", SourceContentType::EcologicalTraits),
+    ("genomic data", SourceContentType::GenomicData),
+    ("specimens", SourceContentType::Specimens),
+    ("non-genomic data", SourceContentType::NongenomicData),
+    ("morphological traits", SourceContentType::MorphologicalTraits),
+    ("biochemical traits", SourceContentType::BiochemicalTraits),
+    ("mixed datatypes", SourceContentType::MixedDatatypes),
+    ("functional traits", SourceContentType::FunctionalTraits),
+    ("ethnobiology", SourceContentType::Ethnobiology),
+    ("ethnobiological traits", SourceContentType::Ethnobiology),
+];
+
 pub fn str_to_content_type(value: &str) -> Result<Option<SourceContentType>, ParseError> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => lookup_term(CONTENT_TYPES, value).map(Some),
+    }
+}
+
+pub fn geodetic_datum_from_str_opt<'de, D>(deserializer: D) -> Result<Option<GeodeticDatum>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    str_to_geodetic_datum(&s.unwrap_or_default()).map_err(serde::de::Error::custom)
+}
+
+pub fn str_to_geodetic_datum(value: &str) -> Result<Option<GeodeticDatum>, ParseError> {
+    match value.trim().to_lowercase().as_str() {
+        "" | "unknown" | "not recorded" => Ok(None),
+        _ => value.parse().map(Some),
+    }
+}
+
+
+/// Deserialize an optional string, treating empty and whitespace only values as missing.
+///
+/// Blank cells in spreadsheets and some CSV exports come through as empty strings, which
+/// would otherwise be logged as atoms without a value and show up as changes between
+/// dataset versions. Any other value is kept verbatim.
+pub fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    Ok(s.filter(|value| !value.trim().is_empty()))
+}
+
+
+/// Deserialize an optional free-text value that a provider can also record as unknown.
+///
+/// Values are missing in the same way as `empty_as_none`. The sentinels for unknown values are
+/// emptied before the row is deserialized and the reader marks the field as unknown afterwards.
+pub fn recorded_from_str<'de, D>(deserializer: D) -> Result<Option<Recorded>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(empty_as_none(deserializer)?.map(Recorded::Value))
+}
+
+
+pub fn doi_from_str<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    match s.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => parse_doi(value).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parse a DOI into its bare `10.<registrant>/<suffix>` form.
+///
+/// DOIs are often written as resolver links or with a `doi:` prefix so those are stripped
+/// before the syntax is checked. The registrant code must be numeric and the suffix can be
+/// anything printable, which is as much as the DOI handbook guarantees.
+pub fn parse_doi(value: &str) -> Result<String, ParseError> {
+    let doi = value.trim();
+    let doi = [
+        "https://doi.org/",
+        "http://doi.org/",
+        "https://dx.doi.org/",
+        "http://dx.doi.org/",
+        "doi:",
+    ]
+    .iter()
+    .find_map(|prefix| doi.strip_prefix(prefix))
+    .unwrap_or(doi);
+
+    let invalid = || ParseError::InvalidValue(value.to_string());
+    let (prefix, suffix) = doi.split_once('/').ok_or_else(invalid)?;
+    let registrant = prefix.strip_prefix("10.").ok_or_else(invalid)?;
+
+    let valid_registrant = !registrant.is_empty()
+        && registrant
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    let valid_suffix = !suffix.is_empty() && !suffix.chars().any(|c| c.is_whitespace() || c.is_control());
+
+    if valid_registrant && valid_suffix {
+        Ok(doi.to_string())
+    }
+    else {
+        Err(invalid())
+    }
+}
+
+
+/// Set the separators used to split list columns into individual values.
+///
+/// This should be called once at startup. Until it is called lists are split on
+/// semicolons and pipes, which covers the Darwin Core recommendation and most providers.
+pub fn set_list_separators(separators: Vec<String>) {
+    let separators = separators.into_iter().filter(|sep| !sep.is_empty()).collect();
+    let _ = LIST_SEPARATORS.set(separators);
+}
+
+/// Split a list column into its trimmed values, dropping any empty values
+pub fn split_list(value: &str) -> Vec<String> {
+    let separators = LIST_SEPARATORS.get_or_init(|| vec![";".to_string(), "|".to_string()]);
+
+    let mut values = vec![value.to_string()];
+    for separator in separators {
+        values = values
+            .iter()
+            .flat_map(|value| value.split(separator.as_str()))
+            .map(String::from)
+            .collect();
+    }
+
+    values
+        .into_iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+pub fn list_from_str<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    Ok(s.map(|s| split_list(&s)).unwrap_or_default())
+}
+
+/// Encode a list of values into a single atom value.
+///
+/// Atoms in arga_core only hold strings so lists are stored as a JSON array. This keeps
+/// the individual values intact even when they contain a separator, such as a comma
+/// between a family name and initials. A single value is stored verbatim, the same as it
+/// was logged before lists were encoded, so that re-importing a specimen with one collector
+/// doesn't log a change to an atom that hasn't changed.
+pub fn encode_list(values: &[String]) -> String {
+    match values {
+        [value] if !value.starts_with('[') => value.clone(),
+        values => serde_json::to_string(values).expect("a list of strings is always valid json"),
+    }
+}
+
+/// Decode an atom value created by `encode_list`.
+///
+/// Operations logged before lists were encoded hold the verbatim column value, so
+/// anything that isn't a JSON array is split with the configured separators instead.
+pub fn decode_list(value: &str) -> Vec<String> {
+    match serde_json::from_str::<Vec<String>>(value) {
+        Ok(values) => values,
+        Err(_) => split_list(value),
+    }
+}
+
+
+/// The inverse of `str_to_data_reuse_status` so that exported values can be imported again
+pub fn data_reuse_status_to_str(value: &DataReuseStatus) -> &'static str {
+    match value {
+        DataReuseStatus::Limited => "limited",
+        DataReuseStatus::Unlimited => "unlimited",
+        DataReuseStatus::None => "none",
+        DataReuseStatus::Variable => "variable",
+    }
+}
+
+/// The inverse of `str_to_access_pill_status` so that exported values can be imported again
+pub fn access_pill_status_to_str(value: &AccessRightsStatus) -> &'static str {
+    use AccessRightsStatus::*;
+
+    match value {
+        Open => "open",
+        Restricted => "restricted",
+        Conditional => "conditional",
+        Variable => "variable",
+    }
+}
+
+/// The inverse of `str_to_content_type` so that exported values can be imported again
+pub fn content_type_to_str(value: &SourceContentType) -> &'static str {
     use SourceContentType::*;
 
-    match value.to_lowercase().as_str() {
-        "taxonomic backbone" => Ok(Some(TaxonomicBackbone)),
-        "ecological traits" => Ok(Some(EcologicalTraits)),
-        "genomic data" => Ok(Some(GenomicData)),
-        "specimens" => Ok(Some(Specimens)),
-        "non-genomic data" => Ok(Some(NongenomicData)),
-        "morphological traits" => Ok(Some(MorphologicalTraits)),
-        "biochemical traits" => Ok(Some(BiochemicalTraits)),
-        "mixed datatypes" => Ok(Some(MixedDatatypes)),
-        "functional traits" => Ok(Some(FunctionalTraits)),
-        "ethnobiology" => Ok(Some(Ethnobiology)),
-        "ethnobiological traits" => Ok(Some(Ethnobiology)),
-        "" => Ok(None),
-        val => Err(ParseError::InvalidValue(val.to_string())),
+    match value {
+        TaxonomicBackbone => "taxonomic backbone",
+        EcologicalTraits => "ecological traits",
+        GenomicData => "genomic data",
+        Specimens => "specimens",
+        NongenomicData => "non-genomic data",
+        MorphologicalTraits => "morphological traits",
+        BiochemicalTraits => "biochemical traits",
+        MixedDatatypes => "mixed datatypes",
+        FunctionalTraits => "functional traits",
+        Ethnobiology => "ethnobiology",
+    }
+}
+
+
+/// Hash the content of a file, or of every file in a directory.
+///
+/// Directory entries are hashed in the order of their paths along with the path relative
+/// to the directory, so that moving content between files changes the hash as well.
+pub fn content_hash(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Xxh3::new();
+
+    if path.is_dir() {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+        files.sort();
+
+        for file in files {
+            let relative = file.strip_prefix(path).unwrap_or(&file);
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update(&[0]);
+            hash_file(&file, &mut hasher)?;
+        }
+    }
+    else {
+        hash_file(path, &mut hasher)?;
+    }
+
+    Ok(format!("{:032x}", hasher.digest128()))
+}
+
+fn hash_file(path: &Path, hasher: &mut Xxh3) -> std::io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 1 << 20];
+
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        }
+        else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+
+/// Compare dataset versions in their natural order.
+///
+/// Versions are free-form strings like `v4`, `20240102` or `1.10.2`, so runs of digits are
+/// compared by their number and everything else by its characters. This orders `v10` after
+/// `v9` where a plain string comparison wouldn't. A version that continues past another is
+/// newer, eg. `1.2.1` after `1.2`, unless it continues with a `-` suffix, which marks a
+/// prerelease like `1.2-rc1` that comes before `1.2`.
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let mut a = version_parts(a).into_iter();
+    let mut b = version_parts(b).into_iter();
+
+    loop {
+        let ordering = match (a.next(), b.next()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (None, Some(part)) if part.is_prerelease() => return std::cmp::Ordering::Greater,
+            (Some(part), None) if part.is_prerelease() => return std::cmp::Ordering::Less,
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(VersionPart::Number(x)), Some(VersionPart::Number(y))) => x.cmp(&y),
+            (Some(x), Some(y)) => x.to_string().cmp(&y.to_string()),
+        };
+
+        if ordering.is_ne() {
+            return ordering;
+        }
+    }
+}
+
+enum VersionPart {
+    Number(u128),
+    Text(String),
+}
+
+impl VersionPart {
+    fn is_prerelease(&self) -> bool {
+        matches!(self, VersionPart::Text(text) if text.starts_with('-'))
+    }
+}
+
+impl std::fmt::Display for VersionPart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionPart::Number(number) => write!(f, "{number}"),
+            VersionPart::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+fn version_parts(version: &str) -> Vec<VersionPart> {
+    let mut parts = Vec::new();
+    let mut chars = version.chars().peekable();
+
+    while let Some(ch) = chars.peek().copied() {
+        let digit = ch.is_ascii_digit();
+        let mut part = String::new();
+        while let Some(ch) = chars.peek().copied() {
+            if ch.is_ascii_digit() != digit {
+                break;
+            }
+            part.push(ch);
+            chars.next();
+        }
+
+        match part.parse() {
+            Ok(number) if digit => parts.push(VersionPart::Number(number)),
+            _ => parts.push(VersionPart::Text(part)),
+        }
+    }
+
+    parts
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn compare_versions_orders_numbers_by_value() {
+        assert_eq!(compare_versions("v9", "v10"), Ordering::Less);
+        assert_eq!(compare_versions("1.10.2", "1.9.2"), Ordering::Greater);
+        assert_eq!(compare_versions("20240102", "20231231"), Ordering::Greater);
+        assert_eq!(compare_versions("v4", "v4"), Ordering::Equal);
+        assert_eq!(compare_versions("1.02", "1.2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn compare_versions_orders_prereleases_before_releases() {
+        assert_eq!(compare_versions("1.2-rc1", "1.2"), Ordering::Less);
+        assert_eq!(compare_versions("1.2", "1.2-rc1"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2-rc1", "1.2-rc2"), Ordering::Less);
+        assert_eq!(compare_versions("1.2-beta", "1.2-alpha"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2-rc1", "1.1"), Ordering::Greater);
+    }
+
+    #[test]
+    fn compare_versions_orders_longer_versions_after_their_prefix() {
+        assert_eq!(compare_versions("1.2", "1.2.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.2.0", "1.2"), Ordering::Greater);
+        assert_eq!(compare_versions("1.3", "1.2.9"), Ordering::Greater);
+        assert_eq!(compare_versions("", "v1"), Ordering::Less);
     }
 }
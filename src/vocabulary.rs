@@ -0,0 +1,50 @@
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::*;
+use serde::Serialize;
+use tracing::info;
+
+use crate::database::PgPool;
+use crate::errors::{Error, ParseError};
+use crate::maintenance::LOG_TABLES;
+
+
+/// A distinct value of an atom and how often it was logged
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct VocabularyTerm {
+    #[diesel(sql_type = Nullable<Text>)]
+    pub value: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    pub operations: i64,
+    #[diesel(sql_type = BigInt)]
+    pub entities: i64,
+}
+
+
+/// Aggregate the distinct values of an atom across every operation in a log table.
+///
+/// Free-text fields like the preparation of a specimen are logged verbatim, so counting the
+/// values shows which fields have a small enough vocabulary to become a controlled enum and
+/// which variants need a mapping first. Values are compared exactly, including case and
+/// whitespace, since those are the differences a mapping has to cover. Every operation is
+/// counted, not only the winning ones, so values that were later corrected still show up.
+pub fn analyze(pool: &PgPool, table: &str, atom: &str) -> Result<Vec<VocabularyTerm>, Error> {
+    // the table name can't be bound as a parameter so only known log tables are allowed
+    if !LOG_TABLES.contains(&table) {
+        let tables = LOG_TABLES.join(", ");
+        return Err(ParseError::InvalidValue(format!("unknown log table {table}, expected one of {tables}")).into());
+    }
+
+    let mut conn = pool.get()?;
+    let terms = sql_query(format!(
+        "SELECT atom ->> $1 AS value, count(*) AS operations, count(DISTINCT entity_id) AS entities
+         FROM {table}
+         WHERE atom ? $1
+         GROUP BY value
+         ORDER BY operations DESC, value"
+    ))
+    .bind::<Text, _>(atom)
+    .load::<VocabularyTerm>(&mut conn)?;
+
+    info!(table, atom, distinct = terms.len(), "Analyzed atom vocabulary");
+    Ok(terms)
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::errors::Error;
+
+
+/// The amount of examples kept for each kind of warning
+const EXAMPLES: usize = 3;
+
+
+/// The warnings logged while running the command, grouped by where they came from and their message
+static WARNINGS: Mutex<Warnings> = Mutex::new(Warnings {
+    categories: BTreeMap::new(),
+    all: None,
+});
+
+struct Warnings {
+    categories: BTreeMap<(String, String), WarningCategory>,
+    all: Option<Vec<Warning>>,
+}
+
+
+/// A kind of warning with the amount of times it was logged
+#[derive(Debug, Clone, Serialize)]
+pub struct WarningCategory {
+    /// The module that logged the warning
+    pub target: String,
+    pub message: String,
+    pub count: usize,
+    /// The fields of the first few warnings, like the entity or name that caused them
+    pub examples: Vec<String>,
+}
+
+
+/// A single logged warning as it is written to the warnings CSV
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    pub target: String,
+    pub message: String,
+    pub fields: String,
+}
+
+
+/// A tracing layer that collects every warning into the command summary.
+///
+/// Warnings like failed links and missing parents don't stop a command, so on a long run
+/// they scroll past among the progress logs and are easy to miss. Each warning is counted
+/// by its module and message, which are static for the warnings logged here with the
+/// details in the fields instead, and the fields of the first few are kept as examples.
+pub struct WarningsLayer;
+
+impl<S: Subscriber> Layer<S> for WarningsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::WARN {
+            return;
+        }
+
+        let mut visitor = WarningVisitor::default();
+        event.record(&mut visitor);
+
+        let warning = Warning {
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields.join(", "),
+        };
+
+        let mut warnings = WARNINGS.lock().expect("warnings poisoned");
+        let category = warnings
+            .categories
+            .entry((warning.target.clone(), warning.message.clone()))
+            .or_insert_with(|| WarningCategory {
+                target: warning.target.clone(),
+                message: warning.message.clone(),
+                count: 0,
+                examples: Vec::new(),
+            });
+
+        category.count += 1;
+        if category.examples.len() < EXAMPLES && !warning.fields.is_empty() {
+            category.examples.push(warning.fields.clone());
+        }
+
+        if let Some(all) = &mut warnings.all {
+            all.push(warning);
+        }
+    }
+}
+
+
+#[derive(Default)]
+struct WarningVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+impl Visit for WarningVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.fields.push(format!("{name}={value}")),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            name => self.fields.push(format!("{name}={value:?}")),
+        }
+    }
+}
+
+
+/// Keep every warning rather than just the counts so that they can be written with `write_csv`
+pub fn keep_all() {
+    let mut warnings = WARNINGS.lock().expect("warnings poisoned");
+    warnings.all.get_or_insert_with(Vec::new);
+}
+
+
+/// The kinds of warnings logged so far, most frequent first
+pub fn summary() -> Vec<WarningCategory> {
+    let warnings = WARNINGS.lock().expect("warnings poisoned");
+    let mut categories: Vec<WarningCategory> = warnings.categories.values().cloned().collect();
+    categories.sort_by(|a, b| b.count.cmp(&a.count));
+    categories
+}
+
+
+/// Print a table of the warnings logged by the command to stderr, if there were any
+pub fn print_summary() {
+    let categories = summary();
+    if categories.is_empty() {
+        return;
+    }
+
+    let width = categories.iter().map(|category| category.message.len()).max().unwrap_or_default();
+    let total: usize = categories.iter().map(|category| category.count).sum();
+
+    eprintln!();
+    eprintln!("{total} warnings");
+    for category in categories {
+        eprintln!("{:>8}  {:width$}  {}", category.count, category.message, category.target);
+        for example in category.examples {
+            eprintln!("{:>8}  {:width$}  {example}", "", "");
+        }
+    }
+}
+
+
+/// Write every warning kept since `keep_all` was called to a CSV
+pub fn write_csv(path: &Path) -> Result<(), Error> {
+    let warnings = WARNINGS.lock().expect("warnings poisoned");
+
+    let mut writer = csv::Writer::from_path(path)?;
+    for warning in warnings.all.iter().flatten() {
+        writer.serialize(warning)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use arga_core::schema::dataset_versions;
+use chrono::{DateTime, Utc};
+use diesel::*;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::database::PgPool;
+use crate::errors::Error;
+use crate::utils::parse_date_time;
+
+
+// watermarks aren't part of arga_core as they are only used by the oplogger to filter
+// incremental file drops. the column is the one in the file the watermark was taken from
+diesel::table! {
+    dataset_watermarks (dataset_id, column_name) {
+        dataset_id -> Uuid,
+        column_name -> Varchar,
+        watermark -> Timestamptz,
+    }
+}
+
+
+/// The latest value of a timestamp column imported from a dataset.
+///
+/// Some providers push a daily file with every row modified since some point, which
+/// overlaps with the files before it. Rows with a value in the column older than the
+/// watermark stored by the last import of the dataset are skipped, and the watermark is
+/// advanced to the latest value imported once the import succeeds. Rows with the same value
+/// as the watermark are imported again since the last file could have been cut off part way
+/// through that timestamp. Rows without a value that parses as a timestamp are always imported.
+///
+/// The watermark never moves past a row whose operations were rejected by the database, so
+/// the rejected rows are imported again by the next file.
+#[derive(Clone)]
+pub struct Watermark {
+    pool: PgPool,
+    dataset_id: Uuid,
+    pub column: String,
+    since: Option<DateTime<Utc>>,
+    state: Arc<Mutex<WatermarkState>>,
+}
+
+#[derive(Default)]
+struct WatermarkState {
+    /// The latest value imported for each entity, kept until the import finishes
+    pending: HashMap<String, DateTime<Utc>>,
+    skipped: usize,
+}
+
+impl Watermark {
+    /// Load the watermark stored for the column by the last import of the dataset the version belongs to
+    pub fn load(pool: PgPool, dataset_version_id: &Uuid, column: &str) -> Result<Watermark, Error> {
+        use dataset_watermarks::dsl;
+
+        let mut conn = pool.get()?;
+        create_dataset_watermarks_table(&mut conn)?;
+
+        let dataset_id = dataset_versions::table
+            .filter(dataset_versions::id.eq(dataset_version_id))
+            .select(dataset_versions::dataset_id)
+            .get_result::<Uuid>(&mut conn)?;
+
+        let since = dsl::dataset_watermarks
+            .filter(dsl::dataset_id.eq(dataset_id))
+            .filter(dsl::column_name.eq(column))
+            .select(dsl::watermark)
+            .get_result::<DateTime<Utc>>(&mut conn)
+            .optional()?;
+
+        info!(column, ?since, "Importing rows since the watermark");
+
+        Ok(Watermark {
+            pool,
+            dataset_id,
+            column: column.to_string(),
+            since,
+            state: Arc::new(Mutex::new(WatermarkState::default())),
+        })
+    }
+
+    /// Returns true if a row with this value in the watermark column should be imported
+    pub fn is_new(&self, value: &str) -> bool {
+        let Ok(timestamp) = parse_date_time(value.trim())
+        else {
+            return true;
+        };
+
+        if self.since.is_some_and(|since| timestamp < since) {
+            self.state.lock().expect("Watermark lock poisoned").skipped += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Queue the value of an imported row until the import finishes
+    pub fn track(&self, entity_id: &str, value: &str) {
+        let Ok(timestamp) = parse_date_time(value.trim())
+        else {
+            return;
+        };
+
+        let mut state = self.state.lock().expect("Watermark lock poisoned");
+        let latest = state.pending.entry(entity_id.to_string()).or_insert(timestamp);
+        *latest = (*latest).max(timestamp);
+    }
+
+    /// Store the latest value imported, but no later than the earliest value of a rejected
+    /// entity. Call this after every frame of the import has been upserted
+    pub fn advance(&self, rejected: &HashSet<String>) -> Result<(), Error> {
+        use dataset_watermarks::dsl::*;
+        use diesel::upsert::excluded;
+
+        let state = self.state.lock().expect("Watermark lock poisoned");
+        info!(
+            column = self.column,
+            since = ?self.since,
+            skipped = state.skipped,
+            "Rows older than the watermark skipped"
+        );
+
+        let (rejected, accepted): (Vec<_>, Vec<_>) =
+            state.pending.iter().partition(|(entity, _)| rejected.contains(*entity));
+
+        let Some(mut latest) = accepted.into_iter().map(|(_, timestamp)| *timestamp).max()
+        else {
+            return Ok(());
+        };
+        if let Some(earliest) = rejected.into_iter().map(|(_, timestamp)| *timestamp).min() {
+            latest = latest.min(earliest);
+        }
+        if self.since.is_some_and(|since| latest <= since) {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(dataset_watermarks)
+            .values((dataset_id.eq(self.dataset_id), column_name.eq(&self.column), watermark.eq(latest)))
+            .on_conflict((dataset_id, column_name))
+            .do_update()
+            .set(watermark.eq(excluded(watermark)))
+            .execute(&mut conn)?;
+
+        info!(column = self.column, watermark = ?latest, "Advanced the dataset watermark");
+        Ok(())
+    }
+}
+
+
+fn create_dataset_watermarks_table(conn: &mut PgConnection) -> Result<(), Error> {
+    sql_query(
+        "CREATE TABLE IF NOT EXISTS dataset_watermarks (
+            dataset_id uuid NOT NULL REFERENCES datasets ON DELETE CASCADE,
+            column_name varchar NOT NULL,
+            watermark timestamptz NOT NULL,
+            PRIMARY KEY (dataset_id, column_name)
+        )",
+    )
+    .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use diesel::sql_types::Text;
+use diesel::{sql_query, Connection, PgConnection, QueryableByName, RunQueryDsl};
+use diesel_migrations::{FileBasedMigrations, MigrationHarness};
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::SyncRunner;
+use testcontainers_modules::testcontainers::Container;
+
+
+/// An ephemeral postgres database with the ARGA schema.
+///
+/// The schema migrations live in the arga-backend repository alongside arga_core so
+/// the ARGA_MIGRATIONS environment variable must point to its `core/migrations` directory.
+/// The container is removed when the database is dropped.
+pub struct TestDatabase {
+    pub url: String,
+    _container: Container<Postgres>,
+}
+
+#[derive(QueryableByName)]
+struct JsonRow {
+    #[diesel(sql_type = Text)]
+    row: String,
+}
+
+impl TestDatabase {
+    pub fn start() -> TestDatabase {
+        let migrations = std::env::var("ARGA_MIGRATIONS").expect(
+            "ARGA_MIGRATIONS must point to the arga-backend core/migrations directory. \
+             The database tests only run with --features db-tests",
+        );
+
+        let container = Postgres::default()
+            .start()
+            .expect("Failed to start the postgres container");
+        let host = container.get_host().expect("Failed to get the container host");
+        let port = container
+            .get_host_port_ipv4(5432)
+            .expect("Failed to get the container port");
+        let url = format!("postgres://postgres:postgres@{host}:{port}/postgres");
+
+        let mut conn = PgConnection::establish(&url).expect("Failed to connect to the test database");
+        let migrations = FileBasedMigrations::from_path(&migrations).expect("Invalid migrations directory");
+        conn.run_pending_migrations(migrations)
+            .expect("Failed to run the ARGA migrations");
+
+        TestDatabase {
+            url,
+            _container: container,
+        }
+    }
+
+    /// Run the oplogger binary against the test database and fail the test if it errors
+    pub fn oplogger(&self, args: &[&str]) -> Output {
+        let output = self.oplogger_unchecked(args);
+
+        if !output.status.success() {
+            panic!("oplogger {} failed\n{}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        }
+
+        output
+    }
+
+    /// Select the columns of a table as a CSV, for the tables that are written without a reducer
+    /// that outputs them. Each row is built as a JSON array so that nulls come out as empty values
+    pub fn select_csv(&self, columns: &[&str], table: &str) -> Vec<u8> {
+        let mut conn = PgConnection::establish(&self.url).expect("Failed to connect to the test database");
+        let rows = sql_query(format!("SELECT json_build_array({})::text AS row FROM {table}", columns.join(", ")))
+            .load::<JsonRow>(&mut conn)
+            .expect("Failed to select the table");
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(columns).expect("Failed to write the header");
+        for row in rows {
+            let values: Vec<Option<String>> = serde_json::from_str(&row.row).expect("Invalid row");
+            writer.serialize(values).expect("Failed to write the row");
+        }
+        writer.into_inner().expect("Failed to write the CSV")
+    }
+
+    /// Run the oplogger binary against the test database without checking its exit status
+    pub fn oplogger_unchecked(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_oplogger"))
+            .args(args)
+            .env("DATABASE_URL", &self.url)
+            .output()
+            .expect("Failed to run oplogger")
+    }
+}
+
+
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+
+/// Build a dataset archive from a fixture directory.
+///
+/// The fixtures are kept as plain CSV files so that they are easy to review, which means
+/// they have to be brotli compressed into the archive layout that the importer expects.
+/// The files are added in name order after the meta.toml file.
+pub fn build_archive(fixture_dir: &Path, output_dir: &Path) -> PathBuf {
+    let path = output_dir.join("archive.tar");
+    let mut builder = tar::Builder::new(File::create(&path).expect("Failed to create archive"));
+
+    builder
+        .append_path_with_name(fixture_dir.join("meta.toml"), "meta.toml")
+        .expect("Failed to add meta.toml");
+
+    let mut csvs: Vec<PathBuf> = std::fs::read_dir(fixture_dir)
+        .expect("Failed to read the fixture directory")
+        .map(|entry| entry.expect("Invalid fixture entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "csv"))
+        .collect();
+    csvs.sort();
+
+    for csv in csvs {
+        let data = std::fs::read(&csv).expect("Failed to read fixture");
+        let mut compressed = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 9, 22);
+            writer.write_all(&data).expect("Failed to compress fixture");
+        }
+
+        let name = format!("{}.br", csv.file_name().unwrap().to_string_lossy());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(compressed.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, compressed.as_slice())
+            .expect("Failed to add fixture");
+    }
+
+    builder.finish().expect("Failed to write archive");
+    path
+}
+
+
+/// Compare a reduced CSV output against a golden file.
+///
+/// Rows are sorted since the reducers don't guarantee an order and UUIDs are masked as
+/// they are generated fresh for every database. A missing golden file fails the test so
+/// that a forgotten golden can't pass silently. Setting UPDATE_GOLDEN=1 writes the new
+/// output to the golden file instead of comparing it.
+pub fn assert_golden(name: &str, output: &[u8]) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(name);
+    let actual = normalize(&String::from_utf8_lossy(output));
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::create_dir_all(path.parent().unwrap()).expect("Failed to create the golden directory");
+        std::fs::write(&path, &actual).expect("Failed to write golden file");
+        eprintln!("wrote golden file {}", path.display());
+        return;
+    }
+
+    if !path.exists() {
+        panic!("golden file {} is missing. rerun with UPDATE_GOLDEN=1 to write it", path.display());
+    }
+
+    let expected = std::fs::read_to_string(&path).expect("Failed to read golden file");
+    assert_eq!(
+        expected, actual,
+        "reduced output differs from {name}. rerun with UPDATE_GOLDEN=1 if the change is intended"
+    );
+}
+
+fn normalize(csv: &str) -> String {
+    let mut lines = csv.lines();
+    let header = lines.next().unwrap_or_default().to_string();
+
+    let mut rows: Vec<String> = lines
+        .map(|line| line.split(',').map(mask_uuid).collect::<Vec<&str>>().join(","))
+        .collect();
+    rows.sort();
+
+    let mut normalized = vec![header];
+    normalized.extend(rows);
+    normalized.join("\n") + "\n"
+}
+
+fn mask_uuid(value: &str) -> &str {
+    let is_uuid = value.len() == 36
+        && value.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+
+    if is_uuid {
+        "<uuid>"
+    }
+    else {
+        value
+    }
+}
@@ -0,0 +1,158 @@
+//! End to end tests of the import, update, and reduce pipelines.
+//!
+//! Each test spins up a fresh postgres container so they need docker and the ARGA
+//! migrations, so they only run with `cargo test --features db-tests`.
+#![cfg(feature = "db-tests")]
+
+mod common;
+
+use common::{assert_golden, build_archive, fixture, TestDatabase};
+
+
+#[test]
+fn taxonomy_pipeline() {
+    let db = TestDatabase::start();
+    let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+    let archive = build_archive(&fixture("taxonomy"), dir.path());
+    let archive = archive.to_str().unwrap();
+
+    db.oplogger(&["import", archive]);
+    db.oplogger(&["update", "taxa"]);
+    db.oplogger(&["update", "taxonomic-acts"]);
+
+    let taxa = db.oplogger(&["reduce", "taxa"]);
+    assert_golden("taxa.csv", &taxa.stdout);
+
+    let consensus = db.oplogger(&["reduce", "taxa", "--consensus", "--precedence", "ARGA:TEST:0001"]);
+    assert_golden("taxa_consensus.csv", &consensus.stdout);
+
+    let acts = db.oplogger(&["reduce", "taxonomic-acts"]);
+    assert_golden("taxonomic_acts.csv", &acts.stdout);
+
+    // names aren't logged so the names from the archive and the taxa are checked in the table
+    let names = db.select_csv(&["scientific_name", "canonical_name", "authorship"], "names");
+    assert_golden("names.csv", &names);
+}
+
+
+#[test]
+fn specimens_pipeline() {
+    let db = TestDatabase::start();
+    let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+    let archive = build_archive(&fixture("specimens"), dir.path());
+    let archive = archive.to_str().unwrap();
+
+    db.oplogger(&["import", archive]);
+    db.oplogger(&["update", "publications"]);
+    db.oplogger(&["update", "nomenclatural-acts"]);
+    db.oplogger(&["update", "collections"]);
+
+    let specimens = db.oplogger(&["reduce", "specimens"]);
+    assert_golden("specimens.csv", &specimens.stdout);
+
+    let publications = db.oplogger(&["reduce", "publications"]);
+    assert_golden("publications.csv", &publications.stdout);
+
+    let acts = db.oplogger(&["reduce", "nomenclatural-acts"]);
+    assert_golden("nomenclatural_acts.csv", &acts.stdout);
+
+    // sequences can't be imported from an archive so they are added as a new version of the dataset
+    let sequences = fixture("sequences").join("sequences.csv");
+    db.oplogger(&[
+        "import-file",
+        "sequences",
+        "ARGA:TEST:0002",
+        "2",
+        "2024-02-01 00:00:00",
+        sequences.to_str().unwrap(),
+    ]);
+
+    let links = db.oplogger(&["reduce", "sequence-links"]);
+    assert_golden("sequence_links.csv", &links.stdout);
+}
+
+
+#[test]
+fn sources_registry() {
+    let db = TestDatabase::start();
+    let sources = fixture("registry").join("sources.csv");
+
+    db.oplogger(&["import-file", "sources", sources.to_str().unwrap()]);
+
+    let exported = db.oplogger(&["reduce", "sources"]);
+    assert_golden("sources.csv", &exported.stdout);
+}
+
+
+#[test]
+fn reimporting_an_archive_is_idempotent() {
+    let db = TestDatabase::start();
+    let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+    let archive = build_archive(&fixture("taxonomy"), dir.path());
+    let archive = archive.to_str().unwrap();
+
+    db.oplogger(&["import", archive]);
+    let first = db.oplogger(&["reduce", "taxa"]);
+
+    db.oplogger(&["import", archive]);
+    let second = db.oplogger(&["reduce", "taxa"]);
+
+    assert_eq!(sorted_lines(&first.stdout), sorted_lines(&second.stdout));
+    db.oplogger(&["verify-determinism", "--table", "taxa", "--parallelism", "2"]);
+}
+
+
+#[test]
+fn updating_unchanged_taxa_skips_every_entity() {
+    let db = TestDatabase::start();
+    let dir = tempfile::tempdir().expect("Failed to create a temp dir");
+    let archive = build_archive(&fixture("taxonomy"), dir.path());
+    let archive = archive.to_str().unwrap();
+
+    db.oplogger(&["import", archive]);
+    db.oplogger(&["update", "taxa"]);
+
+    let logs = tempfile::tempdir().expect("Failed to create a temp dir");
+    let second = logs.path().join("second.log");
+    db.oplogger(&["update", "taxa", "--log-file", second.to_str().unwrap()]);
+    assert!(read_logs(logs.path(), "second.log").contains(r#""written":0"#));
+
+    let full = logs.path().join("full.log");
+    db.oplogger(&["update", "taxa", "--full", "--log-file", full.to_str().unwrap()]);
+    assert!(read_logs(logs.path(), "full.log").contains(r#""skipped":0"#));
+}
+
+
+#[test]
+fn missing_archive_exits_with_config_error() {
+    let db = TestDatabase::start();
+
+    let output = db.oplogger_unchecked(&["import", "does-not-exist.tar", "--json-errors"]);
+    assert_eq!(output.status.code(), Some(3));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let last_line = stderr.lines().last().expect("Nothing written to stderr");
+    let summary: serde_json::Value = serde_json::from_str(last_line).expect("Invalid error summary");
+    assert_eq!(summary["category"], "config");
+    assert_eq!(summary["exit_code"], 3);
+}
+
+
+fn sorted_lines(output: &[u8]) -> Vec<String> {
+    let mut lines: Vec<String> = String::from_utf8_lossy(output).lines().map(String::from).collect();
+    lines.sort();
+    lines
+}
+
+
+/// Read the rotated log files with the prefix, which have the date appended to their name
+fn read_logs(dir: &std::path::Path, prefix: &str) -> String {
+    let mut logs = String::new();
+    for entry in std::fs::read_dir(dir).expect("Failed to read the log dir") {
+        let path = entry.expect("Failed to read the log dir").path();
+        if path.file_name().unwrap().to_string_lossy().starts_with(prefix) {
+            logs.push_str(&std::fs::read_to_string(path).expect("Failed to read the log file"));
+        }
+    }
+    logs
+}